@@ -1,17 +1,30 @@
 use crate::datastore::Index::Persisted;
 use crate::errors::NotusError;
 use crate::file_ops::{
-    create_new_file_pair, fetch_file_pairs, get_lock_file, ActiveFilePair, FilePair,
+    create_new_file_pair, fetch_file_pairs, find_file_pairs, list_prepared_batches,
+    open_active_file_pair, read_prepared_batch, remove_prepared_batch, reset_manifest,
+    write_manifest, write_prepared_batch, ActiveFilePair, FileHandleCache, FilePair, ValueLog,
 };
-use crate::schema::{DataEntry, Encoder, Decoder};
-use fs2::FileExt;
+use crate::nutos::{EvictionPolicy, NotusOptions};
+use crate::storage::{LocalStorage, Storage};
+use crate::schema::{
+    is_expired, record_size, Compression, DataEntry, Decoder, Encoder, HintEntry, IntegrityAlgo,
+    ValuePointer, DATA_ENTRY_HEADER_SIZE,
+};
+use chrono::Utc;
+use crc::{Crc, CRC_64_ECMA_182};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::alloc::Global;
-use std::collections::{BTreeMap, HashMap};
-use std::fs::File;
-use std::ops::{RangeFrom, RangeBounds, Range, RangeInclusive, RangeToInclusive, RangeFull, Bound};
-use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::{RangeFrom, RangeBounds, Range, RangeInclusive, RangeToInclusive, RangeFull, Bound, Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use std::time::{Duration, Instant};
 use std::ops;
 
 use crate::Result;
@@ -21,115 +34,595 @@ pub trait MergeOperator: Fn(&[u8], Option<Vec<u8>>, &[u8]) -> Option<Vec<u8>> {}
 
 impl<F> MergeOperator for F where F: Fn(&[u8], Option<Vec<u8>>, &[u8]) -> Option<Vec<u8>> {}
 
+/// A column registered with `DataStore::create_column_family`.
+#[derive(Clone)]
 pub struct Column {
-    merge_operator: Box<dyn MergeOperator>,
+    merge_operator: Arc<dyn MergeOperator + Send + Sync>,
 }
 
 pub const DEFAULT_INDEX: &str = "$0";
+/// Store level metadata, currently only the application version.
+const META_FILE_NAME: &str = "nutos.meta";
+/// Holds the `Encoding` the keys of the store are written in.
+const ENCODING_FILE_NAME: &str = "nutos.encoding";
+/// Column of records written with `append_raw`, kept apart so they never
+/// show up in the default column once the store is reopened.
+pub const RAW_INDEX: &str = "$raw";
+/// Column holding a marker for each key reserved with `Notus::reserve` and
+/// not fulfilled yet.
+pub const RESERVED_INDEX: &str = "$reserved";
+/// Column holding a marker for each prepared batch whose commit has been
+/// written but not cleaned up yet, so a commit cut short by a crash is not
+/// applied twice.
+const PREPARED_INDEX: &str = "$prepared";
+
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
 
-#[derive(Clone, Serialize, Deserialize)]
+/// A key qualified by the column it belongs to. The encoded form is what gets
+/// stored on disk and in the index: the column length as a big-endian `u32`,
+/// the column name, then the key bytes. Keys of one column therefore share a
+/// prefix and keep their natural byte order within it.
+///
+/// Across columns the index is ordered by the length of the column name,
+/// then by the name, so all of column `"b"` comes before column `"aa"`. As
+/// the length comes first no column's prefix starts with another's, and the
+/// keys of a column are one contiguous run of the index whatever their bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RawKey(pub String, pub Vec<u8>);
 
 impl RawKey {
+    pub fn new(column: &str, key: Vec<u8>) -> Self {
+        Self(column.to_string(), key)
+    }
+
     fn default(key: Vec<u8>) -> Self {
-        Self {
-            0: DEFAULT_INDEX.to_string(),
-            1: key,
+        Self(DEFAULT_INDEX.to_string(), key)
+    }
+
+    /// Returns the prefix shared by every encoded key of `column`.
+    pub fn column_prefix(column: &str) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(4 + column.len());
+        prefix.extend_from_slice(&(column.len() as u32).to_be_bytes());
+        prefix.extend_from_slice(column.as_bytes());
+        prefix
+    }
+
+    /// Maps a range of keys in `column` to the equivalent range of encoded keys.
+    pub fn column_range<R>(column: &str, range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>)
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let encode = |key: &Vec<u8>| RawKey::new(column, key.clone()).encode();
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(encode(key)),
+            Bound::Excluded(key) => Bound::Excluded(encode(key)),
+            Bound::Unbounded => Bound::Included(Self::column_prefix(column)),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(encode(key)),
+            Bound::Excluded(key) => Bound::Excluded(encode(key)),
+            Bound::Unbounded => match prefix_successor(&Self::column_prefix(column)) {
+                Some(successor) => Bound::Excluded(successor),
+                None => Bound::Unbounded,
+            },
+        };
+        (start, end)
+    }
+
+    /// Decodes an encoded key, returning `None` if it is malformed.
+    pub fn from_encoded(encoded: &[u8]) -> Option<Self> {
+        Self::decode(&mut Cursor::new(encoded)).ok()
+    }
+}
+
+/// Returns the smallest byte string greater than every string starting with
+/// `prefix`, or `None` if there is none.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
         }
     }
+    None
 }
 
 impl Encoder for RawKey {
     fn encode(&self) -> Vec<u8> {
-        let mut encoded_bytes = Vec::with_capacity(8 + (self.0.len() + self.1.len()));
-        let key_size = (self.1.len() as u32).to_be_bytes();
-        let column_size = (self.0.len() as u32).to_be_bytes();
-
-
-        encoded_bytes.extend_from_slice(&column_size);
-        encoded_bytes.extend_from_slice(&key_size);
-        encoded_bytes.extend_from_slice(self.0.as_bytes());
+        let mut encoded_bytes = Self::column_prefix(&self.0);
         encoded_bytes.extend_from_slice(&self.1);
-        encoded_bytes.shrink_to_fit();
         encoded_bytes
     }
 }
 
 impl Decoder for RawKey {
     fn decode<R: Read>(rdr: &mut R) -> Result<Self> where Self: Sized {
-        let mut raw_key_size = [0_u8; 4];
         let mut raw_column_size = [0_u8; 4];
-
         rdr.read_exact(&mut raw_column_size)?;
-        rdr.read_exact(&mut raw_key_size)?;
+        let column_size = u32::from_be_bytes(raw_column_size);
 
-        let key_size = u32::from_be_bytes(raw_key_size);
-        let column_size = u32::from_be_bytes(raw_key_size);
-
-        let mut column = Vec::with_capacity(column_size as usize);
-        let mut key = Vec::with_capacity(key_size as usize);
+        let mut column = vec![0_u8; column_size as usize];
         rdr.read_exact(&mut column)?;
-        rdr.read_exact(&mut key)?;
+        let mut key = vec![];
+        rdr.read_to_end(&mut key)?;
 
         Ok(Self(String::from_utf8(column)?, key))
     }
 }
 
+/// How the keys of a store are laid out on disk, see
+/// `DataStore::migrate_encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Keys stored as given, as stores written before columns were
+    /// introduced hold them. Every key belongs to the default column.
+    Unqualified,
+    /// Keys qualified by their column, see `RawKey`.
+    ColumnPrefixed,
+}
+
+impl Encoding {
+    fn id(&self) -> u8 {
+        match self {
+            Encoding::Unqualified => 0,
+            Encoding::ColumnPrefixed => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Encoding::Unqualified),
+            1 => Ok(Encoding::ColumnPrefixed),
+            _ => Err(NotusError::CorruptValue),
+        }
+    }
+
+    /// Reads the encoding recorded in `storage`, `None` if none is.
+    fn read(storage: &Arc<dyn Storage>) -> Result<Option<Self>> {
+        if !storage.list()?.iter().any(|name| name == ENCODING_FILE_NAME) {
+            return Ok(None);
+        }
+        let file = storage.open(ENCODING_FILE_NAME)?;
+        if file.is_empty()? {
+            return Ok(None);
+        }
+        let mut id = [0_u8];
+        file.read_at(0, &mut id)?;
+        Self::from_id(id[0]).map(Some)
+    }
+
+    fn write(&self, storage: &Arc<dyn Storage>) -> Result<()> {
+        if !storage.list()?.iter().any(|name| name == ENCODING_FILE_NAME) {
+            storage.create(ENCODING_FILE_NAME)?;
+        }
+        let file = storage.open_append(ENCODING_FILE_NAME)?;
+        file.truncate()?;
+        file.append(&[self.id()])?;
+        file.close()
+    }
+
+    /// Migrates the keys in `storage`, which must be locked, to be qualified
+    /// by column if they are not yet. Stores written before keys were
+    /// qualified hold records but no encoding, a store without records is
+    /// given one. Returns whether the keys were migrated.
+    fn upgrade(storage: &Arc<dyn Storage>, file_pairs: &BTreeMap<String, FilePair>) -> Result<bool> {
+        let encoding = match Self::read(storage)? {
+            Some(encoding) => encoding,
+            None => {
+                let mut has_records = false;
+                for fp in file_pairs.values() {
+                    has_records |= !fp.is_unused()?;
+                }
+                if has_records {
+                    Encoding::Unqualified
+                } else {
+                    Encoding::ColumnPrefixed.write(storage)?;
+                    Encoding::ColumnPrefixed
+                }
+            }
+        };
+        if encoding == Encoding::ColumnPrefixed {
+            return Ok(false);
+        }
+        let migrated = DataStore::migrate_locked(storage, encoding, Encoding::ColumnPrefixed)?;
+        warn!("keys were not qualified by column, migrated {} records", migrated);
+        Ok(true)
+    }
+
+    fn decode(&self, stored: &[u8]) -> Result<RawKey> {
+        match self {
+            Encoding::Unqualified => Ok(RawKey::default(stored.to_vec())),
+            Encoding::ColumnPrefixed => RawKey::from_encoded(stored).ok_or(NotusError::CorruptValue),
+        }
+    }
+
+    fn encode(&self, key: &RawKey) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Unqualified if key.0 != DEFAULT_INDEX => {
+                Err(NotusError::UnsupportedMigration(format!(
+                    "keys of column {} can not be stored unqualified",
+                    key.0
+                )))
+            }
+            Encoding::Unqualified => Ok(key.1.clone()),
+            Encoding::ColumnPrefixed => Ok(key.encode()),
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct KeyDirEntry {
     file_id: String,
     key_size: u64,
     value_size: u64,
     data_entry_position: u64,
+    timestamp: i64,
+    expires_at: Option<i64>,
+    compression: Compression,
+}
+
+/// The position of a data record in its file and whether the index points
+/// at it.
+pub type RecordPosition = (u64, bool);
+
+/// Where a snapshotted value lives: a record on disk, with the file pair it
+/// was in, or a copy of an entry that was still in the write buffer when the
+/// snapshot was taken.
+#[derive(Debug, Clone)]
+pub enum SnapshotEntry {
+    Persisted(FilePair, KeyDirEntry),
+    Buffered(DataEntry),
+}
+
+impl SnapshotEntry {
+    /// Returns true if both entries refer to the same stored record.
+    pub fn same_record(&self, other: &SnapshotEntry) -> bool {
+        match (self, other) {
+            (SnapshotEntry::Persisted(_, a), SnapshotEntry::Persisted(_, b)) => {
+                a.file_id == b.file_id && a.data_entry_position == b.data_entry_position
+            }
+            (SnapshotEntry::Buffered(a), SnapshotEntry::Buffered(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 enum Index {
     Persisted(KeyDirEntry),
-    InBuffer,
+    /// The latest write is in the write buffer, expiring at the time held.
+    InBuffer(Option<i64>),
+}
+
+impl Index {
+    fn is_expired(&self) -> bool {
+        match self {
+            Persisted(entry) => is_expired(entry.expires_at),
+            Index::InBuffer(expires_at) => is_expired(*expires_at),
+        }
+    }
 }
 
 impl KeyDirEntry {
-    pub fn new(file_id: String, key_size: u64, value_size: u64, pos: u64) -> Self {
+    pub fn new(file_id: String, key_size: u64, value_size: u64, pos: u64, timestamp: i64) -> Self {
         KeyDirEntry {
             file_id,
             key_size,
             value_size,
             data_entry_position: pos,
+            timestamp,
+            expires_at: None,
+            compression: Compression::None,
         }
     }
+
+    /// Notes that the record expires at `expires_at`, see
+    /// `DataEntry::with_expiry`.
+    pub fn with_expiry(mut self, expires_at: Option<i64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// Notes that the value of the record is compressed, so it can not be
+    /// read in part at an offset.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// The size of the data record the entry points at.
+    fn record_size(&self) -> u64 {
+        record_size(self.key_size, self.value_size, self.expires_at)
+    }
+}
+
+/// Space used by one column, as reported by `DataStore::column_stats`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub live_keys: u64,
+    /// Sum of the value sizes of the live keys.
+    pub value_bytes: u64,
+    /// Bytes of data records no longer reachable through the index, which
+    /// the next compaction reclaims.
+    pub dead_bytes: u64,
+}
+
+/// Result of `DataStore::audit`. Each list holds the keys that failed the
+/// corresponding check.
+#[derive(Debug, Default, Clone)]
+pub struct AuditReport {
+    /// Number of index entries checked against the data files.
+    pub checked: usize,
+    /// Index entries whose data record could not be read.
+    pub unreadable: Vec<Vec<u8>>,
+    /// Index entries whose data record failed the CRC check.
+    pub corrupt: Vec<Vec<u8>>,
+    /// Index entries pointing at a record for a different key.
+    pub key_mismatch: Vec<Vec<u8>>,
+    /// Keys live according to the hint files but missing from the index.
+    pub missing_from_index: Vec<Vec<u8>>,
+}
+
+impl AuditReport {
+    pub fn is_ok(&self) -> bool {
+        self.unreadable.is_empty()
+            && self.corrupt.is_empty()
+            && self.key_mismatch.is_empty()
+            && self.missing_from_index.is_empty()
+    }
+}
+
+/// Result of `DataStore::inspect`.
+#[derive(Debug, Default, Clone)]
+pub struct Inspection {
+    /// The column qualified keys a store opened on the files would hold, in
+    /// order.
+    pub keys: Vec<Vec<u8>>,
+    /// Pairs whose hint file is damaged, or holding a live record that can
+    /// not be read, fails its CRC check or belongs to another key.
+    pub corrupt_files: Vec<String>,
+    /// Pairs without a hint file, their keys are taken from the data file
+    /// the way opening the store would rebuild it.
+    pub missing_hints: Vec<String>,
+    /// The file id and position of every data record that no key points
+    /// at, e.g. versions overwritten since the last compaction.
+    pub orphans: Vec<(String, u64)>,
+}
+
+impl Inspection {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_files.is_empty() && self.missing_hints.is_empty()
+    }
 }
 
 type MultiMap<I, K, V> = BTreeMap<I, BTreeMap<K, V>>;
 
 pub struct KeysDir {
     keys: RwLock<BTreeMap<Vec<u8>, Index>>,
+    /// While older files are still waiting to be replayed, the keys that must
+    /// not be brought back by them: keys removed since open and keys whose
+    /// newest hint is a tombstone.
+    shadowed: Mutex<Option<HashSet<Vec<u8>>>>,
+    /// Keys whose latest write is a tombstone, with the file holding it.
+    /// Entries are dropped once compaction removes that file.
+    tombstones: RwLock<HashMap<Vec<u8>, String>>,
+    /// Set by `NotusOptions::hash_keys_over`, see `index_key`.
+    hash_keys_over: Option<usize>,
+}
+
+const KEY_HASH: Crc<u64> = Crc::<u64>::new(&CRC_64_ECMA_182);
+
+/// The form `key` is indexed in. With `hash_keys_over` set to `n`, a key
+/// longer than `n` bytes is replaced by its first `n - 8` bytes followed by
+/// an 8 byte hash of the whole key, so no indexed key is longer than `n`.
+/// The column prefix of a column qualified key is always kept whole, so
+/// the key stays within its column's range. Applying it to a key in that
+/// form returns it unchanged.
+fn hashed_key(key: &[u8], hash_keys_over: Option<usize>) -> Cow<'_, [u8]> {
+    match hash_keys_over.map(|max_len| hashed_len(key, max_len)) {
+        Some(hashed_len) if key.len() > hashed_len => {
+            let mut hashed = key[..hashed_len - 8].to_vec();
+            hashed.extend_from_slice(&KEY_HASH.checksum(key).to_be_bytes());
+            Cow::Owned(hashed)
+        }
+        _ => Cow::Borrowed(key),
+    }
+}
+
+/// Length of `key` once hashed with `hash_keys_over` set to `max_len`,
+/// raised so that the column prefix of `key` and the hash both fit.
+fn hashed_len(key: &[u8], max_len: usize) -> usize {
+    let prefix_len = match key.get(..4) {
+        Some(len) => 4 + u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize,
+        None => 0,
+    };
+    if prefix_len > key.len() {
+        return max_len;
+    }
+    max_len.max(prefix_len + 8)
 }
 
 impl KeysDir {
+    /// The form `key` is held in, see `hashed_key`.
+    pub fn index_key<'k>(&self, key: &'k [u8]) -> Cow<'k, [u8]> {
+        hashed_key(key, self.hash_keys_over)
+    }
+
+    /// Whether `key`, a key written to the store, is held as a hash.
+    pub fn is_hashed(&self, key: &[u8]) -> bool {
+        self.hash_keys_over
+            .is_some_and(|max_len| key.len() > hashed_len(key, max_len))
+    }
+
+    /// Whether `key`, as listed by the index, may stand for a longer key
+    /// that has to be read back from its record.
+    pub fn may_be_hashed(&self, key: &[u8]) -> bool {
+        self.hash_keys_over
+            .is_some_and(|max_len| key.len() == hashed_len(key, max_len))
+    }
+
     pub fn insert(&self, key: Vec<u8>, value: KeyDirEntry) -> Result<()> {
+        let key = self.index_key(&key).into_owned();
         let mut keys_dir_writer = self
             .keys
             .write()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        self.tombstones()?.remove(&key);
         keys_dir_writer.insert(key, Index::Persisted(value));
         Ok(())
     }
 
-    pub fn partial_insert(&self, key: Vec<u8>) -> Result<()> {
+    /// Points `key` at `to` if it still points at the record at `position`
+    /// of file `file_id`. Returns `false`, leaving the index alone, if the
+    /// key was written or removed since.
+    pub fn relocate(&self, key: &[u8], file_id: &str, position: u64, to: KeyDirEntry) -> Result<bool> {
+        let mut keys_dir_writer = self
+            .keys
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        match keys_dir_writer.get_mut(self.index_key(key).as_ref()) {
+            Some(Persisted(entry))
+                if entry.file_id == file_id && entry.data_entry_position == position =>
+            {
+                *entry = to;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn partial_insert(&self, key: Vec<u8>, expires_at: Option<i64>) -> Result<()> {
+        let key = self.index_key(&key).into_owned();
+        let mut keys_dir_writer = self
+            .keys
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        self.tombstones()?.remove(&key);
+        keys_dir_writer.insert(key, Index::InBuffer(expires_at));
+        Ok(())
+    }
+
+    /// Removes `key` if it still points at the expired record at `position`
+    /// of file `file_id`, for a compaction that drops the record. Returns
+    /// `false`, leaving the index alone, if the key was written since.
+    pub fn remove_expired(&self, key: &[u8], file_id: &str, position: u64) -> Result<bool> {
+        let mut keys_dir_writer = self
+            .keys
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let key = self.index_key(key);
+        match keys_dir_writer.get(key.as_ref()) {
+            Some(Persisted(entry))
+                if entry.file_id == file_id
+                    && entry.data_entry_position == position
+                    && is_expired(entry.expires_at) =>
+            {
+                keys_dir_writer.remove(key.as_ref());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Adds `entries`, sorted by key, building them into a tree in one pass
+    /// instead of inserting them one at a time.
+    pub fn extend_sorted(&self, entries: Vec<(Vec<u8>, KeyDirEntry)>) -> Result<()> {
+        let mut loaded: BTreeMap<Vec<u8>, Index> = entries
+            .into_iter()
+            .map(|(key, entry)| (self.index_key(&key).into_owned(), Index::Persisted(entry)))
+            .collect();
+        let mut keys_dir_writer = self
+            .keys
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        {
+            let mut tombstones = self.tombstones()?;
+            if !tombstones.is_empty() {
+                for key in loaded.keys() {
+                    tombstones.remove(key);
+                }
+            }
+        }
+        keys_dir_writer.append(&mut loaded);
+        Ok(())
+    }
+
+    /// Applies the index updates of a batch written to file `file_id`, `None`
+    /// standing for a tombstone, holding the index lock throughout so that
+    /// no reader sees part of the batch.
+    pub fn apply_batch(&self, updates: Vec<(Vec<u8>, Option<KeyDirEntry>)>, file_id: &str) -> Result<()> {
         let mut keys_dir_writer = self
             .keys
             .write()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
-        keys_dir_writer.insert(key, Index::InBuffer);
+        let mut tombstones = self.tombstones()?;
+        let mut shadowed = self.shadowed()?;
+        for (key, entry) in updates {
+            let key = self.index_key(&key).into_owned();
+            match entry {
+                Some(entry) => {
+                    tombstones.remove(&key);
+                    keys_dir_writer.insert(key, Index::Persisted(entry));
+                }
+                None => {
+                    keys_dir_writer.remove(&key);
+                    if let Some(shadowed) = shadowed.as_mut() {
+                        shadowed.insert(key.clone());
+                    }
+                    tombstones.insert(key, file_id.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `key` and remembers that its latest write is the tombstone in
+    /// file `file_id`.
+    pub fn tombstone(&self, key: &[u8], file_id: &str) -> Result<()> {
+        self.remove(key)?;
+        self.tombstones()?
+            .insert(self.index_key(key).into_owned(), file_id.to_string());
+        Ok(())
+    }
+
+    /// Returns whether the latest write of `key` is a tombstone that has not
+    /// been compacted away yet.
+    pub fn is_tombstoned(&self, key: &[u8]) -> Result<bool> {
+        Ok(self
+            .tombstones
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .contains_key(self.index_key(key).as_ref()))
+    }
+
+    /// Forgets the tombstones held by `file_ids`, called once the files are
+    /// removed.
+    pub fn forget_tombstones(&self, file_ids: &[String]) -> Result<()> {
+        self.tombstones()?
+            .retain(|_, file_id| !file_ids.contains(file_id));
         Ok(())
     }
+
+    fn tombstones(&self) -> Result<RwLockWriteGuard<'_, HashMap<Vec<u8>, String>>> {
+        self.tombstones
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
     pub fn remove(&self, key: &[u8]) -> Result<()> {
         let mut keys_dir_writer = self
             .keys
             .write()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
-        keys_dir_writer.remove(key);
+        let key = self.index_key(key);
+        keys_dir_writer.remove(key.as_ref());
+        if let Some(shadowed) = self.shadowed()?.as_mut() {
+            shadowed.insert(key.into_owned());
+        }
         Ok(())
     }
 
@@ -150,7 +643,11 @@ impl KeysDir {
             }
         };
 
-        keys_dir_reader.iter().map(|(k, _)| k.clone()).collect()
+        keys_dir_reader
+            .iter()
+            .filter(|(_, index)| !index.is_expired())
+            .map(|(k, _)| k.clone())
+            .collect()
     }
 
     pub fn range<R>(&self, range : R) -> Vec<Vec<u8>> where R : RangeBounds<Vec<u8>> {
@@ -160,7 +657,31 @@ impl KeysDir {
                 return vec![];
             }
         };
-        keys_dir_reader.range(range).map(|(k, _)| k.clone()).collect()
+        keys_dir_reader
+            .range(range)
+            .filter(|(_, index)| !index.is_expired())
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Returns the keys in `range` with their entry, or `None` for keys whose
+    /// latest value is still in the write buffer.
+    pub fn range_entries<R>(&self, range: R) -> Result<Vec<(Vec<u8>, Option<KeyDirEntry>)>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let keys_dir_reader = self
+            .keys
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(keys_dir_reader
+            .range(range)
+            .filter(|(_, index)| !index.is_expired())
+            .map(|(k, index)| match index {
+                Persisted(entry) => (k.clone(), Some(entry.clone())),
+                Index::InBuffer(_) => (k.clone(), None),
+            })
+            .collect())
     }
 
     pub fn prefix(&self, prefix: &Vec<u8>) -> Vec<Vec<u8>> {
@@ -173,10 +694,105 @@ impl KeysDir {
         keys_dir_reader
             .range(prefix.clone()..)
             .take_while(|(k, _)| k.starts_with(prefix))
+            .filter(|(_, index)| !index.is_expired())
             .map(|(k, _)| k.clone())
             .collect()
     }
 
+    /// Counts the live keys in `range` without cloning them.
+    pub fn count<R>(&self, range: R) -> usize
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let keys_dir_reader = match self.keys.read() {
+            Ok(rdr) => rdr,
+            Err(_) => {
+                return 0;
+            }
+        };
+        keys_dir_reader
+            .range(range)
+            .filter(|(_, index)| !index.is_expired())
+            .count()
+    }
+
+    /// Returns whether any key starts with `prefix`, looking at the first
+    /// key at or after it only.
+    pub fn contains_prefix(&self, prefix: &[u8]) -> bool {
+        let keys_dir_reader = match self.keys.read() {
+            Ok(rdr) => rdr,
+            Err(_) => {
+                return false;
+            }
+        };
+        keys_dir_reader
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .any(|(_, index)| !index.is_expired())
+    }
+
+    /// Returns the columns holding live keys in index order, seeking past
+    /// each column once one of its keys is found.
+    pub fn columns(&self) -> Vec<String> {
+        let keys_dir_reader = match self.keys.read() {
+            Ok(rdr) => rdr,
+            Err(_) => {
+                return vec![];
+            }
+        };
+        let mut columns = vec![];
+        let mut start = Bound::Unbounded;
+        while let Some((key, _)) = keys_dir_reader
+            .range((start, Bound::Unbounded))
+            .find(|(_, index)| !index.is_expired())
+        {
+            start = match RawKey::from_encoded(key) {
+                Some(RawKey(column, _)) => {
+                    let successor = prefix_successor(&RawKey::column_prefix(&column));
+                    columns.push(column);
+                    match successor {
+                        Some(successor) => Bound::Included(successor),
+                        None => break,
+                    }
+                }
+                None => Bound::Excluded(key.clone()),
+            };
+        }
+        columns
+    }
+
+    /// Returns keys whose latest write has a timestamp of at least `timestamp`.
+    /// Keys still waiting in the write buffer are always included.
+    pub fn modified_since(&self, timestamp: i64) -> Result<Vec<Vec<u8>>> {
+        let keys_dir_reader = self
+            .keys
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(keys_dir_reader
+            .iter()
+            .filter(|(_, index)| match index {
+                Persisted(entry) => entry.timestamp >= timestamp,
+                Index::InBuffer(_) => true,
+            })
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    /// Returns the keys and entries that have been written to disk.
+    pub fn entries(&self) -> Result<Vec<(Vec<u8>, KeyDirEntry)>> {
+        let keys_dir_reader = self
+            .keys
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(keys_dir_reader
+            .iter()
+            .filter_map(|(k, index)| match index {
+                Persisted(entry) if !is_expired(entry.expires_at) => Some((k.clone(), entry.clone())),
+                _ => None,
+            })
+            .collect())
+    }
+
     pub fn get(&self, key: &[u8]) -> Option<KeyDirEntry> {
         let keys_dir_reader = match self.keys.read() {
             Ok(rdr) => rdr,
@@ -184,223 +800,2871 @@ impl KeysDir {
                 return None;
             }
         };
-        match keys_dir_reader.get(key) {
+        match keys_dir_reader.get(self.index_key(key).as_ref()) {
             None => None,
             Some(entry) => {
                 if let Persisted(entry) = entry {
+                    if is_expired(entry.expires_at) {
+                        return None;
+                    }
                     return Some(entry.clone());
                 }
-                return None;
+                return None;
+            }
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> Result<bool> {
+        let keys_dir_reader = match self.keys.read() {
+            Ok(rdr) => rdr,
+            Err(error) => {
+                return Err(NotusError::RWLockPoisonError(format!("{}", error)));
+            }
+        };
+
+        Ok(keys_dir_reader
+            .get(self.index_key(key).as_ref())
+            .is_some_and(|index| !index.is_expired()))
+    }
+}
+
+impl KeysDir {
+    pub fn new(
+        file_pairs: &BTreeMap<String, FilePair>,
+        hash_keys_over: Option<usize>,
+    ) -> Result<Self> {
+        let keys = RwLock::new(BTreeMap::new());
+        let keys_dir = Self {
+            keys,
+            shadowed: Mutex::new(None),
+            tombstones: RwLock::new(HashMap::new()),
+            hash_keys_over,
+        };
+        for (_, fp) in file_pairs {
+            fp.fetch_hint_entries(&keys_dir)?;
+        }
+        Ok(keys_dir)
+    }
+
+    /// Replays the newest files first until `budget` has passed and returns
+    /// the older files that were not replayed, newest first. They are applied
+    /// later with `replay_older`.
+    pub fn new_within(
+        file_pairs: &BTreeMap<String, FilePair>,
+        budget: Duration,
+        hash_keys_over: Option<usize>,
+    ) -> Result<(Self, Vec<FilePair>)> {
+        let keys_dir = Self {
+            keys: RwLock::new(BTreeMap::new()),
+            shadowed: Mutex::new(Some(HashSet::new())),
+            tombstones: RwLock::new(HashMap::new()),
+            hash_keys_over,
+        };
+        let started = Instant::now();
+        let mut pending: Vec<FilePair> = file_pairs.values().rev().cloned().collect();
+        while !pending.is_empty() && started.elapsed() < budget {
+            let fp = pending.remove(0);
+            keys_dir.replay_older(&fp)?;
+        }
+        if pending.is_empty() {
+            keys_dir.finish_replay()?;
+        }
+        Ok((keys_dir, pending))
+    }
+
+    /// Applies the hints of `fp`, a file older than every file replayed so
+    /// far. Keys already indexed or shadowed are left untouched.
+    pub fn replay_older(&self, fp: &FilePair) -> Result<()> {
+        let hints = fp.get_hints()?;
+        let mut keys_dir_writer = self
+            .keys
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut shadowed = self.shadowed()?;
+        let shadowed = match shadowed.as_mut() {
+            Some(shadowed) => shadowed,
+            None => return Ok(()),
+        };
+        // The last hint of a key within the file is the one that counts
+        for hint in hints.into_iter().rev() {
+            let key = self.index_key(&hint.key()).into_owned();
+            if keys_dir_writer.contains_key(&key) || shadowed.contains(&key) {
+                continue;
+            }
+            if hint.is_deleted() {
+                self.tombstones()?.insert(key.clone(), fp.file_id());
+                shadowed.insert(key);
+            } else {
+                let entry = KeyDirEntry::new(
+                    fp.file_id(),
+                    hint.key_size(),
+                    hint.value_size(),
+                    hint.data_entry_position(),
+                    hint.timestamp(),
+                )
+                .with_expiry(hint.expires_at())
+                .with_compression(hint.compression());
+                keys_dir_writer.insert(key, Index::Persisted(entry));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops tracking shadowed keys once every file has been replayed.
+    pub fn finish_replay(&self) -> Result<()> {
+        *self.shadowed()? = None;
+        Ok(())
+    }
+
+    fn shadowed(&self) -> Result<MutexGuard<'_, Option<HashSet<Vec<u8>>>>> {
+        self.shadowed
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
+}
+
+#[derive(Default)]
+struct EvictionState {
+    live_bytes: u64,
+    clock: u64,
+    order: BTreeMap<u64, Vec<u8>>,
+    keys: HashMap<Vec<u8>, (u64, u64)>,
+}
+
+/// Tracks the size of every live key and the order they were used in, so
+/// the store can be kept under `max_total_bytes`.
+struct Eviction {
+    max_total_bytes: u64,
+    policy: EvictionPolicy,
+    state: Mutex<EvictionState>,
+    /// Keys are tracked in the form the index holds them in.
+    hash_keys_over: Option<usize>,
+}
+
+impl Eviction {
+    /// Seeds the tracker from the index, ordering keys by write time.
+    fn new(max_total_bytes: u64, policy: EvictionPolicy, keys_dir: &KeysDir) -> Result<Self> {
+        let eviction = Self {
+            max_total_bytes,
+            policy,
+            state: Mutex::new(EvictionState::default()),
+            hash_keys_over: keys_dir.hash_keys_over,
+        };
+        let mut entries = keys_dir.entries()?;
+        entries.sort_by_key(|(_, entry)| entry.timestamp);
+        for (key, entry) in entries {
+            eviction.record_write(&key, entry.key_size + entry.value_size)?;
+        }
+        Ok(eviction)
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, EvictionState>> {
+        self.state
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
+
+    fn record_write(&self, key: &[u8], size: u64) -> Result<()> {
+        let key = hashed_key(key, self.hash_keys_over);
+        let mut state = self.state()?;
+        state.clock += 1;
+        let tick = state.clock;
+        if let Some((old_tick, old_size)) = state.keys.insert(key.to_vec(), (tick, size)) {
+            state.order.remove(&old_tick);
+            state.live_bytes -= old_size;
+        }
+        state.order.insert(tick, key.to_vec());
+        state.live_bytes += size;
+        Ok(())
+    }
+
+    fn record_read(&self, key: &[u8]) -> Result<()> {
+        if self.policy != EvictionPolicy::Lru {
+            return Ok(());
+        }
+        let mut state = self.state()?;
+        state.clock += 1;
+        let tick = state.clock;
+        let key = hashed_key(key, self.hash_keys_over);
+        let old_tick = match state.keys.get_mut(key.as_ref()) {
+            Some((key_tick, _)) => std::mem::replace(key_tick, tick),
+            None => return Ok(()),
+        };
+        state.order.remove(&old_tick);
+        state.order.insert(tick, key.to_vec());
+        Ok(())
+    }
+
+    fn record_delete(&self, key: &[u8]) -> Result<()> {
+        let key = hashed_key(key, self.hash_keys_over);
+        let mut state = self.state()?;
+        if let Some((tick, size)) = state.keys.remove(key.as_ref()) {
+            state.order.remove(&tick);
+            state.live_bytes -= size;
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.state()? = EvictionState::default();
+        Ok(())
+    }
+
+    /// Returns the next key to evict, or `None` if the store fits.
+    fn next_victim(&self) -> Result<Option<Vec<u8>>> {
+        let state = self.state()?;
+        if state.live_bytes <= self.max_total_bytes {
+            return Ok(None);
+        }
+        Ok(state.order.values().next().cloned())
+    }
+}
+
+type IndexCopy = Arc<HashMap<Vec<u8>, KeyDirEntry>>;
+
+/// A copy of the persisted part of the index that `get` reads from when
+/// stale reads are enabled. Readers only hold the lock long enough to clone
+/// the `Arc`, so they never wait on writers updating the real index.
+struct StaleIndex {
+    max_staleness: Duration,
+    copy: RwLock<(Instant, IndexCopy)>,
+}
+
+impl StaleIndex {
+    fn new(max_staleness: Duration, keys_dir: &KeysDir) -> Result<Self> {
+        Ok(Self {
+            max_staleness,
+            copy: RwLock::new((Instant::now(), Arc::new(Self::build(keys_dir)?))),
+        })
+    }
+
+    fn build(keys_dir: &KeysDir) -> Result<HashMap<Vec<u8>, KeyDirEntry>> {
+        Ok(keys_dir.entries()?.into_iter().collect())
+    }
+
+    /// Returns the copy, or `None` once it is older than `max_staleness`.
+    fn current(&self) -> Result<Option<IndexCopy>> {
+        let copy = self
+            .copy
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        if copy.0.elapsed() > self.max_staleness {
+            return Ok(None);
+        }
+        Ok(Some(copy.1.clone()))
+    }
+
+    /// Rebuilds the copy once half of `max_staleness` has passed, so a
+    /// rebuild that runs late still lands within the bound.
+    fn refresh(&self, keys_dir: &KeysDir) -> Result<()> {
+        let built_at = self
+            .copy
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .0;
+        if built_at.elapsed() < self.max_staleness / 2 {
+            return Ok(());
+        }
+        let built_at = Instant::now();
+        let entries = Arc::new(Self::build(keys_dir)?);
+        *self
+            .copy
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))? = (built_at, entries);
+        Ok(())
+    }
+}
+
+/// An immutable copy of the index made by `DataStore::freeze`: a sorted
+/// array searched without locking, with file ids stored once.
+pub struct FrozenIndex {
+    files: Vec<FilePair>,
+    entries: Box<[(Box<[u8]>, FrozenEntry)]>,
+}
+
+struct FrozenEntry {
+    file: u32,
+    position: u64,
+}
+
+impl FrozenIndex {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the file and position of the record of `key`.
+    pub fn get(&self, key: &[u8]) -> Option<(&FilePair, u64)> {
+        let index = self
+            .entries
+            .binary_search_by(|(k, _)| k.as_ref().cmp(key))
+            .ok()?;
+        Some(self.location(index))
+    }
+
+    /// Returns the keys in `range`, in order, with the file and position of
+    /// their record.
+    pub fn range<R>(&self, range: R) -> impl DoubleEndedIterator<Item = (&[u8], &FilePair, u64)>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.entries.partition_point(|(k, _)| k.as_ref() < key.as_slice()),
+            Bound::Excluded(key) => self.entries.partition_point(|(k, _)| k.as_ref() <= key.as_slice()),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.entries.partition_point(|(k, _)| k.as_ref() <= key.as_slice()),
+            Bound::Excluded(key) => self.entries.partition_point(|(k, _)| k.as_ref() < key.as_slice()),
+            Bound::Unbounded => self.entries.len(),
+        };
+        (start..end.max(start)).map(move |index| {
+            let (fp, position) = self.location(index);
+            (self.entries[index].0.as_ref(), fp, position)
+        })
+    }
+
+    fn location(&self, index: usize) -> (&FilePair, u64) {
+        let entry = &self.entries[index].1;
+        (&self.files[entry.file as usize], entry.position)
+    }
+}
+
+/// Returns up to `len` bytes of `value` starting at `offset`.
+fn value_range(value: &[u8], offset: u64, len: usize) -> Vec<u8> {
+    let start = (offset as usize).min(value.len());
+    let end = start.saturating_add(len).min(value.len());
+    value[start..end].to_vec()
+}
+
+/// Chunk size `DataStore::values_equal` reads values in.
+const VALUE_COMPARE_CHUNK: usize = 4096;
+
+/// Where `DataStore::values_equal` reads a value from.
+enum StoredValue<'a> {
+    Buffered(&'a [u8]),
+    /// A compressed value, which is read and decompressed whole.
+    Decompressed(Vec<u8>),
+    /// The file, the position of the value in it and its size.
+    InFile(&'a FilePair, u64, u64),
+    /// The pointer to the value and the size of its key.
+    InValueLog(ValuePointer, u64),
+}
+
+impl StoredValue<'_> {
+    fn size(&self) -> u64 {
+        match self {
+            StoredValue::Buffered(value) => value.len() as u64,
+            StoredValue::Decompressed(value) => value.len() as u64,
+            StoredValue::InFile(_, _, size) => *size,
+            StoredValue::InValueLog(pointer, _) => pointer.value_size,
+        }
+    }
+
+    /// Fills `buf` with the bytes of the value starting at `offset`.
+    fn read(&self, store: &DataStore, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            StoredValue::Buffered(value) => {
+                let start = offset as usize;
+                buf.copy_from_slice(&value[start..start + buf.len()]);
+                Ok(())
+            }
+            StoredValue::Decompressed(value) => {
+                let start = offset as usize;
+                buf.copy_from_slice(&value[start..start + buf.len()]);
+                Ok(())
+            }
+            StoredValue::InFile(fp, position, _) => {
+                store.file_handles.read_at(fp, position + offset, buf)
+            }
+            StoredValue::InValueLog(pointer, key_size) => match &store.value_log {
+                Some(value_log) => value_log.read_value_range(pointer, *key_size, offset, buf),
+                None => Err(NotusError::CorruptValue),
+            },
+        }
+    }
+
+    /// The size of the original key and its length in front of the value,
+    /// see `NotusOptions::key_normalizer`.
+    fn original_key_size(&self, store: &DataStore) -> Result<u64> {
+        if self.size() < 4 {
+            return Err(NotusError::CorruptValue);
+        }
+        let mut len = [0_u8; 4];
+        self.read(store, 0, &mut len)?;
+        let skip = 4 + u32::from_be_bytes(len) as u64;
+        if skip > self.size() {
+            return Err(NotusError::CorruptValue);
+        }
+        Ok(skip)
+    }
+}
+
+/// One stripe of the write buffer.
+type Stripe = HashMap<Vec<u8>, DataEntry>;
+type BufferReadGuard<'a> = BufferGuard<'a, RwLockReadGuard<'a, Stripe>>;
+type BufferWriteGuard<'a> = BufferGuard<'a, RwLockWriteGuard<'a, Stripe>>;
+
+/// Leading bytes of an encoded key, column included, that pick its stripe.
+const STRIPE_PREFIX_LEN: usize = 16;
+
+/// Writes not flushed yet, split into stripes locked on their own, see
+/// `NotusOptions::write_stripes`. Operations on a single key lock the
+/// stripe of the key, others lock every stripe, in order.
+struct WriteBuffer {
+    stripes: Box<[RwLock<Stripe>]>,
+}
+
+impl WriteBuffer {
+    fn new(stripes: usize) -> Self {
+        Self {
+            stripes: (0..stripes.max(1)).map(|_| RwLock::default()).collect(),
+        }
+    }
+
+    fn stripe_of(&self, key: &[u8]) -> usize {
+        if self.stripes.len() == 1 {
+            return 0;
+        }
+        let prefix = &key[..key.len().min(STRIPE_PREFIX_LEN)];
+        (KEY_HASH.checksum(prefix) % self.stripes.len() as u64) as usize
+    }
+
+    fn read(&self) -> Result<BufferReadGuard<'_>> {
+        let stripes = self
+            .stripes
+            .iter()
+            .map(|stripe| stripe.read())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(BufferGuard { buffer: self, stripes, first: 0 })
+    }
+
+    fn write(&self) -> Result<BufferWriteGuard<'_>> {
+        let stripes = self
+            .stripes
+            .iter()
+            .map(|stripe| stripe.write())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(BufferGuard { buffer: self, stripes, first: 0 })
+    }
+
+    /// Read locks only the stripe of `key`.
+    fn read_key(&self, key: &[u8]) -> Result<BufferReadGuard<'_>> {
+        let first = self.stripe_of(key);
+        let stripe = self.stripes[first]
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(BufferGuard { buffer: self, stripes: vec![stripe], first })
+    }
+
+    /// Write locks only the stripe of `key`.
+    fn write_key(&self, key: &[u8]) -> Result<BufferWriteGuard<'_>> {
+        let first = self.stripe_of(key);
+        let stripe = self.stripes[first]
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(BufferGuard { buffer: self, stripes: vec![stripe], first })
+    }
+
+    /// Like `write_key` but returns `None` instead of waiting for the lock.
+    fn try_write_key(&self, key: &[u8]) -> Result<Option<BufferWriteGuard<'_>>> {
+        let first = self.stripe_of(key);
+        match self.stripes[first].try_write() {
+            Ok(stripe) => Ok(Some(BufferGuard { buffer: self, stripes: vec![stripe], first })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Poisoned(e)) => Err(NotusError::RWLockPoisonError(format!("{}", e))),
+        }
+    }
+}
+
+/// Stripes of a `WriteBuffer` locked together, either all of them or the
+/// one of the key being read or written. Looking up a key of a stripe not
+/// held panics.
+struct BufferGuard<'a, G> {
+    buffer: &'a WriteBuffer,
+    stripes: Vec<G>,
+    /// Index of the first stripe held.
+    first: usize,
+}
+
+impl<G: Deref<Target = Stripe>> BufferGuard<'_, G> {
+    fn stripe(&self, key: &[u8]) -> &Stripe {
+        &self.stripes[self.buffer.stripe_of(key) - self.first]
+    }
+
+    /// The buffered entry of `key`, unless it has expired.
+    fn get(&self, key: &[u8]) -> Option<&DataEntry> {
+        self.stripe(key)
+            .get(key)
+            .filter(|entry| !is_expired(entry.expires_at()))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &DataEntry)> {
+        self.stripes.iter().flat_map(|stripe| stripe.iter())
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.iter().map(|(key, _)| key)
+    }
+}
+
+impl<G: DerefMut<Target = Stripe>> BufferGuard<'_, G> {
+    fn stripe_mut(&mut self, key: &[u8]) -> &mut Stripe {
+        let index = self.buffer.stripe_of(key) - self.first;
+        &mut self.stripes[index]
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entry: DataEntry) -> Option<DataEntry> {
+        self.stripe_mut(&key).insert(key, entry)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut DataEntry> {
+        self.stripe_mut(key).get_mut(key)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<DataEntry> {
+        self.stripe_mut(key).remove(key)
+    }
+
+    fn clear(&mut self) {
+        self.stripes.iter_mut().for_each(|stripe| stripe.clear())
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = (Vec<u8>, DataEntry)> + '_ {
+        self.stripes.iter_mut().flat_map(|stripe| stripe.drain())
+    }
+}
+
+/// Read access to a store whose buffer lock is held by
+/// `DataStore::atomically`.
+pub struct LockedStore<'a> {
+    store: &'a DataStore,
+    buffer: &'a BufferWriteGuard<'a>,
+}
+
+impl LockedStore<'_> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .store
+            .read_entry_in(self.buffer, key)?
+            .map(|entry| entry.value()))
+    }
+}
+
+/// Pins taken with `DataStore::pin` and the files whose removal waits for
+/// them.
+#[derive(Default)]
+struct Pins {
+    held: usize,
+    deferred: Vec<String>,
+}
+
+/// The pairs a merge copies records into, filled one after the other up to
+/// about `max_file_size` each. The last one takes whatever is left.
+struct MergeOutput {
+    pairs: Vec<ActiveFilePair>,
+    max_file_size: Option<u64>,
+    current: usize,
+    current_size: u64,
+}
+
+impl MergeOutput {
+    fn new(pairs: Vec<ActiveFilePair>, max_file_size: Option<u64>) -> Self {
+        Self {
+            pairs,
+            max_file_size,
+            current: 0,
+            current_size: 0,
+        }
+    }
+
+    fn write(&mut self, data_entry: &DataEntry) -> Result<KeyDirEntry> {
+        if let Some(max_file_size) = self.max_file_size {
+            if self.current_size >= max_file_size && self.current + 1 < self.pairs.len() {
+                self.current += 1;
+                self.current_size = 0;
+            }
+        }
+        let key_entry = self.pairs[self.current].write(data_entry)?;
+        self.current_size += key_entry.record_size();
+        Ok(key_entry)
+    }
+
+    fn sync(&self) -> Result<()> {
+        for pair in self.pairs.iter() {
+            pair.sync()?;
+        }
+        Ok(())
+    }
+}
+
+pub struct DataStore {
+    storage: Arc<dyn Storage>,
+    active_file: RwLock<ActiveFilePair>,
+    keys_dir: KeysDir,
+    files_dir: RwLock<BTreeMap<String, FilePair>>,
+    file_handles: FileHandleCache,
+    buffer: WriteBuffer,
+    eviction: Option<Eviction>,
+    stale_index: Option<StaleIndex>,
+    /// Files left unreplayed by `recovery_budget`, newest first.
+    pending_replay: Mutex<Vec<FilePair>>,
+    pins: Mutex<Pins>,
+    options: NotusOptions,
+    /// Holds the values when `kv_separation` is set, data records then hold
+    /// an encoded `ValuePointer` instead.
+    value_log: Option<ValueLog>,
+    /// Bytes of data records written since the last compaction began.
+    bytes_written: AtomicU64,
+    integrity: IntegrityAlgo,
+    /// How values are compressed in the data files written from now on.
+    compression: Compression,
+    /// Serializes preparing, committing and aborting batches.
+    prepared_batches: Mutex<()>,
+    /// Columns registered since the store was opened, by name.
+    columns: RwLock<HashMap<String, Column>>,
+}
+
+impl DataStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::open_with_options(dir, NotusOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: NotusOptions) -> Result<Self> {
+        let mut storage = LocalStorage::new(dir)?;
+        if options.mmap_active_file {
+            storage = storage.with_mmap(options.mmap_chunk_size);
+        }
+        Self::open_in(Arc::new(storage), options)
+    }
+
+    /// Reads the file pairs of `storage` the way opening it would and
+    /// reports what a store opened on them would hold, without locking or
+    /// changing anything, so a store in use by someone else can be looked at.
+    pub fn inspect(storage: &Arc<dyn Storage>) -> Result<Inspection> {
+        let mut inspection = Inspection::default();
+        let (file_pairs, without_hints) = find_file_pairs(storage)?;
+        let mut corrupt = BTreeSet::new();
+        let mut hints_by_file = vec![];
+        for (file_id, fp) in file_pairs.iter() {
+            let hints = if without_hints.contains(file_id) {
+                fp.scan_hints()?.0
+            } else {
+                let (hints, complete) = fp.read_hints()?;
+                if !complete {
+                    corrupt.insert(file_id.clone());
+                }
+                hints
+            };
+            hints_by_file.push((fp, hints));
+        }
+
+        let mut live: BTreeMap<Vec<u8>, (String, u64)> = BTreeMap::new();
+        for (fp, hints) in hints_by_file.iter() {
+            for hint in hints {
+                if hint.is_deleted() {
+                    live.remove(&hint.key());
+                } else {
+                    live.insert(hint.key(), (fp.file_id(), hint.data_entry_position()));
+                }
+            }
+        }
+        for (fp, hints) in hints_by_file.iter() {
+            for hint in hints {
+                if hint.is_deleted() {
+                    continue;
+                }
+                let position = hint.data_entry_position();
+                if live.get(&hint.key()) != Some(&(fp.file_id(), position)) {
+                    inspection.orphans.push((fp.file_id(), position));
+                    continue;
+                }
+                match fp.read(position) {
+                    Ok(entry) if entry.key() == hint.key() => {}
+                    _ => {
+                        corrupt.insert(fp.file_id());
+                    }
+                }
+            }
+        }
+
+        inspection.keys = live.into_keys().collect();
+        inspection.corrupt_files = corrupt.into_iter().collect();
+        inspection.missing_hints = without_hints;
+        Ok(inspection)
+    }
+
+    /// Rewrites the live records of the store in `storage` from keys
+    /// encoded as `from` to keys encoded as `to`, returning how many were
+    /// rewritten. The records are copied into a new pair which then replaces
+    /// every other pair in the manifest, so a crash part way leaves the store
+    /// as it was. The store must not be open. `to` is recorded as the
+    /// encoding of the store, even when it equals `from` and nothing is
+    /// rewritten.
+    pub fn migrate_encoding(storage: &Arc<dyn Storage>, from: Encoding, to: Encoding) -> Result<usize> {
+        storage.lock()?;
+        let migrated = Self::migrate_locked(storage, from, to);
+        storage.unlock()?;
+        migrated
+    }
+
+    fn migrate_locked(storage: &Arc<dyn Storage>, from: Encoding, to: Encoding) -> Result<usize> {
+        if from == to {
+            to.write(storage)?;
+            return Ok(0);
+        }
+        // Value log records repeat the key and would no longer match it
+        if ValueLog::exists(storage)? {
+            return Err(NotusError::UnsupportedMigration(
+                "values are kept in a value log".to_string(),
+            ));
+        }
+        let file_pairs = fetch_file_pairs(storage)?;
+        // Pin the manifest to the current pairs so the new one is ignored
+        // until it is complete
+        reset_manifest(storage, file_pairs.keys())?;
+        let keys_dir = KeysDir::new(&file_pairs, None)?;
+        let migrated_file_pair = create_new_file_pair(storage)?;
+        let copy = || -> Result<usize> {
+            let active_file = ActiveFilePair::from(migrated_file_pair.clone())?;
+            let entries = keys_dir.entries()?;
+            for (key, entry) in entries.iter() {
+                let fp = file_pairs
+                    .get(&entry.file_id)
+                    .ok_or_else(|| NotusError::FileNotFound(entry.file_id.clone()))?;
+                let record = fp.read(entry.data_entry_position)?.decompress()?;
+                let key = to.encode(&from.decode(key)?)?;
+                active_file.write(&DataEntry::with_timestamp(key, record.value(), record.timestamp()))?;
+            }
+            active_file.sync()?;
+            Ok(entries.len())
+        };
+        let migrated = match copy() {
+            Ok(migrated) => migrated,
+            Err(e) => {
+                migrated_file_pair.remove()?;
+                return Err(e);
+            }
+        };
+        write_manifest(storage, [migrated_file_pair.file_id()].iter())?;
+        to.write(storage)?;
+        for fp in file_pairs.values() {
+            fp.remove()?;
+        }
+        Ok(migrated)
+    }
+
+    /// Opens a store whose files are kept in `storage`. The mmap options only
+    /// apply to `LocalStorage` and are ignored here.
+    pub fn open_with_storage(storage: Arc<dyn Storage>, mut options: NotusOptions) -> Result<Self> {
+        options.mmap_active_file = false;
+        Self::open_in(storage, options)
+    }
+
+    fn open_in(storage: Arc<dyn Storage>, options: NotusOptions) -> Result<Self> {
+        // Take the lock before reading any hint file so a previous handle on
+        // the same storage has finished flushing
+        storage.lock()?;
+        let mut files_dir = fetch_file_pairs(&storage)?;
+        if Encoding::upgrade(&storage, &files_dir)? {
+            files_dir = fetch_file_pairs(&storage)?;
+        }
+        let active_file_pair = open_active_file_pair(&storage, &mut files_dir)?;
+        reset_manifest(&storage, files_dir.keys())?;
+        let (keys_dir, pending_replay) = match options.recovery_budget {
+            Some(budget) => KeysDir::new_within(&files_dir, budget, options.hash_keys_over)?,
+            None => (KeysDir::new(&files_dir, options.hash_keys_over)?, vec![]),
+        };
+        if !pending_replay.is_empty() {
+            let file_ids: Vec<String> = pending_replay.iter().map(|fp| fp.file_id()).collect();
+            warn!(
+                "recovery budget exceeded, {} files will be replayed in the background: {:?}",
+                file_ids.len(),
+                file_ids
+            );
+        }
+        // Data records hold value pointers under kv_separation, which are
+        // read as they are stored
+        let compression = match options.kv_separation {
+            true => Compression::None,
+            false => options.compression,
+        };
+        let active_file = ActiveFilePair::from(active_file_pair)?
+            .with_integrity(options.integrity)
+            .with_compression(compression);
+        // What is on disk is all that is known of the writes made before
+        let mut bytes_written = 0;
+        for fp in files_dir.values() {
+            bytes_written += fp.data_size()?;
+        }
+        let eviction = match options.max_total_bytes {
+            Some(max_total_bytes) => Some(Eviction::new(
+                max_total_bytes,
+                options.eviction_policy,
+                &keys_dir,
+            )?),
+            None => None,
+        };
+        let stale_index = match options.stale_reads {
+            Some(max_staleness) => Some(StaleIndex::new(max_staleness, &keys_dir)?),
+            None => None,
+        };
+        let value_log = match options.kv_separation {
+            true => Some(ValueLog::open(&storage, options.integrity)?),
+            false => None,
+        };
+        let store = Self {
+            storage,
+            active_file: RwLock::new(active_file),
+            keys_dir,
+            files_dir: RwLock::new(files_dir),
+            file_handles: FileHandleCache::new(options.max_open_files),
+            buffer: WriteBuffer::new(options.write_stripes),
+            eviction,
+            stale_index,
+            pending_replay: Mutex::new(pending_replay),
+            value_log,
+            bytes_written: AtomicU64::new(bytes_written),
+            integrity: options.integrity,
+            compression,
+            pins: Mutex::new(Pins::default()),
+            options,
+            prepared_batches: Mutex::new(()),
+            columns: RwLock::new(HashMap::new()),
+        };
+        store.resolve_prepared()?;
+        Ok(store)
+    }
+
+    /// Replays files left over by the recovery budget until `budget` has
+    /// passed. Returns `true` once nothing is left to replay.
+    pub fn replay_pending(&self, budget: Duration) -> Result<bool> {
+        let started = Instant::now();
+        let mut pending = self
+            .pending_replay
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        if pending.is_empty() {
+            return Ok(true);
+        }
+        while !pending.is_empty() && started.elapsed() < budget {
+            self.keys_dir.replay_older(&pending[0])?;
+            pending.remove(0);
+        }
+        if pending.is_empty() {
+            self.keys_dir.finish_replay()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Ids of the files not replayed yet, newest first.
+    pub fn pending_replay(&self) -> Result<Vec<String>> {
+        let pending = self
+            .pending_replay
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(pending.iter().map(|fp| fp.file_id()).collect())
+    }
+
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put_entry(DataEntry::new(key, value))
+    }
+
+    pub fn put_with_timestamp(&self, key: Vec<u8>, value: Vec<u8>, timestamp: i64) -> Result<()> {
+        // Non-positive timestamps are reserved for tombstone hints
+        if timestamp <= 0 {
+            return Err(NotusError::InvalidTimestamp(timestamp));
+        }
+        self.put_entry(DataEntry::with_timestamp(key, value, timestamp))
+    }
+
+    /// Writes `key` so that it reads as absent once `ttl` has passed. The
+    /// expiry is kept with the record, and compaction drops expired records.
+    pub fn put_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let ttl = ttl.as_millis().min(i64::MAX as u128) as i64;
+        let expires_at = Utc::now().timestamp_millis().saturating_add(ttl);
+        self.put_entry(DataEntry::new(key, value).with_expiry(Some(expires_at)))
+    }
+
+    /// Like `put` but gives up instead of waiting when another thread holds
+    /// the lock of the buffer stripe of `key`, returning whether the value
+    /// was written.
+    pub fn try_put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        match self.buffer.try_write_key(&key)? {
+            Some(buffer) => {
+                self.put_entry_in(buffer, DataEntry::new(key, value))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn put_entry(&self, entry: DataEntry) -> Result<()> {
+        let buffer = self.buffer.write_key(entry.key_slice())?;
+        self.put_entry_in(buffer, entry)
+    }
+
+    fn put_entry_in(
+        &self,
+        mut buffer: BufferWriteGuard<'_>,
+        entry: DataEntry,
+    ) -> Result<()> {
+        let entry = self.separate(entry)?;
+        let key = entry.key();
+        let size = key.len() as u64 + entry.value_size();
+        let expires_at = entry.expires_at();
+        buffer.insert(key.clone(), entry);
+        self.keys_dir.partial_insert(key.clone(), expires_at)?;
+        drop(buffer);
+        if let Some(eviction) = &self.eviction {
+            eviction.record_write(&key, size)?;
+        }
+        self.evict()?;
+        self.sync_if_requested()
+    }
+
+    /// Writes `entries`, which must be sorted by key with no duplicates,
+    /// straight to the active file and adds them to the index in one go.
+    /// The hints are appended last and the file pair is synced once, so a
+    /// failed or rejected load leaves nothing visible. The records it already
+    /// appended stay in the file as dead bytes until a compaction, and are
+    /// only counted as written once the load succeeds. Fails with
+    /// `UnsortedInput` at the first key not greater than the one before it.
+    pub fn bulk_load_sorted<I>(&self, entries: I) -> Result<usize>
+    where
+        I: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut buffer = self.buffer.write()?;
+        let active_file = self.active_file()?;
+        let mut hints = vec![];
+        let mut loaded: Vec<(Vec<u8>, KeyDirEntry)> = vec![];
+        for (key, value) in entries {
+            if let Some((last, _)) = loaded.last() {
+                if key <= *last {
+                    return Err(NotusError::UnsortedInput(key));
+                }
+            }
+            let entry = self.separate(DataEntry::new(key.clone(), value))?;
+            let (key_dir_entry, hint) = active_file.write_data(&entry)?;
+            hints.extend_from_slice(&hint);
+            loaded.push((key, key_dir_entry));
+        }
+        if let Some(value_log) = &self.value_log {
+            value_log.sync()?;
+        }
+        active_file.write_hints(&hints)?;
+        active_file.sync()?;
+
+        for (key, key_dir_entry) in loaded.iter() {
+            self.count_written(key_dir_entry);
+            // A buffered write older than the load must not be flushed over it
+            buffer.remove(key);
+            if let Some(eviction) = &self.eviction {
+                eviction.record_write(key, key.len() as u64 + key_dir_entry.value_size)?;
+            }
+        }
+        let count = loaded.len();
+        self.keys_dir.extend_sorted(loaded)?;
+        drop(active_file);
+        drop(buffer);
+        self.rotate_if_full()?;
+        self.evict()?;
+        Ok(count)
+    }
+
+    /// Writes `key` unless it already holds `value`, in which case nothing is
+    /// written. Fails with `NotusError::Conflict` if it holds another value.
+    pub fn put_idempotent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut buffer = self.buffer.write_key(&key)?;
+        match self.read_entry_in(&buffer, &key)? {
+            Some(entry) if entry.value() == value => return Ok(()),
+            Some(_) => return Err(NotusError::Conflict),
+            None => {}
+        }
+        let entry = self.separate(DataEntry::new(key.clone(), value))?;
+        let size = key.len() as u64 + entry.value_size();
+        buffer.insert(key.clone(), entry);
+        self.keys_dir.partial_insert(key.clone(), None)?;
+        drop(buffer);
+        if let Some(eviction) = &self.eviction {
+            eviction.record_write(&key, size)?;
+        }
+        self.evict()?;
+        self.sync_if_requested()
+    }
+
+    /// Deletes keys in eviction order until the live data fits under
+    /// `max_total_bytes`.
+    fn evict(&self) -> Result<()> {
+        if let Some(eviction) = &self.eviction {
+            while let Some(key) = eviction.next_victim()? {
+                let key = self.full_key(key)?;
+                self.delete(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.get_stale(key)? {
+            return Ok(value);
+        }
+        Ok(self.get_entry(key)?.map(|entry| entry.value()))
+    }
+
+    /// Looks `key` up in the stale index. Returns `None` when there is no
+    /// usable copy and the caller has to read through the real index.
+    fn get_stale(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        let copy = match &self.stale_index {
+            Some(stale_index) => match stale_index.current()? {
+                Some(copy) => copy,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let entry = match copy.get(self.keys_dir.index_key(key).as_ref()) {
+            Some(entry) if !is_expired(entry.expires_at) => entry,
+            _ => return Ok(Some(None)),
+        };
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        match files_dir_rlock.get(&entry.file_id) {
+            Some(fp) => {
+                let data_entry = match self.read_record(fp, entry.data_entry_position, key)? {
+                    Some(data_entry) => data_entry,
+                    None => return Ok(Some(None)),
+                };
+                match self.resolve(data_entry) {
+                    Ok(data_entry) => Ok(Some(Some(data_entry.value()))),
+                    // The value log file was collected since the copy was taken
+                    Err(NotusError::FileNotFound(_)) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            // Compacted away since the copy was taken
+            None => Ok(None),
+        }
+    }
+
+    /// Rebuilds the stale index if it is due, called by the background worker.
+    pub fn refresh_stale_index(&self) -> Result<()> {
+        match &self.stale_index {
+            Some(stale_index) => stale_index.refresh(&self.keys_dir),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes out pending data and copies the index into a `FrozenIndex`.
+    /// Writes made afterwards are not part of the copy, and a compaction
+    /// invalidates it.
+    pub fn freeze(&self) -> Result<FrozenIndex> {
+        self.replay_pending(Duration::MAX)?;
+        self.flush()?;
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let files: Vec<FilePair> = files_dir_rlock.values().cloned().collect();
+        let file_indexes: HashMap<String, u32> = files
+            .iter()
+            .enumerate()
+            .map(|(index, fp)| (fp.file_id(), index as u32))
+            .collect();
+        let mut entries = vec![];
+        for (key, entry) in self.keys_dir.entries()? {
+            let file = match file_indexes.get(&entry.file_id) {
+                Some(file) => *file,
+                None => continue,
+            };
+            let key = match self.keys_dir.may_be_hashed(&key) {
+                true => self.file_handles.read(&files[file as usize], entry.data_entry_position)?.key(),
+                false => key,
+            };
+            entries.push((
+                key.into_boxed_slice(),
+                FrozenEntry {
+                    file,
+                    position: entry.data_entry_position,
+                },
+            ));
+        }
+        // Keys read back from their record are out of order
+        if self.keys_dir.hash_keys_over.is_some() {
+            entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        }
+        Ok(FrozenIndex {
+            files,
+            entries: entries.into_boxed_slice(),
+        })
+    }
+
+    /// Returns whether `key` was deleted and the tombstone has not been
+    /// compacted away yet.
+    pub fn is_deleted(&self, key: &[u8]) -> Result<bool> {
+        self.keys_dir.is_tombstoned(key)
+    }
+
+    /// Returns the latest data entry for `key`, either from the write buffer
+    /// or from disk.
+    pub fn get_entry(&self, key: &[u8]) -> Result<Option<DataEntry>> {
+        let entry = self.read_entry(key)?;
+        if let (Some(eviction), Some(_)) = (&self.eviction, &entry) {
+            eviction.record_read(key)?;
+        }
+        Ok(entry)
+    }
+
+    /// Returns up to `len` bytes of the value of `key` starting at `offset`,
+    /// reading only those bytes from the data file. The crc is not checked
+    /// since the rest of the record is never read. A compressed value is
+    /// read whole and checked.
+    pub fn get_value_range(&self, key: &[u8], offset: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        let buffer = self.buffer.read_key(key)?;
+        if let Some(value_log) = &self.value_log {
+            let pointer = match self.read_stored_in(&buffer, key)? {
+                None => return Ok(None),
+                Some(entry) => ValuePointer::decode(&mut Cursor::new(entry.value_slice()))?,
+            };
+            let offset = offset.min(pointer.value_size);
+            let len = (len as u64).min(pointer.value_size - offset);
+            let mut bytes = vec![0_u8; len as usize];
+            value_log.read_value_range(&pointer, key.len() as u64, offset, &mut bytes)?;
+            return Ok(Some(bytes));
+        }
+        if let Some(entry) = buffer.get(key) {
+            return Ok(Some(value_range(entry.value_slice(), offset, len)));
+        }
+        let key_dir_entry = match self.keys_dir.get(key) {
+            None => return Ok(None),
+            Some(key_dir_entry) => key_dir_entry,
+        };
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let fp = match files_dir_rlock.get(&key_dir_entry.file_id) {
+            None => return Ok(None),
+            Some(fp) => fp,
+        };
+        // The key is not read back, a hashed key is only told apart from a
+        // colliding one by its length
+        if self.keys_dir.is_hashed(key) && key_dir_entry.key_size != key.len() as u64 {
+            return Ok(None);
+        }
+        if key_dir_entry.compression != Compression::None {
+            return match self.read_record(fp, key_dir_entry.data_entry_position, key)? {
+                None => Ok(None),
+                Some(record) => Ok(Some(value_range(record.decompress()?.value_slice(), offset, len))),
+            };
+        }
+        let offset = offset.min(key_dir_entry.value_size);
+        let len = (len as u64).min(key_dir_entry.value_size - offset);
+        let position = key_dir_entry.data_entry_position
+            + DATA_ENTRY_HEADER_SIZE as u64
+            + key_dir_entry.key_size
+            + offset;
+        let mut bytes = vec![0_u8; len as usize];
+        self.file_handles.read_at(fp, position, &mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// Returns whether the values of `a` and `b` are equal, or `None` if
+    /// either key does not exist. Values of different sizes are told apart
+    /// from the index alone, others are read a chunk at a time from both
+    /// until they differ. With `skip_original_key` each value starts with
+    /// the original key written by a key normalizer, which is not compared.
+    pub fn values_equal(&self, a: &[u8], b: &[u8], skip_original_key: bool) -> Result<Option<bool>> {
+        let buffer = self.buffer.read()?;
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let (a, b) = match (
+            self.stored_value_in(&buffer, &files_dir_rlock, a)?,
+            self.stored_value_in(&buffer, &files_dir_rlock, b)?,
+        ) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Ok(None),
+        };
+        let (skip_a, skip_b) = if skip_original_key {
+            (a.original_key_size(self)?, b.original_key_size(self)?)
+        } else {
+            (0, 0)
+        };
+        let size = a.size() - skip_a;
+        if size != b.size() - skip_b {
+            return Ok(Some(false));
+        }
+        let mut chunk_a = vec![0_u8; VALUE_COMPARE_CHUNK];
+        let mut chunk_b = vec![0_u8; VALUE_COMPARE_CHUNK];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(VALUE_COMPARE_CHUNK as u64) as usize;
+            a.read(self, skip_a + offset, &mut chunk_a[..len])?;
+            b.read(self, skip_b + offset, &mut chunk_b[..len])?;
+            if chunk_a[..len] != chunk_b[..len] {
+                return Ok(Some(false));
+            }
+            offset += len as u64;
+        }
+        Ok(Some(true))
+    }
+
+    /// Finds where the value of `key` can be read from without reading it.
+    fn stored_value_in<'a>(
+        &self,
+        buffer: &'a BufferGuard<'_, impl Deref<Target = Stripe>>,
+        files_dir: &'a BTreeMap<String, FilePair>,
+        key: &[u8],
+    ) -> Result<Option<StoredValue<'a>>> {
+        if let Some(entry) = buffer.get(key) {
+            return Ok(Some(match &self.value_log {
+                Some(_) => StoredValue::InValueLog(
+                    ValuePointer::decode(&mut Cursor::new(entry.value_slice()))?,
+                    key.len() as u64,
+                ),
+                None => StoredValue::Buffered(entry.value_slice()),
+            }));
+        }
+        let key_dir_entry = match self.keys_dir.get(key) {
+            None => return Ok(None),
+            Some(key_dir_entry) => key_dir_entry,
+        };
+        if self.keys_dir.is_hashed(key) && key_dir_entry.key_size != key.len() as u64 {
+            return Ok(None);
+        }
+        let fp = match files_dir.get(&key_dir_entry.file_id) {
+            None => return Ok(None),
+            Some(fp) => fp,
+        };
+        if key_dir_entry.compression != Compression::None {
+            return match self.read_record(fp, key_dir_entry.data_entry_position, key)? {
+                None => Ok(None),
+                Some(record) => Ok(Some(StoredValue::Decompressed(record.decompress()?.value()))),
+            };
+        }
+        if self.value_log.is_some() {
+            let record = match self.read_record(fp, key_dir_entry.data_entry_position, key)? {
+                None => return Ok(None),
+                Some(record) => record,
+            };
+            let pointer = ValuePointer::decode(&mut Cursor::new(record.value_slice()))?;
+            return Ok(Some(StoredValue::InValueLog(pointer, key.len() as u64)));
+        }
+        let position =
+            key_dir_entry.data_entry_position + DATA_ENTRY_HEADER_SIZE as u64 + key_dir_entry.key_size;
+        Ok(Some(StoredValue::InFile(fp, position, key_dir_entry.value_size)))
+    }
+
+    /// Reads the latest value of `key` into `buf`, replacing its content.
+    /// Returns `false`, leaving `buf` empty, if the key does not exist.
+    pub fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<bool> {
+        buf.clear();
+        let found = {
+            let buffer = self.buffer.read_key(key)?;
+            let found = if let Some(entry) = buffer.get(key) {
+                buf.extend_from_slice(entry.value_slice());
+                true
+            } else {
+                match self.keys_dir.get(key) {
+                    None => false,
+                    Some(key_dir_entry) => {
+                        let files_dir_rlock = self
+                            .files_dir
+                            .read()
+                            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+                        match files_dir_rlock.get(&key_dir_entry.file_id) {
+                            None => false,
+                            Some(fp) => match self.file_handles.read_value_into(
+                                fp,
+                                key_dir_entry.data_entry_position,
+                                key,
+                                buf,
+                            ) {
+                                Err(NotusError::IndexKeyMismatch(..))
+                                    if self.keys_dir.is_hashed(key) =>
+                                {
+                                    buf.clear();
+                                    false
+                                }
+                                result => {
+                                    result?;
+                                    true
+                                }
+                            },
+                        }
+                    }
+                }
+            };
+            if let (Some(value_log), true) = (&self.value_log, found) {
+                let pointer = ValuePointer::decode(&mut Cursor::new(buf.as_slice()))?;
+                value_log.read_value_into(&pointer, key, buf)?;
+            }
+            found
+        };
+        if let (Some(eviction), true) = (&self.eviction, found) {
+            eviction.record_read(key)?;
+        }
+        Ok(found)
+    }
+
+    fn read_entry(&self, key: &[u8]) -> Result<Option<DataEntry>> {
+        let buffer = self.buffer.read_key(key)?;
+        self.read_entry_in(&buffer, key)
+    }
+
+    /// Reads `key` while the caller holds the buffer lock.
+    fn read_entry_in(
+        &self,
+        buffer: &BufferGuard<'_, impl Deref<Target = Stripe>>,
+        key: &[u8],
+    ) -> Result<Option<DataEntry>> {
+        match self.read_stored_in(buffer, key)? {
+            Some(entry) => Ok(Some(self.resolve(entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `read_entry_in`, but returns the record as written to the data
+    /// file, with the value pointer in place of the value under
+    /// `kv_separation`.
+    fn read_stored_in(
+        &self,
+        buffer: &BufferGuard<'_, impl Deref<Target = Stripe>>,
+        key: &[u8],
+    ) -> Result<Option<DataEntry>> {
+        if let Some(entry) = buffer.get(key) {
+            return Ok(Some(entry.clone()));
+        }
+
+        let key_dir_entry = match self.keys_dir.get(key) {
+            None => {
+                return Ok(None);
+            }
+            Some(value) => value,
+        };
+
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+
+        let fp = match files_dir_rlock.get(&key_dir_entry.file_id) {
+            None => {
+                return Ok(None);
+            }
+            Some(fp) => fp,
+        };
+        self.read_record(fp, key_dir_entry.data_entry_position, key)
+    }
+
+    /// Reads the record of `key` at `position`. The record of another key
+    /// is a hash collision if the index holds `key` as a hash, and `key`
+    /// reads as missing; otherwise it fails with `IndexKeyMismatch`.
+    fn read_record(&self, fp: &FilePair, position: u64, key: &[u8]) -> Result<Option<DataEntry>> {
+        match self.file_handles.read_checked(fp, position, key) {
+            Err(NotusError::IndexKeyMismatch(..)) if self.keys_dir.is_hashed(key) => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    /// The key written to the store that `key`, as listed by the index,
+    /// stands for. Keys held as a hash are read back from their record or
+    /// found in the write buffer.
+    fn full_key(&self, key: Vec<u8>) -> Result<Vec<u8>> {
+        if !self.keys_dir.may_be_hashed(&key) {
+            return Ok(key);
+        }
+        let buffer = self.buffer.read()?;
+        let entry = self.keys_dir.get(&key);
+        self.full_key_in(&buffer, key, entry.as_ref())
+    }
+
+    fn full_key_in(
+        &self,
+        buffer: &BufferGuard<'_, impl Deref<Target = Stripe>>,
+        key: Vec<u8>,
+        entry: Option<&KeyDirEntry>,
+    ) -> Result<Vec<u8>> {
+        if !self.keys_dir.may_be_hashed(&key) {
+            return Ok(key);
+        }
+        if let Some(full_key) = buffer
+            .keys()
+            .find(|full_key| self.keys_dir.index_key(full_key) == key.as_slice())
+        {
+            return Ok(full_key.clone());
+        }
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(key),
+        };
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        match files_dir_rlock.get(&entry.file_id) {
+            Some(fp) => Ok(self.file_handles.read(fp, entry.data_entry_position)?.key()),
+            None => Ok(key),
+        }
+    }
+
+    fn full_keys(&self, keys: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        keys.into_iter()
+            .map(|key| self.full_key(key.clone()).unwrap_or(key))
+            .collect()
+    }
+
+    /// Moves the value of `entry` to the value log under `kv_separation`,
+    /// returning the record to write to the data file in its place.
+    fn separate(&self, entry: DataEntry) -> Result<DataEntry> {
+        match &self.value_log {
+            Some(value_log) => {
+                let pointer = value_log.append(&entry)?;
+                Ok(DataEntry::with_timestamp(
+                    entry.key(),
+                    pointer.encode(),
+                    entry.timestamp(),
+                )
+                .with_expiry(entry.expires_at()))
+            }
+            None => Ok(entry),
+        }
+    }
+
+    /// Undoes `separate`, reading the value a data record points at, and
+    /// decompresses the value. Fails with `FileNotFound` once the value was
+    /// collected from the value log.
+    fn resolve(&self, entry: DataEntry) -> Result<DataEntry> {
+        match &self.value_log {
+            Some(value_log) => {
+                let pointer = ValuePointer::decode(&mut Cursor::new(entry.value_slice()))?;
+                let mut value = vec![];
+                value_log.read_value_into(&pointer, entry.key_slice(), &mut value)?;
+                Ok(DataEntry::with_timestamp(entry.key(), value, entry.timestamp())
+                    .with_expiry(entry.expires_at()))
+            }
+            None => entry.decompress(),
+        }
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut buffer = self.buffer.write_key(key)?;
+
+        buffer.remove(key);
+        // Hold the active file until the index is updated, so a compaction
+        // rotating it sees either both or neither
+        let active_file = self.active_file()?;
+        active_file.remove(key.to_vec())?;
+        self.keys_dir.tombstone(key, &active_file.file_id())?;
+        drop(active_file);
+        drop(buffer);
+        if let Some(eviction) = &self.eviction {
+            eviction.record_delete(key)?;
+        }
+        self.sync_if_requested()
+    }
+
+    /// Applies `ops` in order while holding the buffer lock. Every operation
+    /// is written to the active file straight away, with the hints of the
+    /// whole batch appended at once so it survives a crash entirely or not at
+    /// all. `keys_dir` only sees the last operation for each key.
+    pub fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let mut buffer = self.buffer.write()?;
+        self.write_batch_in(&mut buffer, ops)?;
+        drop(buffer);
+        self.rotate_if_full()?;
+        self.evict()?;
+        self.sync_if_requested()
+    }
+
+    /// Durably stages `ops` as batch `id` without applying them, until
+    /// `commit_prepared` or `abort_prepared` is called, possibly after the
+    /// store is reopened.
+    pub fn prepare(&self, id: &str, ops: Vec<BatchOp>) -> Result<()> {
+        let _prepared_batches = self.prepared_batches()?;
+        write_prepared_batch(&self.storage, id, &ops)
+    }
+
+    /// Applies the prepared batch `id` as one `write_batch`. A marker
+    /// written with the batch tells a reopen that the commit went through
+    /// if a crash leaves the staged batch behind.
+    pub fn commit_prepared(&self, id: &str) -> Result<()> {
+        let _prepared_batches = self.prepared_batches()?;
+        let mut ops = read_prepared_batch(&self.storage, id)?
+            .ok_or_else(|| NotusError::BatchNotPrepared(id.to_string()))?;
+        let marker = RawKey::new(PREPARED_INDEX, id.as_bytes().to_vec()).encode();
+        ops.push(BatchOp::Put(marker.clone(), vec![]));
+        self.write_batch(ops)?;
+        remove_prepared_batch(&self.storage, id)?;
+        self.delete(&marker)
+    }
+
+    pub fn abort_prepared(&self, id: &str) -> Result<()> {
+        let _prepared_batches = self.prepared_batches()?;
+        if read_prepared_batch(&self.storage, id)?.is_none() {
+            return Err(NotusError::BatchNotPrepared(id.to_string()));
+        }
+        remove_prepared_batch(&self.storage, id)
+    }
+
+    /// Ids of the batches prepared and neither committed nor aborted.
+    pub fn prepared(&self) -> Result<Vec<String>> {
+        list_prepared_batches(&self.storage)
+    }
+
+    /// Cleans up after a crash: drops batches cut short while being
+    /// prepared and batches whose commit was written, then the markers of
+    /// those commits.
+    fn resolve_prepared(&self) -> Result<()> {
+        let markers = RawKey::column_prefix(PREPARED_INDEX);
+        let ids = list_prepared_batches(&self.storage)?;
+        if ids.is_empty() && !self.keys_dir.contains_prefix(&markers) {
+            return Ok(());
+        }
+        // A marker may be in a file left to the background replay
+        self.replay_pending(Duration::MAX)?;
+        for id in ids {
+            let marker = RawKey::new(PREPARED_INDEX, id.as_bytes().to_vec()).encode();
+            if self.keys_dir.contains(&marker)?
+                || read_prepared_batch(&self.storage, &id)?.is_none()
+            {
+                remove_prepared_batch(&self.storage, &id)?;
+            }
+        }
+        for marker in self.keys_dir.prefix(&markers) {
+            self.delete(&marker)?;
+        }
+        Ok(())
+    }
+
+    fn prepared_batches(&self) -> Result<MutexGuard<'_, ()>> {
+        self.prepared_batches
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
+
+    /// Runs `f` while holding the buffer lock, so no other write can happen
+    /// until it returns. `f` reads through the `LockedStore` it is given and
+    /// returns the operations to apply, which are written as one batch
+    /// before the lock is released. Nothing is written if `f` fails.
+    pub fn atomically<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&LockedStore) -> Result<(R, Vec<BatchOp>)>,
+    {
+        let mut buffer = self.buffer.write()?;
+        let (result, ops) = f(&LockedStore {
+            store: self,
+            buffer: &buffer,
+        })?;
+        if !ops.is_empty() {
+            self.write_batch_in(&mut buffer, ops)?;
+        }
+        drop(buffer);
+        self.evict()?;
+        Ok(result)
+    }
+
+    /// `write_batch` for a caller already holding the buffer lock.
+    fn write_batch_in(
+        &self,
+        buffer: &mut BufferWriteGuard<'_>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let entries: Vec<(Vec<u8>, Option<DataEntry>)> = ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => {
+                    Ok((key.clone(), Some(self.separate(DataEntry::new(key, value))?)))
+                }
+                BatchOp::Delete(key) => Ok((key, None)),
+            })
+            .collect::<Result<_>>()?;
+        let active_file = self.active_file()?;
+        let key_dir_entries = active_file.write_batch(&entries)?;
+        for key_dir_entry in key_dir_entries.iter().flatten() {
+            self.count_written(key_dir_entry);
+        }
+
+        let mut last_ops = HashMap::new();
+        for ((key, _), key_dir_entry) in entries.into_iter().zip(key_dir_entries) {
+            // A buffered write older than the batch must not be flushed over it
+            buffer.remove(&key);
+            last_ops.insert(key, key_dir_entry);
+        }
+
+        if let Some(eviction) = &self.eviction {
+            for (key, key_dir_entry) in last_ops.iter() {
+                match key_dir_entry {
+                    Some(key_dir_entry) => {
+                        eviction.record_write(key, key.len() as u64 + key_dir_entry.value_size)?
+                    }
+                    None => eviction.record_delete(key)?,
+                }
+            }
+        }
+        self.keys_dir
+            .apply_batch(last_ops.into_iter().collect(), &active_file.file_id())
+    }
+
+    pub fn contains(&self, key: &[u8]) -> Result<bool> {
+        let mut buffer = self.buffer.read_key(key)?;
+
+        if buffer.contains_key(key) {
+            return Ok(true)
+        }
+
+        let result = self.keys_dir.contains(key)?;
+        Ok(result)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.replay_pending(Duration::MAX)?;
+        let active_file = self.active_file()?;
+        for key in self.keys().iter() {
+            active_file.remove(key.clone())?;
+            self.keys_dir.tombstone(key, &active_file.file_id())?;
+        }
+        self.keys_dir.clear()?;
+        let mut buffer = self.buffer.write()?;
+        buffer.clear();
+        if let Some(eviction) = &self.eviction {
+            eviction.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Removes every file pair except the active one and truncates the
+    /// active pair, so the space is reclaimed without waiting for a merge.
+    pub fn clear_and_reclaim(&self) -> Result<()> {
+        let mut buffer = self.buffer.write()?;
+        let mut files_dir_wlock = self
+            .files_dir
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+
+        let active_file = self.active_file()?;
+        let active_file_id = active_file.file_id();
+        let file_ids: Vec<String> = files_dir_wlock.keys().cloned().collect();
+        write_manifest(&self.storage, [&active_file_id])?;
+        for fp in files_dir_wlock.values() {
+            if fp.file_id() == active_file_id {
+                continue;
+            }
+            self.file_handles.remove(&fp.file_id())?;
+            fp.remove()?;
+        }
+        files_dir_wlock.retain(|file_id, _| file_id == &active_file_id);
+        self.pending_replay
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .clear();
+        self.keys_dir.finish_replay()?;
+
+        active_file.truncate()?;
+        self.bytes_written.store(0, Ordering::Release);
+        if let Some(value_log) = &self.value_log {
+            value_log.clear()?;
+        }
+        self.keys_dir.clear()?;
+        self.keys_dir.forget_tombstones(&file_ids)?;
+        buffer.clear();
+        if let Some(eviction) = &self.eviction {
+            eviction.clear()?;
+        }
+        Ok(())
+    }
+
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.full_keys(self.keys_dir.keys())
+    }
+
+
+    pub fn range<R>(&self, range : R) -> Vec<Vec<u8>>  where R: RangeBounds<Vec<u8>>{
+        self.full_keys(self.keys_dir.range(range))
+    }
+
+    pub fn prefix(&self, prefix: &Vec<u8>) -> Vec<Vec<u8>> {
+        self.full_keys(self.keys_dir.prefix(prefix))
+    }
+
+    pub fn contains_prefix(&self, prefix: &[u8]) -> bool {
+        self.keys_dir.contains_prefix(prefix)
+    }
+
+    /// Returns the number of live keys in `column`, counted from the index.
+    pub fn len(&self, column: &str) -> usize {
+        self.keys_dir.count(RawKey::column_range(column, ..))
+    }
+
+    /// Sums the value sizes of the keys in `range` using only the index and
+    /// the write buffer, without reading any value from disk.
+    pub fn range_size<R>(&self, range: R) -> Result<u64>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let buffer = self.buffer.read()?;
+        let mut size = 0;
+        for (key, entry) in self.keys_dir.range_entries(range)? {
+            size += match entry {
+                Some(entry) => entry.value_size,
+                None => buffer.get(&key).map_or(0, |entry| entry.value_size()),
+            };
+        }
+        Ok(size)
+    }
+
+    /// Groups the index and the hint files by column. Keys whose column can
+    /// not be decoded are left out.
+    pub fn column_stats(&self) -> Result<HashMap<String, ColumnStats>> {
+        let mut stats: HashMap<String, ColumnStats> = HashMap::new();
+        {
+            let buffer = self.buffer.read()?;
+            for (key, entry) in self.keys_dir.range_entries(..)? {
+                let column = match RawKey::from_encoded(&key) {
+                    Some(RawKey(column, _)) => column,
+                    None => continue,
+                };
+                let column_stats = stats.entry(column).or_default();
+                column_stats.live_keys += 1;
+                column_stats.value_bytes += match entry {
+                    Some(entry) => entry.value_size,
+                    None => buffer.get(&key).map_or(0, |entry| entry.value_size()),
+                };
+            }
+        }
+
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        for fp in files_dir_rlock.values() {
+            for hint in fp.get_hints()? {
+                if hint.is_deleted() {
+                    continue;
+                }
+                let is_live = self.keys_dir.get(&hint.key()).is_some_and(|entry| {
+                    entry.file_id == fp.file_id()
+                        && entry.data_entry_position == hint.data_entry_position()
+                });
+                if is_live {
+                    continue;
+                }
+                if let Some(RawKey(column, _)) = RawKey::from_encoded(&hint.key()) {
+                    stats.entry(column).or_default().dead_bytes += hint.record_size();
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Counts the live keys of every column by the length of their key,
+    /// without the column prefix.
+    pub fn key_size_histogram(&self) -> Result<BTreeMap<usize, usize>> {
+        let buffer = self.buffer.read()?;
+        let mut histogram = BTreeMap::new();
+        for (key, entry) in self.keys_dir.range_entries(..)? {
+            let key = self.full_key_in(&buffer, key, entry.as_ref())?;
+            if let Some(RawKey(_, key)) = RawKey::from_encoded(&key) {
+                *histogram.entry(key.len()).or_default() += 1;
+            }
+        }
+        Ok(histogram)
+    }
+
+    /// Counts the live keys of every column by the size of their value, as
+    /// recorded in the index or the write buffer.
+    pub fn value_size_histogram(&self) -> Result<BTreeMap<usize, usize>> {
+        let buffer = self.buffer.read()?;
+        let mut histogram = BTreeMap::new();
+        for (key, entry) in self.keys_dir.range_entries(..)? {
+            let value_size = match entry {
+                Some(entry) => entry.value_size,
+                None => buffer.get(&key).map_or(0, |entry| entry.value_size()),
+            };
+            *histogram.entry(value_size as usize).or_default() += 1;
+        }
+        Ok(histogram)
+    }
+
+    /// Returns the live keys in `range` whose value is larger than
+    /// `max_value_size` bytes, as recorded in the index or the write buffer.
+    pub fn keys_with_value_over<R>(&self, range: R, max_value_size: u64) -> Result<Vec<Vec<u8>>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let buffer = self.buffer.read()?;
+        let mut keys = vec![];
+        for (key, entry) in self.keys_dir.range_entries(range)? {
+            let value_size = match &entry {
+                Some(entry) => entry.value_size,
+                None => buffer.get(&key).map_or(0, |entry| entry.value_size()),
+            };
+            if value_size > max_value_size {
+                keys.push(self.full_key_in(&buffer, key, entry.as_ref())?);
+            }
+        }
+        Ok(keys)
+    }
+
+    pub fn keys_modified_since(&self, timestamp: i64) -> Result<Vec<Vec<u8>>> {
+        Ok(self.full_keys(self.keys_dir.modified_since(timestamp)?))
+    }
+
+    pub fn merge(&self) -> Result<()> {
+        self.merge_keeping(1, &AtomicBool::new(false))
+    }
+
+    /// Compacts every file but the active one, copying up to `keep_versions`
+    /// of the most recent records of each live key instead of only the
+    /// latest. Versions written before the key was last deleted are dropped.
+    ///
+    /// Setting `stop` ends a compaction keeping only the latest records
+    /// after the file pair being copied. The pairs copied so far are
+    /// replaced by the merged pair and the others are left as they are.
+    pub fn merge_keeping(&self, keep_versions: usize, stop: &AtomicBool) -> Result<()> {
+        if stop.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        // Entries of unreplayed files are not in the index and would be lost
+        self.replay_pending(Duration::MAX)?;
+        let max_file_size = self
+            .options
+            .max_file_size
+            .filter(|_| self.options.migrate_on_open);
+        let outputs = match max_file_size {
+            Some(max_file_size) => self.live_bytes()?.div_ceil(max_file_size.max(1)).max(1),
+            None => 1,
+        };
+        let mut merged = vec![];
+        for _ in 0..outputs {
+            merged.push(
+                ActiveFilePair::from(create_new_file_pair(&self.storage)?)?
+                    .with_integrity(self.integrity)
+                    .with_compression(self.compression),
+            );
+        }
+        let merged_file_ids: HashSet<String> = merged.iter().map(|pair| pair.file_id()).collect();
+        let next_active_file = ActiveFilePair::from(create_new_file_pair(&self.storage)?)?
+            .with_integrity(self.integrity)
+            .with_compression(self.compression);
+        let next_active_file_id = next_active_file.file_id();
+
+        // Register the merged pairs before the index points at them and move
+        // writes to a pair newer than them, so that on reopen nothing copied
+        // into a merged pair shadows a write or delete made during the merge
+        let to_merge: Vec<FilePair> = {
+            let mut files_dir_wlock = self
+                .files_dir
+                .write()
+                .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+            for pair in merged.iter() {
+                files_dir_wlock.insert(pair.file_id(), pair.get_file_pair());
+            }
+            files_dir_wlock.insert(next_active_file_id.clone(), next_active_file.get_file_pair());
+            write_manifest(&self.storage, files_dir_wlock.keys())?;
+            let retired = {
+                let mut active_file = self
+                    .active_file
+                    .write()
+                    .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+                // Writes from here on land in the merged or the next pair
+                self.bytes_written.store(0, Ordering::Release);
+                std::mem::replace(&mut *active_file, next_active_file)
+            };
+            drop(retired);
+            files_dir_wlock
+                .values()
+                .filter(|fp| {
+                    fp.file_id() != next_active_file_id && !merged_file_ids.contains(&fp.file_id())
+                })
+                .cloned()
+                .collect()
+        };
+
+        let mut output = MergeOutput::new(merged, max_file_size);
+        if keep_versions > 1 {
+            self.copy_versions(&to_merge, &mut output, keep_versions)?;
+            self.release_merged(&to_merge)?;
+        } else if self.options.incremental_compaction {
+            // Oldest first, so a tombstone is never dropped before the
+            // records it shadows
+            for fp in to_merge.chunks(1) {
+                if self.copy_live(fp, &mut output, stop)? == 0 {
+                    break;
+                }
+                output.sync()?;
+                self.release_merged(fp)?;
+            }
+        } else {
+            let copied = self.copy_live(&to_merge, &mut output, stop)?;
+            self.release_merged(&to_merge[..copied])?;
+        }
+        self.release_unused(output)
+    }
+
+    /// Drops the merged pairs nothing was copied into, e.g. because the
+    /// compaction was stopped before its first pair, so stopped compactions
+    /// do not leave empty pairs behind.
+    fn release_unused(&self, output: MergeOutput) -> Result<()> {
+        let pairs: Vec<FilePair> = output.pairs.iter().map(|pair| pair.get_file_pair()).collect();
+        drop(output);
+        let mut unused = vec![];
+        for fp in pairs {
+            if fp.is_unused()? {
+                unused.push(fp);
+            }
+        }
+        if unused.is_empty() {
+            return Ok(());
+        }
+        self.release_merged(&unused)
+    }
+
+    /// Drops `merged` from the store once their live records were copied.
+    fn release_merged(&self, merged: &[FilePair]) -> Result<()> {
+        let mut files_dir_wlock = self
+            .files_dir
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        for fp in merged.iter() {
+            files_dir_wlock.remove(&fp.file_id());
+        }
+        // Stop listing the merged away pairs before removing their files
+        write_manifest(&self.storage, files_dir_wlock.keys())?;
+        let merged_ids: Vec<String> = merged.iter().map(|fp| fp.file_id()).collect();
+        self.keys_dir.forget_tombstones(&merged_ids)?;
+        for fp in merged {
+            self.file_handles.remove(&fp.file_id())?;
+            let _ = self.remove_files(vec![fp.data_file_name(), fp.hint_file_name()]);
+        }
+        Ok(())
+    }
+
+    /// Coalesces the pairs whose data file is smaller than
+    /// `target_file_size`, the active one included, into as few new pairs as
+    /// hold their live records, each filled up to about `target_file_size`.
+    /// Larger pairs are left as they are, and tombstones are carried over
+    /// while such a pair may still hold an older record of the key. Does
+    /// nothing when fewer than two pairs are small.
+    pub fn merge_tiered(&self, target_file_size: u64) -> Result<()> {
+        self.replay_pending(Duration::MAX)?;
+        let mut files_dir_wlock = self
+            .files_dir
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut small = vec![];
+        let mut live_bytes = 0;
+        for fp in files_dir_wlock.values() {
+            if fp.data_size()? >= target_file_size {
+                continue;
+            }
+            for hint in fp.get_hints()? {
+                if let Some(entry) = self.keys_dir.get(&hint.key()) {
+                    if entry.file_id == fp.file_id()
+                        && entry.data_entry_position == hint.data_entry_position()
+                    {
+                        live_bytes += entry.record_size();
+                    }
+                }
+            }
+            small.push(fp.clone());
+        }
+        if small.len() < 2 {
+            return Ok(());
+        }
+        let keeps_older_pairs = small.len() < files_dir_wlock.len();
+
+        // Every pair the records go to is created before the next active
+        // pair, so none of them shadows a write made during the merge
+        let outputs = live_bytes.div_ceil(target_file_size.max(1)).max(1);
+        let mut merged = vec![];
+        for _ in 0..outputs {
+            merged.push(
+                ActiveFilePair::from(create_new_file_pair(&self.storage)?)?
+                    .with_integrity(self.integrity)
+                    .with_compression(self.compression),
+            );
+        }
+        let next_active_file = ActiveFilePair::from(create_new_file_pair(&self.storage)?)?
+            .with_integrity(self.integrity)
+            .with_compression(self.compression);
+        for pair in merged.iter().chain([&next_active_file]) {
+            files_dir_wlock.insert(pair.file_id(), pair.get_file_pair());
+        }
+        write_manifest(&self.storage, files_dir_wlock.keys())?;
+        let retired = {
+            let mut active_file = self
+                .active_file
+                .write()
+                .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+            std::mem::replace(&mut *active_file, next_active_file)
+        };
+        drop(retired);
+        drop(files_dir_wlock);
+
+        let mut output = 0;
+        let mut output_size = 0;
+        let mut carried = HashSet::new();
+        for fp in small.iter() {
+            for hint in fp.get_hints()? {
+                if hint.is_deleted() {
+                    if keeps_older_pairs
+                        && self.keys_dir.get(&hint.key()).is_none()
+                        && carried.insert(hint.key())
+                    {
+                        merged[output].remove(hint.key())?;
+                    }
+                    continue;
+                }
+                if self.drop_expired(fp, &hint)? {
+                    // Older pairs may still hold a record the expired one hid
+                    if keeps_older_pairs && carried.insert(hint.key()) {
+                        merged[output].remove(hint.key())?;
+                    }
+                    continue;
+                }
+                let is_live = self.keys_dir.get(&hint.key()).is_some_and(|entry| {
+                    entry.file_id == fp.file_id()
+                        && entry.data_entry_position == hint.data_entry_position()
+                });
+                if !is_live {
+                    continue;
+                }
+                if output_size >= target_file_size && output + 1 < merged.len() {
+                    output += 1;
+                    output_size = 0;
+                }
+                let data_entry = fp.read(hint.data_entry_position())?;
+                let key_entry = merged[output].write(&data_entry)?;
+                self.count_written(&key_entry);
+                output_size += key_entry.record_size();
+                self.keys_dir.relocate(
+                    &hint.key(),
+                    &fp.file_id(),
+                    hint.data_entry_position(),
+                    key_entry,
+                )?;
+            }
+        }
+        for pair in merged.iter() {
+            pair.sync()?;
+        }
+
+        let mut files_dir_wlock = self
+            .files_dir
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut removed = small;
+        for pair in merged.iter() {
+            if pair.get_file_pair().is_unused()? {
+                removed.push(pair.get_file_pair());
+            }
+        }
+        for fp in removed.iter() {
+            files_dir_wlock.remove(&fp.file_id());
+        }
+        write_manifest(&self.storage, files_dir_wlock.keys())?;
+        let removed_ids: Vec<String> = removed.iter().map(|fp| fp.file_id()).collect();
+        self.keys_dir.forget_tombstones(&removed_ids)?;
+        for fp in removed {
+            self.file_handles.remove(&fp.file_id())?;
+            let _ = self.remove_files(vec![fp.data_file_name(), fp.hint_file_name()]);
+        }
+        Ok(())
+    }
+
+    /// Removes the key of `hint`, a hint of `fp`, from the index if the
+    /// index points at its record and the record has expired, so a merge
+    /// can leave the record behind. Returns whether it was removed.
+    fn drop_expired(&self, fp: &FilePair, hint: &HintEntry) -> Result<bool> {
+        if !is_expired(hint.expires_at()) {
+            return Ok(false);
+        }
+        let key = hint.key();
+        let removed = self
+            .keys_dir
+            .remove_expired(&key, &fp.file_id(), hint.data_entry_position())?;
+        if removed {
+            if let Some(eviction) = &self.eviction {
+                eviction.record_delete(&key)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Copies the record the index points at for every key in `to_merge`,
+    /// one pair at a time until `stop` is set. Returns the number of pairs
+    /// copied.
+    fn copy_live(
+        &self,
+        to_merge: &[FilePair],
+        output: &mut MergeOutput,
+        stop: &AtomicBool,
+    ) -> Result<usize> {
+        for (copied, fp) in to_merge.iter().enumerate() {
+            if stop.load(Ordering::Acquire) {
+                return Ok(copied);
+            }
+            let hints = fp.get_hints()?;
+            for hint in hints {
+                // Every older record of the key is merged away as well, so
+                // nothing is left for a dropped record to uncover
+                if self.drop_expired(fp, &hint)? {
+                    continue;
+                }
+                if let Some(keys_dir_entry) = self.keys_dir.get(&hint.key()) {
+                    // Only the version the index points at is live, older
+                    // versions of the key in the same file are dropped
+                    if keys_dir_entry.file_id == fp.file_id()
+                        && keys_dir_entry.data_entry_position == hint.data_entry_position()
+                    {
+                        let data_entry = fp.read(hint.data_entry_position())?;
+                        let key_entry = output.write(&data_entry)?;
+                        self.count_written(&key_entry);
+                        // The key may have been written or deleted since it
+                        // was found live, the copy is then left unreferenced
+                        self.keys_dir.relocate(
+                            &hint.key(),
+                            &fp.file_id(),
+                            hint.data_entry_position(),
+                            key_entry,
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(to_merge.len())
+    }
+
+    /// Copies the last `keep_versions` records of every key whose live record
+    /// is in `to_merge`, oldest first so the merged file keeps write order.
+    fn copy_versions(
+        &self,
+        to_merge: &[FilePair],
+        output: &mut MergeOutput,
+        keep_versions: usize,
+    ) -> Result<()> {
+        let mut versions: HashMap<Vec<u8>, Vec<(usize, u64)>> = HashMap::new();
+        for (index, fp) in to_merge.iter().enumerate() {
+            for hint in fp.get_hints()? {
+                if hint.is_deleted() {
+                    versions.remove(&hint.key());
+                } else {
+                    versions
+                        .entry(hint.key())
+                        .or_default()
+                        .push((index, hint.data_entry_position()));
+                }
+            }
+        }
+
+        for (key, key_versions) in versions {
+            let (index, position) = match key_versions.last() {
+                Some(last) => *last,
+                None => continue,
+            };
+            let is_live = self.keys_dir.get(&key).is_some_and(|entry| {
+                entry.file_id == to_merge[index].file_id()
+                    && entry.data_entry_position == position
+            });
+            if !is_live {
+                continue;
+            }
+            let first = key_versions.len().saturating_sub(keep_versions);
+            let mut key_entry = None;
+            for (index, position) in &key_versions[first..] {
+                let data_entry = to_merge[*index].read(*position)?;
+                let written = output.write(&data_entry)?;
+                self.count_written(&written);
+                key_entry = Some(written);
+            }
+            if let Some(key_entry) = key_entry {
+                self.keys_dir
+                    .relocate(&key, &to_merge[index].file_id(), position, key_entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the live values of every value log file but the one being
+    /// appended to into a new file and removes the old files, reclaiming
+    /// the space of values that were overwritten or deleted. Records whose
+    /// value moved are appended to the active data file with their original
+    /// timestamp. Writes wait until it is done. Returns the number of bytes
+    /// reclaimed, `0` without `kv_separation`.
+    pub fn collect_value_log(&self) -> Result<u64> {
+        let value_log = match &self.value_log {
+            Some(value_log) => value_log,
+            None => return Ok(0),
+        };
+        let mut buffer = self.buffer.write()?;
+        let active_file = self.active_file()?;
+        let sealed = value_log.rotate()?;
+        let mut reclaimed = 0;
+        for file_id in sealed.iter() {
+            let mut kept = 0;
+            value_log.for_each_record(file_id, |position, entry| {
+                let stored = match self.read_stored_in(&buffer, entry.key_slice())? {
+                    Some(stored) => stored,
+                    None => return Ok(()),
+                };
+                let pointer = ValuePointer::decode(&mut Cursor::new(stored.value_slice()))?;
+                if pointer.file_id != *file_id || pointer.position != position {
+                    return Ok(());
+                }
+                kept += entry.record_size();
+                let moved = DataEntry::with_timestamp(
+                    entry.key(),
+                    value_log.append(&entry)?.encode(),
+                    stored.timestamp(),
+                )
+                .with_expiry(stored.expires_at());
+                match buffer.get_mut(entry.key_slice()) {
+                    Some(buffered) => *buffered = moved,
+                    None => {
+                        let key_dir_entry = active_file.write(&moved)?;
+                        self.count_written(&key_dir_entry);
+                        self.keys_dir.insert(entry.key(), key_dir_entry)?;
+                    }
+                }
+                Ok(())
+            })?;
+            reclaimed += value_log.file_len(file_id)?.saturating_sub(kept);
+        }
+        // The moved values and the records pointing at them must be durable
+        // before the values they replace are removed
+        value_log.sync()?;
+        active_file.sync()?;
+        let mut removed = vec![];
+        for file_id in sealed.iter() {
+            removed.push(value_log.detach(file_id)?);
+        }
+        self.remove_files(removed)?;
+        Ok(reclaimed)
+    }
+
+    /// Returns up to `n` of the most recent values of `key`, newest first,
+    /// reading every hint file. Versions from before the key was last deleted
+    /// are not returned, and only versions kept by compaction remain.
+    pub fn versions(&self, key: &[u8], n: usize) -> Result<Vec<DataEntry>> {
+        if !self.keys_dir.contains(key)? {
+            return Ok(vec![]);
+        }
+        let buffered = {
+            let buffer = self.buffer.read_key(key)?;
+            buffer.get(key).cloned()
+        };
+
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut positions = vec![];
+        for fp in files_dir_rlock.values() {
+            for hint in fp.get_hints()? {
+                if hint.key() != key {
+                    continue;
+                }
+                if hint.is_deleted() {
+                    positions.clear();
+                } else {
+                    positions.push((fp, hint.data_entry_position()));
+                }
+            }
+        }
+
+        let mut entries: Vec<DataEntry> = match buffered {
+            Some(entry) => vec![self.resolve(entry)?],
+            None => vec![],
+        };
+        for (fp, position) in positions.into_iter().rev() {
+            if entries.len() >= n {
+                break;
+            }
+            match self.resolve(self.file_handles.read(fp, position)?) {
+                Ok(entry) => entries.push(entry),
+                // Older values are dropped by `collect_value_log`
+                Err(NotusError::FileNotFound(_)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        entries.truncate(n);
+        Ok(entries)
+    }
+
+    /// Returns the location of every data record that the index no longer
+    /// points at, such as old versions of overwritten keys.
+    pub fn orphan_positions(&self) -> Result<Vec<(FilePair, u64)>> {
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+
+        let mut positions = vec![];
+        for fp in files_dir_rlock.values() {
+            for hint in fp.get_hints()? {
+                if hint.is_deleted() {
+                    continue;
+                }
+                let is_live = match self.keys_dir.get(&hint.key()) {
+                    Some(entry) => {
+                        entry.file_id == fp.file_id()
+                            && entry.data_entry_position == hint.data_entry_position()
+                    }
+                    None => false,
+                };
+                if !is_live {
+                    positions.push((fp.clone(), hint.data_entry_position()));
+                }
             }
         }
+        Ok(positions)
     }
 
-    pub fn contains(&self, key: &[u8]) -> Result<bool> {
-        let keys_dir_reader = match self.keys.read() {
-            Ok(rdr) => rdr,
-            Err(error) => {
-                return Err(NotusError::RWLockPoisonError(format!("{}", error)));
+    /// Flushes the write buffer and groups the location of every live record
+    /// whose key starts with `prefix` by file, files oldest first and
+    /// positions ascending, so the records can be read front to back.
+    pub fn live_positions_by_file(&self, prefix: &[u8]) -> Result<Vec<(FilePair, Vec<u64>)>> {
+        self.flush()?;
+        let mut by_file: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        for (key, entry) in self.keys_dir.entries()? {
+            if key.starts_with(prefix) {
+                by_file
+                    .entry(entry.file_id)
+                    .or_default()
+                    .push(entry.data_entry_position);
             }
-        };
+        }
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(by_file
+            .into_iter()
+            .filter_map(|(file_id, mut positions)| {
+                positions.sort_unstable();
+                files_dir_rlock
+                    .get(&file_id)
+                    .map(|fp| (fp.clone(), positions))
+            })
+            .collect())
+    }
 
-        Ok(keys_dir_reader.contains_key(key))
+    /// Flushes the write buffer and lists the position of every data record
+    /// of every file, files oldest first and records in write order, with
+    /// whether the index points at the record.
+    pub fn physical_positions(&self) -> Result<Vec<(FilePair, Vec<RecordPosition>)>> {
+        self.flush()?;
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut files = vec![];
+        for fp in files_dir_rlock.values() {
+            let positions = fp
+                .get_hints()?
+                .into_iter()
+                .filter(|hint| !hint.is_deleted())
+                .map(|hint| {
+                    let is_live = self.keys_dir.get(&hint.key()).is_some_and(|entry| {
+                        entry.file_id == fp.file_id()
+                            && entry.data_entry_position == hint.data_entry_position()
+                    });
+                    (hint.data_entry_position(), is_live)
+                })
+                .collect();
+            files.push((fp.clone(), positions));
+        }
+        Ok(files)
     }
-}
 
-impl KeysDir {
-    pub fn new(file_pairs: &BTreeMap<String, FilePair>) -> Result<Self> {
-        let keys = RwLock::new(BTreeMap::new());
-        let keys_dir = Self { keys };
-        for (_, fp) in file_pairs {
-            fp.fetch_hint_entries(&keys_dir)?;
+    /// Deletes the live keys starting with `prefix` whose record `keep`
+    /// rejects and returns how many were deleted. Records are read file by
+    /// file in the order they were written. A key written again after its
+    /// record was read is kept, whatever its new value.
+    pub fn retain_prefix<F>(&self, prefix: &[u8], mut keep: F) -> Result<usize>
+    where
+        F: FnMut(&DataEntry) -> Result<bool>,
+    {
+        let mut rejected = vec![];
+        for (fp, positions) in self.live_positions_by_file(prefix)? {
+            for position in positions {
+                let entry = self.read_at(&fp, position)?;
+                if !keep(&entry)? {
+                    rejected.push((entry.key(), entry.timestamp()));
+                }
+            }
         }
-        Ok(keys_dir)
+
+        let mut buffer = self.buffer.write()?;
+        let deletes: Vec<BatchOp> = rejected
+            .into_iter()
+            .filter(|(key, timestamp)| {
+                !buffer.contains_key(key)
+                    && self
+                        .keys_dir
+                        .get(key)
+                        .is_some_and(|entry| entry.timestamp == *timestamp)
+            })
+            .map(|(key, _)| BatchOp::Delete(key))
+            .collect();
+        let deleted = deletes.len();
+        if deleted > 0 {
+            self.write_batch_in(&mut buffer, deletes)?;
+        }
+        Ok(deleted)
     }
-}
 
-pub struct DataStore {
-    lock_file: File,
-    dir: PathBuf,
-    active_file: ActiveFilePair,
-    keys_dir: KeysDir,
-    files_dir: RwLock<BTreeMap<String, FilePair>>,
-    buffer: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
-}
+    /// Reads the record at `position` of `fp`, with its value read from the
+    /// value log under `kv_separation`.
+    pub fn read_at(&self, fp: &FilePair, position: u64) -> Result<DataEntry> {
+        self.resolve(self.file_handles.read(fp, position)?)
+    }
 
-impl DataStore {
-    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
-        let lock_file = get_lock_file(dir.as_ref())?;
-        let active_file_pair = create_new_file_pair(dir.as_ref())?;
-        let files_dir = fetch_file_pairs(dir.as_ref())?;
-        let keys_dir = KeysDir::new(&files_dir)?;
-        let mut instance = Self {
-            lock_file,
-            dir: dir.as_ref().to_path_buf(),
-            active_file: ActiveFilePair::from(active_file_pair)?,
-            keys_dir,
-            files_dir: RwLock::new(files_dir),
-            buffer: RwLock::new(Default::default()),
-        };
-        instance.lock()?;
-        Ok(instance)
+    pub fn file_ids(&self) -> Result<Vec<String>> {
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        Ok(files_dir_rlock.keys().cloned().collect())
     }
 
-    fn lock(&mut self) -> Result<()> {
-        self.lock_file
-            .lock_exclusive()
-            .map_err(|_| NotusError::LockFailed(String::from(self.dir.to_string_lossy())))?;
+    /// Registers `merge_operator` as the one `merge_operator` returns for
+    /// `column`, replacing the one registered before. Registrations are not
+    /// persisted and have to be made again after reopening the store.
+    pub fn create_column_family(
+        &self,
+        column: &str,
+        merge_operator: impl MergeOperator + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.columns
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .insert(
+                column.to_string(),
+                Column {
+                    merge_operator: Arc::new(merge_operator),
+                },
+            );
         Ok(())
     }
 
-    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        let mut buffer = self
-            .buffer
+    /// Returns the columns holding live keys or registered with
+    /// `create_column_family`, sorted by name.
+    pub fn column_families(&self) -> Vec<String> {
+        let mut columns: BTreeSet<String> = self.keys_dir.columns().into_iter().collect();
+        if let Ok(registered) = self.columns.read() {
+            columns.extend(registered.keys().cloned());
+        }
+        columns.into_iter().collect()
+    }
+
+    /// Returns whether `column` holds a live key or is registered.
+    pub fn column_family_exists(&self, column: &str) -> bool {
+        self.columns
+            .read()
+            .is_ok_and(|registered| registered.contains_key(column))
+            || self.keys_dir.contains_prefix(&RawKey::column_prefix(column))
+    }
+
+    /// Unregisters `column` and deletes every key in it with one batch.
+    /// Keys written to it while the batch is built are left in place.
+    /// Returns the number of keys deleted.
+    pub fn drop_column_family(&self, column: &str) -> Result<usize> {
+        self.columns
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .remove(column);
+        let keys = self.range(RawKey::column_range(column, ..));
+        let count = keys.len();
+        self.write_batch(keys.into_iter().map(BatchOp::Delete).collect())?;
+        Ok(count)
+    }
+
+    /// Unregisters the columns that hold no live key, returning their names
+    /// sorted.
+    pub fn gc_columns(&self) -> Result<Vec<String>> {
+        let mut registered = self
+            .columns
             .write()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
-        buffer.insert(key.clone(), value.clone());
-        self.keys_dir.partial_insert(key);
-        Ok(())
+        let mut removed: Vec<String> = registered
+            .keys()
+            .filter(|column| !self.keys_dir.contains_prefix(&RawKey::column_prefix(column)))
+            .cloned()
+            .collect();
+        removed.sort();
+        for column in removed.iter() {
+            registered.remove(column);
+        }
+        Ok(removed)
     }
 
-    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let buffer = self
-            .buffer
+    /// Returns the merge operator registered for `column`, failing with
+    /// `NoMergeOperator` if there is none.
+    pub fn merge_operator(&self, column: &str) -> Result<Arc<dyn MergeOperator + Send + Sync>> {
+        self.columns
             .read()
-            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .get(column)
+            .map(|column| column.merge_operator.clone())
+            .ok_or_else(|| NotusError::NoMergeOperator(column.to_string()))
+    }
 
-        if let Some(value) = buffer.get(key) {
-            return Ok(Some(value.clone()));
+    /// Returns the application version recorded with `set_app_version`, `0`
+    /// if none was ever set.
+    pub fn app_version(&self) -> Result<u32> {
+        if !self.storage.list()?.iter().any(|name| name == META_FILE_NAME) {
+            return Ok(0);
         }
+        let meta_file = self.storage.open(META_FILE_NAME)?;
+        if meta_file.len()? < 4 {
+            return Ok(0);
+        }
+        let mut bytes = [0_u8; 4];
+        meta_file.read_at(0, &mut bytes)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
 
-        let key_dir_entry = match self.keys_dir.get(key) {
-            None => {
-                return Ok(None);
+    pub fn set_app_version(&self, version: u32) -> Result<()> {
+        if !self.storage.list()?.iter().any(|name| name == META_FILE_NAME) {
+            self.storage.create(META_FILE_NAME)?;
+        }
+        let meta_file = self.storage.open_append(META_FILE_NAME)?;
+        meta_file.truncate()?;
+        meta_file.append(&version.to_be_bytes())?;
+        meta_file.close()
+    }
+
+    /// Returns the live keys in `range` whose latest record is in file
+    /// `file_id`, in key order. Keys whose latest write is still buffered
+    /// are in no file.
+    pub fn keys_in_file<R>(&self, file_id: &str, range: R) -> Result<Vec<Vec<u8>>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        if !self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .contains_key(file_id)
+        {
+            return Err(NotusError::FileNotFound(file_id.to_string()));
+        }
+        let mut keys = vec![];
+        for (key, entry) in self.keys_dir.range_entries(range)? {
+            if entry.is_some_and(|entry| entry.file_id == file_id) {
+                keys.push(self.full_key(key)?);
             }
-            Some(value) => value,
-        };
+        }
+        Ok(keys)
+    }
 
+    /// Reads every hint of `file_id` in the order they were written.
+    pub fn hint_entries(&self, file_id: &str) -> Result<Vec<HintEntry>> {
         let files_dir_rlock = self
             .files_dir
             .read()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        match files_dir_rlock.get(file_id) {
+            Some(fp) => fp.get_hints(),
+            None => Err(NotusError::FileNotFound(file_id.to_string())),
+        }
+    }
 
-        let fp = match files_dir_rlock.get(&key_dir_entry.file_id) {
-            None => {
-                return Ok(None);
-            }
-            Some(fp) => fp,
-        };
-        let data_entry = fp.read(key_dir_entry.data_entry_position)?;
-        Ok(Some(data_entry.value()))
+    /// Appends `entry` straight to the active file without indexing it,
+    /// returning the file id and position it was written at.
+    pub fn append_raw(&self, entry: &DataEntry) -> Result<(String, u64)> {
+        let key_dir_entry = self.active_file()?.write(entry)?;
+        Ok((key_dir_entry.file_id, key_dir_entry.data_entry_position))
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<()> {
-        let mut buffer = self
-            .buffer
-            .write()
+    /// Reads the record at `position` of file `file_id`.
+    pub fn read_raw(&self, file_id: &str, position: u64) -> Result<DataEntry> {
+        let files_dir_rlock = self
+            .files_dir
+            .read()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        match files_dir_rlock.get(file_id) {
+            Some(fp) => self.file_handles.read(fp, position),
+            None => Err(NotusError::FileNotFound(file_id.to_string())),
+        }
+    }
 
-        buffer.remove(key);
-        self.active_file.remove(key.to_vec())?;
-        self.keys_dir.remove(key);
-        Ok(())
+    /// Captures the location of every live value. Values still in the write
+    /// buffer are copied so the snapshot does not depend on when they flush.
+    pub fn snapshot(&self) -> Result<BTreeMap<Vec<u8>, SnapshotEntry>> {
+        let buffer = self.buffer.read()?;
+        let files_dir_rlock = self
+            .files_dir
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut entries = BTreeMap::new();
+        for (key, entry) in self.keys_dir.range_entries(..)? {
+            let key = self.full_key_in(&buffer, key, entry.as_ref())?;
+            match entry {
+                Some(entry) => {
+                    let fp = files_dir_rlock
+                        .get(&entry.file_id)
+                        .ok_or(NotusError::CorruptValue)?;
+                    entries.insert(key, SnapshotEntry::Persisted(fp.clone(), entry));
+                }
+                None => {
+                    if let Some(entry) = buffer.get(&key) {
+                        entries.insert(key, SnapshotEntry::Buffered(entry.clone()));
+                    }
+                }
+            }
+        }
+        Ok(entries)
     }
 
-    pub fn contains(&self, key: &[u8]) -> Result<bool> {
-        let mut buffer = self
-            .buffer
+    pub fn read_snapshot_entry(&self, entry: &SnapshotEntry) -> Result<DataEntry> {
+        match entry {
+            SnapshotEntry::Persisted(fp, entry) => {
+                let files_dir_rlock = self
+                    .files_dir
+                    .read()
+                    .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+                // A pair compaction replaced is only left on disk by a pin,
+                // read it without caching a handle to it
+                let record = match files_dir_rlock.contains_key(&entry.file_id) {
+                    true => self.file_handles.read(fp, entry.data_entry_position)?,
+                    false => fp.read(entry.data_entry_position)?,
+                };
+                self.resolve(record)
+            }
+            SnapshotEntry::Buffered(entry) => self.resolve(entry.clone()),
+        }
+    }
+
+    /// Checks that every index entry points at a readable, valid record for
+    /// the same key and that every live hint is present in the index.
+    /// Problems are reported, not repaired.
+    pub fn audit(&self) -> Result<AuditReport> {
+        let mut report = AuditReport::default();
+        let files_dir_rlock = self
+            .files_dir
             .read()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
 
-        if buffer.contains_key(key) {
-            return Ok(true)
+        for (key, entry) in self.keys_dir.entries()? {
+            report.checked += 1;
+            let fp = match files_dir_rlock.get(&entry.file_id) {
+                None => {
+                    report.unreadable.push(key);
+                    continue;
+                }
+                Some(fp) => fp,
+            };
+            match self.file_handles.read(fp, entry.data_entry_position) {
+                Ok(data_entry) => {
+                    if self.keys_dir.index_key(&data_entry.key()) != key.as_slice() {
+                        report.key_mismatch.push(key);
+                    }
+                }
+                Err(NotusError::CorruptValue) => report.corrupt.push(key),
+                Err(_) => report.unreadable.push(key),
+            }
         }
 
-        let result = self.keys_dir.contains(key)?;
-        Ok(result)
+        let replayed = KeysDir::new(&files_dir_rlock, self.keys_dir.hash_keys_over)?;
+        for key in replayed.keys() {
+            if !self.keys_dir.contains(&key)? {
+                report.missing_from_index.push(key);
+            }
+        }
+        Ok(report)
     }
 
-    pub fn clear(&self) -> Result<()> {
-        for key in self.keys().iter() {
-            self.active_file.remove(key.clone())?;
+    /// The options the store was opened with.
+    pub fn options(&self) -> &NotusOptions {
+        &self.options
+    }
+
+    /// Holds back removing the files compaction and `collect_value_log`
+    /// replace until the matching `unpin`, so every file stays on disk.
+    pub fn pin(&self) -> Result<()> {
+        self.pins()?.held += 1;
+        Ok(())
+    }
+
+    /// Releases a pin taken with `pin`. Once none is left the files held
+    /// back are removed.
+    pub fn unpin(&self) -> Result<()> {
+        let mut pins = self.pins()?;
+        pins.held = pins.held.saturating_sub(1);
+        if pins.held > 0 {
+            return Ok(());
+        }
+        for name in std::mem::take(&mut pins.deferred) {
+            self.storage.remove(&name)?;
         }
-        self.keys_dir.clear()?;
-        let mut buffer = self
-            .buffer
-            .write()
-            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
-        buffer.clear();
         Ok(())
     }
 
-    pub fn keys(&self) -> Vec<Vec<u8>> {
-        self.keys_dir.keys()
+    /// Removes `names` from the storage, or once the last pin is released.
+    fn remove_files(&self, names: Vec<String>) -> Result<()> {
+        // Keep the lock while removing so a pin taken meanwhile sees every
+        // file that is still there
+        let mut pins = self.pins()?;
+        if pins.held > 0 {
+            pins.deferred.extend(names);
+            return Ok(());
+        }
+        for name in names {
+            self.storage.remove(&name)?;
+        }
+        Ok(())
     }
 
+    fn pins(&self) -> Result<MutexGuard<'_, Pins>> {
+        self.pins
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
 
-    pub fn range<R>(&self, range : R) -> Vec<Vec<u8>>  where R: RangeBounds<Vec<u8>>{
-        self.keys_dir.range(range)
+    fn count_written(&self, key_dir_entry: &KeyDirEntry) {
+        self.bytes_written
+            .fetch_add(key_dir_entry.record_size(), Ordering::AcqRel);
     }
 
-    pub fn prefix(&self, prefix: &Vec<u8>) -> Vec<Vec<u8>> {
-        self.keys_dir.prefix(prefix)
+    /// Returns the bytes of data records written since the last compaction
+    /// began, the compaction's own copies included, over the bytes of the
+    /// records holding live data. `0` when no data is live. Data on disk
+    /// when the store was opened counts as written.
+    pub fn write_amplification(&self) -> Result<f64> {
+        let live = self.live_bytes()?;
+        if live == 0 {
+            return Ok(0.0);
+        }
+        Ok(self.bytes_written() as f64 / live as f64)
     }
 
-    pub fn merge(&self) -> Result<()> {
-        let merged_file_pair = ActiveFilePair::from(create_new_file_pair(self.dir.as_path())?)?;
-        let mut mark_for_removal = Vec::new();
+    /// Bytes of data records written since the last compaction began, see
+    /// `write_amplification`.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Acquire)
+    }
 
-        let files_dir_rlock = self
-            .files_dir
+    /// Bytes of the data records holding live data, writes still in the
+    /// buffer excluded.
+    pub fn live_bytes(&self) -> Result<u64> {
+        Ok(self
+            .keys_dir
+            .range_entries(..)?
+            .iter()
+            .filter_map(|(_, entry)| entry.as_ref().map(KeyDirEntry::record_size))
+            .sum())
+    }
+
+    fn active_file(&self) -> Result<RwLockReadGuard<'_, ActiveFilePair>> {
+        self.active_file
             .read()
-            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
 
-        for (_, fp) in files_dir_rlock.iter() {
-            if fp.file_id() == self.active_file.file_id() {
-                continue;
+    pub fn flush(&self) -> Result<()> {
+        {
+            let mut buffer = self.buffer.write()?;
+            let active_file = self.active_file()?;
+            for (key, data_entry) in buffer.drain() {
+                let key_dir_entry = active_file.write(&data_entry)?;
+                self.count_written(&key_dir_entry);
+                self.keys_dir.insert(key, key_dir_entry);
             }
-            let hints = fp.get_hints()?;
-            for hint in hints {
-                if let Some(keys_dir_entry) = self.keys_dir.get(&hint.key()) {
-                    if keys_dir_entry.file_id == fp.file_id() {
-                        let data_entry = fp.read(hint.data_entry_position())?;
-                        let key_entry = merged_file_pair.write(&data_entry)?;
-                        self.keys_dir.insert(hint.key(), key_entry);
-                    }
-                }
+            active_file.flush()?;
+            if let Some(value_log) = &self.value_log {
+                value_log.flush()?;
             }
-            mark_for_removal.push(fp.data_file_path());
-            mark_for_removal.push(fp.hint_file_path());
         }
-
-        fs_extra::remove_items(&mark_for_removal);
-        Ok(())
+        self.rotate_if_full()
     }
 
-    pub fn flush(&self) -> Result<()> {
-        let mut buffer = self
-            .buffer
+    /// Moves writes to a new file pair once the active data file has reached
+    /// `max_file_size`. The full pair is synced and left in `files_dir`,
+    /// where merges pick it up like any other.
+    fn rotate_if_full(&self) -> Result<()> {
+        let max_file_size = match self.options.max_file_size {
+            Some(max_file_size) => max_file_size,
+            None => return Ok(()),
+        };
+        if self.active_file()?.data_size()? < max_file_size {
+            return Ok(());
+        }
+        let mut files_dir_wlock = self
+            .files_dir
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut active_file = self
+            .active_file
             .write()
             .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
-        for (key, value) in buffer.drain() {
-            let data_entry = DataEntry::new(key.clone(), value);
-            let key_dir_entry = self.active_file.write(&data_entry)?;
-            self.keys_dir.insert(key, key_dir_entry);
+        // Another writer may have rotated it in the meantime
+        if active_file.data_size()? < max_file_size {
+            return Ok(());
         }
+        let next_active_file = ActiveFilePair::from(create_new_file_pair(&self.storage)?)?
+            .with_integrity(self.integrity)
+            .with_compression(self.compression);
+        files_dir_wlock.insert(next_active_file.file_id(), next_active_file.get_file_pair());
+        write_manifest(&self.storage, files_dir_wlock.keys())?;
+        // A later barrier only syncs the new pair
+        active_file.sync()?;
+        let retired = std::mem::replace(&mut *active_file, next_active_file);
+        drop(active_file);
+        drop(retired);
         Ok(())
     }
+
+    /// Makes the write just made durable when `sync_on_write` is set.
+    fn sync_if_requested(&self) -> Result<()> {
+        match self.options.sync_on_write {
+            true => self.barrier(),
+            false => Ok(()),
+        }
+    }
+
+    /// Writes out the buffer and fsyncs the active file pair, so every write
+    /// made before the call is durable once it returns.
+    pub fn barrier(&self) -> Result<()> {
+        self.flush()?;
+        if let Some(value_log) = &self.value_log {
+            value_log.sync()?;
+        }
+        self.active_file()?.sync()
+    }
 }
 
 impl Drop for DataStore {
     fn drop(&mut self) {
         self.flush();
-        self.lock_file.unlock().unwrap();
+        self.storage.unlock().unwrap();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::datastore::{DataStore, RawKey, DEFAULT_INDEX};
+    use crate::datastore::{BatchOp, DataStore, RawKey, DEFAULT_INDEX, PREPARED_INDEX};
+    use crate::errors::NotusError;
+    use crate::file_ops::create_new_file_pair;
+    use crate::storage::{LocalStorage, Storage};
+    use std::sync::Arc;
+    use crate::nutos::NotusOptions;
+    use crate::schema::{DataEntry, Encoder};
     use serial_test::serial;
 
     #[test]
@@ -515,6 +3779,283 @@ mod tests {
         assert!(open_result.is_err());
     }
 
+    #[test]
+    #[serial]
+    fn test_mmap_active_file() {
+        clean_up();
+        let options = NotusOptions {
+            mmap_active_file: true,
+            mmap_chunk_size: 128,
+            ..Default::default()
+        };
+        let mut data_file_path = String::new();
+        {
+            let ds = DataStore::open_with_options("./testdir/_test_mmap_active_file", options).unwrap();
+            for i in 0..100_u8 {
+                ds.put(vec![i], vec![i; 10]).unwrap();
+            }
+            ds.flush().unwrap();
+            for i in 0..100_u8 {
+                assert_eq!(ds.get(&[i]).unwrap(), Some(vec![i; 10]));
+            }
+            let data_file_name = ds.active_file().unwrap().get_file_pair().data_file_name();
+            data_file_path.push_str(&format!("./testdir/_test_mmap_active_file/{}", data_file_name));
+        }
+
+        // Preallocated space past the last entry is truncated on close
+        let data_file_len = std::fs::metadata(&data_file_path).unwrap().len();
+        let entry_len = DataEntry::new(vec![0], vec![0; 10]).encode().len() as u64;
+        assert_eq!(data_file_len, entry_len * 100);
+
+        let ds = DataStore::open("./testdir/_test_mmap_active_file").unwrap();
+        for i in 0..100_u8 {
+            assert_eq!(ds.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+        drop(ds);
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_open_files() {
+        clean_up();
+        for i in 0..10_u8 {
+            let ds = DataStore::open("./testdir/_test_max_open_files").unwrap();
+            ds.put(vec![i], vec![i, i]).unwrap();
+        }
+
+        let options = NotusOptions {
+            max_open_files: 2,
+            ..Default::default()
+        };
+        let ds = DataStore::open_with_options("./testdir/_test_max_open_files", options).unwrap();
+        for _ in 0..3 {
+            for i in 0..10_u8 {
+                assert_eq!(ds.get(&[i]).unwrap(), Some(vec![i, i]));
+                assert!(ds.file_handles.len() <= 2);
+            }
+        }
+        drop(ds);
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_audit() {
+        clean_up();
+        let ds = DataStore::open("./testdir/_test_audit").unwrap();
+        ds.put(vec![1], vec![1]).unwrap();
+        ds.put(vec![2], vec![2]).unwrap();
+        ds.put(vec![3], vec![3]).unwrap();
+        ds.flush().unwrap();
+        assert!(ds.audit().unwrap().is_ok());
+
+        // Point key 1 at the record of key 2 and drop key 3 from the index
+        let entry = ds.keys_dir.get(&[2]).unwrap();
+        ds.keys_dir.insert(vec![1], entry).unwrap();
+        ds.keys_dir.remove(&[3]).unwrap();
+
+        let report = ds.audit().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.key_mismatch, vec![vec![1]]);
+        assert_eq!(report.missing_from_index, vec![vec![3]]);
+        assert!(report.corrupt.is_empty());
+        assert!(report.unreadable.is_empty());
+        drop(ds);
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_clear_and_reclaim() {
+        clean_up();
+        let dir = "./testdir/_test_clear_and_reclaim";
+        let store_size = || -> u64 {
+            let mut option = fs_extra::dir::DirOptions::new();
+            option.depth = 1;
+            let content = fs_extra::dir::get_dir_content2(dir, &option).unwrap();
+            content
+                .files
+                .iter()
+                .filter(|file| {
+                    !file.ends_with("nutos.lock")
+                        && !file.ends_with("MANIFEST")
+                        && !file.ends_with("FILE_ID")
+                        && !file.ends_with("nutos.encoding")
+                })
+                .map(|file| std::fs::metadata(file).unwrap().len())
+                .sum()
+        };
+
+        for i in 0..3_u8 {
+            let ds = DataStore::open(dir).unwrap();
+            ds.put(vec![i], vec![i; 4096]).unwrap();
+        }
+        let ds = DataStore::open(dir).unwrap();
+        ds.put(vec![3], vec![3; 4096]).unwrap();
+        ds.flush().unwrap();
+        assert!(store_size() > 4 * 4096);
+
+        ds.clear_and_reclaim().unwrap();
+        assert_eq!(store_size(), 0);
+        assert!(ds.keys().is_empty());
+        assert_eq!(ds.get(&[0]).unwrap(), None);
+
+        ds.put(vec![4], vec![4]).unwrap();
+        ds.flush().unwrap();
+        assert_eq!(ds.get(&[4]).unwrap(), Some(vec![4]));
+        drop(ds);
+
+        let ds = DataStore::open(dir).unwrap();
+        assert_eq!(ds.keys(), vec![vec![4]]);
+        drop(ds);
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_duplicate_file_id() {
+        clean_up();
+        let dir = "./testdir/_test_duplicate_file_id";
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir).unwrap());
+        let fp = create_new_file_pair(&storage).unwrap();
+        drop(storage);
+        for file_name in [fp.data_file_name(), fp.hint_file_name()] {
+            let path = format!("{}/{}", dir, file_name);
+            std::fs::copy(&path, format!("{}/0{}", dir, file_name)).unwrap();
+        }
+
+        match DataStore::open(dir) {
+            Err(NotusError::DuplicateFileId(id)) => assert_eq!(id, fp.file_id()),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("open should report the duplicate file id"),
+        }
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_lock_unavailable() {
+        clean_up();
+        let dir = "./testdir/_test_lock_unavailable";
+        // A directory in place of the lock file can not be opened, even by
+        // root, unlike a file with its permissions removed
+        fs_extra::dir::create_all(format!("{}/nutos.lock", dir), false).unwrap();
+
+        match DataStore::open(dir) {
+            Err(NotusError::LockUnavailable(path, _)) => assert!(path.ends_with("nutos.lock")),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("open should report the unavailable lock file"),
+        }
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_index_key_mismatch() {
+        clean_up();
+        let ds = DataStore::open("./testdir/_test_index_key_mismatch").unwrap();
+        ds.put(vec![1], vec![1]).unwrap();
+        ds.put(vec![2], vec![2]).unwrap();
+        ds.flush().unwrap();
+
+        // Point key 1 at the record of key 2
+        let wrong = ds.keys_dir.get(&[2]).unwrap();
+        ds.keys_dir.insert(vec![1], wrong.clone()).unwrap();
+        match ds.get(&[1]) {
+            Err(NotusError::IndexKeyMismatch(file_id, position)) => {
+                assert_eq!(file_id, wrong.file_id);
+                assert_eq!(position, wrong.data_entry_position);
+            }
+            other => panic!("expected a key mismatch, got {:?}", other),
+        }
+        let mut buf = vec![];
+        assert!(matches!(
+            ds.get_into(&[1], &mut buf),
+            Err(NotusError::IndexKeyMismatch(..))
+        ));
+        assert_eq!(ds.get(&[2]).unwrap(), Some(vec![2]));
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_keys_over() {
+        clean_up();
+        let dir = "./testdir/_test_hash_keys_over";
+        let long_key = |i: u8| {
+            let mut key = vec![b'k'; 4096];
+            key.push(i);
+            key
+        };
+        let options = NotusOptions::default().hash_keys_over(64);
+        {
+            let ds = DataStore::open_with_options(dir, options.clone()).unwrap();
+            for i in 0..100_u8 {
+                ds.put(long_key(i), vec![i]).unwrap();
+            }
+            ds.put(vec![1; 8], vec![1]).unwrap();
+            // Listed keys are read back from the buffer and the data files
+            assert_eq!(ds.keys().len(), 101);
+            assert!(ds.keys().contains(&long_key(7)));
+            ds.flush().unwrap();
+            ds.delete(&long_key(0)).unwrap();
+        }
+
+        let ds = DataStore::open_with_options(dir, options).unwrap();
+        assert_eq!(ds.get(&long_key(0)).unwrap(), None);
+        for i in 1..100_u8 {
+            assert_eq!(ds.get(&long_key(i)).unwrap(), Some(vec![i]));
+        }
+        assert_eq!(ds.get(&long_key(200)).unwrap(), None);
+        assert_eq!(ds.get(&[1; 8]).unwrap(), Some(vec![1]));
+        let indexed: usize = ds.keys_dir.keys().iter().map(|key| key.len()).sum();
+        assert_eq!(indexed, 99 * 64 + 8);
+        let mut keys = ds.keys();
+        keys.sort();
+        let mut expected: Vec<_> = (1..100).map(long_key).collect();
+        expected.push(vec![1; 8]);
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        // A key colliding with another reads as missing, not as a mismatch
+        let other = ds.keys_dir.get(&long_key(1)).unwrap();
+        ds.keys_dir.insert(long_key(2), other).unwrap();
+        assert_eq!(ds.get(&long_key(2)).unwrap(), None);
+        let mut buf = vec![];
+        assert!(!ds.get_into(&long_key(2), &mut buf).unwrap());
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_commit_cut_short_is_not_applied_twice() {
+        clean_up();
+        let dir = "./testdir/_test_commit_cut_short";
+        {
+            let ds = DataStore::open(dir).unwrap();
+            ds.prepare("txn", vec![BatchOp::Put(vec![1], vec![1])]).unwrap();
+            // The batch and its marker are written, the staged batch is not
+            // removed yet
+            let marker = RawKey::new(PREPARED_INDEX, b"txn".to_vec()).encode();
+            ds.write_batch(vec![BatchOp::Put(vec![1], vec![1]), BatchOp::Put(marker, vec![])])
+                .unwrap();
+            ds.put(vec![1], vec![2]).unwrap();
+            ds.flush().unwrap();
+        }
+
+        let ds = DataStore::open(dir).unwrap();
+        assert!(ds.prepared().unwrap().is_empty());
+        assert!(!ds.keys_dir.contains_prefix(&RawKey::column_prefix(PREPARED_INDEX)));
+        assert_eq!(ds.get(&[1]).unwrap(), Some(vec![2]));
+        assert!(matches!(
+            ds.commit_prepared("txn"),
+            Err(NotusError::BatchNotPrepared(_))
+        ));
+        clean_up()
+    }
+
     fn clean_up() {
         fs_extra::dir::remove("./testdir");
     }
@@ -12,12 +12,45 @@ pub enum NotusError {
     FSExtraError(#[from] fs_extra::error::Error),
     #[error("Data Corrupt")]
     CorruptValue,
+    #[error("value of key {key:?} can not be decompressed")]
+    DecompressionError {
+        /// The key as stored, qualified by its column.
+        key: Vec<u8>,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("reached the end of the input")]
+    UnexpectedEof,
     #[error("Merge failed")]
     MergeError,
+    #[error("no merge operator is registered for column {0}")]
+    NoMergeOperator(String),
     #[error("failed to lock nutos director {0}")]
     LockFailed(String),
+    #[error("lock file {0} can not be opened, check that it is a readable file or remove it if no process has the store open")]
+    LockUnavailable(String, #[source] io::Error),
     #[error("RW lock poison {0}")]
     RWLockPoisonError(String),
+    #[error("invalid timestamp {0}, timestamps must be positive")]
+    InvalidTimestamp(i64),
+    #[error("two file pairs share the file id {0}")]
+    DuplicateFileId(String),
+    #[error("no file pair with id {0}")]
+    FileNotFound(String),
+    #[error("the index points at a record of another key, in file {0} at {1}")]
+    IndexKeyMismatch(String, u64),
+    #[error("input is not sorted, key {0:?} does not come after the key before it")]
+    UnsortedInput(Vec<u8>),
+    #[error("key already holds a different value")]
+    Conflict,
+    #[error("no batch is prepared with id {0}")]
+    BatchNotPrepared(String),
+    #[error("the store can not be migrated: {0}")]
+    UnsupportedMigration(String),
+    #[error("the key is not reserved")]
+    NotReserved,
+    #[error("the operation did not complete in time")]
+    Timeout,
     #[error("unknown data store error")]
     Unknown,
 }
@@ -1,133 +1,503 @@
-use chrono::Utc;
-use fs_extra::dir::DirOptions;
-use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write, Cursor};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::io::{self, Cursor, Read};
 use crate::Result;
-use crate::datastore::{KeyDirEntry, KeysDir, RawKey};
+use crate::datastore::{BatchOp, KeyDirEntry, KeysDir};
 use crate::errors::NotusError;
-use crate::schema::{DataEntry, Decoder, Encoder, HintEntry};
-use fs2::FileExt;
+use crate::schema::{
+    Compression, DataEntry, Decoder, Encoder, HintEntry, IntegrityAlgo, ValuePointer, CRC_CKSUM,
+    DATA_ENTRY_HEADER_SIZE,
+};
+use crate::storage::{Storage, StorageFile};
+use crossbeam_queue::ArrayQueue;
+use log::warn;
+use std::sync::{Arc, Mutex, RwLock};
 
 const DATA_FILE_EXTENSION: &str = "data";
 const HINT_FILE_EXTENSION: &str = "hint";
+const VALUE_LOG_EXTENSION: &str = "vlog";
+const PREPARED_BATCH_EXTENSION: &str = "prepared";
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+/// Holds the last file id handed out, so ids keep growing after the files
+/// with the highest ids are removed.
+const FILE_ID_FILE_NAME: &str = "FILE_ID";
+/// The first file id of a new store. Older stores named their files after
+/// the creation time in nanoseconds, 19 digits long; starting here keeps
+/// every id at 19 digits, so ids of both kinds sort as strings.
+const FIRST_FILE_ID: u64 = 1_000_000_000_000_000_000;
+const READ_BUFFER_POOL_SIZE: usize = 64;
+const MAX_POOLED_BUFFER_SIZE: usize = 1024 * 1024;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct FilePair {
     file_id: String,
-    data_file_path: PathBuf,
-    hint_file_path: PathBuf,
+    storage: Arc<dyn Storage>,
+}
+
+impl Debug for FilePair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FilePair")
+            .field("file_id", &self.file_id)
+            .finish()
+    }
 }
 
 impl FilePair {
-    fn new(file_id: &str) -> Self {
+    fn new(file_id: &str, storage: Arc<dyn Storage>) -> Self {
         Self {
             file_id: file_id.to_string(),
-            data_file_path: Default::default(),
-            hint_file_path: Default::default(),
+            storage,
         }
     }
 
-    pub fn data_file_path(&self) -> String {
-        String::from(self.data_file_path.to_string_lossy())
+    pub fn data_file_name(&self) -> String {
+        format!("{}.{}", self.file_id, DATA_FILE_EXTENSION)
+    }
+
+    pub fn hint_file_name(&self) -> String {
+        format!("{}.{}", self.file_id, HINT_FILE_EXTENSION)
     }
+}
+
+/// Adapts a `StorageFile` to `Read`, starting at `position`.
+struct StorageReader<'a> {
+    file: &'a dyn StorageFile,
+    position: u64,
+}
 
-    pub fn hint_file_path(&self) -> String {
-        String::from(self.hint_file_path.to_string_lossy())
+impl Read for StorageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file
+            .read_at(self.position, buf)
+            .map_err(|e| match e {
+                NotusError::IOError(e) => e,
+                e => io::Error::other(e),
+            })?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
     }
 }
 
+fn read_data_entry(data_file: &dyn StorageFile, entry_position: u64, buf: &mut Vec<u8>) -> Result<DataEntry> {
+    let mut reader = StorageReader {
+        file: data_file,
+        position: entry_position,
+    };
+    DataEntry::decode_checked(&mut reader, buf)
+}
+
 impl FilePair {
     pub fn read(&self, entry_position: u64) -> Result<DataEntry> {
-        let data_file = File::open(&self.data_file_path.as_path())?;
-        let mut reader = BufReader::new(data_file);
-        reader.seek(SeekFrom::Start(entry_position))?;
-        let data_entry = DataEntry::decode(&mut reader)?;
-        if !data_entry.check_crc() {
-            return Err(NotusError::CorruptValue);
-        }
-        Ok(data_entry)
+        let data_file = self.storage.open(&self.data_file_name())?;
+        read_data_entry(data_file.as_ref(), entry_position, &mut vec![])
+    }
+
+    fn read_hint_file(&self) -> Result<Cursor<Vec<u8>>> {
+        let hint_file = self.storage.open(&self.hint_file_name())?;
+        let mut bytes = vec![0_u8; hint_file.len()? as usize];
+        hint_file.read_at(0, &mut bytes)?;
+        Ok(Cursor::new(bytes))
     }
 
     pub fn fetch_hint_entries(&self, keys_dir: &KeysDir) -> Result<()> {
-        let hint_file = File::open(&self.hint_file_path.as_path())?;
-        let mut rdr = BufReader::new(hint_file);
-        while let Ok(hint_entry) = HintEntry::decode(&mut rdr) {
+        for hint_entry in self.get_hints()? {
             if hint_entry.is_deleted() {
-                keys_dir.remove(&hint_entry.key());
+                keys_dir.tombstone(&hint_entry.key(), &self.file_id)?;
             } else {
                 let key_dir_entry = KeyDirEntry::new(
                     self.file_id.to_string(),
                     hint_entry.key_size(),
                     hint_entry.value_size(),
                     hint_entry.data_entry_position(),
-                );
+                    hint_entry.timestamp(),
+                )
+                .with_expiry(hint_entry.expires_at())
+                .with_compression(hint_entry.compression());
                 keys_dir.insert(hint_entry.key(), key_dir_entry);
             }
         }
         Ok(())
     }
 
+    /// Reads the hints of the pair in the order they were written. A hint
+    /// cut short, e.g. by a crash while it was appended, ends the list.
     pub fn get_hints(&self) -> Result<Vec<HintEntry>> {
+        Ok(self.read_hints()?.0)
+    }
+
+    /// Like `get_hints`, also returning whether the hint file was read to its
+    /// end without running into a damaged hint.
+    pub fn read_hints(&self) -> Result<(Vec<HintEntry>, bool)> {
+        let (hints, _, complete) = self.decode_hints()?;
+        Ok((hints, complete))
+    }
+
+    /// Reads the hints of the pair up to the first one that is cut short or
+    /// points past the end of the data file, returning them with the number
+    /// of hint bytes they take up and whether that is the whole hint file.
+    fn decode_hints(&self) -> Result<(Vec<HintEntry>, u64, bool)> {
         let mut hints = vec![];
-        let hint_file = File::open(&self.hint_file_path.as_path())?;
-        let mut rdr = BufReader::new(hint_file);
-        while let Ok(hint_entry) = HintEntry::decode(&mut rdr) {
-            hints.push(hint_entry)
+        let mut rdr = self.read_hint_file()?;
+        let len = rdr.get_ref().len() as u64;
+        // Read after the hints, the data file only grew since they were
+        // appended
+        let data_size = self.data_size()?;
+        loop {
+            let start = rdr.position();
+            match HintEntry::decode(&mut rdr) {
+                Ok(hint_entry)
+                    if !hint_entry.is_deleted()
+                        && hint_entry.data_entry_position() + hint_entry.record_size() > data_size =>
+                {
+                    warn!(
+                        "hint file {} points past the end of {} after {} hints",
+                        self.hint_file_name(),
+                        self.data_file_name(),
+                        hints.len()
+                    );
+                    return Ok((hints, start, false));
+                }
+                Ok(hint_entry) => hints.push(hint_entry),
+                Err(NotusError::UnexpectedEof) => return Ok((hints, start, start == len)),
+                Err(NotusError::CorruptValue) => {
+                    warn!(
+                        "hint file {} is corrupt after {} hints",
+                        self.hint_file_name(),
+                        hints.len()
+                    );
+                    return Ok((hints, start, false));
+                }
+                Err(e) => return Err(e),
+            }
         }
-        Ok(hints)
+    }
+
+    /// Cuts off what a crash left behind at the end of the pair: a damaged
+    /// hint and everything after it, and the data records past the last one
+    /// a kept hint points at, which no hint made visible. Returns whether
+    /// anything was cut.
+    pub fn truncate_torn_tail(&self) -> Result<bool> {
+        let (hints, hints_len, _) = self.decode_hints()?;
+        let data_len = hints
+            .iter()
+            .filter(|hint| !hint.is_deleted())
+            .map(|hint| hint.data_entry_position() + hint.record_size())
+            .max()
+            .unwrap_or(0);
+        let hint_size = self.storage.open(&self.hint_file_name())?.len()?;
+        let data_size = self.data_size()?;
+        if hint_size == hints_len && data_size == data_len {
+            return Ok(false);
+        }
+        warn!(
+            "truncating pair {} to {} of {} hint bytes and {} of {} data bytes",
+            self.file_id, hints_len, hint_size, data_len, data_size
+        );
+        let data_file = self.storage.open_append(&self.data_file_name())?;
+        let hint_file = self.storage.open_append(&self.hint_file_name())?;
+        data_file.set_len(data_len)?;
+        hint_file.set_len(hints_len)?;
+        data_file.close()?;
+        hint_file.close()?;
+        Ok(true)
+    }
+
+    /// Rebuilds the hints of the pair by reading the data file front to
+    /// back, stopping at the first record that can not be decoded. Returns
+    /// them with the number of data bytes they cover.
+    pub fn scan_hints(&self) -> Result<(Vec<HintEntry>, u64)> {
+        let data_file = self.storage.open(&self.data_file_name())?;
+        let len = data_file.len()?;
+        let mut hints = vec![];
+        let mut buf = vec![];
+        let mut position = 0;
+        while position < len {
+            let entry = match read_data_entry(data_file.as_ref(), position, &mut buf) {
+                Ok(entry) => entry,
+                Err(_) => break,
+            };
+            hints.push(HintEntry::from(&entry, position));
+            position += entry.record_size();
+        }
+        Ok((hints, position))
     }
 
     pub fn file_id(&self) -> String {
         self.file_id.to_owned()
     }
+
+    /// Returns whether nothing was ever written to the pair.
+    pub fn is_unused(&self) -> Result<bool> {
+        Ok(self.storage.open(&self.data_file_name())?.is_empty()?
+            && self.storage.open(&self.hint_file_name())?.is_empty()?)
+    }
+
+    /// Returns the size of the data file in bytes.
+    pub fn data_size(&self) -> Result<u64> {
+        self.storage.open(&self.data_file_name())?.len()
+    }
+
+    /// Removes the data and hint files of the pair from the storage.
+    pub fn remove(&self) -> Result<()> {
+        self.storage.remove(&self.data_file_name())?;
+        self.storage.remove(&self.hint_file_name())?;
+        Ok(())
+    }
+}
+
+/// A bounded pool of scratch buffers shared by the read paths. Buffers that
+/// grew past `max_buffer_size` are dropped instead of being kept around.
+pub struct BufferPool {
+    buffers: ArrayQueue<Vec<u8>>,
+    max_buffer_size: usize,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize, max_buffer_size: usize) -> Self {
+        Self {
+            buffers: ArrayQueue::new(capacity.max(1)),
+            max_buffer_size,
+        }
+    }
+
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_default()
+    }
+
+    pub fn give_back(&self, mut buf: Vec<u8>) {
+        if buf.capacity() > self.max_buffer_size {
+            return;
+        }
+        buf.clear();
+        // The pool is full, let the buffer be freed
+        let _ = self.buffers.push(buf);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct HandleLru {
+    handles: HashMap<String, Arc<dyn StorageFile>>,
+    order: VecDeque<String>,
+}
+
+/// Keeps up to `max_open_files` data files open for reading, closing the least
+/// recently used handle when a new one would exceed the limit.
+pub struct FileHandleCache {
+    max_open_files: usize,
+    lru: Mutex<HandleLru>,
+    buffers: BufferPool,
+}
+
+impl FileHandleCache {
+    pub fn new(max_open_files: usize) -> Self {
+        Self {
+            max_open_files,
+            lru: Mutex::new(HandleLru::default()),
+            buffers: BufferPool::new(READ_BUFFER_POOL_SIZE, MAX_POOLED_BUFFER_SIZE),
+        }
+    }
+
+    pub fn read(&self, file_pair: &FilePair, entry_position: u64) -> Result<DataEntry> {
+        let mut buf = self.buffers.take();
+        let result = if self.max_open_files == 0 {
+            let data_file = file_pair.storage.open(&file_pair.data_file_name())?;
+            read_data_entry(data_file.as_ref(), entry_position, &mut buf)
+        } else {
+            let data_file = self.handle(file_pair)?;
+            read_data_entry(data_file.as_ref(), entry_position, &mut buf)
+        };
+        self.buffers.give_back(buf);
+        result
+    }
+
+    /// Fills `buf` with the raw bytes of the data file starting at `position`.
+    pub fn read_at(&self, file_pair: &FilePair, position: u64, buf: &mut [u8]) -> Result<()> {
+        if self.max_open_files == 0 {
+            let data_file = file_pair.storage.open(&file_pair.data_file_name())?;
+            data_file.read_at(position, buf)
+        } else {
+            self.handle(file_pair)?.read_at(position, buf)
+        }
+    }
+
+    /// Reads the value of the entry at `entry_position` into `buf`, failing
+    /// with `IndexKeyMismatch` if the entry is not one of `key`.
+    pub fn read_value_into(
+        &self,
+        file_pair: &FilePair,
+        entry_position: u64,
+        key: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        let data_file = if self.max_open_files == 0 {
+            Arc::from(file_pair.storage.open(&file_pair.data_file_name())?)
+        } else {
+            self.handle(file_pair)?
+        };
+        let mut reader = StorageReader {
+            file: data_file.as_ref(),
+            position: entry_position,
+        };
+        let key_size = DataEntry::read_into(&mut reader, buf)?;
+        if &buf[..key_size] != key {
+            return Err(NotusError::IndexKeyMismatch(file_pair.file_id(), entry_position));
+        }
+        buf.drain(..key_size);
+        Ok(())
+    }
+
+    /// Reads the entry at `entry_position`, failing with `IndexKeyMismatch`
+    /// if it is not one of `key`.
+    pub fn read_checked(&self, file_pair: &FilePair, entry_position: u64, key: &[u8]) -> Result<DataEntry> {
+        let entry = self.read(file_pair, entry_position)?;
+        if entry.key_slice() != key {
+            return Err(NotusError::IndexKeyMismatch(file_pair.file_id(), entry_position));
+        }
+        Ok(entry)
+    }
+
+    fn handle(&self, file_pair: &FilePair) -> Result<Arc<dyn StorageFile>> {
+        let mut lru = self
+            .lru
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        if let Some(handle) = lru.handles.get(&file_pair.file_id).cloned() {
+            lru.order.retain(|file_id| file_id != &file_pair.file_id);
+            lru.order.push_back(file_pair.file_id.clone());
+            return Ok(handle);
+        }
+
+        while lru.handles.len() >= self.max_open_files {
+            match lru.order.pop_front() {
+                Some(file_id) => {
+                    lru.handles.remove(&file_id);
+                }
+                None => break,
+            }
+        }
+        let handle: Arc<dyn StorageFile> =
+            Arc::from(file_pair.storage.open(&file_pair.data_file_name())?);
+        lru.handles.insert(file_pair.file_id.clone(), handle.clone());
+        lru.order.push_back(file_pair.file_id.clone());
+        Ok(handle)
+    }
+
+    /// Closes the handle for `file_id`, used once the file is removed.
+    pub fn remove(&self, file_id: &str) -> Result<()> {
+        let mut lru = self
+            .lru
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        lru.handles.remove(file_id);
+        lru.order.retain(|id| id != file_id);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        match self.lru.lock() {
+            Ok(lru) => lru.handles.len(),
+            Err(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 pub struct ActiveFilePair {
-    hint_file: File,
-    data_file: File,
+    hint_file: Box<dyn StorageFile>,
+    data_file: Box<dyn StorageFile>,
     file_pair: FilePair,
+    integrity: IntegrityAlgo,
+    compression: Compression,
 }
 
 impl ActiveFilePair {
     pub fn from(file_pair: FilePair) -> Result<Self> {
-        let data_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&file_pair.data_file_path.as_path())?;
-        let hint_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&file_pair.hint_file_path.as_path())?;
+        let data_file = file_pair.storage.open_append(&file_pair.data_file_name())?;
+        let hint_file = file_pair.storage.open_append(&file_pair.hint_file_name())?;
         Ok(Self {
             hint_file,
             data_file,
             file_pair,
+            integrity: IntegrityAlgo::default(),
+            compression: Compression::default(),
         })
     }
 
+    /// Protects the records written from now on with `integrity`.
+    pub fn with_integrity(mut self, integrity: IntegrityAlgo) -> Self {
+        self.integrity = integrity;
+        self
+    }
+
+    /// Compresses the values written from now on with `compression`.
+    /// Records read back from another pair keep the compression they have.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     pub fn get_file_pair(&self) -> FilePair {
         self.file_pair.clone()
     }
 
+    /// Waits until the data records and their hints are durable.
     pub fn sync(&self) -> Result<()> {
-        self.hint_file.sync_all()?;
-        self.data_file.sync_all()?;
+        self.flush_hint()
+    }
+
+    /// Waits until every data record appended so far is durable, leaving
+    /// the hint file as it is.
+    pub fn flush_data(&self) -> Result<()> {
+        self.data_file.sync()
+    }
+
+    /// Waits until every hint appended so far is durable. The data file is
+    /// synced first: a hint that outlives a crash its record did not would
+    /// point the index at bytes that are not there, while a record without
+    /// its hint is only missing from the index.
+    pub fn flush_hint(&self) -> Result<()> {
+        self.flush_data()?;
+        self.hint_file.sync()
+    }
+
+    /// Drops every entry written to the pair.
+    pub fn truncate(&self) -> Result<()> {
+        self.data_file.truncate()?;
+        self.hint_file.truncate()?;
         Ok(())
     }
 
+    /// Schedules a write back of the data file without waiting for it.
+    pub fn flush(&self) -> Result<()> {
+        self.data_file.flush()
+    }
+
     pub fn file_id(&self) -> String {
         self.file_pair.file_id.to_owned()
     }
+
+    /// Bytes appended to the data file so far.
+    pub fn data_size(&self) -> Result<u64> {
+        self.data_file.len()
+    }
 }
 
 impl Drop for ActiveFilePair {
     fn drop(&mut self) {
-        match self.sync() {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Sync Error: {:#?}", e)
+        for file in [&self.data_file, &self.hint_file] {
+            if let Err(e) = file.close() {
+                eprintln!("Close Error: {:#?}", e)
             }
         }
     }
@@ -135,134 +505,901 @@ impl Drop for ActiveFilePair {
 
 impl ActiveFilePair {
     pub fn write(&self, entry: &DataEntry) -> Result<KeyDirEntry> {
-        self.data_file.try_lock_exclusive()?;
-        self.hint_file.try_lock_exclusive()?;
-
         //Appends entry to data file
-        let mut dfw = BufWriter::new(&self.data_file);
-        let data_entry_position = dfw.seek(SeekFrom::End(0))?;
-        dfw.write_all(&entry.encode())?;
-        dfw.flush();
+        let (key_dir_entry, hint) = self.write_data(entry)?;
         //Append hint to hint file
-        let hint_entry = HintEntry::from(entry, data_entry_position);
-        let mut hfw = BufWriter::new(&self.hint_file);
-        hfw.seek(SeekFrom::End(0))?;
-        hfw.write_all(&hint_entry.encode())?;
-        hfw.flush();
+        self.hint_file.append(&hint)?;
+        Ok(key_dir_entry)
+    }
 
-        self.data_file.unlock()?;
-        self.hint_file.unlock()?;
+    /// Appends the data records of `entries` first and then all of their
+    /// hints with a single append, so a crash before the hints land leaves
+    /// none of the batch visible on reopen. `None` stands for a tombstone of
+    /// the key. Returns the index entry of every put, in order.
+    pub fn write_batch(
+        &self,
+        entries: &[(Vec<u8>, Option<DataEntry>)],
+    ) -> Result<Vec<Option<KeyDirEntry>>> {
+        let mut hints = vec![];
+        let mut key_dir_entries = vec![];
+        for (key, entry) in entries {
+            match entry {
+                Some(entry) => {
+                    let (key_dir_entry, hint) = self.write_data(entry)?;
+                    hints.extend_from_slice(&hint);
+                    key_dir_entries.push(Some(key_dir_entry));
+                }
+                None => {
+                    hints.extend_from_slice(&HintEntry::tombstone(key.clone()).encode());
+                    key_dir_entries.push(None);
+                }
+            }
+        }
+        self.write_hints(&hints)?;
+        Ok(key_dir_entries)
+    }
 
-        Ok(KeyDirEntry::new(
+    /// Appends the data record of `entry` but not its hint, so the record
+    /// stays invisible on reopen until the returned hint is appended with
+    /// `write_hints`. Returns the index entry and the encoded hint.
+    pub fn write_data(&self, entry: &DataEntry) -> Result<(KeyDirEntry, Vec<u8>)> {
+        let compressed;
+        let entry = match self.compression {
+            Compression::None => entry,
+            compression => {
+                compressed = entry.compressed(compression)?;
+                &compressed
+            }
+        };
+        let data_entry_position = self.data_file.append(&entry.encode_with(self.integrity))?;
+        let hint_entry = HintEntry::from(entry, data_entry_position);
+        let key_dir_entry = KeyDirEntry::new(
             self.file_pair.file_id.to_string(),
             hint_entry.key_size(),
             hint_entry.value_size(),
             data_entry_position,
-        ))
+            hint_entry.timestamp(),
+        )
+        .with_expiry(hint_entry.expires_at())
+        .with_compression(hint_entry.compression());
+        Ok((key_dir_entry, hint_entry.encode()))
+    }
+
+    /// Appends hints returned by `write_data` with a single append, after the
+    /// data records they point at.
+    pub fn write_hints(&self, hints: &[u8]) -> Result<()> {
+        self.data_file.flush()?;
+        self.hint_file.append(hints)?;
+        Ok(())
     }
 
+    /// Appends a tombstone for `key`. `key` must be the column qualified key
+    /// the entry was written with, otherwise reopening removes the wrong key.
     pub fn remove(&self, key: Vec<u8>) -> Result<()> {
-        self.hint_file.try_lock_exclusive()?;
         //Append hint to hint file
         let hint_entry = HintEntry::tombstone(key);
-        let mut hfw = BufWriter::new(&self.hint_file);
-        hfw.seek(SeekFrom::End(0))?;
-        hfw.write_all(&hint_entry.encode())?;
-        hfw.flush();
-        self.hint_file.unlock()?;
+        self.hint_file.append(&hint_entry.encode())?;
         Ok(())
     }
 }
 
-pub fn create_new_file_pair<P: AsRef<Path>>(dir: P) -> Result<FilePair> {
-    fs_extra::dir::create_all(dir.as_ref(), false)?;
-    let file_name = Utc::now().timestamp_nanos().to_string();
-    let mut data_file_path = PathBuf::new();
-    data_file_path.push(dir.as_ref());
-    data_file_path.push(format!("{}.{}", file_name, DATA_FILE_EXTENSION));
-    data_file_path.set_extension(DATA_FILE_EXTENSION);
-
-    let mut hint_file_path = PathBuf::new();
-    hint_file_path.push(dir.as_ref());
-    hint_file_path.push(format!("{}.{}", file_name, HINT_FILE_EXTENSION));
-    hint_file_path.set_extension(HINT_FILE_EXTENSION);
-
-    OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(data_file_path.as_path())?;
-    OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(hint_file_path.as_path())?;
-
-    Ok(FilePair {
-        data_file_path,
-        hint_file_path,
-        file_id: file_name,
-    })
-}
-
-pub fn get_lock_file<P: AsRef<Path>>(dir: P) -> Result<File> {
-    let mut lock_file_path = PathBuf::new();
-    lock_file_path.push(dir.as_ref());
-    lock_file_path.push("nutos.lock");
-    fs_extra::dir::create_all(dir.as_ref(), false)?;
-    let file = OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(true)
-        .open(lock_file_path.as_path())?;
-    Ok(file)
-}
-
-pub fn fetch_file_pairs<P: AsRef<Path>>(dir: P) -> Result<BTreeMap<String, FilePair>> {
+/// The files values are appended to when `NotusOptions::kv_separation` is
+/// set. Records are encoded like data entries, key included, so that
+/// `ValueLog::for_each_record` can tell which key a value belongs to.
+/// Values are only ever appended to the newest file, older files are
+/// rewritten and removed by `DataStore::collect_value_log`.
+pub struct ValueLog {
+    storage: Arc<dyn Storage>,
+    active: RwLock<(String, Box<dyn StorageFile>)>,
+    files: RwLock<BTreeMap<String, Arc<dyn StorageFile>>>,
+    integrity: IntegrityAlgo,
+}
+
+impl ValueLog {
+    /// Opens the value log files in `storage` and starts a new one to append
+    /// to, protecting the values appended with `integrity`. Files left empty
+    /// by earlier opens are removed.
+    pub fn open(storage: &Arc<dyn Storage>, integrity: IntegrityAlgo) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for file in storage.list()? {
+            if let Some((file_id, VALUE_LOG_EXTENSION)) = file.rsplit_once('.') {
+                let handle: Arc<dyn StorageFile> = Arc::from(storage.open(&file)?);
+                if handle.is_empty()? {
+                    storage.remove(&file)?;
+                } else {
+                    files.insert(file_id.to_owned(), handle);
+                }
+            }
+        }
+        let active = Self::create_file(storage, &mut files)?;
+        Ok(Self {
+            storage: storage.clone(),
+            active: RwLock::new(active),
+            files: RwLock::new(files),
+            integrity,
+        })
+    }
+
+    /// Returns whether `storage` holds any value log file.
+    pub fn exists(storage: &Arc<dyn Storage>) -> Result<bool> {
+        Ok(storage
+            .list()?
+            .iter()
+            .any(|file| matches!(file.rsplit_once('.'), Some((_, VALUE_LOG_EXTENSION)))))
+    }
+
+    fn file_name(file_id: &str) -> String {
+        format!("{}.{}", file_id, VALUE_LOG_EXTENSION)
+    }
+
+    fn create_file(
+        storage: &Arc<dyn Storage>,
+        files: &mut BTreeMap<String, Arc<dyn StorageFile>>,
+    ) -> Result<(String, Box<dyn StorageFile>)> {
+        let file_id = new_file_id(storage)?;
+        let file_name = Self::file_name(&file_id);
+        storage.create(&file_name)?;
+        files.insert(file_id.clone(), Arc::from(storage.open(&file_name)?));
+        Ok((file_id, storage.open_append(&file_name)?))
+    }
+
+    /// Appends `entry` and returns where its value was written.
+    pub fn append(&self, entry: &DataEntry) -> Result<ValuePointer> {
+        let active = self
+            .active
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let position = active.1.append(&entry.encode_with(self.integrity))?;
+        active.1.flush()?;
+        Ok(ValuePointer {
+            file_id: active.0.clone(),
+            position,
+            value_size: entry.value_size(),
+        })
+    }
+
+    fn handle(&self, file_id: &str) -> Result<Arc<dyn StorageFile>> {
+        self.files
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .get(file_id)
+            .cloned()
+            .ok_or_else(|| NotusError::FileNotFound(file_id.to_string()))
+    }
+
+    /// Reads the record `pointer` points at into `buf`, leaving only the
+    /// value, and fails with `IndexKeyMismatch` if it is not one of `key`.
+    pub fn read_value_into(&self, pointer: &ValuePointer, key: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+        let file = self.handle(&pointer.file_id)?;
+        let mut reader = StorageReader {
+            file: file.as_ref(),
+            position: pointer.position,
+        };
+        let key_size = DataEntry::read_into(&mut reader, buf)?;
+        if &buf[..key_size] != key {
+            return Err(NotusError::IndexKeyMismatch(
+                pointer.file_id.clone(),
+                pointer.position,
+            ));
+        }
+        buf.drain(..key_size);
+        Ok(())
+    }
+
+    /// Fills `buf` with the bytes of the value `pointer` points at, starting
+    /// `offset` bytes into it. The crc is not checked.
+    pub fn read_value_range(
+        &self,
+        pointer: &ValuePointer,
+        key_size: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let position = pointer.position + DATA_ENTRY_HEADER_SIZE as u64 + key_size + offset;
+        self.handle(&pointer.file_id)?.read_at(position, buf)
+    }
+
+    /// Starts appending to a new file and returns the ids of the files
+    /// written before, oldest first.
+    pub fn rotate(&self) -> Result<Vec<String>> {
+        let mut files = self
+            .files
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let next = Self::create_file(&self.storage, &mut files)?;
+        let mut active = self
+            .active
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let retired = std::mem::replace(&mut *active, next);
+        retired.1.close()?;
+        Ok(files
+            .keys()
+            .filter(|file_id| **file_id != active.0)
+            .cloned()
+            .collect())
+    }
+
+    /// Calls `f` with the position and the record of every record of file
+    /// `file_id`, in the order they were written. A record cut short by a
+    /// crash ends the file.
+    pub fn for_each_record<F>(&self, file_id: &str, mut f: F) -> Result<()>
+    where
+        F: FnMut(u64, DataEntry) -> Result<()>,
+    {
+        let file = self.handle(file_id)?;
+        let len = file.len()?;
+        let mut reader = StorageReader {
+            file: file.as_ref(),
+            position: 0,
+        };
+        let mut buf = vec![];
+        while reader.position < len {
+            let position = reader.position;
+            match DataEntry::decode_checked(&mut reader, &mut buf) {
+                Ok(entry) => f(position, entry)?,
+                Err(NotusError::IOError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    warn!("value log {} is cut short at {}", file_id, position);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Size in bytes of file `file_id`.
+    pub fn file_len(&self, file_id: &str) -> Result<u64> {
+        self.handle(file_id)?.len()
+    }
+
+    /// Stops reading from file `file_id`, which must not be the one appended
+    /// to, and returns the name of its file, which the caller removes from
+    /// the storage.
+    pub fn detach(&self, file_id: &str) -> Result<String> {
+        self.files
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .remove(file_id);
+        Ok(Self::file_name(file_id))
+    }
+
+    /// Removes every file and empties the one appended to.
+    pub fn clear(&self) -> Result<()> {
+        let active = self
+            .active
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let mut files = self
+            .files
+            .write()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        for file_id in files.keys().filter(|file_id| **file_id != active.0) {
+            self.storage.remove(&Self::file_name(file_id))?;
+        }
+        files.retain(|file_id, _| *file_id == active.0);
+        active.1.truncate()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.active
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .1
+            .flush()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.active
+            .read()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?
+            .1
+            .sync()
+    }
+}
+
+/// Serializes handing out file ids between the data files and value logs.
+static FILE_ID_LOCK: Mutex<()> = Mutex::new(());
+
+/// Hands out a file id greater than any id used before in `storage`, so file
+/// ids sort in creation order whatever the system clock does. The id is
+/// recorded before it is returned.
+fn new_file_id(storage: &Arc<dyn Storage>) -> Result<String> {
+    let _guard = FILE_ID_LOCK
+        .lock()
+        .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+    let files = storage.list()?;
+    let mut next = FIRST_FILE_ID;
+    for file in files.iter() {
+        if let Some(id) = file
+            .rsplit_once('.')
+            .and_then(|(stem, _)| stem.parse::<u64>().ok())
+        {
+            next = next.max(id.saturating_add(1));
+        }
+    }
+    if files.iter().any(|name| name == FILE_ID_FILE_NAME) {
+        let file = storage.open(FILE_ID_FILE_NAME)?;
+        if file.len()? >= 8 {
+            let mut bytes = [0_u8; 8];
+            file.read_at(0, &mut bytes)?;
+            next = next.max(u64::from_be_bytes(bytes).saturating_add(1));
+        }
+    } else {
+        storage.create(FILE_ID_FILE_NAME)?;
+    }
+    let file = storage.open_append(FILE_ID_FILE_NAME)?;
+    file.truncate()?;
+    file.append(&next.to_be_bytes())?;
+    file.close()?;
+    Ok(next.to_string())
+}
+
+pub fn create_new_file_pair(storage: &Arc<dyn Storage>) -> Result<FilePair> {
+    let file_name = new_file_id(storage)?;
+    let file_pair = FilePair::new(&file_name, storage.clone());
+    storage.create(&file_pair.data_file_name())?;
+    // Don't leave a data file without its hint file behind
+    if let Err(e) = storage.create(&file_pair.hint_file_name()) {
+        let _ = storage.remove(&file_pair.data_file_name());
+        return Err(e);
+    }
+    Ok(file_pair)
+}
+
+/// Picks the pair to append to after `file_pairs` were fetched on open and
+/// adds it to them. Pairs that earlier opens left empty are removed, except
+/// the newest one which is appended to again, so reopening a store does not
+/// pile up empty pairs.
+pub fn open_active_file_pair(
+    storage: &Arc<dyn Storage>,
+    file_pairs: &mut BTreeMap<String, FilePair>,
+) -> Result<FilePair> {
+    let newest = file_pairs.keys().next_back().cloned();
+    let mut reused = None;
+    let mut unused = vec![];
+    for (file_id, file_pair) in file_pairs.iter() {
+        if !file_pair.is_unused()? {
+            continue;
+        }
+        if Some(file_id) == newest.as_ref() {
+            reused = Some(file_pair.clone());
+        } else {
+            unused.push(file_id.clone());
+        }
+    }
+    for file_id in unused {
+        if let Some(file_pair) = file_pairs.remove(&file_id) {
+            file_pair.remove()?;
+        }
+    }
+    match reused {
+        Some(file_pair) => Ok(file_pair),
+        None => {
+            let file_pair = create_new_file_pair(storage)?;
+            file_pairs.insert(file_pair.file_id(), file_pair.clone());
+            Ok(file_pair)
+        }
+    }
+}
+
+/// Rebuilds the hint file of a pair whose data file has none by reading the
+/// data file front to back. Reading stops at the first record that can not
+/// be decoded. Deletes only ever lived in the lost hint file, so keys deleted
+/// in this pair come back.
+fn rebuild_hint_file(file_pair: &FilePair) -> Result<()> {
+    let (hints, position) = file_pair.scan_hints()?;
+    warn!(
+        "rebuilt missing hint file {} from {} of {} data bytes",
+        file_pair.hint_file_name(),
+        position,
+        file_pair.data_size()?
+    );
+    let hints: Vec<u8> = hints.iter().flat_map(HintEntry::encode).collect();
+    file_pair.storage.create(&file_pair.hint_file_name())?;
+    let hint_file = file_pair.storage.open_append(&file_pair.hint_file_name())?;
+    hint_file.append(&hints)?;
+    hint_file.close()
+}
+
+/// Records `file_ids` as the pairs making up the store. Records are
+/// appended, each with its length and crc, so one cut short by a crash is
+/// skipped and the one before it still applies.
+pub fn write_manifest<'a>(
+    storage: &Arc<dyn Storage>,
+    file_ids: impl IntoIterator<Item = &'a String>,
+) -> Result<()> {
+    if !storage.list()?.iter().any(|name| name == MANIFEST_FILE_NAME) {
+        storage.create(MANIFEST_FILE_NAME)?;
+    }
+    let manifest = storage.open_append(MANIFEST_FILE_NAME)?;
+    manifest.append(&manifest_record(file_ids))?;
+    manifest.close()
+}
+
+/// Replaces the manifest with a single record of `file_ids`, so it does not
+/// grow across opens. A crash in between leaves it empty, and the next open
+/// falls back to listing the directory.
+pub fn reset_manifest<'a>(
+    storage: &Arc<dyn Storage>,
+    file_ids: impl IntoIterator<Item = &'a String>,
+) -> Result<()> {
+    if !storage.list()?.iter().any(|name| name == MANIFEST_FILE_NAME) {
+        storage.create(MANIFEST_FILE_NAME)?;
+    }
+    let manifest = storage.open_append(MANIFEST_FILE_NAME)?;
+    manifest.truncate()?;
+    manifest.append(&manifest_record(file_ids))?;
+    manifest.close()
+}
+
+fn manifest_record<'a>(file_ids: impl IntoIterator<Item = &'a String>) -> Vec<u8> {
+    let body = file_ids
+        .into_iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join("\n");
+    frame(body.as_bytes())
+}
+
+/// Prefixes `body` with its length and crc, as manifest records and
+/// prepared batches are stored.
+fn frame(body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(8 + body.len());
+    record.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    record.extend_from_slice(&CRC_CKSUM.checksum(body).to_be_bytes());
+    record.extend_from_slice(body);
+    record
+}
+
+/// Returns the file ids of the last complete manifest record, or `None` if
+/// there is no manifest or no complete record in it.
+pub fn read_manifest(storage: &Arc<dyn Storage>) -> Result<Option<HashSet<String>>> {
+    if !storage.list()?.iter().any(|name| name == MANIFEST_FILE_NAME) {
+        return Ok(None);
+    }
+    let manifest = storage.open(MANIFEST_FILE_NAME)?;
+    let mut bytes = vec![0_u8; manifest.len()? as usize];
+    manifest.read_at(0, &mut bytes)?;
+
+    let mut last = None;
+    let mut rest = bytes.as_slice();
+    while rest.len() >= 8 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let crc = u32::from_be_bytes([rest[4], rest[5], rest[6], rest[7]]);
+        let body = match rest.get(8..8 + len) {
+            Some(body) if CRC_CKSUM.checksum(body) == crc => body,
+            _ => break,
+        };
+        last = Some(body);
+        rest = &rest[8 + len..];
+    }
+    Ok(last.map(|body| {
+        String::from_utf8_lossy(body)
+            .split('\n')
+            .filter(|file_id| !file_id.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }))
+}
+
+fn prepared_batch_file_name(id: &str) -> String {
+    let hex: String = id.bytes().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}.{}", hex, PREPARED_BATCH_EXTENSION)
+}
+
+/// Durably stores `ops` as the prepared batch `id`, failing with `Conflict`
+/// if a batch with that id is already prepared.
+pub fn write_prepared_batch(storage: &Arc<dyn Storage>, id: &str, ops: &[BatchOp]) -> Result<()> {
+    let mut body = vec![];
+    for op in ops {
+        let (tag, key, value) = match op {
+            BatchOp::Put(key, value) => (0_u8, key, Some(value)),
+            BatchOp::Delete(key) => (1_u8, key, None),
+        };
+        body.push(tag);
+        body.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        body.extend_from_slice(key);
+        if let Some(value) = value {
+            body.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            body.extend_from_slice(value);
+        }
+    }
+    let file_name = prepared_batch_file_name(id);
+    match storage.create(&file_name) {
+        Err(NotusError::IOError(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(NotusError::Conflict);
+        }
+        result => result?,
+    }
+    let file = storage.open_append(&file_name)?;
+    file.append(&frame(&body))?;
+    file.close()
+}
+
+/// Reads the prepared batch `id`. Returns `None` if there is none, or if
+/// it was cut short by a crash while being prepared.
+pub fn read_prepared_batch(storage: &Arc<dyn Storage>, id: &str) -> Result<Option<Vec<BatchOp>>> {
+    let file = match storage.open(&prepared_batch_file_name(id)) {
+        Err(NotusError::IOError(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        result => result?,
+    };
+    let mut bytes = vec![0_u8; file.len()? as usize];
+    file.read_at(0, &mut bytes)?;
+    if bytes.len() < 8 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let crc = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let mut body = match bytes.get(8..8 + len) {
+        Some(body) if CRC_CKSUM.checksum(body) == crc => body,
+        _ => return Ok(None),
+    };
+
+    let mut ops = vec![];
+    let read_bytes = |body: &mut &[u8]| -> Result<Vec<u8>> {
+        let mut len = [0_u8; 4];
+        body.read_exact(&mut len)?;
+        let mut bytes = vec![0_u8; u32::from_be_bytes(len) as usize];
+        body.read_exact(&mut bytes)?;
+        Ok(bytes)
+    };
+    while let Some((&tag, rest)) = body.split_first() {
+        body = rest;
+        let key = read_bytes(&mut body)?;
+        ops.push(match tag {
+            0 => BatchOp::Put(key, read_bytes(&mut body)?),
+            _ => BatchOp::Delete(key),
+        });
+    }
+    Ok(Some(ops))
+}
+
+/// Returns the ids of the prepared batches in the storage, including ones
+/// cut short while being prepared.
+pub fn list_prepared_batches(storage: &Arc<dyn Storage>) -> Result<Vec<String>> {
+    let mut ids = vec![];
+    for file in storage.list()? {
+        let hex = match file.rsplit_once('.') {
+            Some((hex, PREPARED_BATCH_EXTENSION)) => hex,
+            _ => continue,
+        };
+        let bytes: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect();
+        if let Some(id) = bytes.and_then(|bytes| String::from_utf8(bytes).ok()) {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+pub fn remove_prepared_batch(storage: &Arc<dyn Storage>, id: &str) -> Result<()> {
+    storage.remove(&prepared_batch_file_name(id))
+}
+
+/// Finds the pairs of the store. When there is a manifest only the pairs it
+/// lists are returned, other files are left alone. Without one every pair
+/// in the storage is returned. Pairs a crash left a torn tail in are cut
+/// back to their last complete record.
+pub fn fetch_file_pairs(storage: &Arc<dyn Storage>) -> Result<BTreeMap<String, FilePair>> {
+    let (file_pairs, without_hints) = find_file_pairs(storage)?;
+    for file_id in without_hints {
+        rebuild_hint_file(&file_pairs[&file_id])?;
+    }
+    for file_pair in file_pairs.values() {
+        file_pair.truncate_torn_tail()?;
+    }
+    Ok(file_pairs)
+}
+
+/// Like `fetch_file_pairs` but leaves the storage untouched, returning the
+/// ids of the pairs whose hint file is missing instead of rebuilding it.
+pub fn find_file_pairs(
+    storage: &Arc<dyn Storage>,
+) -> Result<(BTreeMap<String, FilePair>, Vec<String>)> {
     let mut file_pairs = BTreeMap::new();
-    let mut option = DirOptions::new();
-    option.depth = 1;
-
-    let dir_content = fs_extra::dir::get_dir_content2(dir, &option)?;
-    for file in dir_content.files.iter() {
-        let file_path = Path::new(file);
-        let file_extension =
-            String::from(file_path.extension().unwrap_or_default().to_string_lossy());
-        match file_extension.as_str() {
-            DATA_FILE_EXTENSION => {}
-            HINT_FILE_EXTENSION => {}
+    let mut file_ids = HashMap::new();
+    let mut with_hint = HashSet::new();
+    let listed = read_manifest(storage)?;
+
+    for file in storage.list()? {
+        let file_name = match file.rsplit_once('.') {
+            Some((file_name, DATA_FILE_EXTENSION)) | Some((file_name, HINT_FILE_EXTENSION)) => {
+                file_name
+            }
             _ => {
                 continue;
             }
         };
-
-        let file_name = String::from(file_path.file_name().unwrap().to_string_lossy());
-        let file_name = &file_name[..file_name.len() - 5];
-        let file_pair = file_pairs
+        if listed.as_ref().is_some_and(|listed| !listed.contains(file_name)) {
+            continue;
+        }
+        // File ids are numbers, stems such as `042` and `42` name the same
+        // id and would otherwise be merged in an unpredictable order
+        if let Ok(id) = file_name.parse::<u128>() {
+            match file_ids.get(&id) {
+                Some(stem) if stem != file_name => {
+                    return Err(NotusError::DuplicateFileId(id.to_string()));
+                }
+                Some(_) => {}
+                None => {
+                    file_ids.insert(id, file_name.to_owned());
+                }
+            }
+        }
+        file_pairs
             .entry(file_name.to_owned())
-            .or_insert(FilePair::new(file_name));
-        match file_extension.as_str() {
-            DATA_FILE_EXTENSION => file_pair.data_file_path = file_path.to_path_buf(),
-            HINT_FILE_EXTENSION => file_pair.hint_file_path = file_path.to_path_buf(),
-            _ => {}
-        };
+            .or_insert_with(|| FilePair::new(file_name, storage.clone()));
+        if file.ends_with(HINT_FILE_EXTENSION) {
+            with_hint.insert(file_name.to_owned());
+        }
     }
-    Ok(file_pairs)
+    let without_hints = file_pairs
+        .keys()
+        .filter(|file_id| !with_hint.contains(*file_id))
+        .cloned()
+        .collect();
+    Ok((file_pairs, without_hints))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::file_ops::{create_new_file_pair, fetch_file_pairs};
+    use crate::errors::NotusError;
+    use crate::file_ops::{create_new_file_pair, fetch_file_pairs, ActiveFilePair, BufferPool};
+    use crate::schema::DataEntry;
+    use crate::storage::{LocalStorage, Storage, StorageFile};
+    use crate::Result;
+    use serial_test::serial;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Fails to create hint files, to simulate a crash or a full disk between
+    /// creating the two files of a pair.
+    struct FailingHints(LocalStorage);
+
+    impl Storage for FailingHints {
+        fn create(&self, name: &str) -> Result<()> {
+            if name.ends_with(".hint") {
+                return Err(NotusError::Unknown);
+            }
+            self.0.create(name)
+        }
+
+        fn open(&self, name: &str) -> Result<Box<dyn StorageFile>> {
+            self.0.open(name)
+        }
+
+        fn list(&self) -> Result<Vec<String>> {
+            self.0.list()
+        }
+
+        fn remove(&self, name: &str) -> Result<()> {
+            self.0.remove(name)
+        }
+    }
+
+    /// Remembers how much of every file was synced, so `crash` can drop
+    /// what a power loss would.
+    struct Durability {
+        storage: LocalStorage,
+        synced: Arc<Mutex<HashMap<String, u64>>>,
+    }
+
+    struct DurableFile {
+        file: Box<dyn StorageFile>,
+        name: String,
+        synced: Arc<Mutex<HashMap<String, u64>>>,
+    }
+
+    impl Durability {
+        fn crash(&self) {
+            for (name, len) in self.synced.lock().unwrap().iter() {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(self.storage.dir().join(name))
+                    .unwrap()
+                    .set_len(*len)
+                    .unwrap();
+            }
+        }
+    }
+
+    impl Storage for Durability {
+        fn create(&self, name: &str) -> Result<()> {
+            self.storage.create(name)?;
+            self.synced.lock().unwrap().insert(name.to_string(), 0);
+            Ok(())
+        }
+
+        fn open(&self, name: &str) -> Result<Box<dyn StorageFile>> {
+            self.storage.open(name)
+        }
+
+        fn open_append(&self, name: &str) -> Result<Box<dyn StorageFile>> {
+            Ok(Box::new(DurableFile {
+                file: self.storage.open_append(name)?,
+                name: name.to_string(),
+                synced: self.synced.clone(),
+            }))
+        }
+
+        fn list(&self) -> Result<Vec<String>> {
+            self.storage.list()
+        }
+
+        fn remove(&self, name: &str) -> Result<()> {
+            self.synced.lock().unwrap().remove(name);
+            self.storage.remove(name)
+        }
+    }
+
+    impl StorageFile for DurableFile {
+        fn append(&self, bytes: &[u8]) -> Result<u64> {
+            self.file.append(bytes)
+        }
+
+        fn read_at(&self, position: u64, buf: &mut [u8]) -> Result<()> {
+            self.file.read_at(position, buf)
+        }
+
+        fn len(&self) -> Result<u64> {
+            self.file.len()
+        }
+
+        fn truncate(&self) -> Result<()> {
+            self.file.truncate()
+        }
+
+        fn set_len(&self, len: u64) -> Result<()> {
+            self.file.set_len(len)
+        }
+
+        fn sync(&self) -> Result<()> {
+            self.file.sync()?;
+            let len = self.file.len()?;
+            self.synced.lock().unwrap().insert(self.name.clone(), len);
+            Ok(())
+        }
+    }
 
     #[test]
+    #[serial]
     fn test_create_file_pairs() {
-        create_new_file_pair("./testdir").unwrap();
-        create_new_file_pair("./testdir").unwrap();
-        create_new_file_pair("./testdir").unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new("./testdir").unwrap());
+        create_new_file_pair(&storage).unwrap();
+        create_new_file_pair(&storage).unwrap();
+        create_new_file_pair(&storage).unwrap();
 
-        let b = fetch_file_pairs("./testdir").unwrap();
+        let b = fetch_file_pairs(&storage).unwrap();
         println!("{:#?}", b);
         clean_up()
     }
 
+    #[test]
+    #[serial]
+    fn test_create_file_pair_cleans_up() {
+        let dir = "./testdir/_test_create_file_pair_cleans_up";
+        let storage: Arc<dyn Storage> = Arc::new(FailingHints(LocalStorage::new(dir).unwrap()));
+        assert!(create_new_file_pair(&storage).is_err());
+        assert_eq!(storage.list().unwrap(), vec!["FILE_ID".to_string()]);
+        assert!(fetch_file_pairs(&storage).unwrap().is_empty());
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_rebuild_missing_hint_file() {
+        let dir = "./testdir/_test_rebuild_missing_hint_file";
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir).unwrap());
+        let fp = create_new_file_pair(&storage).unwrap();
+        {
+            let active = ActiveFilePair::from(fp.clone()).unwrap();
+            for i in 0..10_u8 {
+                active.write(&DataEntry::new(vec![i], vec![i; 20])).unwrap();
+            }
+        }
+        let hints = fp.get_hints().unwrap();
+        storage.remove(&fp.hint_file_name()).unwrap();
+
+        let file_pairs = fetch_file_pairs(&storage).unwrap();
+        assert_eq!(file_pairs.len(), 1);
+        let rebuilt = file_pairs[&fp.file_id()].get_hints().unwrap();
+        assert_eq!(rebuilt.len(), hints.len());
+        for (rebuilt, hint) in rebuilt.iter().zip(hints.iter()) {
+            assert_eq!(rebuilt.key(), hint.key());
+            assert_eq!(rebuilt.data_entry_position(), hint.data_entry_position());
+        }
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_file_ids_do_not_follow_the_clock() {
+        let dir = "./testdir/_test_file_ids_do_not_follow_the_clock";
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir).unwrap());
+        // A pair named after a nanosecond time far ahead, as if it was
+        // created before the clock was moved back
+        let ahead = "5000000000000000000";
+        storage.create(&format!("{}.data", ahead)).unwrap();
+        storage.create(&format!("{}.hint", ahead)).unwrap();
+
+        let first = create_new_file_pair(&storage).unwrap();
+        assert!(first.file_id().as_str() > ahead);
+        let second = create_new_file_pair(&storage).unwrap();
+        assert!(second.file_id() > first.file_id());
+        assert_eq!(second.file_id().len(), ahead.len());
+
+        // Ids are not handed out again after the newest pair is removed
+        second.remove().unwrap();
+        let third = create_new_file_pair(&storage).unwrap();
+        assert!(third.file_id() > second.file_id());
+
+        let file_ids: Vec<_> = fetch_file_pairs(&storage).unwrap().into_keys().collect();
+        assert_eq!(file_ids, vec![ahead.to_string(), first.file_id(), third.file_id()]);
+        clean_up()
+    }
+
+    #[test]
+    #[serial]
+    fn test_hints_are_not_durable_before_their_data() {
+        let dir = "./testdir/_test_hints_are_not_durable_before_their_data";
+        let durability = Arc::new(Durability {
+            storage: LocalStorage::new(dir).unwrap(),
+            synced: Arc::default(),
+        });
+        let storage: Arc<dyn Storage> = durability.clone();
+        let fp = create_new_file_pair(&storage).unwrap();
+        let active = ActiveFilePair::from(fp.clone()).unwrap();
+        for i in 0..10_u8 {
+            active.write(&DataEntry::new(vec![i], vec![i; 20])).unwrap();
+        }
+        // Syncing the hints syncs the records they point at
+        active.flush_hint().unwrap();
+        durability.crash();
+        for hint in fp.get_hints().unwrap() {
+            let entry = fp.read(hint.data_entry_position()).unwrap();
+            assert_eq!(entry.key(), hint.key());
+        }
+
+        for i in 10..20_u8 {
+            active.write(&DataEntry::new(vec![i], vec![i; 20])).unwrap();
+        }
+        // A crash between the two flushes keeps the records, not the hints
+        active.flush_data().unwrap();
+        durability.crash();
+
+        assert_eq!(fp.get_hints().unwrap().len(), 10);
+        let data_file = storage.open(&fp.data_file_name()).unwrap();
+        assert_eq!(fp.scan_hints().unwrap().1, data_file.len().unwrap());
+        assert_eq!(fp.scan_hints().unwrap().0.len(), 20);
+        drop(active);
+        clean_up()
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(2, 16);
+        let mut buf = pool.take();
+        buf.extend_from_slice(&[1, 2, 3]);
+        pool.give_back(buf);
+        assert_eq!(pool.len(), 1);
+
+        let buf = pool.take();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 3);
+        assert!(pool.is_empty());
+
+        pool.give_back(vec![0; 32]);
+        assert!(pool.is_empty());
+        for _ in 0..3 {
+            pool.give_back(vec![]);
+        }
+        assert_eq!(pool.len(), 2);
+    }
+
     fn clean_up() {
         fs_extra::dir::remove("./testdir");
     }
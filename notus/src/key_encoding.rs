@@ -0,0 +1,54 @@
+use std::convert::TryInto;
+
+const TIMESTAMP_SIZE: usize = 8;
+
+/// Encodes `ts` into 8 bytes that sort in descending timestamp order, so the
+/// newest entry comes first in a forward scan.
+pub fn descending_timestamp(ts: i64) -> [u8; TIMESTAMP_SIZE] {
+    // Flipping the sign bit orders negative timestamps before positive ones,
+    // complementing the result reverses the order.
+    (!((ts as u64) ^ (1 << 63))).to_be_bytes()
+}
+
+/// Builds a key made of the descending encoding of `ts` followed by `suffix`.
+pub fn descending_time_key(ts: i64, suffix: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(TIMESTAMP_SIZE + suffix.len());
+    key.extend_from_slice(&descending_timestamp(ts));
+    key.extend_from_slice(suffix);
+    key
+}
+
+/// Splits a key built by `descending_time_key` back into its timestamp and
+/// suffix. Returns `None` if the key is too short.
+pub fn decode_descending_time_key(key: &[u8]) -> Option<(i64, &[u8])> {
+    if key.len() < TIMESTAMP_SIZE {
+        return None;
+    }
+    let (raw_ts, suffix) = key.split_at(TIMESTAMP_SIZE);
+    let encoded = u64::from_be_bytes(raw_ts.try_into().ok()?);
+    Some(((!encoded ^ (1 << 63)) as i64, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_encoding::{decode_descending_time_key, descending_time_key};
+
+    #[test]
+    fn descending_time_key_test() {
+        let timestamps = [i64::MIN, -10, -1, 0, 1, 10, 1_600_000_000, i64::MAX];
+        let mut keys: Vec<_> = timestamps
+            .iter()
+            .map(|ts| descending_time_key(*ts, b"suffix"))
+            .collect();
+        keys.sort();
+
+        let decoded: Vec<_> = keys
+            .iter()
+            .map(|key| decode_descending_time_key(key).unwrap())
+            .collect();
+        let mut expected: Vec<_> = timestamps.iter().map(|ts| (*ts, &b"suffix"[..])).collect();
+        expected.reverse();
+        assert_eq!(decoded, expected);
+        assert_eq!(decode_descending_time_key(&[1, 2, 3]), None);
+    }
+}
@@ -5,8 +5,10 @@ use crate::errors::NotusError;
 pub mod datastore;
 pub mod errors;
 pub mod file_ops;
+pub mod key_encoding;
 pub mod nutos;
 pub mod schema;
+pub mod storage;
 
 pub type Result<T> = std::result::Result<T, NotusError>;
 
@@ -1,184 +1,2297 @@
-use crate::datastore::{DataStore, MergeOperator, RawKey, DEFAULT_INDEX};
+use crate::datastore::{
+    AuditReport, BatchOp, ColumnStats, DataStore, Encoding, FrozenIndex, Inspection, LockedStore,
+    MergeOperator, RawKey, SnapshotEntry, DEFAULT_INDEX, RAW_INDEX, RESERVED_INDEX,
+};
 use crate::errors::NotusError;
+use crate::key_encoding::{descending_time_key, descending_timestamp};
+use crate::schema::{Compression, DataEntry, Decoder, Encoder, IntegrityAlgo};
+use crate::storage::{LocalStorage, Storage};
+use log::warn;
 use crate::Result;
+use chrono::{DateTime, NaiveTime, Utc};
 use std::alloc::Global;
-use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Read, Write};
 use std::ops::{RangeFrom, Range, RangeBounds};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::ops::Bound;
+/// Options used when opening a store with `Notus::open_with_options`.
+#[derive(Debug, Clone)]
+pub struct NotusOptions {
+    /// Keep the active data file memory-mapped so appends are copied into the
+    /// mapping instead of seeking and writing on every entry. The mapping is
+    /// flushed by the background worker and on close.
+    pub mmap_active_file: bool,
+    /// Number of bytes the mapped active data file is grown by when it fills.
+    pub mmap_chunk_size: u64,
+    /// Maximum number of data files kept open for reads. The least recently
+    /// used file is closed when the limit is reached, `0` disables caching.
+    pub max_open_files: usize,
+    /// Caps the bytes taken by live keys and values. Once exceeded, keys are
+    /// deleted in `eviction_policy` order until the store fits again.
+    pub max_total_bytes: Option<u64>,
+    pub eviction_policy: EvictionPolicy,
+    /// Compacts the store from the background worker on this schedule, so
+    /// space held by overwritten and deleted keys is reclaimed without
+    /// calling `compact`.
+    pub compaction_schedule: Option<Schedule>,
+    /// Lets the background worker decide when to compact from what it
+    /// observes of the writes, see `NotusOptions::auto_compaction`.
+    pub auto_compaction: Option<AutoPolicy>,
+    /// Bytes per second of writes the disk is expected to sustain, see
+    /// `NotusOptions::compaction_io_budget`.
+    pub compaction_io_budget: Option<u64>,
+    /// Lets `get` answer from a copy of the index that the background worker
+    /// rebuilds, so reads do not contend with writers. A read may miss writes
+    /// made within this bound.
+    pub stale_reads: Option<Duration>,
+    /// Bounds the time `open` spends replaying hint files. Files left over
+    /// are replayed newest first by the background worker; until then their
+    /// keys are missing from the index. See `Notus::pending_replay`.
+    pub recovery_budget: Option<Duration>,
+    /// Applied to keys before they are indexed or looked up, see
+    /// `NotusOptions::key_normalizer`.
+    pub key_normalizer: Option<KeyNormalizer>,
+    /// Called with errors hit by the background worker, such as a failed
+    /// flush, see `NotusOptions::on_background_error`.
+    pub on_background_error: Option<BackgroundErrorHandler>,
+    /// Keeps values in a separate value log, see
+    /// `NotusOptions::kv_separation`.
+    pub kv_separation: bool,
+    /// How records are protected against corruption, see
+    /// `NotusOptions::integrity`.
+    pub integrity: IntegrityAlgo,
+    /// How values are compressed in the data files, see
+    /// `NotusOptions::compression`.
+    pub compression: Compression,
+    /// Holds keys longer than this many bytes as a hash in the index, see
+    /// `NotusOptions::hash_keys_over`.
+    pub hash_keys_over: Option<usize>,
+    /// Number of independently locked stripes of the write buffer, see
+    /// `NotusOptions::write_stripes`.
+    pub write_stripes: usize,
+    /// Runs a background thread for the store, see
+    /// `NotusOptions::background_worker`.
+    pub background_worker: bool,
+    /// Size at which the active data file is closed and writes move to a
+    /// new one, see `NotusOptions::max_file_size`.
+    pub max_file_size: Option<u64>,
+    /// Splits merge output into files of `max_file_size`, see
+    /// `NotusOptions::migrate_on_open`.
+    pub migrate_on_open: bool,
+    /// Frees each merged file as soon as it is copied, see
+    /// `NotusOptions::incremental_compaction`.
+    pub incremental_compaction: bool,
+    /// Write amplification at which the background worker compacts, see
+    /// `NotusOptions::merge_trigger_ratio`.
+    pub merge_trigger_ratio: Option<f64>,
+    /// Makes every write durable before it returns, see
+    /// `NotusOptions::sync_on_write`.
+    pub sync_on_write: bool,
+    /// How often the background worker flushes the write buffer, see
+    /// `NotusOptions::background_flush_interval`.
+    pub background_flush_interval: Duration,
+}
+
+impl NotusOptions {
+    /// Indexes and looks keys up by `normalizer(key)`, e.g. lowercasing them
+    /// for case-insensitive lookups. The key as written is kept next to the
+    /// value, and iterators yield it instead of the normalized key.
+    ///
+    /// Keys that normalize to the same bytes are the same entry: a write to
+    /// one overwrites the other and iteration reports whichever original key
+    /// was written last. Ranges and prefixes are matched against normalized
+    /// keys, and methods that report keys without reading values, such as
+    /// `keys_modified_since` and `diff`, return normalized keys. The
+    /// normalizer must not change between opens of the same store.
+    pub fn key_normalizer(
+        mut self,
+        normalizer: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.key_normalizer = Some(KeyNormalizer(Arc::new(normalizer)));
+        self
+    }
+
+    /// Calls `handler` from the background worker whenever flushing the write
+    /// buffer, replaying hint files or a scheduled compaction fails. Without a
+    /// handler these errors only surface once a later read or write fails.
+    pub fn on_background_error(
+        mut self,
+        handler: impl Fn(&NotusError) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_background_error = Some(BackgroundErrorHandler(Arc::new(handler)));
+        self
+    }
+
+    /// Appends values to a separate value log and keeps only the key and a
+    /// pointer to the value in the data files. Compaction then copies
+    /// pointers instead of values, which pays off with large values. Space
+    /// taken by overwritten and deleted values is reclaimed by
+    /// `Notus::collect_value_log`, which also runs after every scheduled
+    /// compaction and drops the older versions kept for `get_versions`.
+    ///
+    /// Sizes worked out from the index alone, such as `range_size`,
+    /// `column_stats` and `max_total_bytes`, count pointers instead of
+    /// values. The setting must not change between opens of the same store.
+    pub fn kv_separation(mut self, enabled: bool) -> Self {
+        self.kv_separation = enabled;
+        self
+    }
+
+    /// Chooses how the records written from now on are protected.
+    /// `IntegrityAlgo::None` skips computing and verifying a CRC per record,
+    /// for benchmarks and setups that trust their disks. Each record notes
+    /// whether it has a CRC, so the setting may change between opens:
+    /// records written with one are still verified when read.
+    pub fn integrity(mut self, integrity: IntegrityAlgo) -> Self {
+        self.integrity = integrity;
+        self
+    }
+
+    /// Compresses the values written from now on, including those copied by
+    /// compaction, and reads them back as they were put. Values that do not
+    /// get smaller are stored as they are. Like `integrity` the codec is
+    /// noted in every record, so the setting may change between opens.
+    ///
+    /// The index and everything worked out from it, such as `range_size`
+    /// and `column_stats`, count compressed sizes, and `get_prefix_bytes`
+    /// reads a compressed value whole. Values in the value log of
+    /// `kv_separation` are not compressed.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Keeps keys longer than `bytes` in the in-memory index as their first
+    /// `bytes - 8` bytes and an 8 byte hash of the whole key, for stores
+    /// whose keys are too long to hold in memory. Reads check the key of
+    /// the record they find against the key asked for, and methods listing
+    /// keys read hashed keys back from their records. `bytes` is raised to
+    /// at least 16. It counts the column a key is qualified by, which is
+    /// always kept whole, so keys of a column with a long name keep more
+    /// than `bytes - 8` bytes.
+    ///
+    /// Keys are ordered by their first `bytes - 8` bytes, then by hash, so
+    /// long keys sharing those bytes come out of iteration in no particular
+    /// order and ranges and prefixes reaching past them match by hash. Two
+    /// long keys with the same first bytes and the same hash share an entry,
+    /// a chance of about one in 2^64 per pair: writing one replaces the
+    /// other in the index, which then reads as deleted. The setting only
+    /// affects memory and may change between opens.
+    pub fn hash_keys_over(mut self, bytes: usize) -> Self {
+        self.hash_keys_over = Some(bytes.max(16));
+        self
+    }
+
+    /// Splits the write buffer into `stripes` parts, each with its own lock,
+    /// picked by a hash of the first 16 bytes of the column qualified key.
+    /// Writes to keys of different stripes then proceed at once instead of
+    /// one at a time, while batches, `atomically`, flushes and other
+    /// operations spanning many keys lock every stripe. `stripes` is raised
+    /// to at least 1.
+    pub fn write_stripes(mut self, stripes: usize) -> Self {
+        self.write_stripes = stripes.max(1);
+        self
+    }
+
+    /// Whether to spawn the background thread that flushes the write buffer
+    /// every `background_flush_interval`. Short-lived or tiny stores can go
+    /// without it: their writes then stay buffered until `barrier` is called
+    /// or the store is closed, and space is only reclaimed by calling
+    /// `compact`. Scheduled and adaptive compaction, rebuilding the index of
+    /// `stale_reads` and replaying the files `recovery_budget` left over
+    /// never happen.
+    pub fn background_worker(mut self, enabled: bool) -> Self {
+        self.background_worker = enabled;
+        self
+    }
+
+    /// Closes the active data file once it holds `bytes` and moves writes to
+    /// a new file pair, so no file grows without bound and full files can be
+    /// merged away. The check runs after the write buffer is flushed and
+    /// after batches and bulk loads, so a file ends up larger by at most
+    /// what one of those wrote.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Makes compactions write their output into pairs of about
+    /// `max_file_size` each instead of into a single one, so that the first
+    /// compaction of a store written before `max_file_size` was set splits
+    /// its oversized data file. Later compactions keep the files bounded.
+    /// Has no effect without `max_file_size`.
+    pub fn migrate_on_open(mut self, enabled: bool) -> Self {
+        self.migrate_on_open = enabled;
+        self
+    }
+
+    /// Compacts one file pair at a time: its live records are copied and
+    /// synced, then the pair is removed before the next one is read. A
+    /// compaction then needs room for the live records of one pair on top
+    /// of the store instead of for all of them, at the cost of a sync per
+    /// pair. Works best with `max_file_size`, which keeps pairs small.
+    /// Applies to `compact` and to scheduled and adaptive compactions.
+    pub fn incremental_compaction(mut self, enabled: bool) -> Self {
+        self.incremental_compaction = enabled;
+        self
+    }
+
+    /// Compacts from the background worker once `Notus::write_amplification`
+    /// reaches `ratio`, i.e. once the bytes written since the last
+    /// compaction are `ratio` times the live bytes. A fixed alternative to
+    /// `AutoPolicy::Adaptive`, checked as often. `ratio` is raised to at
+    /// least 1.
+    pub fn merge_trigger_ratio(mut self, ratio: f64) -> Self {
+        self.merge_trigger_ratio = Some(ratio.max(1.0));
+        self
+    }
+
+    /// Fsyncs the data written by every `put`, `delete` and batch before it
+    /// returns, as `Notus::barrier` does, so no acknowledged write is lost
+    /// to a crash. Off by default: writes are then buffered and flushed by
+    /// the background worker, and are only durable after a `barrier`.
+    pub fn sync_on_write(mut self, enabled: bool) -> Self {
+        self.sync_on_write = enabled;
+        self
+    }
+
+    /// Sets how long the background worker sleeps between flushes of the
+    /// write buffer, 10ms by default. Longer intervals write larger groups
+    /// at once, while reads of a key keep being answered from the buffer.
+    pub fn background_flush_interval(mut self, interval: Duration) -> Self {
+        self.background_flush_interval = interval;
+        self
+    }
+
+    /// Compacts from the background worker whenever `policy` says so. It
+    /// works alongside `compaction_schedule`, either may start a compaction.
+    pub fn auto_compaction(mut self, policy: AutoPolicy) -> Self {
+        self.auto_compaction = Some(policy);
+        self
+    }
+
+    /// Tells `AutoPolicy::Adaptive` how many bytes per second the disk can
+    /// take. The more of it foreground writes use, the longer compaction is
+    /// put off. Without a budget IO is never considered scarce.
+    pub fn compaction_io_budget(mut self, bytes_per_second: u64) -> Self {
+        self.compaction_io_budget = Some(bytes_per_second.max(1));
+        self
+    }
+}
+
+type NormalizeFn = dyn Fn(&[u8]) -> Vec<u8> + Send + Sync;
+
+/// A key transform set with `NotusOptions::key_normalizer`.
+#[derive(Clone)]
+pub struct KeyNormalizer(Arc<NormalizeFn>);
+
+impl Debug for KeyNormalizer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeyNormalizer")
+    }
+}
+
+type BackgroundErrorFn = dyn Fn(&NotusError) + Send + Sync;
+
+/// An error callback set with `NotusOptions::on_background_error`.
+#[derive(Clone)]
+pub struct BackgroundErrorHandler(Arc<BackgroundErrorFn>);
+
+impl Debug for BackgroundErrorHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BackgroundErrorHandler")
+    }
+}
+
+/// Prefixes `value` with the key it was written under, so the original key
+/// survives normalization.
+fn wrap_original_key(key: &[u8], value: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + key.len() + value.len());
+    out.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    out.extend_from_slice(key);
+    out.extend_from_slice(&value);
+    out
+}
+
+/// Splits a value written by `wrap_original_key` into the key and value.
+fn unwrap_original_key(stored: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (key, value) = split_original_key(stored)?;
+    Ok((key.to_vec(), value.to_vec()))
+}
+
+/// Like `unwrap_original_key` but borrows the key and value from `stored`.
+fn split_original_key(stored: &[u8]) -> Result<(&[u8], &[u8])> {
+    if stored.len() < 4 {
+        return Err(NotusError::CorruptValue);
+    }
+    let key_len = u32::from_be_bytes([stored[0], stored[1], stored[2], stored[3]]) as usize;
+    if stored.len() < 4 + key_len {
+        return Err(NotusError::CorruptValue);
+    }
+    Ok(stored[4..].split_at(key_len))
+}
+
+/// Options for `Notus::compact_with_options`.
+#[derive(Debug, Clone)]
+pub struct CompactOptions {
+    keep_versions: usize,
+}
+
+impl Default for CompactOptions {
+    fn default() -> Self {
+        Self { keep_versions: 1 }
+    }
+}
+
+impl CompactOptions {
+    /// Keeps up to `n` of the most recent versions of every key instead of
+    /// only the latest. Older versions can be read with `Notus::get_versions`.
+    pub fn keep_versions(mut self, n: usize) -> Self {
+        self.keep_versions = n.max(1);
+        self
+    }
+}
+
+/// Order in which keys are evicted once `NotusOptions::max_total_bytes` is
+/// exceeded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    /// Evict the key that was least recently read or written.
+    Lru,
+    /// Evict the key that was written first.
+    Fifo,
+}
+
+/// When the background worker runs a compaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// Every time the interval elapses, starting one interval after open.
+    Every(Duration),
+    /// Once a day at `hour:minute` UTC.
+    DailyAt { hour: u32, minute: u32 },
+}
+
+impl Schedule {
+    /// The first time the schedule fires strictly after `now`.
+    pub fn next_after(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match *self {
+            Schedule::Every(interval) => {
+                now + chrono::Duration::from_std(interval).unwrap_or(chrono::Duration::MAX)
+            }
+            Schedule::DailyAt { hour, minute } => {
+                let at = NaiveTime::from_hms_opt(hour % 24, minute % 60, 0).unwrap_or(NaiveTime::MIN);
+                let today = now.date_naive().and_time(at).and_utc();
+                if today > now {
+                    today
+                } else {
+                    today + chrono::Duration::days(1)
+                }
+            }
+        }
+    }
+}
+
+/// How the background worker decides to compact on its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoPolicy {
+    /// Compacts once the data written since the last compaction reaches a
+    /// multiple of the live data. The multiple is learned: it falls toward
+    /// 1.5 as more of the written bytes overwrite or delete earlier records
+    /// and rises toward 4 as fewer do, and grows further while foreground
+    /// writes take up the `compaction_io_budget`.
+    Adaptive,
+}
+
+/// How often the background worker checks whether `AutoPolicy::Adaptive` or
+/// `NotusOptions::merge_trigger_ratio` call for a compaction.
+const COMPACTION_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(test)]
+thread_local! {
+    /// Background workers started by stores opened on this thread.
+    pub(crate) static WORKERS_STARTED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    /// Threads started on this thread for `Notus::get_timeout`.
+    pub(crate) static TIMED_READERS_STARTED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// What `AutoPolicy::Adaptive` has learned about the writes to a store.
+pub(crate) struct AdaptiveCompaction {
+    io_budget: Option<u64>,
+    /// Moving average of the share of written bytes that did not add live
+    /// data.
+    churn: f64,
+    /// Moving average of the foreground write rate over `io_budget`.
+    io_pressure: f64,
+    /// Bytes written and live at the previous sample.
+    last: Option<(u64, u64)>,
+}
+
+impl AdaptiveCompaction {
+    const MIN_THRESHOLD: f64 = 1.5;
+    const MAX_THRESHOLD: f64 = 4.0;
+    /// Weight of the newest sample in the moving averages.
+    const SMOOTHING: f64 = 0.3;
+
+    pub(crate) fn new(io_budget: Option<u64>) -> Self {
+        Self {
+            io_budget,
+            churn: 0.0,
+            io_pressure: 0.0,
+            last: None,
+        }
+    }
+
+    /// The write amplification at which a compaction starts.
+    pub(crate) fn threshold(&self) -> f64 {
+        let threshold = Self::MAX_THRESHOLD - (Self::MAX_THRESHOLD - Self::MIN_THRESHOLD) * self.churn;
+        threshold * (1.0 + self.io_pressure)
+    }
+
+    /// Learns from `written` bytes having been written since the last
+    /// compaction began, `live` of them live, `elapsed` after the previous
+    /// sample, and returns whether to compact now.
+    pub(crate) fn sample(&mut self, written: u64, live: u64, elapsed: Duration) -> bool {
+        if let Some((last_written, last_live)) = self.last {
+            let delta = written.saturating_sub(last_written);
+            if delta > 0 {
+                let added = live as f64 - last_live as f64;
+                let churn = (1.0 - added / delta as f64).clamp(0.0, 1.0);
+                self.churn += Self::SMOOTHING * (churn - self.churn);
+            }
+            if let Some(budget) = self.io_budget {
+                let rate = delta as f64 / elapsed.as_secs_f64().max(0.001);
+                let pressure = rate / budget.max(1) as f64;
+                self.io_pressure += Self::SMOOTHING * (pressure - self.io_pressure);
+            }
+        }
+        self.last = Some((written, live));
+        live > 0 && written as f64 >= self.threshold() * live as f64
+    }
+
+    /// Starts measuring again from the state a compaction left, so its
+    /// copies are not taken for foreground writes.
+    pub(crate) fn compacted(&mut self, written: u64, live: u64) {
+        self.last = Some((written, live));
+    }
+}
+
+impl Default for NotusOptions {
+    fn default() -> Self {
+        Self {
+            mmap_active_file: false,
+            mmap_chunk_size: 64 * 1024 * 1024,
+            max_open_files: 64,
+            max_total_bytes: None,
+            eviction_policy: EvictionPolicy::Lru,
+            compaction_schedule: None,
+            auto_compaction: None,
+            compaction_io_budget: None,
+            stale_reads: None,
+            recovery_budget: None,
+            key_normalizer: None,
+            on_background_error: None,
+            kv_separation: false,
+            integrity: IntegrityAlgo::Crc32,
+            compression: Compression::None,
+            hash_keys_over: None,
+            write_stripes: 1,
+            background_worker: true,
+            max_file_size: None,
+            migrate_on_open: false,
+            incremental_compaction: false,
+            merge_trigger_ratio: None,
+            sync_on_write: false,
+            background_flush_interval: Duration::from_millis(10),
+        }
+    }
+}
+
+/// A record yielded by `Notus::physical_scan`, as
+/// `(file_id, offset, key, value, is_live)`.
+pub type PhysicalRecord = (String, u64, Vec<u8>, Vec<u8>, bool);
+
+/// Handle to a store. Clones share the store and its background worker,
+/// which stops once the last clone is dropped.
+#[derive(Clone)]
 pub struct Notus {
-    dir: PathBuf,
-    temp: bool,
+    store: Arc<DataStore>,
+    shutdown: Arc<Shutdown>,
+    key_normalizer: Option<KeyNormalizer>,
+}
+
+/// Owned by every clone of a `Notus`, makes the writes durable and stops
+/// the background worker when the last one goes away.
+struct Shutdown {
     store: Arc<DataStore>,
     dropped: Arc<AtomicBool>,
+    compactions: CompactionCancel,
+    readers: TimedReaders,
+    /// Dropped to wake the worker up so it stops without waiting for its
+    /// next flush.
+    wake: Mutex<Option<mpsc::Sender<()>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Threads `Notus::get_timeout` reads on. Reads stuck on slow storage take
+/// up at most this many threads, later reads wait for one to be free.
+const TIMED_READERS: usize = 4;
+
+type ReadJob = Box<dyn FnOnce(&DataStore) + Send>;
+
+/// The threads `Notus::get_timeout` reads on, started on first use. They
+/// only hold the store while reading and stop once the last handle on it
+/// is dropped.
+#[derive(Default)]
+struct TimedReaders {
+    jobs: Mutex<Option<mpsc::Sender<ReadJob>>>,
+}
+
+impl TimedReaders {
+    fn run(&self, store: &Arc<DataStore>, job: ReadJob) -> Result<()> {
+        let mut jobs = self
+            .jobs
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        jobs.get_or_insert_with(|| Self::start(Arc::downgrade(store)))
+            .send(job)
+            .map_err(|_| NotusError::Unknown)
+    }
+
+    fn start(store: Weak<DataStore>) -> mpsc::Sender<ReadJob> {
+        #[cfg(test)]
+        TIMED_READERS_STARTED.with(|started| started.set(started.get() + TIMED_READERS));
+        let (sender, receiver) = mpsc::channel::<ReadJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..TIMED_READERS {
+            let (store, receiver) = (store.clone(), receiver.clone());
+            thread::spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+                match (job, store.upgrade()) {
+                    (Ok(job), Some(store)) => job(&store),
+                    _ => break,
+                }
+            });
+        }
+        sender
+    }
+}
+
+/// Lets `request_shutdown` stop the compactions started through `compact`
+/// that are running, without stopping those started after them.
+#[derive(Default)]
+struct CompactionCancel {
+    running: Mutex<usize>,
+    cancel: AtomicBool,
+}
+
+impl CompactionCancel {
+    /// Runs `compact` with the flag telling it to stop, which is cleared
+    /// once the last running compaction is done.
+    fn run(&self, compact: impl FnOnce(&AtomicBool) -> Result<()>) -> Result<()> {
+        *self.running()? += 1;
+        let result = compact(&self.cancel);
+        let mut running = self.running()?;
+        *running -= 1;
+        if *running == 0 {
+            self.cancel.store(false, Ordering::Release);
+        }
+        result
+    }
+
+    fn cancel(&self) {
+        if let Ok(running) = self.running() {
+            if *running > 0 {
+                self.cancel.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    fn running(&self) -> Result<MutexGuard<'_, usize>> {
+        self.running
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
 }
 
-impl Display for Notus {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let mut out = String::new();
-        for res in self.iter() {
-            match res {
-                Ok((k, v)) => out.push_str(&format!("{:?} : {:?} \n", k, v)),
-                Err(_) => {}
+impl Shutdown {
+    fn new(store: Arc<DataStore>) -> Arc<Self> {
+        Arc::new(Self {
+            store,
+            dropped: Arc::new(AtomicBool::new(false)),
+            compactions: CompactionCancel::default(),
+            readers: TimedReaders::default(),
+            wake: Mutex::new(None),
+            worker: Mutex::new(None),
+        })
+    }
+
+    /// Tells the background worker to stop, without waiting for it.
+    fn stop_worker(&self) {
+        self.dropped.store(true, Ordering::Release);
+        if let Ok(mut wake) = self.wake.lock() {
+            wake.take();
+        }
+    }
+}
+
+impl Display for Notus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut out = String::new();
+        for res in self.iter() {
+            match res {
+                Ok((k, v)) => out.push_str(&format!("{:?} : {:?} \n", k, v)),
+                Err(_) => {}
+            }
+        }
+        writeln!(f, "{}", out)
+    }
+}
+
+impl Notus {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::open_with_options(dir, NotusOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: NotusOptions) -> Result<Self> {
+        let store = DataStore::open_with_options(dir.as_ref(), options)?;
+        Ok(Self::from_store(store))
+    }
+
+    /// Reads the store in `dir` without opening it: nothing is locked or
+    /// written, so it works on a store another process has open, e.g. to
+    /// assess a damaged store before deciding how to recover it. Writes the
+    /// owner has not flushed yet are not seen.
+    pub fn inspect<P: AsRef<Path>>(dir: P) -> Result<Inspection> {
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::read_only(dir)?);
+        DataStore::inspect(&storage)
+    }
+
+    /// Rewrites the store in `dir`, which must not be open, so that its keys
+    /// are encoded as `to` instead of `from`. Returns the number of records
+    /// rewritten. A store written before keys were qualified by their column
+    /// is migrated this way when it is opened.
+    pub fn migrate_encoding<P: AsRef<Path>>(dir: P, from: Encoding, to: Encoding) -> Result<usize> {
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir)?);
+        DataStore::migrate_encoding(&storage, from, to)
+    }
+
+    /// Opens a store backed by `storage` instead of a local directory.
+    pub fn open_with_storage(storage: Arc<dyn Storage>, options: NotusOptions) -> Result<Self> {
+        let store = DataStore::open_with_storage(storage, options)?;
+        Ok(Self::from_store(store))
+    }
+
+    /// Wraps an opened `store`, starting its background worker unless its
+    /// options turn it off.
+    fn from_store(store: DataStore) -> Self {
+        let store = Arc::new(store);
+        let instance = Self {
+            key_normalizer: store.options().key_normalizer.clone(),
+            shutdown: Shutdown::new(store.clone()),
+            store,
+        };
+        if instance.store.options().background_worker {
+            instance.start_background_workers();
+        }
+        instance
+    }
+
+    fn start_background_workers(&self) {
+        #[cfg(test)]
+        WORKERS_STARTED.with(|started| started.set(started.get() + 1));
+        let options = self.store.options();
+        let compaction_schedule = options.compaction_schedule;
+        let mut adaptive = options
+            .auto_compaction
+            .map(|AutoPolicy::Adaptive| AdaptiveCompaction::new(options.compaction_io_budget));
+        let merge_trigger_ratio = options.merge_trigger_ratio;
+        let flush_interval = options.background_flush_interval;
+        let on_error = options.on_background_error.clone();
+        let is_dropped = self.shutdown.dropped.clone();
+        let store = self.store.clone();
+        let (wake, woken) = mpsc::channel::<()>();
+        let worker = thread::spawn(move || {
+            let report = |result: Result<()>| {
+                if let (Err(e), Some(BackgroundErrorHandler(handler))) = (result, &on_error) {
+                    handler(&e)
+                }
+            };
+            let mut next_compaction =
+                compaction_schedule.map(|schedule| schedule.next_after(Utc::now()));
+            let mut last_sample = Instant::now();
+            // Woken up only to stop
+            while let Err(RecvTimeoutError::Timeout) = woken.recv_timeout(flush_interval) {
+                if is_dropped.load(Ordering::Acquire) {
+                    break;
+                }
+                report(store.flush());
+                report(store.replay_pending(Duration::from_millis(10)).map(|_| ()));
+                report(store.refresh_stale_index());
+                if let (Some(schedule), Some(due)) = (compaction_schedule, next_compaction) {
+                    let now = Utc::now();
+                    if now >= due {
+                        report(store.merge_keeping(1, &is_dropped));
+                        report(store.collect_value_log().map(|_| ()));
+                        next_compaction = Some(schedule.next_after(Utc::now()));
+                    }
+                }
+                let elapsed = last_sample.elapsed();
+                if elapsed >= COMPACTION_SAMPLE_INTERVAL {
+                    last_sample = Instant::now();
+                    if let Some(policy) = adaptive.as_mut() {
+                        report(Self::compact_adaptively(&store, policy, elapsed, &is_dropped));
+                    }
+                    if let Some(ratio) = merge_trigger_ratio {
+                        report(Self::compact_over_ratio(&store, ratio, &is_dropped));
+                    }
+                }
+            }
+            drop(store)
+        });
+        if let (Ok(mut w), Ok(mut t)) = (self.shutdown.wake.lock(), self.shutdown.worker.lock()) {
+            *w = Some(wake);
+            *t = Some(worker);
+        }
+    }
+
+    fn compact_adaptively(
+        store: &DataStore,
+        policy: &mut AdaptiveCompaction,
+        elapsed: Duration,
+        stop: &AtomicBool,
+    ) -> Result<()> {
+        if policy.sample(store.bytes_written(), store.live_bytes()?, elapsed) {
+            store.merge_keeping(1, stop)?;
+            store.collect_value_log()?;
+            policy.compacted(store.bytes_written(), store.live_bytes()?);
+        }
+        Ok(())
+    }
+
+    fn compact_over_ratio(store: &DataStore, ratio: f64, stop: &AtomicBool) -> Result<()> {
+        let live = store.live_bytes()?;
+        if live > 0 && store.bytes_written() as f64 >= ratio * live as f64 {
+            store.merge_keeping(1, stop)?;
+            store.collect_value_log()?;
+        }
+        Ok(())
+    }
+
+    pub fn temp<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let store = DataStore::open_with_options(dir.as_ref(), NotusOptions::default())?;
+        Ok(Self::from_store(store))
+    }
+    /// The column qualified key `key` is indexed under.
+    fn index_key(&self, column: &str, key: &[u8]) -> Vec<u8> {
+        match &self.key_normalizer {
+            Some(KeyNormalizer(normalize)) => RawKey::new(column, normalize(key)).encode(),
+            None => RawKey::new(column, key.to_vec()).encode(),
+        }
+    }
+
+    /// The key and value written to the store for a put of `key`.
+    fn stored_entry(&self, column: &str, key: Vec<u8>, value: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        match &self.key_normalizer {
+            Some(_) => (self.index_key(column, &key), wrap_original_key(&key, value)),
+            None => (RawKey::new(column, key).encode(), value),
+        }
+    }
+
+    fn user_value(&self, stored: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.key_normalizer {
+            Some(_) => Ok(unwrap_original_key(&stored)?.1),
+            None => Ok(stored),
+        }
+    }
+
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put_cf(DEFAULT_INDEX, key, value)
+    }
+
+    pub fn put_cf(&self, column: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let (key, value) = self.stored_entry(column, key, value);
+        self.store.put(key, value)
+    }
+
+    /// Like `put` but returns `Ok(false)` right away, writing nothing, when
+    /// another write holds the store lock instead of waiting for it, so a
+    /// latency sensitive caller can shed load. The background flush takes
+    /// the lock too, so an occasional `false` is expected even without
+    /// other writers.
+    pub fn try_put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        let (key, value) = self.stored_entry(DEFAULT_INDEX, key, value);
+        self.store.try_put(key, value)
+    }
+
+    /// Like `put` but records `ts` (unix seconds) as the write time instead of
+    /// the current time. `ts` must be positive.
+    pub fn put_with_timestamp(&self, key: Vec<u8>, value: Vec<u8>, ts: i64) -> Result<()> {
+        let (key, value) = self.stored_entry(DEFAULT_INDEX, key, value);
+        self.store.put_with_timestamp(key, value, ts)
+    }
+
+    /// Writes `key` so that `get`, `contains`, iterators and every other read
+    /// treat it as absent once `ttl` has passed, e.g. for a cache. The expiry
+    /// is stored with the record and survives reopening, and compaction
+    /// drops the expired record. Writing or deleting the key before then
+    /// replaces the expiry along with the value.
+    pub fn put_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let (key, value) = self.stored_entry(DEFAULT_INDEX, key, value);
+        self.store.put_with_ttl(key, value, ttl)
+    }
+
+    /// Loads `entries` into the default column much faster than one `put`
+    /// each: records are appended straight to disk, synced once at the end,
+    /// and indexed in a single pass. `entries` must be sorted by key with no
+    /// duplicates, otherwise the load fails with `NotusError::UnsortedInput`
+    /// and none of it is visible, though the records appended before the
+    /// failure take up space until the next compaction. With a key normalizer
+    /// the normalized keys are the ones that must be sorted. Returns the
+    /// number of entries loaded.
+    pub fn bulk_load_sorted(&self, entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<usize> {
+        let prefix_len = RawKey::column_prefix(DEFAULT_INDEX).len();
+        let result = self.store.bulk_load_sorted(
+            entries.map(|(key, value)| self.stored_entry(DEFAULT_INDEX, key, value)),
+        );
+        match result {
+            // Report the key the caller passed in
+            Err(NotusError::UnsortedInput(key)) if self.key_normalizer.is_none() => {
+                Err(NotusError::UnsortedInput(key[prefix_len..].to_vec()))
+            }
+            result => result,
+        }
+    }
+
+    /// Stores `value` under `key` unless the key already holds an equal
+    /// value, in which case nothing is written. Fails with
+    /// `NotusError::Conflict` if the key holds a different value.
+    pub fn put_idempotent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let (key, value) = self.stored_entry(DEFAULT_INDEX, key, value);
+        self.store.put_idempotent(key, value)
+    }
+
+    pub fn get(&self, key: &Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.get_cf(DEFAULT_INDEX, key)
+    }
+
+    pub fn get_cf(&self, column: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if key.is_empty() {
+            return Ok(None);
+        }
+        match self.store.get(&self.index_key(column, key))? {
+            Some(value) => Ok(Some(self.user_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `get`, but gives up with `NotusError::Timeout` once `timeout` has
+    /// passed, so a read stuck on slow storage does not hold up the caller.
+    /// The read runs on one of a few threads kept for such reads, which
+    /// finishes it in the background after a timeout. While all of them are
+    /// stuck, later reads wait for one and time out as well; reads still
+    /// queued once their caller gave up are skipped.
+    pub fn get_timeout(&self, key: &[u8], timeout: Duration) -> Result<Option<Vec<u8>>> {
+        if key.is_empty() {
+            return Ok(None);
+        }
+        let index_key = self.index_key(DEFAULT_INDEX, key);
+        let original_keys = self.key_normalizer.is_some();
+        let (sender, receiver) = mpsc::channel();
+        let deadline = Instant::now() + timeout;
+        let read = move |store: &DataStore| {
+            // Reads that did not start in time are not worth making
+            if Instant::now() >= deadline {
+                let _ = sender.send(Err(NotusError::Timeout));
+                return;
+            }
+            let value = match store.get(&index_key) {
+                Ok(Some(stored)) if original_keys => {
+                    unwrap_original_key(&stored).map(|(_, value)| Some(value))
+                }
+                value => value,
+            };
+            let _ = sender.send(value);
+        };
+        self.shutdown.readers.run(&self.store, Box::new(read))?;
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(NotusError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(NotusError::Unknown),
+        }
+    }
+
+    /// Reads the value of `key` into `buf`, reusing its capacity instead of
+    /// allocating a new vector. `buf` is cleared first and left empty if the
+    /// key is not found. Returns whether the key was found.
+    pub fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<bool> {
+        if key.is_empty() {
+            buf.clear();
+            return Ok(false);
+        }
+        if !self
+            .store
+            .get_into(&self.index_key(DEFAULT_INDEX, key), buf)?
+        {
+            return Ok(false);
+        }
+        if self.key_normalizer.is_some() {
+            if buf.len() < 4 {
+                return Err(NotusError::CorruptValue);
+            }
+            let key_len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if buf.len() < 4 + key_len {
+                return Err(NotusError::CorruptValue);
+            }
+            buf.drain(..4 + key_len);
+        }
+        Ok(true)
+    }
+
+    /// Returns at most the first `n` bytes of the value of `key`, reading only
+    /// those bytes from disk. Unlike `get`, the record crc is not verified.
+    pub fn get_prefix_bytes(&self, key: &[u8], n: usize) -> Result<Option<Vec<u8>>> {
+        if key.is_empty() {
+            return Ok(None);
+        }
+        let index_key = self.index_key(DEFAULT_INDEX, key);
+        let offset = match &self.key_normalizer {
+            None => 0,
+            Some(_) => match self.store.get_value_range(&index_key, 0, 4)? {
+                None => return Ok(None),
+                Some(len) if len.len() == 4 => {
+                    4 + u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as u64
+                }
+                Some(_) => return Err(NotusError::CorruptValue),
+            },
+        };
+        self.store.get_value_range(&index_key, offset, n)
+    }
+
+    /// Returns whether `a` and `b` hold equal values, or `None` if either
+    /// does not exist, e.g. to spot duplicates. The values are compared a
+    /// chunk at a time, stopping at the first difference, and values of
+    /// different sizes are not read at all. Like `get_prefix_bytes`, record
+    /// crcs are not verified.
+    pub fn values_equal(&self, a: &[u8], b: &[u8]) -> Result<Option<bool>> {
+        if a.is_empty() || b.is_empty() {
+            return Ok(None);
+        }
+        self.store.values_equal(
+            &self.index_key(DEFAULT_INDEX, a),
+            &self.index_key(DEFAULT_INDEX, b),
+            self.key_normalizer.is_some(),
+        )
+    }
+
+    /// Like `get`, but tells a deleted key apart from one that was never
+    /// written. Compaction drops tombstones, after which deleted keys are
+    /// reported as `KeyState::Absent`.
+    pub fn get_state(&self, key: &[u8]) -> Result<KeyState> {
+        if let Some(value) = self.get_cf(DEFAULT_INDEX, key)? {
+            return Ok(KeyState::Present(value));
+        }
+        if key.is_empty() {
+            return Ok(KeyState::Absent);
+        }
+        if self.store.contains(&self.index_key(RESERVED_INDEX, key))? {
+            return Ok(KeyState::Reserved);
+        }
+        if self.store.is_deleted(&self.index_key(DEFAULT_INDEX, key))? {
+            return Ok(KeyState::Deleted);
+        }
+        Ok(KeyState::Absent)
+    }
+
+    /// Reserves `key` for the caller if it holds no value and is not
+    /// reserved already, returning whether it was reserved. Until the value
+    /// is written with `fulfill`, `get` reports the key as missing and
+    /// `get_state` as `KeyState::Reserved`. Reservations survive reopening.
+    pub fn reserve(&self, key: &[u8]) -> Result<bool> {
+        if key.is_empty() {
+            return Ok(false);
+        }
+        self.atomically(|view| {
+            if view.get(key)?.is_some() || view.get_cf(RESERVED_INDEX, key)?.is_some() {
+                return Ok(false);
+            }
+            view.put_cf(RESERVED_INDEX, key.to_vec(), vec![]);
+            Ok(true)
+        })
+    }
+
+    /// Writes the value of a key reserved with `reserve` and releases the
+    /// reservation, in one batch. Fails with `NotusError::NotReserved` if the
+    /// key is not reserved.
+    pub fn fulfill(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.atomically(|view| {
+            if view.get_cf(RESERVED_INDEX, key)?.is_none() {
+                return Err(NotusError::NotReserved);
+            }
+            view.delete_cf(RESERVED_INDEX, key);
+            view.put(key.to_vec(), value);
+            Ok(())
+        })
+    }
+
+    pub fn get_with_meta(&self, key: &[u8]) -> Result<Option<(Vec<u8>, EntryMeta)>> {
+        if key.is_empty() {
+            return Ok(None);
+        }
+        let entry = match self.store.get_entry(&self.index_key(DEFAULT_INDEX, key))? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let meta = EntryMeta {
+            timestamp: entry.timestamp(),
+        };
+        Ok(Some((self.user_value(entry.value())?, meta)))
+    }
+
+    pub fn contains(&self, key: &Vec<u8>) -> Result<bool> {
+        self.contains_cf(DEFAULT_INDEX, key)
+    }
+
+    pub fn contains_cf(&self, column: &str, key: &[u8]) -> Result<bool> {
+        if key.is_empty() {
+            return Ok(false);
+        }
+        self.store.contains(&self.index_key(column, key))
+    }
+
+    pub fn delete(&self, key: &Vec<u8>) -> Result<()> {
+        self.delete_cf(DEFAULT_INDEX, key)
+    }
+
+    /// Deletes `key` from `column`. The tombstone is written for the column
+    /// qualified key, so the same key in other columns is left untouched.
+    pub fn delete_cf(&self, column: &str, key: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            return Ok(());
+        }
+        self.store.delete(&self.index_key(column, key))
+    }
+
+    /// Yields the key and value of every data record that is no longer
+    /// reachable through the index, e.g. versions overwritten since the last
+    /// compaction. Useful to salvage data after a bad merge. Only records of
+    /// the default column are returned.
+    pub fn orphans(&self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        let (positions, error) = match self.store.orphan_positions() {
+            Ok(positions) => (positions, None),
+            Err(e) => (vec![], Some(Err(e))),
+        };
+        let store = self.store.clone();
+        let prefix = RawKey::column_prefix(DEFAULT_INDEX);
+        let entries = positions
+            .into_iter()
+            .map(move |(fp, position)| store.read_at(&fp, position));
+        error.into_iter().chain(entries.filter_map(move |entry| match entry {
+            Ok(entry) => {
+                let key = entry.key();
+                key.strip_prefix(prefix.as_slice())
+                    .map(|key| Ok((key.to_vec(), entry.value())))
+            }
+            // The value was already collected from the value log
+            Err(NotusError::FileNotFound(_)) => None,
+            Err(e) => Some(Err(e)),
+        }))
+    }
+
+    /// Lists the ids of the files `open` left for the background worker to
+    /// replay because of `NotusOptions::recovery_budget`, newest first.
+    pub fn pending_replay(&self) -> Result<Vec<String>> {
+        self.store.pending_replay()
+    }
+
+    /// Returns the options the store is running with, defaults included. The
+    /// mmap options are reported off for a store opened with
+    /// `open_with_storage`, which ignores them.
+    pub fn config(&self) -> &NotusOptions {
+        self.store.options()
+    }
+
+    /// Returns the version stamped on the store with `set_app_version`, or
+    /// `0` for a store that never had one set.
+    pub fn app_version(&self) -> Result<u32> {
+        self.store.app_version()
+    }
+
+    /// Records the version of the application's value format in the store
+    /// metadata, so a later open can detect data written by an older
+    /// version. Notus itself does not interpret it.
+    pub fn set_app_version(&self, version: u32) -> Result<()> {
+        self.store.set_app_version(version)
+    }
+
+    /// Lists the ids of the file pairs in the store, oldest first.
+    pub fn file_ids(&self) -> Result<Vec<String>> {
+        self.store.file_ids()
+    }
+
+    /// Lists the keys of the default column whose latest value is in file
+    /// `file_id`, e.g. to check that a copy of the file holds what it should.
+    /// Fails with `NotusError::FileNotFound` if the store has no such file.
+    pub fn keys_in_file(&self, file_id: &str) -> Result<Vec<Vec<u8>>> {
+        self.keys_in_file_cf(DEFAULT_INDEX, file_id)
+    }
+
+    pub fn keys_in_file_cf(&self, column: &str, file_id: &str) -> Result<Vec<Vec<u8>>> {
+        let prefix_len = RawKey::column_prefix(column).len();
+        Ok(self
+            .store
+            .keys_in_file(file_id, RawKey::column_range(column, ..))?
+            .into_iter()
+            .map(|key| key[prefix_len..].to_vec())
+            .collect())
+    }
+
+    /// Lists the hints recorded in file `file_id`, including tombstones, in
+    /// the order they were written.
+    pub fn hint_entries(&self, file_id: &str) -> Result<Vec<HintEntryInfo>> {
+        let hints = self.store.hint_entries(file_id)?;
+        Ok(hints
+            .into_iter()
+            .map(|hint| {
+                let raw_key = hint.key();
+                let (column, key) = match RawKey::from_encoded(&raw_key) {
+                    Some(RawKey(column, key)) => (Some(column), key),
+                    None => (None, raw_key),
+                };
+                HintEntryInfo {
+                    column,
+                    key,
+                    value_size: hint.value_size(),
+                    position: hint.data_entry_position(),
+                    deleted: hint.is_deleted(),
+                    timestamp: hint.timestamp(),
+                }
+            })
+            .collect())
+    }
+
+    /// Appends `key` and `value` to the active file and returns the file id
+    /// and position of the record, for callers keeping their own index.
+    /// The record is not indexed, so `get` and the iterators do not see it.
+    /// Positions stay valid until the next compaction, which drops or moves
+    /// raw records.
+    pub fn append_raw(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(String, u64)> {
+        let entry = DataEntry::new(RawKey::new(RAW_INDEX, key).encode(), value);
+        self.store.append_raw(&entry)
+    }
+
+    /// Reads the key and value of the record at `position` of file `file_id`,
+    /// as returned by `append_raw`.
+    pub fn read_raw(&self, file_id: &str, position: u64) -> Result<(Vec<u8>, Vec<u8>)> {
+        let entry = self.store.read_raw(file_id, position)?;
+        let key = match RawKey::from_encoded(&entry.key()) {
+            Some(RawKey(_, key)) => key,
+            None => entry.key(),
+        };
+        Ok((key, entry.value()))
+    }
+
+    /// Verifies the in-memory index against the data and hint files and
+    /// reports any mismatch without fixing it.
+    pub fn audit(&self) -> Result<AuditReport> {
+        self.store.audit()
+    }
+
+    /// Makes every write issued before the call durable, fsyncing once for
+    /// the whole group instead of after each write. A write is durable once
+    /// `barrier` has returned after it, or once the last handle on the store
+    /// is dropped. Until then it is only buffered, in memory or by the OS,
+    /// and may be lost on a crash or power failure unless `sync_on_write` is
+    /// set.
+    pub fn barrier(&self) -> Result<()> {
+        self.store.barrier()
+    }
+
+    /// Alias of `barrier`.
+    pub fn flush(&self) -> Result<()> {
+        self.barrier()
+    }
+
+    /// Merges the data files into one holding only the latest record of
+    /// each live key. After `request_shutdown` the compaction stops once the
+    /// file pair it is copying is done, and the rest is left for later.
+    pub fn compact(&self) -> Result<()> {
+        self.shutdown
+            .compactions
+            .run(|stop| self.store.merge_keeping(1, stop))
+    }
+
+    /// Keeps every file of the store on disk until the returned guard is
+    /// dropped, so the directory can be copied for a backup while the store
+    /// is in use. Compaction and `collect_value_log` still run, but the
+    /// files they replace are only removed once the last guard is gone.
+    /// Writes go on in files of their own. `clear_and_reclaim` is not held
+    /// back.
+    pub fn pin(&self) -> Result<PinGuard> {
+        self.store.pin()?;
+        Ok(PinGuard {
+            store: self.store.clone(),
+        })
+    }
+
+    /// Reclaims the space taken in the value log by values that were
+    /// overwritten or deleted, see `NotusOptions::kv_separation`. Writes wait
+    /// until it is done. Returns the number of bytes reclaimed.
+    pub fn collect_value_log(&self) -> Result<u64> {
+        self.store.collect_value_log()
+    }
+
+    /// Merges the data files smaller than `target_file_size` into files of
+    /// about that size, so a store left with many small files, e.g. after
+    /// many reopens, opens faster. Larger files are not rewritten.
+    pub fn compact_tiered(&self, target_file_size: u64) -> Result<()> {
+        self.store.merge_tiered(target_file_size)
+    }
+
+    pub fn compact_with_options(&self, options: CompactOptions) -> Result<()> {
+        self.shutdown
+            .compactions
+            .run(|stop| self.store.merge_keeping(options.keep_versions, stop))
+    }
+
+    /// Stops the background worker and any compaction in progress, e.g. on a
+    /// shutdown signal. The store stays usable, but writes are no longer
+    /// flushed in the background and are written out when it is dropped.
+    /// Compactions started afterwards run to completion.
+    pub fn request_shutdown(&self) {
+        self.shutdown.stop_worker();
+        self.shutdown.compactions.cancel();
+    }
+
+    /// Deletes every key of the default column in `range` in one batch and
+    /// compacts right after, so the deleted records and their tombstones
+    /// leave the disk. Returns the number of keys deleted.
+    pub fn delete_range_and_compact<R>(&self, range: R) -> Result<usize>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let keys = self.store.range(RawKey::column_range(DEFAULT_INDEX, range));
+        let count = keys.len();
+        self.store
+            .write_batch(keys.into_iter().map(BatchOp::Delete).collect())?;
+        self.compact()?;
+        Ok(count)
+    }
+
+    /// Returns up to `n` of the most recent values of `key`, newest first.
+    /// Only the latest value survives a plain `compact`, use
+    /// `CompactOptions::keep_versions` to retain more.
+    pub fn get_versions(&self, key: &[u8], n: usize) -> Result<Vec<Vec<u8>>> {
+        self.store
+            .versions(&self.index_key(DEFAULT_INDEX, key), n)?
+            .into_iter()
+            .map(|entry| self.user_value(entry.value()))
+            .collect()
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.store.clear()
+    }
+
+    /// Like `clear`, but deletes the data files straight away instead of
+    /// writing tombstones and waiting for compaction to reclaim the space.
+    pub fn clear_and_reclaim(&self) -> Result<()> {
+        self.store.clear_and_reclaim()
+    }
+
+
+    pub fn merge(
+        &self,
+        merge_operator: impl MergeOperator + 'static,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<()> {
+        let old_value = self.get(&key)?;
+        let merged_value = merge_operator(&key, old_value, &value);
+        match merged_value {
+            None => self.delete(&key),
+            Some(value) => self.put(key, value),
+        }
+    }
+
+    /// Registers `merge_operator` for `column`, to be applied by `merge_cf`.
+    /// Registering again replaces the operator. Registrations last until
+    /// the store is closed.
+    pub fn create_column_family(
+        &self,
+        column: &str,
+        merge_operator: impl MergeOperator + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.store.create_column_family(column, merge_operator)
+    }
+
+    /// Lists the columns holding live keys or registered with
+    /// `create_column_family`, sorted by name. Columns the store uses
+    /// internally, whose names start with `$`, are left out, the default
+    /// column included.
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.store
+            .column_families()
+            .into_iter()
+            .filter(|column| !column.starts_with('$'))
+            .collect()
+    }
+
+    /// Returns whether `column` holds a live key or has a merge operator
+    /// registered.
+    pub fn column_family_exists(&self, column: &str) -> bool {
+        self.store.column_family_exists(column)
+    }
+
+    /// Deletes every key of `column` in one batch and forgets its merge
+    /// operator. Returns the number of keys deleted. The space is reclaimed
+    /// by the next compaction.
+    pub fn drop_column_family(&self, column: &str) -> Result<usize> {
+        self.store.drop_column_family(column)
+    }
+
+    /// Forgets the merge operators of the columns left without live keys,
+    /// e.g. after all of their keys were deleted, so they are no longer
+    /// listed by `list_column_families`. Returns the columns forgotten.
+    pub fn gc_columns(&self) -> Result<Vec<String>> {
+        self.store.gc_columns()
+    }
+
+    /// Like `merge`, applying the operator registered for `column` with
+    /// `create_column_family`. Fails with `NotusError::NoMergeOperator` if
+    /// none is.
+    pub fn merge_cf(&self, column: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let merge_operator = self.store.merge_operator(column)?;
+        let old_value = self.get_cf(column, &key)?;
+        match merge_operator(&key, old_value, &value) {
+            None => self.delete_cf(column, &key),
+            Some(value) => self.put_cf(column, key, value),
+        }
+    }
+    /// Writes every live entry of every column to `w` as encoded data entries,
+    /// keeping their original timestamps. Returns the number of entries written.
+    pub fn export<W: Write>(&self, w: &mut W) -> Result<usize> {
+        let mut count = 0;
+        for key in self.store.keys() {
+            if let Some(entry) = self.store.get_entry(&key)? {
+                w.write_all(&entry.encode())?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Writes the live entries of `column` to `w` like `export`, but with the
+    /// keys as the user gave them, so `import_cf` can load them into any
+    /// column of any store. Returns the number of entries written.
+    pub fn export_cf<W: Write>(&self, column: &str, w: &mut W) -> Result<usize> {
+        let mut count = 0;
+        for key in self.store.range(RawKey::column_range(column, ..)) {
+            let entry = match self.store.get_entry(&key)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let (key, value) = match &self.key_normalizer {
+                Some(_) => unwrap_original_key(&entry.value())?,
+                None => (key[RawKey::column_prefix(column).len()..].to_vec(), entry.value()),
+            };
+            w.write_all(&DataEntry::with_timestamp(key, value, entry.timestamp()).encode())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Copies the store into two new stores, keys below `pivot` into
+    /// `left_dir` and the rest into `right_dir`. Every column is split on the
+    /// same pivot and entries keep their timestamps; this store is left as is.
+    pub fn split<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        pivot: &[u8],
+        left_dir: P,
+        right_dir: Q,
+    ) -> Result<()> {
+        let left = DataStore::open(left_dir)?;
+        let right = DataStore::open(right_dir)?;
+        for key in self.store.keys() {
+            let raw_key = match RawKey::from_encoded(&key) {
+                Some(raw_key) => raw_key,
+                None => continue,
+            };
+            if let Some(entry) = self.store.get_entry(&key)? {
+                let target = if raw_key.1.as_slice() < pivot {
+                    &left
+                } else {
+                    &right
+                };
+                target.put_with_timestamp(entry.key(), entry.value(), entry.timestamp())?;
+            }
+        }
+        left.flush()?;
+        right.flush()?;
+        Ok(())
+    }
+
+    /// Reads entries written by `export` and stores them with their original
+    /// timestamps. Returns the number of entries imported.
+    pub fn import<R: Read>(&self, r: &mut R) -> Result<usize> {
+        let mut count = 0;
+        loop {
+            let entry = match DataEntry::decode(r) {
+                Ok(entry) => entry,
+                Err(NotusError::UnexpectedEof) => break,
+                Err(e) => return Err(e),
+            };
+            if !entry.check_crc() {
+                return Err(NotusError::CorruptValue);
+            }
+            self.store
+                .put_with_timestamp(entry.key(), entry.value(), entry.timestamp())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Reads entries written by `export_cf` into `column`, keeping their
+    /// original timestamps. Returns the number of entries imported.
+    pub fn import_cf<R: Read>(&self, column: &str, r: &mut R) -> Result<usize> {
+        let mut count = 0;
+        loop {
+            let entry = match DataEntry::decode(r) {
+                Ok(entry) => entry,
+                Err(NotusError::UnexpectedEof) => break,
+                Err(e) => return Err(e),
+            };
+            if !entry.check_crc() {
+                return Err(NotusError::CorruptValue);
+            }
+            let (key, value) = self.stored_entry(column, entry.key(), entry.value());
+            self.store.put_with_timestamp(key, value, entry.timestamp())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Turns the store into a read-only `FrozenNotus` whose index is a sorted
+    /// array, for serving a dataset that no longer changes. The background
+    /// worker stops, so writes made through other clones of this handle
+    /// are no longer flushed and are not seen by the frozen store; drop them
+    /// first.
+    pub fn freeze(self) -> Result<FrozenNotus> {
+        // A scheduled compaction would move the records the index points at
+        self.shutdown.stop_worker();
+        let index = self.store.freeze()?;
+        Ok(FrozenNotus {
+            store: self.store.clone(),
+            index,
+            key_normalizer: self.key_normalizer.clone(),
+        })
+    }
+
+    /// Captures a point-in-time view of the live keys of the default column.
+    /// Reads through the snapshot return the values as they were when it was
+    /// taken, whatever is written, deleted or compacted afterwards. The files
+    /// it reads from are kept on disk until it is dropped, like with `pin`.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        // Pin first so no file the captured entries point at goes away
+        let pin = self.pin()?;
+        let prefix = RawKey::column_prefix(DEFAULT_INDEX);
+        let entries = self
+            .store
+            .snapshot()?
+            .into_iter()
+            .filter_map(|(key, entry)| {
+                key.strip_prefix(prefix.as_slice())
+                    .map(|key| (key.to_vec(), entry))
+            })
+            .collect();
+        Ok(Snapshot {
+            store: self.store.clone(),
+            entries,
+            key_normalizer: self.key_normalizer.clone(),
+            _pin: pin,
+        })
+    }
+
+    /// Lists the keys added, removed or changed between two snapshots, in key
+    /// order. Keys rewritten with an identical value are not reported.
+    pub fn diff(&self, old: &Snapshot, new: &Snapshot) -> impl Iterator<Item = Change> {
+        let mut changes = vec![];
+        for (key, old_entry) in old.entries.iter() {
+            match new.entries.get(key) {
+                None => changes.push(Change::Removed(key.clone())),
+                Some(new_entry) => {
+                    if old_entry.same_record(new_entry) {
+                        continue;
+                    }
+                    let old_value = self.store.read_snapshot_entry(old_entry);
+                    let new_value = self.store.read_snapshot_entry(new_entry);
+                    match (old_value, new_value) {
+                        (Ok(old_value), Ok(new_value)) if old_value.value() == new_value.value() => {}
+                        _ => changes.push(Change::Modified(key.clone())),
+                    }
+                }
             }
         }
-        writeln!(f, "{}", out)
+        for key in new.entries.keys() {
+            if !old.entries.contains_key(key) {
+                changes.push(Change::Added(key.clone()));
+            }
+        }
+        changes.sort_by(|a, b| a.key().cmp(b.key()));
+        changes.into_iter()
     }
-}
 
-impl Notus {
-    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
-        let store = Arc::new(DataStore::open(dir.as_ref())?);
-        let instance = Self {
-            dir: PathBuf::from(dir.as_ref()),
-            temp: false,
-            store,
-            dropped: Arc::new(AtomicBool::new(false)),
-        };
-        instance.start_background_workers();
-        Ok(instance)
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.store.write_batch(self.stored_ops(batch)?)
     }
 
-    fn start_background_workers(&self) {
-        let is_dropped = self.dropped.clone();
+    /// Durably stages `batch` under `id` without applying it, for a
+    /// coordinator of a distributed transaction: none of its writes are
+    /// visible until `PreparedBatch::commit` applies them all at once, and
+    /// `PreparedBatch::abort` drops them. A prepared batch outlives a crash,
+    /// `prepared` returns it after the store is reopened. Fails with
+    /// `NotusError::Conflict` if a batch with `id` is already prepared.
+    pub fn prepare(&self, id: &str, batch: WriteBatch) -> Result<PreparedBatch> {
+        self.store.prepare(id, self.stored_ops(batch)?)?;
+        Ok(PreparedBatch {
+            store: self.store.clone(),
+            id: id.to_string(),
+        })
+    }
+
+    /// The batches prepared and not yet committed or aborted, ordered by
+    /// id, including those prepared before the store was reopened.
+    pub fn prepared(&self) -> Result<Vec<PreparedBatch>> {
+        Ok(self
+            .store
+            .prepared()?
+            .into_iter()
+            .map(|id| PreparedBatch {
+                store: self.store.clone(),
+                id,
+            })
+            .collect())
+    }
+
+    /// The operations of `batch` with their keys and values in the form
+    /// they are stored in.
+    fn stored_ops(&self, batch: WriteBatch) -> Result<Vec<BatchOp>> {
+        if self.key_normalizer.is_none() {
+            return Ok(batch.ops);
+        }
+        let mut ops = Vec::with_capacity(batch.ops.len());
+        for op in batch.ops {
+            ops.push(match op {
+                BatchOp::Put(key, value) => {
+                    let RawKey(column, key) =
+                        RawKey::from_encoded(&key).ok_or(NotusError::CorruptValue)?;
+                    let (key, value) = self.stored_entry(&column, key, value);
+                    BatchOp::Put(key, value)
+                }
+                BatchOp::Delete(key) => {
+                    let RawKey(column, key) =
+                        RawKey::from_encoded(&key).ok_or(NotusError::CorruptValue)?;
+                    BatchOp::Delete(self.index_key(&column, &key))
+                }
+            });
+        }
+        Ok(ops)
+    }
+
+    /// Runs `f` as a critical section: no other write to the store happens
+    /// until it returns, and reads from other threads wait for it. Writes
+    /// made through the view are seen by its own reads and are written as
+    /// one batch once `f` succeeds; if `f` returns an error they are
+    /// dropped and the store is left as it was. `f` must not call back into
+    /// this store, which would deadlock.
+    pub fn atomically<R>(&self, f: impl FnOnce(&StoreTxnView) -> Result<R>) -> Result<R> {
+        self.store.atomically(|locked| {
+            let view = StoreTxnView {
+                notus: self,
+                locked,
+                writes: RefCell::new(BTreeMap::new()),
+            };
+            let result = f(&view)?;
+            let ops = view
+                .writes
+                .into_inner()
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => BatchOp::Put(key, value),
+                    None => BatchOp::Delete(key),
+                })
+                .collect();
+            Ok((result, ops))
+        })
+    }
+
+    pub fn iter(&self) -> DBIterator {
+        self.iter_cf(DEFAULT_INDEX)
+    }
+
+    /// Iterates over the default column like `iter`, yielding what `f`
+    /// returns for each key and value instead of copies of them. Values are
+    /// read into one buffer reused across items. Keys deleted after the
+    /// iterator was created are skipped.
+    pub fn iter_map<R>(
+        &self,
+        mut f: impl FnMut(&[u8], &[u8]) -> R,
+    ) -> impl Iterator<Item = Result<R>> {
         let store = self.store.clone();
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_millis(10));
-                let is_dropped = is_dropped.load(Ordering::Acquire);
-                if is_dropped {
-                    break;
+        let prefix_len = RawKey::column_prefix(DEFAULT_INDEX).len();
+        let original_keys = self.key_normalizer.is_some();
+        let mut value = vec![];
+        self.store
+            .range(RawKey::column_range(DEFAULT_INDEX, ..))
+            .into_iter()
+            .filter_map(move |key| match store.get_into(&key, &mut value) {
+                Err(e) => Some(Err(e)),
+                Ok(false) => None,
+                Ok(true) if original_keys => {
+                    Some(split_original_key(&value).map(|(key, value)| f(key, value)))
                 }
-                store.flush();
-            }
-            drop(store)
+                Ok(true) => Some(Ok(f(&key[prefix_len..], &value))),
+            })
+    }
+
+    /// Yields every entry of the default column one data file at a time, in
+    /// the order the records sit in the file. Reads are sequential, which
+    /// suits full dumps, but entries are not in key order. Pending writes
+    /// are flushed first.
+    pub fn iter_by_file(&self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        let prefix = RawKey::column_prefix(DEFAULT_INDEX);
+        let (files, error) = match self.store.live_positions_by_file(&prefix) {
+            Ok(files) => (files, None),
+            Err(e) => (vec![], Some(Err(e))),
+        };
+        let store = self.store.clone();
+        let original_keys = self.key_normalizer.is_some();
+        let entries = files.into_iter().flat_map(move |(fp, positions)| {
+            let store = store.clone();
+            positions
+                .into_iter()
+                .map(move |position| store.read_at(&fp, position))
         });
+        error.into_iter().chain(entries.map(move |entry| {
+            let entry = entry?;
+            if original_keys {
+                return unwrap_original_key(&entry.value());
+            }
+            Ok((entry.key()[prefix.len()..].to_vec(), entry.value()))
+        }))
     }
 
-    pub fn temp<P: AsRef<Path>>(dir: P) -> Result<Self> {
-        let store = Arc::new(DataStore::open(dir.as_ref())?);
-        let instance = Self {
-            dir: PathBuf::from(dir.as_ref()),
-            temp: true,
-            store,
-            dropped: Arc::new(AtomicBool::new(false)),
+    /// Deletes every key of the default column for which `f` returns false,
+    /// like `HashMap::retain`, and returns how many were deleted. Values are
+    /// read file by file rather than in key order.
+    pub fn retain<F>(&self, f: F) -> Result<usize>
+    where
+        F: Fn(&[u8], &[u8]) -> bool,
+    {
+        self.retain_cf(DEFAULT_INDEX, f)
+    }
+
+    pub fn retain_cf<F>(&self, column: &str, f: F) -> Result<usize>
+    where
+        F: Fn(&[u8], &[u8]) -> bool,
+    {
+        let prefix = RawKey::column_prefix(column);
+        self.store.retain_prefix(&prefix, |entry| {
+            let (key, value) = match &self.key_normalizer {
+                Some(_) => unwrap_original_key(&entry.value())?,
+                None => (entry.key()[prefix.len()..].to_vec(), entry.value()),
+            };
+            Ok(f(&key, &value))
+        })
+    }
+
+    /// Yields `(file_id, offset, key, value, is_live)` for every data record
+    /// on disk, files oldest first and records in the order they were
+    /// written, for inspecting fragmentation. Keys and values are returned as
+    /// stored, keys with their column prefix and values replaced by their
+    /// pointer under `NotusOptions::kv_separation`. A record is live only if the
+    /// index points at it. Pending writes are flushed first; if that fails
+    /// the error is the only item.
+    pub fn physical_scan(&self) -> impl Iterator<Item = Result<PhysicalRecord>> {
+        let (files, error) = match self.store.physical_positions() {
+            Ok(files) => (files, None),
+            Err(e) => (vec![], Some(Err(e))),
         };
-        instance.start_background_workers();
-        Ok(instance)
+        let store = self.store.clone();
+        let records = files.into_iter().flat_map(move |(fp, positions)| {
+            let store = store.clone();
+            positions.into_iter().map(move |(position, is_live)| {
+                let entry = store.read_raw(&fp.file_id(), position)?;
+                Ok((fp.file_id(), position, entry.key(), entry.value(), is_live))
+            })
+        });
+        error.into_iter().chain(records)
     }
-    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+
+    /// Lists the keys of the default column in order from the index alone,
+    /// without reading any value. With a key normalizer the normalized keys
+    /// are returned.
+    pub fn keys(&self) -> impl Iterator<Item = Vec<u8>> {
+        self.keys_cf(DEFAULT_INDEX)
+    }
+
+    pub fn keys_cf(&self, column: &str) -> impl Iterator<Item = Vec<u8>> {
+        let prefix_len = RawKey::column_prefix(column).len();
         self.store
-            .put(key, value)
+            .range(RawKey::column_range(column, ..))
+            .into_iter()
+            .map(move |key| key[prefix_len..].to_vec())
     }
-    pub fn get(&self, key: &Vec<u8>) -> Result<Option<Vec<u8>>> {
-        if key.is_empty() {
-            return Ok(None);
-        }
+
+    /// Returns the number of keys in the default column from the index,
+    /// without reading any value like `iter().count()` does.
+    pub fn len(&self) -> usize {
+        self.len_cf(DEFAULT_INDEX)
+    }
+
+    pub fn len_cf(&self, column: &str) -> usize {
+        self.store.len(column)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Yields the entries of `column` only, in byte order of their keys. With
+    /// a key normalizer the order is that of the normalized keys.
+    pub fn iter_cf(&self, column: &str) -> DBIterator {
+        DBIterator::new(self.store.clone(), column).original_keys(self.key_normalizer.is_some())
+    }
+
+    pub fn range<R>(&self, range :R) -> DBIterator where R : RangeBounds<Vec<u8>> {
+        self.range_cf(DEFAULT_INDEX, range)
+    }
+
+    pub fn range_cf<R>(&self, column: &str, range: R) -> DBIterator
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        DBIterator::range(self.store.clone(), column, range)
+            .original_keys(self.key_normalizer.is_some())
+    }
+
+    pub fn prefix(&self, prefix: &Vec<u8>) -> DBIterator {
+        self.prefix_cf(DEFAULT_INDEX, prefix)
+    }
+
+    pub fn prefix_cf(&self, column: &str, prefix: &[u8]) -> DBIterator {
+        DBIterator::prefix(self.store.clone(), column, prefix)
+            .original_keys(self.key_normalizer.is_some())
+    }
+
+    /// Returns whether any key starts with `prefix`, without collecting the
+    /// matching keys like `prefix` does.
+    pub fn contains_prefix(&self, prefix: &[u8]) -> bool {
+        self.contains_prefix_cf(DEFAULT_INDEX, prefix)
+    }
+
+    pub fn contains_prefix_cf(&self, column: &str, prefix: &[u8]) -> bool {
         self.store
-            .get(&key)
+            .contains_prefix(&RawKey::new(column, prefix.to_vec()).encode())
     }
 
-    pub fn contains(&self, key: &Vec<u8>) -> Result<bool> {
-        if key.is_empty() {
-            return Ok(false);
-        }
+    /// Estimates the bytes taken by the values of the keys in `range` from the
+    /// index alone, without reading the values.
+    pub fn range_size<R>(&self, range: R) -> Result<u64>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
         self.store
-            .contains(&key)
+            .range_size(RawKey::column_range(DEFAULT_INDEX, range))
     }
 
-    pub fn delete(&self, key: &Vec<u8>) -> Result<()> {
+    /// Reports the live keys, value bytes and reclaimable bytes of every
+    /// column, keyed by column name.
+    pub fn column_stats(&self) -> Result<HashMap<String, ColumnStats>> {
+        self.store.column_stats()
+    }
+
+    /// Maps every key length to the number of live keys with that length,
+    /// across all columns. Only the index is read.
+    pub fn key_size_histogram(&self) -> Result<BTreeMap<usize, usize>> {
+        self.store.key_size_histogram()
+    }
+
+    /// Maps every value size to the number of live keys holding a value of
+    /// that size, across all columns. Only the index is read.
+    pub fn value_size_histogram(&self) -> Result<BTreeMap<usize, usize>> {
+        self.store.value_size_histogram()
+    }
+
+    /// Lists the keys of the default column whose value is larger than
+    /// `max_value_size` bytes, in key order, to spot unexpectedly large
+    /// values. Sizes come from the index, no value is read.
+    pub fn scan_anomalies(&self, max_value_size: u64) -> Result<Vec<Vec<u8>>> {
+        self.scan_anomalies_cf(DEFAULT_INDEX, max_value_size)
+    }
+
+    pub fn scan_anomalies_cf(&self, column: &str, max_value_size: u64) -> Result<Vec<Vec<u8>>> {
+        let prefix_len = RawKey::column_prefix(column).len();
+        Ok(self
+            .store
+            .keys_with_value_over(RawKey::column_range(column, ..), max_value_size)?
+            .into_iter()
+            .map(|key| key[prefix_len..].to_vec())
+            .collect())
+    }
+
+    /// Reports how many bytes were written to the data files for every byte
+    /// of live data, counting from the start of the last compaction, which
+    /// brings it back towards `1.0`. Writes still in the buffer are not
+    /// counted yet, and `0.0` is returned when nothing is live.
+    pub fn write_amplification(&self) -> Result<f64> {
+        self.store.write_amplification()
+    }
+
+    /// Stores `value` under a key whose prefix is the descending encoding of
+    /// `ts`, so a forward scan returns the newest entries first.
+    /// See `key_encoding::descending_time_key`.
+    pub fn put_descending_time(&self, key_suffix: &[u8], ts: i64, value: Vec<u8>) -> Result<()> {
+        self.put(descending_time_key(ts, key_suffix), value)
+    }
+
+    /// Scans entries written with `put_descending_time` whose timestamp lies
+    /// in `oldest..=newest`, newest first.
+    pub fn range_descending_time(&self, newest: i64, oldest: i64) -> DBIterator {
+        let start = Bound::Included(descending_timestamp(newest).to_vec());
+        let end = match oldest.checked_sub(1) {
+            Some(before_oldest) => Bound::Excluded(descending_timestamp(before_oldest).to_vec()),
+            None => Bound::Unbounded,
+        };
+        self.range((start, end))
+    }
+
+    /// Returns the keys whose latest write happened at or after `ts`, a unix
+    /// timestamp in seconds. Writes not yet flushed to disk are included.
+    pub fn keys_modified_since(&self, ts: i64) -> Result<Vec<Vec<u8>>> {
+        let prefix = RawKey::column_prefix(DEFAULT_INDEX);
+        let keys = self.store.keys_modified_since(ts)?;
+        Ok(keys
+            .iter()
+            .filter_map(|key| key.strip_prefix(prefix.as_slice()))
+            .map(|key| key.to_vec())
+            .collect())
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        // Snapshots and iterators may keep the store open for a while, the
+        // writes made through the handles are synced now
+        if let Err(e) = self.store.barrier() {
+            warn!("failed to sync the store when its last handle was dropped: {}", e);
+        }
+        self.stop_worker();
+        // The worker holds the store, and with it the directory lock, until
+        // it is done
+        if let Ok(Some(worker)) = self.worker.get_mut().map(Option::take) {
+            if worker.thread().id() != thread::current().id() {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+/// What `Notus::get_state` knows about a key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyState {
+    Present(Vec<u8>),
+    /// The key was reserved with `Notus::reserve` and not fulfilled yet.
+    Reserved,
+    /// The key was deleted and the tombstone has not been compacted away.
+    Deleted,
+    /// The key was never written, or its tombstone was compacted away.
+    Absent,
+}
+
+/// Metadata stored alongside a value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryMeta {
+    /// Unix timestamp in seconds of the write that produced the value.
+    pub timestamp: i64,
+}
+
+/// A hint file record as reported by `Notus::hint_entries`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HintEntryInfo {
+    /// Column the key belongs to, `None` if the stored key is not column
+    /// qualified, in which case `key` holds the stored bytes.
+    pub column: Option<String>,
+    pub key: Vec<u8>,
+    pub value_size: u64,
+    /// Offset of the data entry in the data file.
+    pub position: u64,
+    /// True for tombstones.
+    pub deleted: bool,
+    pub timestamp: i64,
+}
+
+/// A read-only store made by `Notus::freeze`. Lookups binary search a
+/// sorted array and take no index lock.
+pub struct FrozenNotus {
+    store: Arc<DataStore>,
+    index: FrozenIndex,
+    key_normalizer: Option<KeyNormalizer>,
+}
+
+impl FrozenNotus {
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(DEFAULT_INDEX, key)
+    }
+
+    pub fn get_cf(&self, column: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
         if key.is_empty() {
-            return Ok(());
+            return Ok(None);
+        }
+        let index_key = match &self.key_normalizer {
+            Some(KeyNormalizer(normalize)) => RawKey::new(column, normalize(key)).encode(),
+            None => RawKey::new(column, key.to_vec()).encode(),
+        };
+        match self.index.get(&index_key) {
+            Some((fp, position)) => {
+                let (_, value) = self.item(&[], self.store.read_at(fp, position)?.value())?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
         }
-        self.store
-            .delete(&key)
     }
 
-    pub fn compact(&self) -> Result<()> {
-        self.store.merge()
+    pub fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
     }
 
-    pub fn clear(&self) -> Result<()> {
-        self.store.clear()
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.range_cf(DEFAULT_INDEX, ..)
     }
 
+    pub fn range<R>(&self, range: R) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        self.range_cf(DEFAULT_INDEX, range)
+    }
 
-    pub fn merge(
+    pub fn range_cf<R>(
         &self,
-        merge_operator: impl MergeOperator + 'static,
-        key: Vec<u8>,
-        value: Vec<u8>,
-    ) -> Result<()> {
-        let old_value = self.store.get(&key)?;
-        let merged_value = merge_operator(&key, old_value, &value);
-        match merged_value {
-            None => {
-                self.delete(&key);
-            }
-            Some(value) => {
-                self.put(key, value);
-            }
+        column: &str,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let prefix_len = RawKey::column_prefix(column).len();
+        self.index
+            .range(RawKey::column_range(column, range))
+            .map(move |(key, fp, position)| {
+                let value = self.store.read_at(fp, position)?.value();
+                self.item(&key[prefix_len..], value)
+            })
+    }
+
+    /// The user facing key and value of a record, `key` being the key
+    /// without its column prefix.
+    fn item(&self, key: &[u8], value: Vec<u8>) -> Result<(Vec<u8>, Vec<u8>)> {
+        match &self.key_normalizer {
+            Some(_) => unwrap_original_key(&value),
+            None => Ok((key.to_vec(), value)),
         }
-        Ok(())
     }
-    pub fn iter(&self) -> DBIterator {
-        DBIterator::new(self.store.clone())
+}
+
+/// A point-in-time view of the store taken with `Notus::snapshot`.
+pub struct Snapshot {
+    store: Arc<DataStore>,
+    entries: BTreeMap<Vec<u8>, SnapshotEntry>,
+    key_normalizer: Option<KeyNormalizer>,
+    _pin: PinGuard,
+}
+
+impl Snapshot {
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
-    pub fn range<R>(&self, range :R) -> DBIterator where R : RangeBounds<Vec<u8>> {
-        DBIterator::range(self.store.clone(), range)
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 
-    pub fn prefix(&self, prefix: &Vec<u8>) -> DBIterator {
-        DBIterator::prefix(self.store.clone(), prefix)
+    /// Returns the value `key` had when the snapshot was taken.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = match &self.key_normalizer {
+            Some(KeyNormalizer(normalize)) => normalize(key),
+            None => key.to_vec(),
+        };
+        match self.entries.get(&key) {
+            Some(entry) => Ok(Some(self.read(&key, entry)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.range(..)
+    }
+
+    pub fn range<R>(&self, range: R) -> impl DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        self.entries
+            .range(range)
+            .map(move |(key, entry)| self.read(key, entry))
+    }
+
+    /// Reads the record `entry` points at, returning the user facing key and
+    /// value.
+    fn read(&self, key: &[u8], entry: &SnapshotEntry) -> Result<(Vec<u8>, Vec<u8>)> {
+        let value = self.store.read_snapshot_entry(entry)?.value();
+        match &self.key_normalizer {
+            Some(_) => unwrap_original_key(&value),
+            None => Ok((key.to_vec(), value)),
+        }
+    }
+}
+
+/// A difference between two snapshots reported by `Notus::diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(Vec<u8>),
+    Removed(Vec<u8>),
+    Modified(Vec<u8>),
+}
+
+impl Change {
+    pub fn key(&self) -> &Vec<u8> {
+        match self {
+            Change::Added(key) | Change::Removed(key) | Change::Modified(key) => key,
+        }
+    }
+}
+
+/// A group of writes applied together by `Notus::write_batch`, possibly
+/// spanning several columns. After a crash either the whole batch or none of
+/// it is visible on reopen, and iterators created while it is applied list
+/// either all of its keys or none.
+///
+/// Operations are applied in the order they were added, so when the same key
+/// appears more than once the last operation wins.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.put_cf(DEFAULT_INDEX, key, value)
+    }
+
+    pub fn put_cf(&mut self, column: &str, key: Vec<u8>, value: Vec<u8>) {
+        let key = RawKey::new(column, key).encode();
+        self.ops.push(BatchOp::Put(key, value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.delete_cf(DEFAULT_INDEX, key)
+    }
+
+    pub fn delete_cf(&mut self, column: &str, key: Vec<u8>) {
+        let key = RawKey::new(column, key).encode();
+        self.ops.push(BatchOp::Delete(key));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// A batch staged by `Notus::prepare`, waiting to be committed or aborted.
+pub struct PreparedBatch {
+    store: Arc<DataStore>,
+    id: String,
+}
+
+impl PreparedBatch {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Applies the batch. Fails with `NotusError::BatchNotPrepared` if it
+    /// was committed or aborted since.
+    pub fn commit(self) -> Result<()> {
+        self.store.commit_prepared(&self.id)
+    }
+
+    /// Drops the batch without applying any of it.
+    pub fn abort(self) -> Result<()> {
+        self.store.abort_prepared(&self.id)
     }
 }
 
-impl Drop for Notus {
+/// Returned by `Notus::pin`, lets the store remove replaced files again once
+/// dropped.
+pub struct PinGuard {
+    store: Arc<DataStore>,
+}
+
+impl Drop for PinGuard {
     fn drop(&mut self) {
-        self.dropped.store(true, Ordering::Release);
-        if self.temp {
-            //fs_extra::dir::remove(self.dir.as_path());
+        if let Err(e) = self.store.unpin() {
+            warn!("failed to remove files held back by a pin: {}", e);
+        }
+    }
+}
+
+/// The view of the store handed to the closure of `Notus::atomically`.
+pub struct StoreTxnView<'a> {
+    notus: &'a Notus,
+    locked: &'a LockedStore<'a>,
+    /// Stored value, or `None` for a delete, by index key.
+    writes: RefCell<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl StoreTxnView<'_> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(DEFAULT_INDEX, key)
+    }
+
+    pub fn get_cf(&self, column: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if key.is_empty() {
+            return Ok(None);
+        }
+        let index_key = self.notus.index_key(column, key);
+        let stored = match self.writes.borrow().get(&index_key) {
+            Some(written) => written.clone(),
+            None => self.locked.get(&index_key)?,
+        };
+        match stored {
+            Some(value) => Ok(Some(self.notus.user_value(value)?)),
+            None => Ok(None),
         }
     }
+
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.put_cf(DEFAULT_INDEX, key, value)
+    }
+
+    pub fn put_cf(&self, column: &str, key: Vec<u8>, value: Vec<u8>) {
+        let (key, value) = self.notus.stored_entry(column, key, value);
+        self.writes.borrow_mut().insert(key, Some(value));
+    }
+
+    pub fn delete(&self, key: &[u8]) {
+        self.delete_cf(DEFAULT_INDEX, key)
+    }
+
+    pub fn delete_cf(&self, column: &str, key: &[u8]) {
+        if key.is_empty() {
+            return;
+        }
+        let key = self.notus.index_key(column, key);
+        self.writes.borrow_mut().insert(key, None);
+    }
 }
 
+/// Iterates over the keys of one column. `inner` holds the column qualified
+/// keys, the column prefix is stripped from the keys that are yielded.
 pub struct DBIterator {
     store: Arc<DataStore>,
     inner: Vec<Vec<u8>>,
+    /// Position of the next key yielded by `next`.
     cursor: usize,
+    /// One past the position of the next key yielded by `next_back`. The
+    /// iteration ends once the two meet.
+    back_cursor: usize,
+    prefix_len: usize,
+    cancel: Option<Arc<AtomicBool>>,
+    /// Values carry the key they were written under, see
+    /// `NotusOptions::key_normalizer`.
+    original_keys: bool,
 }
 
 impl DBIterator {
-    fn new(store: Arc<DataStore>) -> Self {
-        let keys = store.keys();
+    fn new(store: Arc<DataStore>, column: &str) -> Self {
+        Self::range(store, column, ..)
+    }
+
+    fn range<R>(store: Arc<DataStore>, column: &str, range : R) -> Self where  R : RangeBounds<Vec<u8>> {
+        let keys = store.range(RawKey::column_range(column, range));
         Self {
             store,
+            back_cursor: keys.len(),
             inner: keys,
             cursor: 0,
+            prefix_len: RawKey::column_prefix(column).len(),
+            cancel: None,
+            original_keys: false,
         }
     }
 
-    fn range<R>(store: Arc<DataStore>, range : R) -> Self where  R : RangeBounds<Vec<u8>> {
-        let keys = store.range(range);
+    fn prefix(store: Arc<DataStore>, column: &str, prefix: &[u8]) -> Self {
+        let keys = store.prefix(&RawKey::new(column, prefix.to_vec()).encode());
         Self {
             store,
+            back_cursor: keys.len(),
             inner: keys,
             cursor: 0,
+            prefix_len: RawKey::column_prefix(column).len(),
+            cancel: None,
+            original_keys: false,
         }
     }
 
-    fn prefix(store: Arc<DataStore>, prefix: &Vec<u8>) -> Self {
-        let keys = store.prefix( prefix);
-        Self {
-            store,
-            inner: keys,
-            cursor: 0,
+    /// Stops the iteration once `cancel` is set, letting another thread end
+    /// a long scan. The flag is checked before every item is read.
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    fn original_keys(mut self, original_keys: bool) -> Self {
+        self.original_keys = original_keys;
+        self
+    }
+
+    fn item(&self, key: &[u8], value: Vec<u8>) -> Result<(Vec<u8>, Vec<u8>)> {
+        if self.original_keys {
+            return unwrap_original_key(&value);
+        }
+        Ok((key[self.prefix_len..].to_vec(), value))
+    }
+
+    fn is_cancelled(&self) -> bool {
+        match &self.cancel {
+            Some(cancel) => cancel.load(Ordering::Acquire),
+            None => false,
         }
     }
 }
@@ -187,49 +2300,37 @@ impl Iterator for DBIterator {
     type Item = Result<(Vec<u8>, Vec<u8>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let key = match self.inner.get(self.cursor) {
-            None => {
+        // Keys deleted since the iterator was made are skipped
+        while self.cursor < self.back_cursor {
+            if self.is_cancelled() {
                 return None;
             }
-            Some(key) => key,
-        };
-        match self.store.get(key) {
-            Ok(Some(value)) => {
-                self.cursor += 1;
-                Some(Ok((key.clone(), value)))
+            let key = &self.inner[self.cursor];
+            self.cursor += 1;
+            match self.store.get(key) {
+                Ok(Some(value)) => return Some(self.item(key, value)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
             }
-            _ => None,
         }
+        None
     }
 }
 
 impl DoubleEndedIterator for DBIterator {
     fn next_back(&mut self) -> Option<Self::Item> {
-        let position = match self.inner.len().checked_sub(1) {
-            None => {
+        while self.back_cursor > self.cursor {
+            if self.is_cancelled() {
                 return None;
             }
-            Some(position) => match position.checked_sub(self.cursor) {
-                None => {
-                    return None;
-                }
-                Some(position) => position,
-            },
-        };
-
-        let key = match self.inner.get(position) {
-            None => {
-                return None;
-            }
-            Some(key) => key,
-        };
-
-        match self.store.get(key) {
-            Ok(Some(value)) => {
-                self.cursor += 1;
-                Some(Ok((key.clone(), value)))
+            self.back_cursor -= 1;
+            let key = &self.inner[self.back_cursor];
+            match self.store.get(key) {
+                Ok(Some(value)) => return Some(self.item(key, value)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
             }
-            _ => None,
         }
+        None
     }
 }
@@ -1,8 +1,9 @@
 use chrono::Utc;
 use crc::{Crc, CRC_32_CKSUM};
-use std::io::Read;
+use std::io::{self, Read};
 pub const CRC_CKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
 use crate::Result;
+use crate::errors::NotusError;
 #[derive(Debug, Clone, PartialOrd, PartialEq)]
 pub struct DataEntry {
     crc: u32,
@@ -11,6 +12,11 @@ pub struct DataEntry {
     value_size: u64,
     key: Vec<u8>,
     value: Vec<u8>,
+    /// Milliseconds since the epoch after which the entry reads as absent.
+    expires_at: Option<i64>,
+    /// How `value` is compressed. Records are decoded as stored, see
+    /// `DataEntry::decompress`.
+    compression: Compression,
 }
 
 pub trait Encoder {
@@ -23,6 +29,24 @@ pub trait Decoder {
         Self: Sized;
 }
 
+/// Fills `buf` from `rdr`. Running out of input before the first byte of a
+/// record is a clean end and fails with `NotusError::UnexpectedEof`; running
+/// out anywhere else means the record was cut short and fails with
+/// `NotusError::CorruptValue`.
+fn read_field<R: Read>(rdr: &mut R, buf: &mut [u8], record_start: bool) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match rdr.read(&mut buf[filled..]) {
+            Ok(0) if record_start && filled == 0 => return Err(NotusError::UnexpectedEof),
+            Ok(0) => return Err(NotusError::CorruptValue),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
 impl Encoder for DataEntry {
     fn encode(&self) -> Vec<u8> {
         let content = self.encode_content();
@@ -46,27 +70,36 @@ impl Decoder for DataEntry {
             value_size: 0,
             key: vec![],
             value: vec![],
+            expires_at: None,
+            compression: Compression::None,
         };
         let mut raw_crc_bytes = [0_u8; 4];
         let mut raw_timestamp_bytes = [0_u8; 8];
         let mut raw_key_size_bytes = [0_u8; 8];
         let mut raw_value_size_bytes = [0_u8; 8];
 
-        rdr.read_exact(&mut raw_crc_bytes)?;
-        rdr.read_exact(&mut raw_timestamp_bytes)?;
-        rdr.read_exact(&mut raw_key_size_bytes)?;
-        rdr.read_exact(&mut raw_value_size_bytes)?;
+        read_field(rdr, &mut raw_crc_bytes, true)?;
+        read_field(rdr, &mut raw_timestamp_bytes, false)?;
+        read_field(rdr, &mut raw_key_size_bytes, false)?;
+        read_field(rdr, &mut raw_value_size_bytes, false)?;
 
         out.crc = u32::from_be_bytes(raw_crc_bytes);
         out.timestamp = i64::from_be_bytes(raw_timestamp_bytes);
-        out.key_size = u64::from_be_bytes(raw_key_size_bytes);
+        let key_size = u64::from_be_bytes(raw_key_size_bytes);
+        out.key_size = key_size & !KEY_SIZE_FLAGS;
         out.value_size = u64::from_be_bytes(raw_value_size_bytes);
+        out.compression = Compression::from_key_size(key_size)?;
 
         let mut raw_key_bytes = vec![0_u8; out.key_size as usize];
         let mut raw_value_bytes = vec![0_u8; out.value_size as usize];
 
-        rdr.read_exact(&mut raw_key_bytes);
-        rdr.read_exact(&mut raw_value_bytes);
+        read_field(rdr, &mut raw_key_bytes, false)?;
+        read_field(rdr, &mut raw_value_bytes, false)?;
+        if key_size & EXPIRES_FLAG != 0 {
+            let mut raw_expires_at_bytes = [0_u8; 8];
+            read_field(rdr, &mut raw_expires_at_bytes, false)?;
+            out.expires_at = Some(i64::from_be_bytes(raw_expires_at_bytes));
+        }
 
         out.key = raw_key_bytes;
         out.value = raw_value_bytes;
@@ -75,7 +108,182 @@ impl Decoder for DataEntry {
     }
 }
 
+/// Size of the crc, timestamp, key size and value size fields that precede
+/// the key and value of an encoded `DataEntry`.
+pub(crate) const DATA_ENTRY_HEADER_SIZE: usize = 28;
+
+/// Set in the key size field of a record written without a checksum. Every
+/// record says whether it has one, so files written with and without can
+/// be mixed and records with a checksum are always verified.
+const UNCHECKED_FLAG: u64 = 1 << 63;
+
+/// Set in the key size field of a record or hint that expires. The expiry
+/// follows the value of the record and the key of the hint, so values are
+/// found at the same offset with or without one.
+const EXPIRES_FLAG: u64 = 1 << 62;
+
+/// The two bits of the key size field of a record or hint holding the
+/// `Compression` of its value, zero for uncompressed values.
+const COMPRESSION_SHIFT: u32 = 60;
+const COMPRESSION_MASK: u64 = 0b11 << COMPRESSION_SHIFT;
+
+/// Every bit of the key size field that is not part of the size.
+const KEY_SIZE_FLAGS: u64 = UNCHECKED_FLAG | EXPIRES_FLAG | COMPRESSION_MASK;
+
+/// Whether an entry expiring at `expires_at` has expired.
+pub fn is_expired(expires_at: Option<i64>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= Utc::now().timestamp_millis())
+}
+
+/// How data records are protected against corruption on disk, see
+/// `NotusOptions::integrity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntegrityAlgo {
+    /// A CRC-32 of each record, verified whenever the record is read.
+    #[default]
+    Crc32,
+    /// No checksum is computed or verified, damaged records are returned as
+    /// they are read.
+    None,
+}
+
+/// How values are compressed in the data files, see
+/// `NotusOptions::compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// Level `Compression::Zstd` compresses at, zstd's own default.
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    fn from_key_size(key_size: u64) -> Result<Self> {
+        match (key_size & COMPRESSION_MASK) >> COMPRESSION_SHIFT {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(NotusError::CorruptValue),
+        }
+    }
+
+    fn key_size_bits(self) -> u64 {
+        let id = match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        };
+        id << COMPRESSION_SHIFT
+    }
+
+    fn compress(self, value: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(value.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(value)),
+            Compression::Zstd => Ok(zstd::bulk::compress(value, ZSTD_LEVEL)?),
+        }
+    }
+
+    /// Decompresses the value of the record of `key`.
+    fn decompress(self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let decompressed = match self {
+            Compression::None => return Ok(value.to_vec()),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(value).map_err(Into::into),
+            Compression::Zstd => zstd::stream::decode_all(value).map_err(Into::into),
+        };
+        decompressed.map_err(|source| NotusError::DecompressionError {
+            key: key.to_vec(),
+            source,
+        })
+    }
+}
+
 impl DataEntry {
+    /// Decodes an entry reading the key and value through `buf`, so callers
+    /// can reuse one allocation across reads. The crc is verified while
+    /// decoding, `NotusError::CorruptValue` is returned on mismatch.
+    pub fn decode_checked<R: Read>(rdr: &mut R, buf: &mut Vec<u8>) -> Result<Self> {
+        let (crc, timestamp, key_size, value_size, expires_at, compression) =
+            Self::read_checked(rdr, buf)?;
+        let (key, value) = buf.split_at(key_size as usize);
+        Ok(Self {
+            crc,
+            timestamp,
+            key_size,
+            value_size,
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at,
+            compression,
+        })
+    }
+
+    /// Reads the key and value of an entry into `buf`, reusing the capacity
+    /// of `buf` instead of allocating, and returns the key size. The crc is
+    /// verified as in `decode_checked`, and unlike there the value is
+    /// decompressed.
+    pub fn read_into<R: Read>(rdr: &mut R, buf: &mut Vec<u8>) -> Result<usize> {
+        let (_, _, key_size, _, _, compression) = Self::read_checked(rdr, buf)?;
+        let key_size = key_size as usize;
+        if compression != Compression::None {
+            let value = compression.decompress(&buf[..key_size], &buf[key_size..])?;
+            buf.truncate(key_size);
+            buf.extend_from_slice(&value);
+        }
+        Ok(key_size)
+    }
+
+    /// Reads the key and value of an entry into `buf` and checks them against
+    /// the crc. Returns the crc, timestamp, key size, value size, expiry and
+    /// compression.
+    #[allow(clippy::type_complexity)]
+    fn read_checked<R: Read>(
+        rdr: &mut R,
+        buf: &mut Vec<u8>,
+    ) -> Result<(u32, i64, u64, u64, Option<i64>, Compression)> {
+        let mut header = [0_u8; DATA_ENTRY_HEADER_SIZE];
+        rdr.read_exact(&mut header)?;
+        let field = |start: usize| {
+            let mut bytes = [0_u8; 8];
+            bytes.copy_from_slice(&header[start..start + 8]);
+            bytes
+        };
+        let crc = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let timestamp = i64::from_be_bytes(field(4));
+        let key_size = u64::from_be_bytes(field(12));
+        let value_size = u64::from_be_bytes(field(20));
+        let checked = key_size & UNCHECKED_FLAG == 0;
+        let expires = key_size & EXPIRES_FLAG != 0;
+        let compression = Compression::from_key_size(key_size)?;
+        let key_size = key_size & !KEY_SIZE_FLAGS;
+
+        buf.clear();
+        buf.resize((key_size + value_size) as usize, 0);
+        rdr.read_exact(buf)?;
+        let mut raw_expires_at_bytes = [0_u8; 8];
+        if expires {
+            rdr.read_exact(&mut raw_expires_at_bytes)?;
+        }
+        let expires_at = expires.then(|| i64::from_be_bytes(raw_expires_at_bytes));
+
+        if !checked {
+            return Ok((crc, timestamp, key_size, value_size, expires_at, compression));
+        }
+        let mut digest = CRC_CKSUM.digest();
+        digest.update(&header[4..]);
+        digest.update(buf);
+        if expires {
+            digest.update(&raw_expires_at_bytes);
+        }
+        if digest.finalize() != crc {
+            return Err(NotusError::CorruptValue);
+        }
+        Ok((crc, timestamp, key_size, value_size, expires_at, compression))
+    }
+
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
         let timestamp = Utc::now().timestamp();
         let key_size = key.len() as u64;
@@ -88,6 +296,78 @@ impl DataEntry {
             value_size,
             key,
             value,
+            expires_at: None,
+            compression: Compression::None,
+        }
+    }
+
+    /// Makes the entry read as absent once `expires_at`, in milliseconds
+    /// since the epoch, has passed. `None` keeps it until it is overwritten
+    /// or deleted.
+    pub fn with_expiry(mut self, expires_at: Option<i64>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn with_timestamp(key: Vec<u8>, value: Vec<u8>, timestamp: i64) -> Self {
+        let mut entry = Self::new(key, value);
+        entry.timestamp = timestamp;
+        entry
+    }
+
+    /// Returns the entry with its value compressed by `compression`. Values
+    /// that do not get smaller and entries that are already compressed are
+    /// returned as they are.
+    pub fn compressed(&self, compression: Compression) -> Result<Self> {
+        if compression == Compression::None || self.compression != Compression::None {
+            return Ok(self.clone());
+        }
+        let value = compression.compress(&self.value)?;
+        if value.len() >= self.value.len() {
+            return Ok(self.clone());
+        }
+        Ok(Self {
+            value_size: value.len() as u64,
+            value,
+            compression,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the entry with its original value, undoing `compressed`.
+    /// Fails with `NotusError::DecompressionError` if the value can not be
+    /// decompressed.
+    pub fn decompress(self) -> Result<Self> {
+        if self.compression == Compression::None {
+            return Ok(self);
+        }
+        let value = self.compression.decompress(&self.key, &self.value)?;
+        Ok(Self {
+            value_size: value.len() as u64,
+            value,
+            compression: Compression::None,
+            ..self
+        })
+    }
+
+    /// Encodes the entry protected by `integrity`. `IntegrityAlgo::Crc32` is
+    /// the same as `encode`.
+    pub fn encode_with(&self, integrity: IntegrityAlgo) -> Vec<u8> {
+        match integrity {
+            IntegrityAlgo::Crc32 => self.encode(),
+            IntegrityAlgo::None => {
+                let mut buf = Vec::with_capacity(DATA_ENTRY_HEADER_SIZE + self.key.len() + self.value.len());
+                buf.extend_from_slice(&0_u32.to_be_bytes());
+                buf.extend_from_slice(&self.timestamp.to_be_bytes());
+                buf.extend_from_slice(&(self.flagged_key_size() | UNCHECKED_FLAG).to_be_bytes());
+                buf.extend_from_slice(&self.value_size.to_be_bytes());
+                buf.extend_from_slice(&self.key);
+                buf.extend_from_slice(&self.value);
+                if let Some(expires_at) = self.expires_at {
+                    buf.extend_from_slice(&expires_at.to_be_bytes());
+                }
+                buf
+            }
         }
     }
 
@@ -98,19 +378,61 @@ impl DataEntry {
     fn encode_content(&self) -> Vec<u8> {
         let mut buf = vec![];
         buf.extend_from_slice(&self.timestamp.to_be_bytes());
-        buf.extend_from_slice(&self.key_size.to_be_bytes());
+        buf.extend_from_slice(&self.flagged_key_size().to_be_bytes());
         buf.extend_from_slice(&self.value_size.to_be_bytes());
         buf.extend_from_slice(&self.key);
         buf.extend_from_slice(&self.value);
+        if let Some(expires_at) = self.expires_at {
+            buf.extend_from_slice(&expires_at.to_be_bytes());
+        }
         buf
     }
 
+    /// The key size field, telling whether an expiry follows the value and
+    /// how the value is compressed.
+    fn flagged_key_size(&self) -> u64 {
+        let key_size = self.key_size | self.compression.key_size_bits();
+        match self.expires_at {
+            Some(_) => key_size | EXPIRES_FLAG,
+            None => key_size,
+        }
+    }
+
+    /// Size of the encoded entry.
+    pub fn record_size(&self) -> u64 {
+        record_size(self.key_size, self.value_size, self.expires_at)
+    }
+
     pub fn key(&self) -> Vec<u8> {
         self.key.to_owned()
     }
     pub fn value(&self) -> Vec<u8> {
         self.value.to_owned()
     }
+    pub fn value_slice(&self) -> &[u8] {
+        &self.value
+    }
+    pub fn key_slice(&self) -> &[u8] {
+        &self.key
+    }
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+    pub fn value_size(&self) -> u64 {
+        self.value_size
+    }
+    pub fn expires_at(&self) -> Option<i64> {
+        self.expires_at
+    }
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
+
+/// Size of an encoded data record with a key and value of these sizes.
+pub(crate) fn record_size(key_size: u64, value_size: u64, expires_at: Option<i64>) -> u64 {
+    let expiry_size = if expires_at.is_some() { 8 } else { 0 };
+    DATA_ENTRY_HEADER_SIZE as u64 + key_size + value_size + expiry_size
 }
 
 pub struct HintEntry {
@@ -119,6 +441,8 @@ pub struct HintEntry {
     value_size: u64,
     data_entry_position: u64,
     key: Vec<u8>,
+    expires_at: Option<i64>,
+    compression: Compression,
 }
 
 impl HintEntry {
@@ -129,6 +453,8 @@ impl HintEntry {
             value_size: entry.value_size,
             data_entry_position: position,
             key: entry.key.clone(),
+            expires_at: entry.expires_at,
+            compression: entry.compression,
         }
     }
     pub fn tombstone(key: Vec<u8>) -> Self {
@@ -138,6 +464,8 @@ impl HintEntry {
             value_size: 0,
             data_entry_position: 0,
             key,
+            expires_at: None,
+            compression: Compression::None,
         }
     }
     pub fn data_entry_position(&self) -> u64 {
@@ -160,16 +488,35 @@ impl HintEntry {
     pub fn key(&self) -> Vec<u8> {
         self.key.to_owned()
     }
+    pub fn expires_at(&self) -> Option<i64> {
+        self.expires_at
+    }
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Size of the data record the hint points at.
+    pub fn record_size(&self) -> u64 {
+        record_size(self.key_size, self.value_size, self.expires_at)
+    }
 }
 
 impl Encoder for HintEntry {
     fn encode(&self) -> Vec<u8> {
+        let key_size = self.key_size | self.compression.key_size_bits();
+        let key_size = match self.expires_at {
+            Some(_) => key_size | EXPIRES_FLAG,
+            None => key_size,
+        };
         let mut buf = vec![];
         buf.extend_from_slice(&self.timestamp.to_be_bytes());
-        buf.extend_from_slice(&self.key_size.to_be_bytes());
+        buf.extend_from_slice(&key_size.to_be_bytes());
         buf.extend_from_slice(&self.value_size.to_be_bytes());
         buf.extend_from_slice(&self.data_entry_position.to_be_bytes());
         buf.extend_from_slice(&self.key);
+        if let Some(expires_at) = self.expires_at {
+            buf.extend_from_slice(&expires_at.to_be_bytes());
+        }
         buf
     }
 }
@@ -185,6 +532,8 @@ impl Decoder for HintEntry {
             value_size: 0,
             data_entry_position: 0,
             key: vec![],
+            expires_at: None,
+            compression: Compression::None,
         };
 
         let mut raw_timestamp_bytes = [0_u8; 8];
@@ -192,27 +541,75 @@ impl Decoder for HintEntry {
         let mut raw_value_size_bytes = [0_u8; 8];
         let mut raw_data_entry_pos_size_bytes = [0_u8; 8];
 
-        rdr.read_exact(&mut raw_timestamp_bytes)?;
-        rdr.read_exact(&mut raw_key_size_bytes)?;
-        rdr.read_exact(&mut raw_value_size_bytes)?;
-        rdr.read_exact(&mut raw_data_entry_pos_size_bytes)?;
+        read_field(rdr, &mut raw_timestamp_bytes, true)?;
+        read_field(rdr, &mut raw_key_size_bytes, false)?;
+        read_field(rdr, &mut raw_value_size_bytes, false)?;
+        read_field(rdr, &mut raw_data_entry_pos_size_bytes, false)?;
 
+        let key_size = u64::from_be_bytes(raw_key_size_bytes);
         out.timestamp = i64::from_be_bytes(raw_timestamp_bytes);
-        out.key_size = u64::from_be_bytes(raw_key_size_bytes);
+        out.key_size = key_size & !(EXPIRES_FLAG | COMPRESSION_MASK);
+        out.compression = Compression::from_key_size(key_size)?;
         out.value_size = u64::from_be_bytes(raw_value_size_bytes);
         out.data_entry_position = u64::from_be_bytes(raw_data_entry_pos_size_bytes);
 
         let mut raw_key_bytes = vec![0_u8; out.key_size as usize];
-        rdr.read_exact(&mut raw_key_bytes);
+        read_field(rdr, &mut raw_key_bytes, false)?;
         out.key = raw_key_bytes;
+        if key_size & EXPIRES_FLAG != 0 {
+            let mut raw_expires_at_bytes = [0_u8; 8];
+            read_field(rdr, &mut raw_expires_at_bytes, false)?;
+            out.expires_at = Some(i64::from_be_bytes(raw_expires_at_bytes));
+        }
 
         Ok(out)
     }
 }
 
+/// Where a value kept in the value log lives, stored in the data file in
+/// place of the value when `NotusOptions::kv_separation` is set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValuePointer {
+    pub file_id: String,
+    pub position: u64,
+    pub value_size: u64,
+}
+
+impl Encoder for ValuePointer {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&self.position.to_be_bytes());
+        buf.extend_from_slice(&self.value_size.to_be_bytes());
+        buf.extend_from_slice(self.file_id.as_bytes());
+        buf
+    }
+}
+
+impl Decoder for ValuePointer {
+    fn decode<R: Read>(rdr: &mut R) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut raw_position_bytes = [0_u8; 8];
+        let mut raw_value_size_bytes = [0_u8; 8];
+        read_field(rdr, &mut raw_position_bytes, false)?;
+        read_field(rdr, &mut raw_value_size_bytes, false)?;
+        let mut raw_file_id_bytes = vec![];
+        rdr.read_to_end(&mut raw_file_id_bytes)?;
+        Ok(Self {
+            file_id: String::from_utf8(raw_file_id_bytes)?,
+            position: u64::from_be_bytes(raw_position_bytes),
+            value_size: u64::from_be_bytes(raw_value_size_bytes),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::schema::{DataEntry, Decoder, Encoder};
+    use crate::errors::NotusError;
+    use crate::schema::{
+        Compression, DataEntry, Decoder, Encoder, HintEntry, IntegrityAlgo, ValuePointer, CRC_CKSUM,
+    };
     use std::io::Cursor;
 
     #[test]
@@ -223,4 +620,196 @@ mod tests {
         println!("{:#?}", d);
         println!("{}", d.check_crc())
     }
+
+    #[test]
+    fn decode_clean_eof() {
+        let rec = DataEntry::new(vec![1, 2, 3], vec![4, 5, 6]);
+        let mut rdr = Cursor::new(rec.encode());
+        let decoded = DataEntry::decode(&mut rdr).unwrap();
+        assert_eq!((decoded.key(), decoded.value()), (rec.key(), rec.value()));
+        assert!(matches!(
+            DataEntry::decode(&mut rdr),
+            Err(NotusError::UnexpectedEof)
+        ));
+
+        let hint = HintEntry::from(&rec, 0);
+        let mut rdr = Cursor::new(hint.encode());
+        assert!(HintEntry::decode(&mut rdr).is_ok());
+        assert!(matches!(
+            HintEntry::decode(&mut rdr),
+            Err(NotusError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn decode_empty_value() {
+        let rec = DataEntry::new(vec![1, 2, 3], vec![]);
+        let decoded = DataEntry::decode_checked(&mut Cursor::new(rec.encode()), &mut vec![]).unwrap();
+        assert!(decoded.check_crc());
+        assert_eq!(decoded.value_size(), 0);
+        assert_eq!(decoded.value(), Vec::<u8>::new());
+
+        // An empty value at the start of a file is not a tombstone
+        let hint = HintEntry::from(&rec, 0);
+        let decoded = HintEntry::decode(&mut Cursor::new(hint.encode())).unwrap();
+        assert!(!decoded.is_deleted());
+        assert!(HintEntry::tombstone(vec![1, 2, 3]).is_deleted());
+    }
+
+    #[test]
+    fn decode_truncated_record() {
+        let rec = DataEntry::new(vec![1, 2, 3], vec![4, 5, 6]);
+        let encoded = rec.encode();
+        // Cut inside the header and inside the value
+        for len in [2, encoded.len() - 1] {
+            let mut rdr = Cursor::new(encoded[..len].to_vec());
+            assert!(matches!(
+                DataEntry::decode(&mut rdr),
+                Err(NotusError::CorruptValue)
+            ));
+        }
+
+        let hint = HintEntry::from(&rec, 0).encode();
+        let mut rdr = Cursor::new(hint[..hint.len() - 1].to_vec());
+        assert!(matches!(
+            HintEntry::decode(&mut rdr),
+            Err(NotusError::CorruptValue)
+        ));
+    }
+
+    #[test]
+    fn decode_value_pointer() {
+        let pointer = ValuePointer {
+            file_id: "1650000000000000000".to_string(),
+            position: 42,
+            value_size: 7,
+        };
+        let decoded = ValuePointer::decode(&mut Cursor::new(pointer.encode())).unwrap();
+        assert_eq!(decoded, pointer);
+        assert!(matches!(
+            ValuePointer::decode(&mut Cursor::new(vec![0_u8; 10])),
+            Err(NotusError::CorruptValue)
+        ));
+    }
+
+    #[test]
+    fn decode_unchecked_record() {
+        let rec = DataEntry::new(vec![1, 2, 3], vec![4, 5, 6]);
+        assert_eq!(rec.encode_with(IntegrityAlgo::Crc32), rec.encode());
+
+        let mut encoded = rec.encode_with(IntegrityAlgo::None);
+        assert_eq!(encoded.len(), rec.encode().len());
+        let decoded = DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]).unwrap();
+        assert_eq!((decoded.key(), decoded.value()), (rec.key(), rec.value()));
+        let decoded = DataEntry::decode(&mut Cursor::new(&encoded)).unwrap();
+        assert_eq!((decoded.key(), decoded.value()), (rec.key(), rec.value()));
+
+        // Damage goes unnoticed without a crc, but not with one
+        *encoded.last_mut().unwrap() = 9;
+        let decoded = DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]).unwrap();
+        assert_eq!(decoded.value(), vec![4, 5, 9]);
+        let mut encoded = rec.encode();
+        *encoded.last_mut().unwrap() = 9;
+        assert!(matches!(
+            DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]),
+            Err(NotusError::CorruptValue)
+        ));
+    }
+
+    #[test]
+    fn decode_expiring_record() {
+        let rec = DataEntry::new(vec![1, 2, 3], vec![4, 5, 6]).with_expiry(Some(1_000));
+        for integrity in [IntegrityAlgo::Crc32, IntegrityAlgo::None] {
+            let encoded = rec.encode_with(integrity);
+            assert_eq!(encoded.len() as u64, rec.record_size());
+            let decoded = DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]).unwrap();
+            assert_eq!(decoded.key(), rec.key());
+            assert_eq!(decoded.value(), rec.value());
+            assert_eq!(decoded.expires_at(), Some(1_000));
+            let decoded = DataEntry::decode(&mut Cursor::new(&encoded)).unwrap();
+            assert_eq!(decoded.expires_at(), Some(1_000));
+        }
+
+        let hint = HintEntry::from(&rec, 7).encode();
+        let decoded = HintEntry::decode(&mut Cursor::new(hint)).unwrap();
+        assert_eq!(decoded.key(), rec.key());
+        assert_eq!(decoded.key_size(), 3);
+        assert_eq!(decoded.expires_at(), Some(1_000));
+        assert_eq!(decoded.record_size(), rec.record_size());
+
+        // The expiry is covered by the crc
+        let mut encoded = rec.encode();
+        *encoded.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]),
+            Err(NotusError::CorruptValue)
+        ));
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        // xorshift, so the random value is the same on every run
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let json = br#"{"name":"notus","tags":["a","b","c"],"count":42}"#.repeat(100);
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            for value in [vec![], random.clone(), json.clone()] {
+                let rec = DataEntry::new(vec![1, 2, 3], value.clone())
+                    .compressed(compression)
+                    .unwrap();
+                let encoded = rec.encode();
+                assert_eq!(encoded.len() as u64, rec.record_size());
+                let decoded = DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]).unwrap();
+                assert_eq!(decoded.compression(), rec.compression());
+                assert_eq!(decoded.record_size(), rec.record_size());
+                assert_eq!(decoded.decompress().unwrap().value(), value);
+                let decoded = DataEntry::decode(&mut Cursor::new(&encoded)).unwrap();
+                assert_eq!(decoded.decompress().unwrap().value(), value);
+                let mut buf = vec![];
+                let key_size = DataEntry::read_into(&mut Cursor::new(&encoded), &mut buf).unwrap();
+                assert_eq!(&buf[key_size..], value.as_slice());
+
+                let hint = HintEntry::decode(&mut Cursor::new(HintEntry::from(&rec, 0).encode())).unwrap();
+                assert_eq!(hint.compression(), rec.compression());
+                assert_eq!(hint.key_size(), 3);
+            }
+
+            // Only values that get smaller are stored compressed
+            let rec = DataEntry::new(vec![1], json.clone()).compressed(compression).unwrap();
+            assert_eq!(rec.compression(), compression);
+            assert_eq!(rec.value_size() < json.len() as u64, compression != Compression::None);
+            let rec = DataEntry::new(vec![1], random.clone()).compressed(compression).unwrap();
+            assert_eq!(rec.compression(), Compression::None);
+            assert_eq!(rec.value(), random);
+        }
+
+        // The crc covers the value as stored
+        let rec = DataEntry::new(vec![1], json.clone()).compressed(Compression::Zstd).unwrap();
+        let mut encoded = rec.encode();
+        let crc = u32::from_be_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]);
+        assert_eq!(crc, CRC_CKSUM.checksum(&encoded[4..]));
+        *encoded.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]),
+            Err(NotusError::CorruptValue)
+        ));
+
+        // Without a crc the damage shows when decompressing
+        let mut encoded = rec.encode_with(IntegrityAlgo::None);
+        let value_start = encoded.len() - rec.value_size() as usize;
+        encoded[value_start..].iter_mut().for_each(|byte| *byte = 0xff);
+        let decoded = DataEntry::decode_checked(&mut Cursor::new(&encoded), &mut vec![]).unwrap();
+        match decoded.decompress() {
+            Err(NotusError::DecompressionError { key, .. }) => assert_eq!(key, vec![1]),
+            other => panic!("expected a decompression error, got {:?}", other),
+        }
+    }
 }
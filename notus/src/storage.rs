@@ -0,0 +1,428 @@
+use crate::errors::NotusError;
+use crate::Result;
+use fs2::FileExt;
+use fs_extra::dir::DirOptions;
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const LOCK_FILE_NAME: &str = "nutos.lock";
+const DATA_FILE_SUFFIX: &str = ".data";
+
+/// The place a store keeps its data and hint files. Files are only ever
+/// appended to, read at a position, truncated or removed as a whole, which
+/// lets a store live on something other than a local directory, e.g. an
+/// object store.
+pub trait Storage: Send + Sync {
+    /// Creates an empty file, failing if `name` already exists.
+    fn create(&self, name: &str) -> Result<()>;
+
+    /// Opens an existing file for reading.
+    fn open(&self, name: &str) -> Result<Box<dyn StorageFile>>;
+
+    /// Opens an existing file that is going to be appended to.
+    fn open_append(&self, name: &str) -> Result<Box<dyn StorageFile>> {
+        self.open(name)
+    }
+
+    /// Lists the names of every file in the store.
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// Removes `name`, removing a file that does not exist is not an error.
+    fn remove(&self, name: &str) -> Result<()>;
+
+    /// Takes exclusive ownership of the store. Called once when the store is
+    /// opened and released with `unlock` when it is dropped.
+    fn lock(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A file handed out by a `Storage`.
+pub trait StorageFile: Send + Sync {
+    /// Appends `bytes` to the end of the file, returning the position they
+    /// were written at.
+    fn append(&self, bytes: &[u8]) -> Result<u64>;
+
+    /// Fills `buf` with the bytes starting at `position`. Reading past the end
+    /// of the file fails with an `UnexpectedEof` io error.
+    fn read_at(&self, position: u64, buf: &mut [u8]) -> Result<()>;
+
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Drops the content of the file.
+    fn truncate(&self) -> Result<()>;
+
+    /// Cuts the file down to its first `len` bytes, leaving shorter files
+    /// as they are. The default rewrites the kept bytes after `truncate`,
+    /// so a crash in between loses them.
+    fn set_len(&self, len: u64) -> Result<()> {
+        if len >= self.len()? {
+            return Ok(());
+        }
+        let mut kept = vec![0_u8; len as usize];
+        self.read_at(0, &mut kept)?;
+        self.truncate()?;
+        self.append(&kept)?;
+        Ok(())
+    }
+
+    /// Waits until everything appended is durable.
+    fn sync(&self) -> Result<()>;
+
+    /// Starts writing appended bytes back without waiting for them.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once the file will no longer be appended to.
+    fn close(&self) -> Result<()> {
+        self.sync()
+    }
+}
+
+/// The default `Storage`, keeping every file in a local directory guarded by
+/// a `nutos.lock` file.
+pub struct LocalStorage {
+    dir: PathBuf,
+    /// `None` when opened with `read_only`.
+    lock_file: Option<File>,
+    mmap_chunk_size: Option<u64>,
+}
+
+impl LocalStorage {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs_extra::dir::create_all(dir.as_ref(), false)?;
+        // The lock file is never recreated here: removing it while another
+        // process holds a lock on it would let two processes open the store
+        let lock_path = dir.as_ref().join(LOCK_FILE_NAME);
+        let lock_file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| NotusError::LockUnavailable(String::from(lock_path.to_string_lossy()), e))?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            lock_file: Some(lock_file),
+            mmap_chunk_size: None,
+        })
+    }
+
+    /// Opens an existing directory for reading only. Nothing is created,
+    /// the store can not be locked and every change to the files fails, so
+    /// it can be read while another process has it open.
+    pub fn read_only<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        if !dir.as_ref().is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", dir.as_ref().to_string_lossy()),
+            )
+            .into());
+        }
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            lock_file: None,
+            mmap_chunk_size: None,
+        })
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match self.lock_file {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is opened read only", self.dir.to_string_lossy()),
+            )
+            .into()),
+        }
+    }
+
+    /// Memory-maps data files opened for appending, growing the mapping by
+    /// `chunk_size` bytes whenever an append does not fit.
+    pub fn with_mmap(mut self, chunk_size: u64) -> Self {
+        self.mmap_chunk_size = Some(chunk_size);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+}
+
+impl Storage for LocalStorage {
+    fn create(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(self.dir.join(name))?;
+        Ok(())
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn StorageFile>> {
+        let file = File::open(self.dir.join(name))?;
+        Ok(Box::new(LocalFile::new(file)))
+    }
+
+    fn open_append(&self, name: &str) -> Result<Box<dyn StorageFile>> {
+        self.check_writable()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.dir.join(name))?;
+        match self.mmap_chunk_size {
+            Some(chunk_size) if name.ends_with(DATA_FILE_SUFFIX) => {
+                Ok(Box::new(LocalFile::mapped(file, chunk_size)?))
+            }
+            _ => Ok(Box::new(LocalFile::new(file))),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut option = DirOptions::new();
+        option.depth = 1;
+        let dir_content = fs_extra::dir::get_dir_content2(&self.dir, &option)?;
+        Ok(dir_content
+            .files
+            .iter()
+            .filter_map(|file| Path::new(file).file_name())
+            .map(|name| String::from(name.to_string_lossy()))
+            .filter(|name| name != LOCK_FILE_NAME)
+            .collect())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        match std::fs::remove_file(self.dir.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn lock(&self) -> Result<()> {
+        self.lock_file
+            .as_ref()
+            .ok_or(())
+            .and_then(|lock_file| lock_file.lock_exclusive().map_err(|_| ()))
+            .map_err(|_| NotusError::LockFailed(String::from(self.dir.to_string_lossy())))
+    }
+
+    fn unlock(&self) -> Result<()> {
+        if let Some(lock_file) = &self.lock_file {
+            lock_file.unlock()?;
+        }
+        Ok(())
+    }
+}
+
+struct LocalFile {
+    file: Mutex<File>,
+    mapped: Option<MappedDataFile>,
+}
+
+impl LocalFile {
+    fn new(file: File) -> Self {
+        Self {
+            file: Mutex::new(file),
+            mapped: None,
+        }
+    }
+
+    fn mapped(file: File, chunk_size: u64) -> Result<Self> {
+        let mapped = MappedDataFile::new(&file, chunk_size)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            mapped: Some(mapped),
+        })
+    }
+
+    fn file(&self) -> Result<std::sync::MutexGuard<'_, File>> {
+        self.file
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))
+    }
+}
+
+impl StorageFile for LocalFile {
+    fn append(&self, bytes: &[u8]) -> Result<u64> {
+        let mut file = self.file()?;
+        match &self.mapped {
+            Some(mapped) => mapped.append(&file, bytes),
+            None => {
+                let position = file.seek(SeekFrom::End(0))?;
+                file.write_all(bytes)?;
+                Ok(position)
+            }
+        }
+    }
+
+    fn read_at(&self, position: u64, buf: &mut [u8]) -> Result<()> {
+        if let Some(mapped) = &self.mapped {
+            return mapped.read_at(position, buf);
+        }
+        let mut file = self.file()?;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        match &self.mapped {
+            Some(mapped) => Ok(mapped.len.load(Ordering::Acquire)),
+            None => Ok(self.file()?.metadata()?.len()),
+        }
+    }
+
+    fn truncate(&self) -> Result<()> {
+        match &self.mapped {
+            Some(mapped) => mapped.reset(),
+            None => {
+                self.file()?.set_len(0)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        match &self.mapped {
+            Some(mapped) => mapped.shrink(len),
+            None => {
+                let file = self.file()?;
+                if len < file.metadata()?.len() {
+                    file.set_len(len)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        if let Some(mapped) = &self.mapped {
+            mapped.flush(true)?;
+        }
+        self.file()?.sync_all()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        if let Some(mapped) = &self.mapped {
+            mapped.flush(false)?;
+        }
+        Ok(())
+    }
+
+    fn close(&self) -> Result<()> {
+        self.sync()?;
+        if let Some(mapped) = &self.mapped {
+            mapped.close(&*self.file()?)?;
+        }
+        Ok(())
+    }
+}
+
+/// A data file preallocated in `chunk_size` steps and appended to through a
+/// memory map. `len` is the logical end of the file; everything past it is
+/// preallocated space that is truncated away when the file is closed.
+struct MappedDataFile {
+    map: Mutex<MmapMut>,
+    len: AtomicU64,
+    chunk_size: u64,
+}
+
+impl MappedDataFile {
+    fn new(file: &File, chunk_size: u64) -> Result<Self> {
+        let chunk_size = chunk_size.max(1);
+        let len = file.metadata()?.len();
+        file.set_len(len + chunk_size)?;
+        let map = unsafe { MmapMut::map_mut(file)? };
+        Ok(Self {
+            map: Mutex::new(map),
+            len: AtomicU64::new(len),
+            chunk_size,
+        })
+    }
+
+    fn append(&self, file: &File, bytes: &[u8]) -> Result<u64> {
+        let mut map = self
+            .map
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let position = self.len.load(Ordering::Acquire);
+        let end = position + bytes.len() as u64;
+        if end > map.len() as u64 {
+            // Preallocated region is full, grow the file and remap it
+            map.flush()?;
+            let mut capacity = map.len() as u64;
+            while capacity < end {
+                capacity += self.chunk_size;
+            }
+            file.set_len(capacity)?;
+            *map = unsafe { MmapMut::map_mut(file)? };
+        }
+        map[position as usize..end as usize].copy_from_slice(bytes);
+        self.len.store(end, Ordering::Release);
+        Ok(position)
+    }
+
+    fn read_at(&self, position: u64, buf: &mut [u8]) -> Result<()> {
+        let map = self
+            .map
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        let end = position + buf.len() as u64;
+        if end > self.len.load(Ordering::Acquire) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        buf.copy_from_slice(&map[position as usize..end as usize]);
+        Ok(())
+    }
+
+    fn flush(&self, sync: bool) -> Result<()> {
+        let map = self
+            .map
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        if sync {
+            map.flush()?;
+        } else {
+            map.flush_async()?;
+        }
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<()> {
+        let _map = self
+            .map
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        self.len.store(0, Ordering::Release);
+        Ok(())
+    }
+
+    fn shrink(&self, len: u64) -> Result<()> {
+        let _map = self
+            .map
+            .lock()
+            .map_err(|e| NotusError::RWLockPoisonError(format!("{}", e)))?;
+        self.len.fetch_min(len, Ordering::AcqRel);
+        Ok(())
+    }
+
+    fn close(&self, file: &File) -> Result<()> {
+        file.set_len(self.len.load(Ordering::Acquire))?;
+        Ok(())
+    }
+}
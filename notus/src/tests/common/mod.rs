@@ -39,3 +39,160 @@ pub fn cleanup(dir: &str) {
         std::fs::remove_dir_all(dir).unwrap();
     }
 }
+
+/// A `Storage` keeping every file in memory, used to run the store without
+/// touching the file system.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: std::sync::Mutex<std::collections::BTreeMap<String, MemoryFile>>,
+    /// Makes every append fail while set, to simulate a failing disk.
+    pub fail_appends: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// Milliseconds every read waits before returning, to simulate slow
+    /// storage.
+    pub read_delay: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    /// Bytes all files may hold together, `0` for no limit. Appends that
+    /// would go past it fail, to simulate a full disk.
+    pub capacity: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    used: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    syncs: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryFile {
+    bytes: std::sync::Arc<std::sync::RwLock<Vec<u8>>>,
+    fail_appends: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    read_delay: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    bytes_read: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    capacity: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    used: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    syncs: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl MemoryStorage {
+    fn file(&self, name: &str) -> crate::Result<MemoryFile> {
+        let files = self.files.lock().unwrap();
+        match files.get(name) {
+            Some(file) => Ok(file.clone()),
+            None => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    /// Bytes read so far from the files whose name ends with `suffix`.
+    pub fn bytes_read(&self, suffix: &str) -> u64 {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .filter(|(name, _)| name.ends_with(suffix))
+            .map(|(_, file)| file.bytes_read.load(std::sync::atomic::Ordering::Acquire))
+            .sum()
+    }
+
+    /// Bytes all files hold together.
+    pub fn used(&self) -> u64 {
+        self.used.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Number of times any file was synced.
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(std::sync::atomic::Ordering::Acquire)
+    }
+}
+
+impl crate::storage::Storage for MemoryStorage {
+    fn create(&self, name: &str) -> crate::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(name) {
+            return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+        }
+        let file = MemoryFile {
+            fail_appends: self.fail_appends.clone(),
+            read_delay: self.read_delay.clone(),
+            capacity: self.capacity.clone(),
+            used: self.used.clone(),
+            syncs: self.syncs.clone(),
+            ..MemoryFile::default()
+        };
+        files.insert(name.to_string(), file);
+        Ok(())
+    }
+
+    fn open(&self, name: &str) -> crate::Result<Box<dyn crate::storage::StorageFile>> {
+        Ok(Box::new(self.file(name)?))
+    }
+
+    fn list(&self) -> crate::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn remove(&self, name: &str) -> crate::Result<()> {
+        if let Some(file) = self.files.lock().unwrap().remove(name) {
+            crate::storage::StorageFile::truncate(&file)?;
+        }
+        Ok(())
+    }
+}
+
+impl crate::storage::StorageFile for MemoryFile {
+    fn append(&self, bytes: &[u8]) -> crate::Result<u64> {
+        if self.fail_appends.load(std::sync::atomic::Ordering::Acquire) {
+            return Err(std::io::Error::other("append failed").into());
+        }
+        let capacity = self.capacity.load(std::sync::atomic::Ordering::Acquire);
+        let used = self
+            .used
+            .fetch_add(bytes.len() as u64, std::sync::atomic::Ordering::AcqRel);
+        if capacity > 0 && used + bytes.len() as u64 > capacity {
+            self.used
+                .fetch_sub(bytes.len() as u64, std::sync::atomic::Ordering::AcqRel);
+            return Err(std::io::Error::other("no space left").into());
+        }
+        let mut content = self.bytes.write().unwrap();
+        let position = content.len() as u64;
+        content.extend_from_slice(bytes);
+        Ok(position)
+    }
+
+    fn read_at(&self, position: u64, buf: &mut [u8]) -> crate::Result<()> {
+        let delay = self.read_delay.load(std::sync::atomic::Ordering::Acquire);
+        std::thread::sleep(std::time::Duration::from_millis(delay));
+        let content = self.bytes.read().unwrap();
+        let start = position as usize;
+        match content.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                self.bytes_read
+                    .fetch_add(bytes.len() as u64, std::sync::atomic::Ordering::AcqRel);
+                Ok(())
+            }
+            None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    fn len(&self) -> crate::Result<u64> {
+        Ok(self.bytes.read().unwrap().len() as u64)
+    }
+
+    fn truncate(&self) -> crate::Result<()> {
+        let mut content = self.bytes.write().unwrap();
+        self.used
+            .fetch_sub(content.len() as u64, std::sync::atomic::Ordering::AcqRel);
+        content.clear();
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> crate::Result<()> {
+        let mut content = self.bytes.write().unwrap();
+        let len = (len as usize).min(content.len());
+        self.used.fetch_sub(
+            (content.len() - len) as u64,
+            std::sync::atomic::Ordering::AcqRel,
+        );
+        content.truncate(len);
+        Ok(())
+    }
+
+    fn sync(&self) -> crate::Result<()> {
+        self.syncs.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+        Ok(())
+    }
+}
@@ -1,8 +1,17 @@
 mod common;
 
-use crate::nutos::Notus;
+use crate::datastore::{ColumnStats, DataStore, Encoding, RawKey, DEFAULT_INDEX};
+use crate::schema::{Compression, DataEntry, Encoder, IntegrityAlgo};
+use crate::errors::NotusError;
+use crate::nutos::{
+    AdaptiveCompaction, AutoPolicy, Change, CompactOptions, EntryMeta, EvictionPolicy, KeyState,
+    Notus, NotusOptions, Schedule, WriteBatch, TIMED_READERS_STARTED, WORKERS_STARTED,
+};
+use crate::storage::Storage;
+use common::MemoryStorage;
 use log::{debug, warn};
 use std::alloc::Global;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 const N_THREADS: usize = 10;
@@ -65,6 +74,113 @@ fn monotonic_inserts() {
     }
 }
 
+#[test]
+fn test_double_ended_iteration() {
+    clean_up("_test_double_ended_iteration");
+    let db = Notus::temp("./testdir/_test_double_ended_iteration").unwrap();
+    let keys: Vec<Vec<u8>> = (0..1024_u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    for key in keys.iter() {
+        db.put(key.clone(), key.clone()).unwrap();
+    }
+
+    // Pulling from both ends alternately yields every key once
+    let mut iter = db.iter();
+    let mut front = vec![];
+    let mut back = vec![];
+    while let Some(item) = iter.next() {
+        front.push(item.unwrap().0);
+        match iter.next_back() {
+            Some(item) => back.push(item.unwrap().0),
+            None => break,
+        }
+    }
+    assert!(iter.next().is_none());
+    assert!(iter.next_back().is_none());
+    assert_eq!(front.len() + back.len(), keys.len());
+    back.reverse();
+    front.extend(back);
+    assert_eq!(front, keys);
+
+    let forward: Vec<_> = db
+        .range(keys[100].clone()..keys[900].clone())
+        .map(|item| item.unwrap())
+        .collect();
+    let mut reversed: Vec<_> = db
+        .range(keys[100].clone()..keys[900].clone())
+        .rev()
+        .map(|item| item.unwrap())
+        .collect();
+    reversed.reverse();
+    assert_eq!(forward.len(), 800);
+    assert_eq!(reversed, forward);
+}
+
+#[test]
+fn test_iter_skips_deleted_keys() {
+    clean_up("_test_iter_skips_deleted_keys");
+    let db = Notus::temp("./testdir/_test_iter_skips_deleted_keys").unwrap();
+    let keys: Vec<Vec<u8>> = (0..100_u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    for key in keys.iter() {
+        db.put(key.clone(), key.clone()).unwrap();
+    }
+
+    let iter = db.iter();
+    let mut back = db.iter();
+    db.delete(&keys[50]).unwrap();
+    db.delete(&keys[99]).unwrap();
+    let expected: Vec<_> = keys
+        .iter()
+        .filter(|key| *key != &keys[50] && *key != &keys[99])
+        .cloned()
+        .collect();
+    let forward: Vec<_> = iter.map(|item| item.unwrap().0).collect();
+    assert_eq!(forward, expected);
+
+    // The deleted last key is skipped from the back too
+    assert_eq!(back.next_back().unwrap().unwrap().0, keys[98]);
+    assert_eq!(back.by_ref().rev().count(), 97);
+    assert!(back.next().is_none());
+}
+
+#[test]
+fn test_iter_map() {
+    clean_up("_test_iter_map");
+    let db = Notus::temp("./testdir/_test_iter_map").unwrap();
+    for i in 0..200_usize {
+        db.put(kv(i), format!("user{}|{}|{}", i, i % 90, i * 3).into_bytes()).unwrap();
+    }
+    db.barrier().unwrap();
+    db.delete(&kv(5)).unwrap();
+
+    let age = |value: &[u8]| value.split(|b| *b == b'|').nth(1).unwrap().to_vec();
+    let mapped: Vec<(Vec<u8>, Vec<u8>)> = db
+        .iter_map(|key, value| (key.to_vec(), age(value)))
+        .map(|item| item.unwrap())
+        .collect();
+    let naive: Vec<(Vec<u8>, Vec<u8>)> = db
+        .iter()
+        .map(|item| item.unwrap())
+        .map(|(key, value)| (key, age(&value)))
+        .collect();
+    assert_eq!(mapped.len(), 199);
+    assert_eq!(mapped, naive);
+
+    // Keys are the ones written, not their normalized form
+    clean_up("_test_iter_map_normalized");
+    let options = NotusOptions::default().key_normalizer(|key| key.to_ascii_lowercase());
+    let db = Notus::open_with_options("./testdir/_test_iter_map_normalized", options).unwrap();
+    db.put(b"Alice".to_vec(), b"a|30".to_vec()).unwrap();
+    db.put(b"BOB".to_vec(), b"b|40".to_vec()).unwrap();
+    let mapped: Vec<(Vec<u8>, Vec<u8>)> = db
+        .iter_map(|key, value| (key.to_vec(), age(value)))
+        .map(|item| item.unwrap())
+        .collect();
+    assert_eq!(
+        mapped,
+        vec![(b"Alice".to_vec(), b"30".to_vec()), (b"BOB".to_vec(), b"40".to_vec())]
+    );
+}
+
 #[test]
 fn get_set() {
     clean_up("_test_monotonic_inserts");
@@ -273,3 +389,3316 @@ fn test_merge_operator() {
     db.merge(concatenate_merge, k.to_vec(), vec![4]);
     assert_eq!(db.get(&k.to_vec()).unwrap().unwrap(), vec![4]);
 }
+
+fn counter_merge(_key: &[u8], old_value: Option<Vec<u8>>, increment: &[u8]) -> Option<Vec<u8>> {
+    use std::convert::TryInto;
+    let count = old_value.map_or(0, |old| u64::from_be_bytes(old.try_into().unwrap()));
+    let increment = u64::from_be_bytes(increment.try_into().unwrap());
+    Some((count + increment).to_be_bytes().to_vec())
+}
+
+#[test]
+fn test_column_family_merge_operator() {
+    clean_up("_test_column_family_merge_operator");
+    let db = Notus::temp("./testdir/_test_column_family_merge_operator").unwrap();
+    let k = b"hits".to_vec();
+
+    assert!(matches!(
+        db.merge_cf("counters", k.clone(), 1_u64.to_be_bytes().to_vec()),
+        Err(NotusError::NoMergeOperator(column)) if column == "counters"
+    ));
+
+    db.create_column_family("counters", counter_merge).unwrap();
+    db.create_column_family("logs", concatenate_merge).unwrap();
+    for increment in [1_u64, 2, 3, 4] {
+        db.merge_cf("counters", k.clone(), increment.to_be_bytes().to_vec())
+            .unwrap();
+    }
+    db.merge_cf("logs", k.clone(), vec![1]).unwrap();
+    db.merge_cf("logs", k.clone(), vec![2]).unwrap();
+    assert_eq!(db.get_cf("counters", &k).unwrap(), Some(10_u64.to_be_bytes().to_vec()));
+    assert_eq!(db.get_cf("logs", &k).unwrap(), Some(vec![1, 2]));
+
+    // Registering again replaces the operator
+    db.create_column_family("counters", concatenate_merge).unwrap();
+    db.merge_cf("counters", k.clone(), vec![0]).unwrap();
+    assert_eq!(db.get_cf("counters", &k).unwrap().unwrap().len(), 9);
+    assert!(matches!(
+        db.merge_cf(DEFAULT_INDEX, k, vec![0]),
+        Err(NotusError::NoMergeOperator(_))
+    ));
+}
+
+#[test]
+fn test_column_family_management() {
+    clean_up("_test_column_family_management");
+    let dir = "./testdir/_test_column_family_management";
+    {
+        let db = Notus::open(dir).unwrap();
+        assert!(db.list_column_families().is_empty());
+        assert!(!db.column_family_exists("users"));
+
+        // The same key in two columns holds two values
+        db.put(kv(1), vec![0]).unwrap();
+        db.put_cf("users", kv(1), vec![1]).unwrap();
+        db.put_cf("orders", kv(1), vec![2]).unwrap();
+        db.put_cf("orders", kv(2), vec![2]).unwrap();
+        assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![0]));
+        assert_eq!(db.get_cf("users", &kv(1)).unwrap(), Some(vec![1]));
+        assert_eq!(db.get_cf("orders", &kv(1)).unwrap(), Some(vec![2]));
+
+        db.create_column_family("counters", concatenate_merge).unwrap();
+        assert_eq!(db.list_column_families(), vec!["counters", "orders", "users"]);
+        assert!(db.column_family_exists("counters"));
+        assert!(db.column_family_exists("orders"));
+        assert!(db.column_family_exists(DEFAULT_INDEX));
+
+        assert_eq!(db.drop_column_family("orders").unwrap(), 2);
+        assert_eq!(db.drop_column_family("counters").unwrap(), 0);
+        assert!(!db.column_family_exists("orders"));
+        assert!(!db.column_family_exists("counters"));
+        assert_eq!(db.get_cf("orders", &kv(1)).unwrap(), None);
+        assert_eq!(db.get_cf("users", &kv(1)).unwrap(), Some(vec![1]));
+        assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![0]));
+        assert_eq!(db.list_column_families(), vec!["users"]);
+    }
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.list_column_families(), vec!["users"]);
+    assert_eq!(db.get_cf("orders", &kv(2)).unwrap(), None);
+    assert_eq!(db.get_cf("users", &kv(1)).unwrap(), Some(vec![1]));
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![0]));
+}
+
+#[test]
+fn test_column_isolation() {
+    clean_up("_test_column_isolation");
+    let dir = "./testdir/_test_column_isolation";
+    let columns = ["a", "b", "c"];
+    type Entry = (Vec<u8>, Vec<u8>);
+    fn entries(iter: impl Iterator<Item = crate::Result<Entry>>) -> Vec<Entry> {
+        iter.map(Result::unwrap).collect()
+    }
+    {
+        let db = Notus::open(dir).unwrap();
+        for (i, column) in columns.iter().enumerate() {
+            db.put_cf(column, b"key:1".to_vec(), vec![i as u8]).unwrap();
+            db.put_cf(column, b"key:2".to_vec(), vec![i as u8 + 10]).unwrap();
+        }
+        db.delete_cf("b", b"key:1").unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(db.get_cf("a", b"key:1").unwrap(), Some(vec![0]));
+            assert_eq!(db.get_cf("b", b"key:1").unwrap(), None);
+            assert_eq!(db.get_cf("c", b"key:1").unwrap(), Some(vec![2]));
+            assert!(db.contains_cf("a", b"key:1").unwrap());
+            assert!(!db.contains_cf("b", b"key:1").unwrap());
+            assert!(!db.contains(&b"key:1".to_vec()).unwrap());
+            for (i, column) in columns.iter().enumerate() {
+                assert_eq!(
+                    db.get_cf(column, b"key:2").unwrap(),
+                    Some(vec![i as u8 + 10])
+                );
+            }
+            assert_eq!(
+                entries(db.range_cf("a", b"key:1".to_vec()..)),
+                vec![
+                    (b"key:1".to_vec(), vec![0]),
+                    (b"key:2".to_vec(), vec![10])
+                ]
+            );
+            assert_eq!(
+                entries(db.prefix_cf("b", b"key:")),
+                vec![(b"key:2".to_vec(), vec![11])]
+            );
+            assert_eq!(db.len_cf("c"), 2);
+            assert_eq!(db.len(), 0);
+            db.compact().unwrap();
+        }
+    }
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get_cf("a", b"key:1").unwrap(), Some(vec![0]));
+    assert_eq!(db.get_cf("b", b"key:1").unwrap(), None);
+    assert_eq!(db.get_cf("c", b"key:1").unwrap(), Some(vec![2]));
+    assert_eq!(
+        entries(db.iter_cf("c")),
+        vec![
+            (b"key:1".to_vec(), vec![2]),
+            (b"key:2".to_vec(), vec![12])
+        ]
+    );
+}
+
+#[test]
+fn test_write_batch_last_write_wins() {
+    clean_up("_test_write_batch_last_write_wins");
+    let db = Notus::temp("./testdir/_test_write_batch_last_write_wins").unwrap();
+
+    db.put(b"k2".to_vec(), vec![0]).unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"k1".to_vec(), vec![1]);
+    batch.delete(b"k1".to_vec());
+    batch.put(b"k1".to_vec(), vec![2]);
+    batch.put(b"k2".to_vec(), vec![3]);
+    batch.delete(b"k2".to_vec());
+    db.write_batch(batch).unwrap();
+
+    assert_eq!(db.get(&b"k1".to_vec()).unwrap(), Some(vec![2]));
+    assert_eq!(db.get(&b"k2".to_vec()).unwrap(), None);
+    assert_eq!(db.iter().count(), 1);
+}
+
+#[test]
+fn test_write_batch_is_never_seen_partially() {
+    const ROUNDS: usize = 10;
+    const BATCHES: u8 = 10;
+    const BATCH_SIZE: u8 = 200;
+    // Short rounds on fresh stores keep the scans quick, so they often land
+    // while a batch is being applied
+    for round in 0..ROUNDS {
+        let dir = format!("_test_write_batch_is_never_seen_partially_{}", round);
+        clean_up(&dir);
+        let db = Arc::new(Notus::temp(format!("./testdir/{}", dir)).unwrap());
+        let writing = Arc::new(std::sync::atomic::AtomicUsize::new(2));
+        let writers: Vec<_> = (0..2_u8)
+            .map(|t| {
+                let db = db.clone();
+                let writing = writing.clone();
+                std::thread::spawn(move || {
+                    for b in 0..BATCHES {
+                        let mut batch = WriteBatch::new();
+                        for i in 0..BATCH_SIZE {
+                            batch.put(vec![t, b, i], vec![t, b]);
+                        }
+                        db.write_batch(batch).unwrap();
+                    }
+                    writing.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        // Every batch is seen whole or not at all by iterators racing the writers
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                let writing = writing.clone();
+                std::thread::spawn(move || {
+                    while writing.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+                        let mut seen: BTreeMap<Vec<u8>, u8> = BTreeMap::new();
+                        for item in db.iter() {
+                            let (key, value) = item.unwrap();
+                            assert_eq!(key[..2], value[..]);
+                            *seen.entry(value).or_default() += 1;
+                        }
+                        for (batch, count) in seen {
+                            assert_eq!(count, BATCH_SIZE, "batch {:?} seen partially", batch);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+        assert_eq!(db.iter().count(), 2 * BATCHES as usize * BATCH_SIZE as usize);
+    }
+}
+
+#[test]
+fn test_keys_modified_since() {
+    clean_up("_test_keys_modified_since");
+    let db = Notus::temp("./testdir/_test_keys_modified_since").unwrap();
+
+    db.put(b"old1".to_vec(), vec![1]).unwrap();
+    db.put(b"old2".to_vec(), vec![2]).unwrap();
+
+    db.barrier().unwrap();
+    // Entry timestamps have a resolution of one second
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let cutoff = chrono::Utc::now().timestamp();
+
+    db.put(b"new1".to_vec(), vec![3]).unwrap();
+    db.put(b"old1".to_vec(), vec![4]).unwrap();
+
+    let modified = db.keys_modified_since(cutoff).unwrap();
+    assert_eq!(modified, vec![b"new1".to_vec(), b"old1".to_vec()]);
+}
+
+#[test]
+fn test_import_preserves_timestamps() {
+    clean_up("_test_import_preserves_timestamps_src");
+    clean_up("_test_import_preserves_timestamps_dst");
+    let src = Notus::temp("./testdir/_test_import_preserves_timestamps_src").unwrap();
+    src.put_with_timestamp(b"k1".to_vec(), vec![1], 1_000).unwrap();
+    src.put_with_timestamp(b"k2".to_vec(), vec![2], 2_000).unwrap();
+    assert!(src.put_with_timestamp(b"k3".to_vec(), vec![3], 0).is_err());
+
+    let mut exported = vec![];
+    assert_eq!(src.export(&mut exported).unwrap(), 2);
+
+    let dst = Notus::temp("./testdir/_test_import_preserves_timestamps_dst").unwrap();
+    assert_eq!(dst.import(&mut exported.as_slice()).unwrap(), 2);
+    assert_eq!(
+        dst.get_with_meta(b"k1").unwrap(),
+        Some((vec![1], EntryMeta { timestamp: 1_000 }))
+    );
+    assert_eq!(
+        dst.get_with_meta(b"k2").unwrap(),
+        Some((vec![2], EntryMeta { timestamp: 2_000 }))
+    );
+    assert_eq!(dst.get_with_meta(b"k3").unwrap(), None);
+}
+
+#[test]
+fn test_export_cf_round_trip() {
+    clean_up("_test_export_cf_round_trip_src");
+    clean_up("_test_export_cf_round_trip_dst");
+    let src = Notus::temp("./testdir/_test_export_cf_round_trip_src").unwrap();
+    for i in 0..50_usize {
+        src.put_cf("users", kv(i), vec![i as u8; 10]).unwrap();
+        src.put_cf("orders", kv(i), vec![0xff; 10]).unwrap();
+        src.put(kv(i), vec![0xee]).unwrap();
+    }
+    src.delete_cf("users", &kv(7)).unwrap();
+
+    let mut exported = vec![];
+    assert_eq!(src.export_cf("users", &mut exported).unwrap(), 49);
+
+    let dst = Notus::temp("./testdir/_test_export_cf_round_trip_dst").unwrap();
+    dst.put_cf("people", kv(100), vec![1]).unwrap();
+    assert_eq!(dst.import_cf("people", &mut exported.as_slice()).unwrap(), 49);
+    for i in 0..50_usize {
+        let expected = if i == 7 { None } else { Some(vec![i as u8; 10]) };
+        assert_eq!(dst.get_cf("people", &kv(i)).unwrap(), expected);
+        assert_eq!(dst.get_cf("users", &kv(i)).unwrap(), None);
+        assert_eq!(dst.get_cf("orders", &kv(i)).unwrap(), None);
+        assert_eq!(dst.get(&kv(i)).unwrap(), None);
+    }
+    assert_eq!(dst.get_cf("people", &kv(100)).unwrap(), Some(vec![1]));
+    assert_eq!(dst.keys_cf("people").count(), 50);
+}
+
+#[test]
+fn test_orphans() {
+    clean_up("_test_orphans");
+    let db = Notus::temp("./testdir/_test_orphans").unwrap();
+
+    // Flush each version to disk
+    db.put(b"k1".to_vec(), vec![1]).unwrap();
+    db.put(b"k2".to_vec(), vec![2]).unwrap();
+    db.barrier().unwrap();
+    db.put(b"k1".to_vec(), vec![3]).unwrap();
+    db.barrier().unwrap();
+
+    let orphans: Vec<_> = db.orphans().map(|res| res.unwrap()).collect();
+    assert_eq!(orphans, vec![(b"k1".to_vec(), vec![1])]);
+    assert_eq!(db.get(&b"k1".to_vec()).unwrap(), Some(vec![3]));
+}
+
+#[test]
+fn test_descending_time_keys() {
+    use crate::key_encoding::decode_descending_time_key;
+
+    clean_up("_test_descending_time_keys");
+    let db = Notus::temp("./testdir/_test_descending_time_keys").unwrap();
+    db.put_descending_time(b"a", 100, vec![1]).unwrap();
+    db.put_descending_time(b"b", 300, vec![3]).unwrap();
+    db.put_descending_time(b"c", 200, vec![2]).unwrap();
+
+    let timestamps: Vec<_> = db
+        .range(..)
+        .map(|res| decode_descending_time_key(&res.unwrap().0).unwrap().0)
+        .collect();
+    assert_eq!(timestamps, vec![300, 200, 100]);
+
+    let values: Vec<_> = db
+        .range_descending_time(250, 100)
+        .map(|res| res.unwrap().1)
+        .collect();
+    assert_eq!(values, vec![vec![2], vec![1]]);
+}
+
+#[test]
+fn test_range_size() {
+    clean_up("_test_range_size");
+    let db = Notus::temp("./testdir/_test_range_size").unwrap();
+    for i in 0..50_usize {
+        db.put(kv(i), vec![0; i * 3]).unwrap();
+    }
+    // Write the first half to disk so both the index and the buffer are used
+    db.barrier().unwrap();
+    for i in 50..100_usize {
+        db.put(kv(i), vec![0; i * 3]).unwrap();
+    }
+
+    let range = kv(20)..kv(80);
+    let expected: u64 = db
+        .range(range.clone())
+        .map(|res| res.unwrap().1.len() as u64)
+        .sum();
+    assert_eq!(db.range_size(range).unwrap(), expected);
+    assert_eq!(db.range_size(kv(200)..).unwrap(), 0);
+}
+
+#[test]
+fn test_snapshot_diff() {
+    clean_up("_test_snapshot_diff");
+    let db = Notus::temp("./testdir/_test_snapshot_diff").unwrap();
+    db.put(b"same".to_vec(), vec![1]).unwrap();
+    db.put(b"rewritten".to_vec(), vec![2]).unwrap();
+    db.put(b"modified".to_vec(), vec![3]).unwrap();
+    db.put(b"removed".to_vec(), vec![4]).unwrap();
+    db.barrier().unwrap();
+    let old = db.snapshot().unwrap();
+
+    db.put(b"rewritten".to_vec(), vec![2]).unwrap();
+    db.put(b"modified".to_vec(), vec![30]).unwrap();
+    db.delete(&b"removed".to_vec()).unwrap();
+    db.put(b"added".to_vec(), vec![5]).unwrap();
+    let new = db.snapshot().unwrap();
+
+    let changes: Vec<_> = db.diff(&old, &new).collect();
+    assert_eq!(
+        changes,
+        vec![
+            Change::Added(b"added".to_vec()),
+            Change::Modified(b"modified".to_vec()),
+            Change::Removed(b"removed".to_vec()),
+        ]
+    );
+    assert_eq!(db.diff(&new, &new).count(), 0);
+}
+
+#[test]
+fn test_snapshot_reads() {
+    clean_up("_test_snapshot_reads");
+    let db = Notus::open("./testdir/_test_snapshot_reads").unwrap();
+    for i in 0..100 {
+        db.put(kv(i), vec![i as u8]).unwrap();
+    }
+    db.barrier().unwrap();
+    db.put(kv(100), vec![100]).unwrap();
+    let snapshot = db.snapshot().unwrap();
+    let expected: Vec<_> = (0..=100).map(|i| (kv(i), vec![i as u8])).collect();
+
+    // Mutate every key the iteration has not reached yet
+    let mut seen = vec![];
+    for (n, item) in snapshot.iter().enumerate() {
+        seen.push(item.unwrap());
+        if n == 10 {
+            for i in 0..=100 {
+                if i % 2 == 0 {
+                    db.delete(&kv(i)).unwrap();
+                } else {
+                    db.put(kv(i), vec![0xff]).unwrap();
+                }
+            }
+            db.put(kv(200), vec![0xff]).unwrap();
+            db.barrier().unwrap();
+            db.compact().unwrap();
+        }
+    }
+    assert_eq!(seen, expected);
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![0xff]));
+    assert_eq!(db.get(&kv(2)).unwrap(), None);
+
+    assert_eq!(snapshot.get(&kv(2)).unwrap(), Some(vec![2]));
+    assert_eq!(snapshot.get(&kv(100)).unwrap(), Some(vec![100]));
+    assert_eq!(snapshot.get(&kv(200)).unwrap(), None);
+    let range: Vec<_> = snapshot
+        .range(kv(40)..kv(45))
+        .map(|item| item.unwrap())
+        .collect();
+    assert_eq!(range, expected[40..45].to_vec());
+    assert_eq!(snapshot.iter().next_back().unwrap().unwrap(), expected[100]);
+}
+
+#[test]
+fn test_delete_cf_survives_reopen() {
+    clean_up("_test_delete_cf_survives_reopen");
+    let key = b"key".to_vec();
+    {
+        let db = Notus::temp("./testdir/_test_delete_cf_survives_reopen").unwrap();
+        db.put_cf("a", key.clone(), vec![1]).unwrap();
+        db.put_cf("b", key.clone(), vec![2]).unwrap();
+        db.barrier().unwrap();
+        db.delete_cf("a", &key).unwrap();
+        assert_eq!(db.get_cf("a", &key).unwrap(), None);
+    }
+    let db = Notus::temp("./testdir/_test_delete_cf_survives_reopen").unwrap();
+    assert_eq!(db.get_cf("a", &key).unwrap(), None);
+    assert_eq!(db.get_cf("b", &key).unwrap(), Some(vec![2]));
+    assert_eq!(db.get(&key).unwrap(), None);
+}
+
+#[test]
+fn test_concurrent_reads() {
+    clean_up("_test_concurrent_reads");
+    let db = Arc::new(Notus::temp("./testdir/_test_concurrent_reads").unwrap());
+    for i in 0..200_usize {
+        db.put(kv(i), vec![i as u8; i * 7]).unwrap();
+    }
+    db.barrier().unwrap();
+
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    for i in 0..200_usize {
+                        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; i * 7]));
+                    }
+                }
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+}
+
+/// Writes and deletes keys from many threads into a store with `stripes`
+/// write stripes, checks what each thread wrote and returns how long the
+/// writes took.
+fn striped_writes(stripes: usize) -> std::time::Duration {
+    let dir = format!("./testdir/_test_write_stripes_{}", stripes);
+    clean_up(&format!("_test_write_stripes_{}", stripes));
+    let options = NotusOptions::default().write_stripes(stripes);
+    let db = Arc::new(Notus::open_with_options(&dir, options).unwrap());
+    let started = std::time::Instant::now();
+    let writers: Vec<_> = (0..N_THREADS)
+        .map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in (t * N_PER_THREAD)..((t + 1) * N_PER_THREAD) {
+                    db.put(kv(i), vec![t as u8; 32]).unwrap();
+                    db.put(kv(i), kv(i)).unwrap();
+                    if i % 10 == 0 {
+                        db.delete(&kv(i)).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let elapsed = started.elapsed();
+    db.barrier().unwrap();
+    drop(db);
+
+    let db = Notus::open(&dir).unwrap();
+    for i in 0..N {
+        let expected = if i % 10 == 0 { None } else { Some(kv(i)) };
+        assert_eq!(db.get(&kv(i)).unwrap(), expected, "key {}", i);
+    }
+    assert_eq!(db.keys().count(), N - N / 10);
+    elapsed
+}
+
+#[test]
+fn test_write_stripes() {
+    common::setup_logger();
+    let single = striped_writes(1);
+    let striped = striped_writes(16);
+    debug!(
+        "{} threads wrote {} keys in {:?} with one stripe, {:?} with 16",
+        N_THREADS, N, single, striped
+    );
+}
+
+#[test]
+fn test_concurrent_distinct_puts() {
+    clean_up("_test_concurrent_distinct_puts");
+    let dir = "./testdir/_test_concurrent_distinct_puts";
+    let db = Arc::new(Notus::open(dir).unwrap());
+    let writers: Vec<_> = (0..16_usize)
+        .map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in (t * 1000)..((t + 1) * 1000) {
+                    db.put((i as u32).to_be_bytes().to_vec(), vec![t as u8; i % 64 + 1]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    let check = |db: &Notus| {
+        for i in 0..16_000 {
+            let t = i / 1000;
+            let value = db.get(&(i as u32).to_be_bytes().to_vec()).unwrap();
+            assert_eq!(value, Some(vec![t as u8; i % 64 + 1]), "key {}", i);
+        }
+    };
+    check(&db);
+    drop(db);
+
+    // Every record landed at its own offset in the data and hint files
+    let db = Notus::open(dir).unwrap();
+    check(&db);
+}
+
+fn workers_started() -> usize {
+    WORKERS_STARTED.with(|started| started.get())
+}
+
+#[test]
+fn test_without_background_worker() {
+    let before = workers_started();
+    let stores: Vec<_> = (0..100)
+        .map(|_| {
+            let storage = Arc::new(MemoryStorage::default());
+            let options = NotusOptions::default().background_worker(false);
+            let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+            (db, storage)
+        })
+        .collect();
+    assert_eq!(workers_started(), before);
+
+    for (i, (db, _)) in stores.iter().enumerate() {
+        db.put(kv(i), kv(i)).unwrap();
+    }
+    for (i, (db, storage)) in stores.iter().enumerate() {
+        // Nothing flushes the buffer but an explicit barrier
+        assert_eq!(data_bytes(storage), 0);
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(kv(i)));
+        db.barrier().unwrap();
+        assert!(data_bytes(storage) > 0);
+    }
+
+    let (db, storage) = stores.into_iter().nth(7).unwrap();
+    drop(db);
+    let db = Notus::open_with_storage(storage, NotusOptions::default().background_worker(false)).unwrap();
+    assert_eq!(db.get(&kv(7)).unwrap(), Some(kv(7)));
+    assert_eq!(workers_started(), before);
+
+    let _db = Notus::open_with_storage(Arc::new(MemoryStorage::default()), NotusOptions::default())
+        .unwrap();
+    assert_eq!(workers_started(), before + 1);
+}
+
+#[test]
+fn test_values_equal() {
+    clean_up("_test_values_equal");
+    let db = Notus::temp("./testdir/_test_values_equal").unwrap();
+    let large: Vec<u8> = (0..10_000_u32).map(|i| i as u8).collect();
+    let mut large_changed = large.clone();
+    large_changed[9_000] ^= 1;
+    db.put(b"a".to_vec(), large.clone()).unwrap();
+    db.put(b"b".to_vec(), large.clone()).unwrap();
+    db.put(b"c".to_vec(), large_changed).unwrap();
+    db.put(b"d".to_vec(), large[..9_999].to_vec()).unwrap();
+    db.barrier().unwrap();
+    // Compare values on disk with values still in the write buffer
+    db.put(b"e".to_vec(), large).unwrap();
+
+    for (a, b) in [(b"a", b"b"), (b"a", b"e"), (b"a", b"a")] {
+        assert_eq!(db.values_equal(a, b).unwrap(), Some(true));
+    }
+    assert_eq!(db.values_equal(b"a", b"c").unwrap(), Some(false));
+    assert_eq!(db.values_equal(b"e", b"c").unwrap(), Some(false));
+    assert_eq!(db.values_equal(b"a", b"d").unwrap(), Some(false));
+    assert_eq!(db.values_equal(b"a", b"missing").unwrap(), None);
+    db.delete(&b"b".to_vec()).unwrap();
+    assert_eq!(db.values_equal(b"b", b"a").unwrap(), None);
+
+    // The original keys kept by a key normalizer are not compared
+    clean_up("_test_values_equal_normalized");
+    let options = NotusOptions::default().key_normalizer(|key| key.to_ascii_lowercase());
+    let db = Notus::open_with_options("./testdir/_test_values_equal_normalized", options).unwrap();
+    db.put(b"A".to_vec(), vec![1, 2, 3]).unwrap();
+    db.put(b"bb".to_vec(), vec![1, 2, 3]).unwrap();
+    db.put(b"c".to_vec(), vec![1, 2]).unwrap();
+    db.barrier().unwrap();
+    assert_eq!(db.values_equal(b"a", b"BB").unwrap(), Some(true));
+    assert_eq!(db.values_equal(b"a", b"c").unwrap(), Some(false));
+
+    clean_up("_test_values_equal_separated");
+    let options = NotusOptions::default().kv_separation(true);
+    let db = Notus::open_with_options("./testdir/_test_values_equal_separated", options).unwrap();
+    db.put(b"a".to_vec(), vec![7; 5_000]).unwrap();
+    db.put(b"b".to_vec(), vec![7; 5_000]).unwrap();
+    db.barrier().unwrap();
+    db.put(b"c".to_vec(), vec![8; 5_000]).unwrap();
+    assert_eq!(db.values_equal(b"a", b"b").unwrap(), Some(true));
+    assert_eq!(db.values_equal(b"a", b"c").unwrap(), Some(false));
+}
+
+#[test]
+fn test_put_with_ttl() {
+    clean_up("_test_put_with_ttl");
+    let dir = "./testdir/_test_put_with_ttl";
+    let ttl = std::time::Duration::from_millis(300);
+    let db = Notus::open(dir).unwrap();
+    db.put_with_ttl(b"short".to_vec(), b"a".to_vec(), ttl).unwrap();
+    db.put_with_ttl(b"long".to_vec(), b"b".to_vec(), ttl * 100).unwrap();
+    db.put(b"kept".to_vec(), b"c".to_vec()).unwrap();
+    assert_eq!(db.get(&b"short".to_vec()).unwrap(), Some(b"a".to_vec()));
+    assert!(db.contains(&b"short".to_vec()).unwrap());
+    assert_eq!(db.keys().count(), 3);
+
+    std::thread::sleep(ttl + std::time::Duration::from_millis(100));
+    assert_eq!(db.get(&b"short".to_vec()).unwrap(), None);
+    assert!(!db.contains(&b"short".to_vec()).unwrap());
+    let keys: Vec<_> = db.keys().collect();
+    assert_eq!(keys, vec![b"kept".to_vec(), b"long".to_vec()]);
+    assert_eq!(db.iter().count(), 2);
+
+    // Expiring before the store is closed and after it is reopened
+    db.put_with_ttl(b"short".to_vec(), b"d".to_vec(), ttl).unwrap();
+    db.barrier().unwrap();
+    drop(db);
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&b"short".to_vec()).unwrap(), Some(b"d".to_vec()));
+    assert_eq!(db.get(&b"long".to_vec()).unwrap(), Some(b"b".to_vec()));
+    std::thread::sleep(ttl);
+    assert_eq!(db.get(&b"short".to_vec()).unwrap(), None);
+
+    db.compact().unwrap();
+    let mut stored = vec![];
+    for file_id in db.file_ids().unwrap() {
+        for hint in db.hint_entries(&file_id).unwrap() {
+            if !hint.deleted {
+                stored.push(hint.key);
+            }
+        }
+    }
+    stored.sort();
+    assert_eq!(stored, vec![b"kept".to_vec(), b"long".to_vec()]);
+    drop(db);
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&b"short".to_vec()).unwrap(), None);
+    assert_eq!(db.keys().count(), 2);
+}
+
+#[test]
+fn test_len() {
+    clean_up("_test_len");
+    let dir = "./testdir/_test_len";
+    let db = Notus::open(dir).unwrap();
+    assert!(db.is_empty());
+    for i in 0..50u32 {
+        db.put(i.to_be_bytes().to_vec(), b"v".to_vec()).unwrap();
+    }
+    db.put_cf("other", b"a".to_vec(), b"1".to_vec()).unwrap();
+    db.put_cf("other", b"b".to_vec(), b"2".to_vec()).unwrap();
+    assert_eq!(db.len(), 50);
+    assert!(!db.is_empty());
+    assert_eq!(db.len_cf("other"), 2);
+    assert_eq!(db.len_cf("missing"), 0);
+
+    // Overwrites don't add keys and deletes remove them
+    db.put(0u32.to_be_bytes().to_vec(), b"w".to_vec()).unwrap();
+    for i in 0..20u32 {
+        db.delete(&i.to_be_bytes().to_vec()).unwrap();
+    }
+    db.delete_cf("other", b"a").unwrap();
+    assert_eq!(db.len(), 30);
+    assert_eq!(db.len(), db.keys().count());
+    assert_eq!(db.len_cf("other"), 1);
+
+    db.barrier().unwrap();
+    db.compact().unwrap();
+    assert_eq!(db.len(), 30);
+    assert_eq!(db.len_cf("other"), db.keys_cf("other").count());
+    drop(db);
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.len(), 30);
+    assert_eq!(db.len_cf("other"), 1);
+}
+
+#[test]
+fn test_keys_in_file() {
+    clean_up("_test_keys_in_file");
+    let dir = "./testdir/_test_keys_in_file";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(b"a".to_vec(), vec![1]).unwrap();
+        db.put(b"b".to_vec(), vec![2]).unwrap();
+        db.put(b"d".to_vec(), vec![4]).unwrap();
+        db.put_cf("other", b"x".to_vec(), vec![5]).unwrap();
+    }
+
+    // Reopening starts a new file
+    let db = Notus::open(dir).unwrap();
+    db.put(b"c".to_vec(), vec![3]).unwrap();
+    db.put(b"a".to_vec(), vec![6]).unwrap();
+    db.delete(&b"d".to_vec()).unwrap();
+    db.barrier().unwrap();
+    db.put(b"e".to_vec(), vec![7]).unwrap();
+
+    let file_ids = db.file_ids().unwrap();
+    assert_eq!(file_ids.len(), 2);
+    assert_eq!(db.keys_in_file(&file_ids[0]).unwrap(), vec![b"b".to_vec()]);
+    assert_eq!(
+        db.keys_in_file(&file_ids[1]).unwrap(),
+        vec![b"a".to_vec(), b"c".to_vec()]
+    );
+    assert_eq!(
+        db.keys_in_file_cf("other", &file_ids[0]).unwrap(),
+        vec![b"x".to_vec()]
+    );
+    assert!(db.keys_in_file_cf("other", &file_ids[1]).unwrap().is_empty());
+    assert!(matches!(
+        db.keys_in_file("missing"),
+        Err(NotusError::FileNotFound(_))
+    ));
+}
+
+#[test]
+fn test_migrate_encoding() {
+    clean_up("_test_migrate_encoding");
+    clean_up("_test_migrate_encoding_on_open");
+    let dir = "./testdir/_test_migrate_encoding";
+    let opened_dir = "./testdir/_test_migrate_encoding_on_open";
+    // Write keys the way stores did before they were qualified by column.
+    // Such stores have records but no recorded encoding
+    let write_unqualified = |dir: &str| {
+        for round in 0..2_u8 {
+            let store = DataStore::open(dir).unwrap();
+            for i in 0..50 {
+                store.put(kv(i), vec![round, i as u8]).unwrap();
+            }
+            if round == 1 {
+                store.delete(&kv(7)).unwrap();
+            }
+        }
+        std::fs::remove_file(std::path::Path::new(dir).join("nutos.encoding")).unwrap();
+    };
+    let check = |db: &Notus| {
+        assert_eq!(db.file_ids().unwrap().len(), 2);
+        assert_eq!(db.keys().count(), 49);
+        for i in (0..50).filter(|i| *i != 7) {
+            assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![1, i as u8]));
+        }
+        assert_eq!(db.get(&kv(7)).unwrap(), None);
+    };
+
+    // Opening such a store migrates it
+    write_unqualified(opened_dir);
+    let db = Notus::open(opened_dir).unwrap();
+    check(&db);
+    drop(db);
+    check(&Notus::open(opened_dir).unwrap());
+
+    write_unqualified(dir);
+    let migrated = Notus::migrate_encoding(dir, Encoding::Unqualified, Encoding::ColumnPrefixed)
+        .unwrap();
+    assert_eq!(migrated, 49);
+    {
+        let db = Notus::open(dir).unwrap();
+        check(&db);
+        db.put_cf("other", kv(1), vec![2]).unwrap();
+    }
+
+    // Keys of other columns have no unqualified form, the store is left as is
+    assert!(matches!(
+        Notus::migrate_encoding(dir, Encoding::ColumnPrefixed, Encoding::Unqualified),
+        Err(NotusError::UnsupportedMigration(_))
+    ));
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![1, 1]));
+    assert_eq!(db.get_cf("other", &kv(1)).unwrap(), Some(vec![2]));
+}
+
+#[test]
+fn test_hint_entries() {
+    clean_up("_test_hint_entries");
+    let db = Notus::temp("./testdir/_test_hint_entries").unwrap();
+    let flush = || db.barrier().unwrap();
+    db.put(b"a".to_vec(), vec![1, 2, 3]).unwrap();
+    flush();
+    db.put(b"b".to_vec(), vec![4]).unwrap();
+    flush();
+    db.delete(&b"a".to_vec()).unwrap();
+    db.put_cf("other", b"b".to_vec(), vec![5, 6]).unwrap();
+    flush();
+
+    let file_ids = db.file_ids().unwrap();
+    assert_eq!(file_ids.len(), 1);
+    let hints = db.hint_entries(&file_ids[0]).unwrap();
+    let summary: Vec<_> = hints
+        .iter()
+        .map(|hint| {
+            (
+                hint.column.as_deref(),
+                hint.key.as_slice(),
+                hint.value_size,
+                hint.deleted,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (Some("$0"), &b"a"[..], 3, false),
+            (Some("$0"), &b"b"[..], 1, false),
+            (Some("$0"), &b"a"[..], 0, true),
+            (Some("other"), &b"b"[..], 2, false),
+        ]
+    );
+    assert_eq!(hints[0].position, 0);
+    assert!(hints[1].position > hints[0].position);
+    assert!(hints[3].position > hints[1].position);
+    assert!(db.hint_entries("missing").is_err());
+}
+
+#[test]
+fn test_hash_keys_over_columns() {
+    let storage = Arc::new(MemoryStorage::default());
+    let options = || NotusOptions::default().hash_keys_over(16);
+    let key = |i: usize| format!("session-{:032}", i).into_bytes();
+    let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+    for i in 0..20_usize {
+        db.put_cf("sessions", key(i), vec![i as u8]).unwrap();
+    }
+    db.put(key(0), vec![100]).unwrap();
+
+    // Hashed keys stay within the range of their column
+    let check = |db: &Notus| {
+        assert_eq!(db.len_cf("sessions"), 20);
+        assert_eq!(db.iter_cf("sessions").count(), 20);
+        let mut keys: Vec<_> = db.keys_cf("sessions").collect();
+        keys.sort();
+        assert_eq!(keys, (0..20).map(key).collect::<Vec<_>>());
+        assert_eq!(db.range_cf("sessions", ..).count(), 20);
+        assert_eq!(db.column_stats().unwrap()["sessions"].live_keys, 20);
+        assert_eq!(db.get_cf("sessions", &key(3)).unwrap(), Some(vec![3]));
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.get(&key(0)).unwrap(), Some(vec![100]));
+    };
+    check(&db);
+    db.barrier().unwrap();
+    check(&db);
+    drop(db);
+    let db = Notus::open_with_storage(storage, options()).unwrap();
+    check(&db);
+}
+
+#[test]
+fn test_memory_storage() {
+    let storage = Arc::new(MemoryStorage::default());
+    {
+        let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+        for i in 0..20_usize {
+            db.put(kv(i), vec![i as u8; 16]).unwrap();
+        }
+        db.barrier().unwrap();
+        for i in 0..10_usize {
+            db.put(kv(i), vec![i as u8 + 1; 16]).unwrap();
+        }
+        db.delete(&kv(19)).unwrap();
+        db.barrier().unwrap();
+    }
+
+    {
+        let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+        db.compact().unwrap();
+    }
+
+    let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+    for i in 0..10_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8 + 1; 16]));
+    }
+    for i in 10..19_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 16]));
+    }
+    assert_eq!(db.get(&kv(19)).unwrap(), None);
+    // The first pair was merged away, leaving the merged pair and the
+    // active pair the compaction switched to, which the last open reuses,
+    // next to the manifest, the last file id and the key encoding
+    assert_eq!(storage.list().unwrap().len(), 7);
+}
+
+#[test]
+fn test_max_total_bytes_eviction() {
+    clean_up("_test_max_total_bytes_eviction");
+    // Every entry takes the 6 byte column prefix, a 3 byte key and 100 bytes
+    let entry_size = 109;
+    let options = NotusOptions {
+        max_total_bytes: Some(10 * entry_size),
+        eviction_policy: EvictionPolicy::Lru,
+        ..Default::default()
+    };
+    let db = Notus::open_with_options("./testdir/_test_max_total_bytes_eviction", options).unwrap();
+    for i in 0..10_usize {
+        db.put(kv(i), vec![i as u8; 100]).unwrap();
+    }
+    assert_eq!(db.iter().count(), 10);
+
+    // Reading key 0 makes key 1 the least recently used
+    assert!(db.get(&kv(0)).unwrap().is_some());
+    for i in 10..15_usize {
+        db.put(kv(i), vec![i as u8; 100]).unwrap();
+    }
+
+    assert_eq!(db.iter().count(), 10);
+    assert!(db.range_size(..).unwrap() <= 10 * 100);
+    assert_eq!(db.get(&kv(0)).unwrap(), Some(vec![0; 100]));
+    for i in 1..6_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), None);
+    }
+    for i in 6..15_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 100]));
+    }
+}
+
+#[test]
+fn test_iterator_cancel() {
+    clean_up("_test_iterator_cancel");
+    let db = Notus::temp("./testdir/_test_iterator_cancel").unwrap();
+    for i in 0..100_usize {
+        db.put(kv(i), kv(i)).unwrap();
+    }
+
+    let cancel = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let mut iter = db.iter().with_cancel(cancel.clone());
+    for i in 0..5_usize {
+        assert_eq!(iter.next().unwrap().unwrap(), (kv(i), kv(i)));
+    }
+    let flag = cancel.clone();
+    std::thread::spawn(move || flag.store(true, std::sync::atomic::Ordering::Release))
+        .join()
+        .unwrap();
+    assert!(iter.next().is_none());
+    assert!(iter.next_back().is_none());
+}
+
+#[test]
+fn test_barrier() {
+    clean_up("_test_barrier");
+    clean_up("_test_barrier_crashed");
+    let db = Notus::temp("./testdir/_test_barrier").unwrap();
+    for i in 0..500_usize {
+        db.put(kv(i), kv(i)).unwrap();
+    }
+    db.barrier().unwrap();
+
+    // Copy the files as they are on disk right now, as a crash would leave them
+    fs_extra::dir::create_all("./testdir/_test_barrier_crashed", false).unwrap();
+    for entry in std::fs::read_dir("./testdir/_test_barrier").unwrap() {
+        let path = entry.unwrap().path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "data" || ext == "hint" || ext == "encoding")
+        {
+            let target = std::path::Path::new("./testdir/_test_barrier_crashed")
+                .join(path.file_name().unwrap());
+            std::fs::copy(&path, target).unwrap();
+        }
+    }
+
+    let crashed = Notus::temp("./testdir/_test_barrier_crashed").unwrap();
+    for i in 0..500_usize {
+        assert_eq!(crashed.get(&kv(i)).unwrap(), Some(kv(i)));
+    }
+}
+
+fn data_bytes(storage: &MemoryStorage) -> u64 {
+    storage
+        .list()
+        .unwrap()
+        .iter()
+        .filter(|name| name.ends_with(".data"))
+        .map(|name| storage.open(name).unwrap().len().unwrap())
+        .sum()
+}
+
+fn data_files(storage: &MemoryStorage) -> usize {
+    storage
+        .list()
+        .unwrap()
+        .iter()
+        .filter(|name| name.ends_with(".data"))
+        .count()
+}
+
+#[test]
+fn test_max_file_size() {
+    let storage = Arc::new(MemoryStorage::default());
+    let options = || NotusOptions::default().max_file_size(4096);
+    let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+    for i in 0..400_usize {
+        db.put(kv(i), vec![i as u8; 100]).unwrap();
+        if i % 20 == 19 {
+            db.barrier().unwrap();
+        }
+    }
+    db.barrier().unwrap();
+    // About 45KB were written, a file is closed every 4KB or so
+    assert!(data_files(&storage) >= 4, "{} data files", data_files(&storage));
+    for i in 0..400_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 100]));
+    }
+
+    drop(db);
+    let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+    for i in 0..400_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 100]));
+    }
+    db.compact().unwrap();
+    // The merged pair and the one taking writes
+    assert_eq!(data_files(&storage), 2);
+    for i in 0..400_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 100]));
+    }
+}
+
+#[test]
+fn test_migrate_on_open() {
+    let storage = Arc::new(MemoryStorage::default());
+    let data_file_sizes = || -> Vec<u64> {
+        storage
+            .list()
+            .unwrap()
+            .iter()
+            .filter(|name| name.ends_with(".data"))
+            .map(|name| storage.open(name).unwrap().len().unwrap())
+            .collect()
+    };
+    // Written before files rolled over, everything is in one data file
+    let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+    for i in 0..400_usize {
+        db.put(kv(i), vec![i as u8; 100]).unwrap();
+    }
+    drop(db);
+    assert_eq!(data_files(&storage), 1);
+    assert!(data_bytes(&storage) > 10 * 4096);
+
+    let options = || NotusOptions::default().max_file_size(4096).migrate_on_open(true);
+    let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+    db.compact().unwrap();
+    // Each file holds at most one record more than the bound
+    let sizes = data_file_sizes();
+    assert!(sizes.len() > 10, "{} data files", sizes.len());
+    assert!(sizes.iter().all(|size| *size < 4096 + 256), "{:?}", sizes);
+    for i in 0..400_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 100]));
+    }
+
+    // Later compactions keep the files bounded
+    for i in 0..200_usize {
+        db.put(kv(i), vec![i as u8 + 1; 100]).unwrap();
+    }
+    drop(db);
+    let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+    db.compact().unwrap();
+    let sizes = data_file_sizes();
+    assert!(sizes.len() > 10, "{} data files", sizes.len());
+    assert!(sizes.iter().all(|size| *size < 4096 + 256), "{:?}", sizes);
+    for i in 0..400_usize {
+        let version = if i < 200 { i as u8 + 1 } else { i as u8 };
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![version; 100]));
+    }
+}
+
+/// A store of many small pairs where a third of the records are stale,
+/// left on storage that has room for `headroom` more bytes.
+fn nearly_full_store(headroom: u64, incremental: bool) -> (Notus, Arc<MemoryStorage>) {
+    let storage = Arc::new(MemoryStorage::default());
+    let options = NotusOptions::default()
+        .max_file_size(4096)
+        .incremental_compaction(incremental)
+        .background_worker(false);
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    for i in 0..600_usize {
+        db.put(kv(i % 400), vec![i as u8; 100]).unwrap();
+        if i % 20 == 19 {
+            db.barrier().unwrap();
+        }
+    }
+    db.barrier().unwrap();
+    storage
+        .capacity
+        .store(storage.used() + headroom, std::sync::atomic::Ordering::Release);
+    (db, storage)
+}
+
+#[test]
+fn test_incremental_compaction() {
+    let headroom = 10 * 1024;
+    // Copying all live records before freeing anything does not fit
+    let (db, _storage) = nearly_full_store(headroom, false);
+    assert!(db.compact().is_err());
+
+    let (db, storage) = nearly_full_store(headroom, true);
+    let before = data_bytes(&storage);
+    db.compact().unwrap();
+    assert!(data_bytes(&storage) < before);
+    for i in 0..400_usize {
+        let last = if i < 200 { i + 400 } else { i };
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![last as u8; 100]));
+    }
+}
+
+#[test]
+fn test_compaction_schedule() {
+    let storage = Arc::new(MemoryStorage::default());
+    {
+        let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+        for round in 0..5_u8 {
+            for i in 0..50_usize {
+                db.put(kv(i), vec![round; 64]).unwrap();
+            }
+            // Write each round to disk instead of overwriting it in the buffer
+            db.barrier().unwrap();
+        }
+    }
+    let before = data_bytes(&storage);
+
+    let options = NotusOptions {
+        compaction_schedule: Some(Schedule::Every(std::time::Duration::from_millis(100))),
+        ..Default::default()
+    };
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    // Only the latest of the five versions of each key is left
+    assert!(data_bytes(&storage) < before / 2);
+    for i in 0..50_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![4; 64]));
+    }
+}
+
+/// Runs `policy` over `samples` rounds of writing `written` bytes of which
+/// `added` become live, returning how many compactions it asked for.
+fn simulate_compactions(
+    policy: &mut AdaptiveCompaction,
+    samples: usize,
+    written: u64,
+    added: u64,
+    state: &mut (u64, u64),
+) -> usize {
+    let mut compactions = 0;
+    for _ in 0..samples {
+        state.0 += written;
+        state.1 += added;
+        if policy.sample(state.0, state.1, std::time::Duration::from_millis(100)) {
+            compactions += 1;
+            // Compaction copies the live records and nothing else
+            state.0 = state.1;
+            policy.compacted(state.0, state.1);
+        }
+    }
+    compactions
+}
+
+#[test]
+fn test_adaptive_compaction() {
+    // Overwrites only, then mostly new keys
+    let mut policy = AdaptiveCompaction::new(None);
+    let mut state = (10_000, 10_000);
+    let high_churn = simulate_compactions(&mut policy, 50, 5_000, 0, &mut state);
+    let high_churn_threshold = policy.threshold();
+    let low_churn = simulate_compactions(&mut policy, 50, 5_000, 4_500, &mut state);
+    assert!(high_churn >= 20, "{} compactions", high_churn);
+    assert!(low_churn <= 2, "{} compactions", low_churn);
+    assert!(policy.threshold() > high_churn_threshold);
+
+    // The same churn with foreground writes taking twice the IO budget
+    let mut scarce = AdaptiveCompaction::new(Some(25_000));
+    let mut state = (10_000, 10_000);
+    let with_scarce_io = simulate_compactions(&mut scarce, 50, 5_000, 0, &mut state);
+    assert!(with_scarce_io * 2 < high_churn, "{} compactions", with_scarce_io);
+
+    // The background worker compacts a store being overwritten
+    let storage = Arc::new(MemoryStorage::default());
+    let options = NotusOptions::default().auto_compaction(AutoPolicy::Adaptive);
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    let mut peak = 0;
+    for round in 0..40_u8 {
+        for i in 0..50_usize {
+            db.put(kv(i), vec![round; 64]).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        peak = peak.max(data_bytes(&storage));
+    }
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    // Without compaction the 40 rounds would all still be on disk
+    assert!(peak < 40 * 50 * 64 / 2, "peaked at {} bytes", peak);
+    for i in 0..50_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![39; 64]));
+    }
+}
+
+#[test]
+fn test_split() {
+    clean_up("_test_split");
+    clean_up("_test_split_left");
+    clean_up("_test_split_right");
+    {
+        let db = Notus::temp("./testdir/_test_split").unwrap();
+        for i in 0..100_usize {
+            db.put(kv(i), kv(i)).unwrap();
+        }
+        db.delete(&kv(10)).unwrap();
+        db.delete(&kv(90)).unwrap();
+        db.split(&kv(50), "./testdir/_test_split_left", "./testdir/_test_split_right")
+            .unwrap();
+        // The source keeps every key
+        assert_eq!(db.iter().count(), 98);
+    }
+
+    let left = Notus::temp("./testdir/_test_split_left").unwrap();
+    let expected: Vec<_> = (0..50_usize).filter(|i| *i != 10).map(kv).collect();
+    let keys: Vec<_> = left.iter().map(|res| res.unwrap().0).collect();
+    assert_eq!(keys, expected);
+    drop(left);
+
+    let right = Notus::temp("./testdir/_test_split_right").unwrap();
+    let expected: Vec<_> = (50..100_usize).filter(|i| *i != 90).map(kv).collect();
+    let keys: Vec<_> = right.iter().map(|res| res.unwrap().0).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(right.get(&kv(75)).unwrap(), Some(kv(75)));
+}
+
+#[test]
+fn test_append_raw() {
+    clean_up("_test_append_raw");
+    let db = Notus::temp("./testdir/_test_append_raw").unwrap();
+    db.put(kv(1), kv(1)).unwrap();
+    let locations: Vec<_> = (0..10_usize)
+        .map(|i| db.append_raw(kv(i), vec![i as u8; 32]).unwrap())
+        .collect();
+    for (i, (file_id, position)) in locations.iter().enumerate() {
+        assert_eq!(
+            db.read_raw(file_id, *position).unwrap(),
+            (kv(i), vec![i as u8; 32])
+        );
+    }
+    // Raw records are not indexed
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(kv(1)));
+    assert_eq!(db.get(&kv(2)).unwrap(), None);
+    assert!(db.read_raw("missing", 0).is_err());
+}
+
+#[test]
+fn test_stale_reads() {
+    clean_up("_test_stale_reads");
+    let max_staleness = std::time::Duration::from_millis(100);
+    let options = NotusOptions {
+        stale_reads: Some(max_staleness),
+        ..Default::default()
+    };
+    let db = Arc::new(Notus::open_with_options("./testdir/_test_stale_reads", options).unwrap());
+    for i in 0..100_usize {
+        db.put(kv(i), kv(i)).unwrap();
+    }
+    std::thread::sleep(max_staleness * 2);
+
+    let writers: Vec<_> = (0..4_usize)
+        .map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 0..2000_usize {
+                    db.put(format!("w{}-{}", t, i).into_bytes(), vec![0; 64]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for _ in 0..50 {
+        for i in 0..100_usize {
+            assert_eq!(db.get(&kv(i)).unwrap(), Some(kv(i)));
+        }
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    db.put(kv(200), kv(200)).unwrap();
+    std::thread::sleep(max_staleness * 2);
+    assert_eq!(db.get(&kv(200)).unwrap(), Some(kv(200)));
+}
+
+#[test]
+fn test_column_stats() {
+    clean_up("_test_column_stats");
+    let db = Notus::temp("./testdir/_test_column_stats").unwrap();
+    for i in 0..10_usize {
+        db.put_cf("a", kv(i), vec![1; 10]).unwrap();
+    }
+    for i in 0..20_usize {
+        db.put_cf("b", kv(i), vec![2; 100]).unwrap();
+    }
+    for i in 0..5_usize {
+        db.put_cf("c", kv(i), vec![3; 1000]).unwrap();
+    }
+    db.barrier().unwrap();
+    // Overwriting every key of "c" leaves the first versions dead
+    for i in 0..5_usize {
+        db.put_cf("c", kv(i), vec![4; 1000]).unwrap();
+    }
+
+    let stats = db.column_stats().unwrap();
+    assert_eq!(stats.len(), 3);
+    assert_eq!(
+        stats["a"],
+        ColumnStats {
+            live_keys: 10,
+            value_bytes: 100,
+            dead_bytes: 0
+        }
+    );
+    assert_eq!(
+        stats["b"],
+        ColumnStats {
+            live_keys: 20,
+            value_bytes: 2000,
+            dead_bytes: 0
+        }
+    );
+    // Each dead record is a 28 byte header, the 8 byte column key and the value
+    assert_eq!(
+        stats["c"],
+        ColumnStats {
+            live_keys: 5,
+            value_bytes: 5000,
+            dead_bytes: 5 * (28 + 8 + 1000)
+        }
+    );
+}
+
+#[test]
+fn test_scan_anomalies() {
+    clean_up("_test_scan_anomalies");
+    let db = Notus::temp("./testdir/_test_scan_anomalies").unwrap();
+    for i in 0..20_usize {
+        let size = if i % 5 == 0 { 4096 } else { 10 };
+        db.put(kv(i), vec![0; size]).unwrap();
+    }
+    db.put_cf("other", kv(1), vec![0; 4096]).unwrap();
+    db.barrier().unwrap();
+    // Oversized values still in the write buffer are found too
+    db.put(kv(20), vec![0; 2048]).unwrap();
+    db.put(kv(5), vec![0; 10]).unwrap();
+    db.put(kv(6), vec![0; 1025]).unwrap();
+
+    assert_eq!(
+        db.scan_anomalies(1024).unwrap(),
+        vec![kv(0), kv(6), kv(10), kv(15), kv(20)]
+    );
+    assert_eq!(db.scan_anomalies(4096).unwrap(), Vec::<Vec<u8>>::new());
+    assert_eq!(db.scan_anomalies_cf("other", 1024).unwrap(), vec![kv(1)]);
+}
+
+#[test]
+fn test_size_histograms() {
+    clean_up("_test_size_histograms");
+    let db = Notus::temp("./testdir/_test_size_histograms").unwrap();
+    for i in 0..3_u8 {
+        db.put(vec![i; 4], vec![i; 10]).unwrap();
+    }
+    for i in 0..5_u8 {
+        db.put_cf("other", vec![i; 8], vec![i; 10]).unwrap();
+    }
+    db.put(vec![9; 16], vec![]).unwrap();
+    db.put(vec![8; 16], vec![8; 1000]).unwrap();
+    db.barrier().unwrap();
+    // Values still in the write buffer are counted too
+    db.put(vec![7; 4], vec![7; 1000]).unwrap();
+
+    assert_eq!(
+        db.key_size_histogram().unwrap(),
+        BTreeMap::from([(4, 4), (8, 5), (16, 2)])
+    );
+    assert_eq!(
+        db.value_size_histogram().unwrap(),
+        BTreeMap::from([(0, 1), (10, 8), (1000, 2)])
+    );
+
+    db.delete(&vec![9; 16]).unwrap();
+    assert_eq!(
+        db.key_size_histogram().unwrap(),
+        BTreeMap::from([(4, 4), (8, 5), (16, 1)])
+    );
+}
+
+#[test]
+fn test_recovery_budget() {
+    clean_up("_test_recovery_budget");
+    let dir = "./testdir/_test_recovery_budget";
+    for session in 0..10_usize {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..200_usize {
+            db.put(kv(i), vec![session as u8; 16]).unwrap();
+        }
+        if session == 5 {
+            db.delete(&kv(199)).unwrap();
+        }
+    }
+
+    let options = NotusOptions {
+        recovery_budget: Some(std::time::Duration::ZERO),
+        ..Default::default()
+    };
+    let db = Notus::open_with_options(dir, options).unwrap();
+    // Nothing fits in the budget, every file is left to the background
+    // worker, including the empty pair created by this open
+    assert_eq!(db.pending_replay().unwrap().len(), 11);
+    // Writes made before the replay catches up are not undone by it
+    db.put(kv(0), vec![100; 16]).unwrap();
+    db.delete(&kv(1)).unwrap();
+
+    let started = std::time::Instant::now();
+    while !db.pending_replay().unwrap().is_empty() {
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(db.get(&kv(0)).unwrap(), Some(vec![100; 16]));
+    assert_eq!(db.get(&kv(1)).unwrap(), None);
+    for i in 2..200_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![9; 16]));
+    }
+}
+
+#[test]
+fn test_clone_handles() {
+    clean_up("_test_clone_handles");
+    let dir = "./testdir/_test_clone_handles";
+    {
+        let db = Notus::open(dir).unwrap();
+        let handles: Vec<_> = (0..4_usize)
+            .map(|t| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50_usize {
+                        db.put(kv(t * 50 + i), kv(i)).unwrap();
+                    }
+                    // Each thread drops its clone when it finishes
+                })
+            })
+            .collect();
+        let extra = db.clone();
+        drop(db);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // The remaining clone keeps the store open
+        assert_eq!(extra.iter().count(), 200);
+    }
+
+    let db = Notus::open(dir).unwrap();
+    for t in 0..4_usize {
+        for i in 0..50_usize {
+            assert_eq!(db.get(&kv(t * 50 + i)).unwrap(), Some(kv(i)));
+        }
+    }
+}
+
+#[test]
+fn test_put_idempotent() {
+    clean_up("_test_put_idempotent");
+    let db = Notus::temp("./testdir/_test_put_idempotent").unwrap();
+    let hint_count = |db: &Notus| -> usize {
+        db.file_ids()
+            .unwrap()
+            .iter()
+            .map(|file_id| db.hint_entries(file_id).unwrap().len())
+            .sum()
+    };
+
+    // Absent keys are written
+    db.put_idempotent(kv(1), vec![1]).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![1]));
+    db.barrier().unwrap();
+    assert_eq!(hint_count(&db), 1);
+
+    // Equal values are not written again
+    db.put_idempotent(kv(1), vec![1]).unwrap();
+    db.barrier().unwrap();
+    assert_eq!(hint_count(&db), 1);
+
+    // Different values are rejected and leave the stored value alone
+    assert!(matches!(
+        db.put_idempotent(kv(1), vec![2]),
+        Err(NotusError::Conflict)
+    ));
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![1]));
+}
+
+#[test]
+fn test_key_normalizer() {
+    clean_up("_test_key_normalizer");
+    let options = NotusOptions::default().key_normalizer(|key| key.to_ascii_lowercase());
+    let db = Notus::open_with_options("./testdir/_test_key_normalizer", options).unwrap();
+    db.put(b"foo".to_vec(), b"1".to_vec()).unwrap();
+    db.put(b"Bar".to_vec(), b"2".to_vec()).unwrap();
+
+    assert_eq!(db.get(&b"Foo".to_vec()).unwrap(), Some(b"1".to_vec()));
+    assert_eq!(db.get(&b"FOO".to_vec()).unwrap(), Some(b"1".to_vec()));
+    assert!(db.contains(&b"bar".to_vec()).unwrap());
+
+    // Iteration returns the keys as they were written
+    let entries: Vec<_> = db.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(
+        entries,
+        vec![
+            (b"Bar".to_vec(), b"2".to_vec()),
+            (b"foo".to_vec(), b"1".to_vec())
+        ]
+    );
+
+    // Keys that normalize alike are the same entry
+    db.put(b"FOO".to_vec(), b"3".to_vec()).unwrap();
+    assert_eq!(db.get(&b"foo".to_vec()).unwrap(), Some(b"3".to_vec()));
+    assert_eq!(db.iter().count(), 2);
+    db.delete(&b"fOo".to_vec()).unwrap();
+    assert_eq!(db.get(&b"foo".to_vec()).unwrap(), None);
+}
+
+#[test]
+fn test_contains_prefix() {
+    clean_up("_test_contains_prefix");
+    let db = Notus::temp("./testdir/_test_contains_prefix").unwrap();
+    db.put(b"user:1".to_vec(), vec![1]).unwrap();
+    db.put(b"user:2".to_vec(), vec![2]).unwrap();
+    db.put_cf("other", b"post:1".to_vec(), vec![3]).unwrap();
+
+    assert!(db.contains_prefix(b"user:"));
+    assert!(!db.contains_prefix(b"post:"));
+    assert!(!db.contains_prefix(b"users"));
+    // A prefix that is a whole key matches that key
+    assert!(db.contains_prefix(b"user:1"));
+    assert!(!db.contains_prefix(b"user:1:"));
+
+    assert!(db.contains_prefix_cf("other", b"post:"));
+    assert!(!db.contains_prefix_cf("other", b"user:"));
+    assert!(!db.contains_prefix_cf("missing", b""));
+
+    db.delete(&b"user:1".to_vec()).unwrap();
+    db.delete(&b"user:2".to_vec()).unwrap();
+    assert!(!db.contains_prefix(b"user:"));
+}
+
+#[test]
+fn test_iter_by_file() {
+    clean_up("_test_iter_by_file");
+    let dir = "./testdir/_test_iter_by_file";
+    for session in 0..3_usize {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..50_usize {
+            db.put(kv(session * 50 + i), vec![session as u8; 8]).unwrap();
+        }
+        // Overwrite a key of the first session so its live record moves
+        db.put(kv(session), vec![9; 8]).unwrap();
+    }
+
+    let db = Notus::open(dir).unwrap();
+    db.put_cf("other", kv(1), vec![1]).unwrap();
+    let by_file: Vec<_> = db.iter_by_file().map(|res| res.unwrap()).collect();
+    let by_key: Vec<_> = db.iter().map(|res| res.unwrap()).collect();
+    assert_eq!(by_file.len(), 150);
+    let mut sorted = by_file.clone();
+    sorted.sort();
+    assert_eq!(sorted, by_key);
+
+    // Entries come out file by file, front to back within each file
+    let mut location = std::collections::HashMap::new();
+    for (file_index, file_id) in db.file_ids().unwrap().iter().enumerate() {
+        for hint in db.hint_entries(file_id).unwrap() {
+            if hint.column.as_deref() == Some("$0") && !hint.deleted {
+                location.insert(hint.key, (file_index, hint.position));
+            }
+        }
+    }
+    let locations: Vec<_> = by_file.iter().map(|(key, _)| location[key]).collect();
+    assert!(locations.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn test_write_batch_columns() {
+    clean_up("_test_write_batch_columns");
+    let dir = "./testdir/_test_write_batch_columns";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put_cf("sessions", b"s1".to_vec(), vec![0]).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put_cf("users", b"alice".to_vec(), vec![1]);
+        batch.put_cf("sessions", b"s2".to_vec(), vec![2]);
+        batch.delete_cf("sessions", b"s1".to_vec());
+        batch.put(b"alice".to_vec(), vec![3]);
+        db.write_batch(batch).unwrap();
+
+        assert_eq!(db.get_cf("users", b"alice").unwrap(), Some(vec![1]));
+        assert_eq!(db.get_cf("sessions", b"s1").unwrap(), None);
+    }
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get_cf("users", b"alice").unwrap(), Some(vec![1]));
+    assert_eq!(db.get_cf("sessions", b"s1").unwrap(), None);
+    assert_eq!(db.get_cf("sessions", b"s2").unwrap(), Some(vec![2]));
+    assert_eq!(db.get(&b"alice".to_vec()).unwrap(), Some(vec![3]));
+    assert_eq!(db.iter_cf("users").count(), 1);
+    assert_eq!(db.iter_cf("sessions").count(), 1);
+}
+
+#[test]
+fn test_app_version() {
+    clean_up("_test_app_version");
+    let dir = "./testdir/_test_app_version";
+    {
+        let db = Notus::open(dir).unwrap();
+        assert_eq!(db.app_version().unwrap(), 0);
+        db.set_app_version(3).unwrap();
+        db.set_app_version(7).unwrap();
+        db.put(kv(1), kv(1)).unwrap();
+    }
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.app_version().unwrap(), 7);
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(kv(1)));
+}
+
+#[test]
+fn test_compact_keep_versions() {
+    clean_up("_test_compact_keep_versions");
+    let dir = "./testdir/_test_compact_keep_versions";
+    {
+        let db = Notus::open(dir).unwrap();
+        for version in 1..=5_u8 {
+            db.put(kv(1), vec![version]).unwrap();
+            // Write every version to disk instead of replacing it in the buffer
+            db.barrier().unwrap();
+        }
+        db.put(kv(2), vec![1]).unwrap();
+    }
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get_versions(&kv(1), 10).unwrap().len(), 5);
+    db.compact_with_options(CompactOptions::default().keep_versions(3))
+        .unwrap();
+
+    let expected = vec![vec![5], vec![4], vec![3]];
+    assert_eq!(db.get_versions(&kv(1), 10).unwrap(), expected);
+    assert_eq!(db.get_versions(&kv(1), 2).unwrap(), expected[..2].to_vec());
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![5]));
+    assert_eq!(db.get_versions(&kv(2), 10).unwrap(), vec![vec![1]]);
+    drop(db);
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![5]));
+    assert_eq!(db.get_versions(&kv(1), 10).unwrap(), expected);
+}
+
+#[test]
+fn test_get_into() {
+    clean_up("_test_get_into");
+    let db = Notus::temp("./testdir/_test_get_into").unwrap();
+    for i in 0..100_usize {
+        db.put(kv(i), vec![i as u8; i + 1]).unwrap();
+    }
+    let mut buf = Vec::new();
+
+    // Values read from the write buffer
+    for i in 0..100_usize {
+        assert!(db.get_into(&kv(i), &mut buf).unwrap());
+        assert_eq!(buf, vec![i as u8; i + 1]);
+    }
+
+    // Values read from disk, shrinking and growing the same buffer
+    db.barrier().unwrap();
+    for i in (0..100_usize).rev().chain(0..100) {
+        assert!(db.get_into(&kv(i), &mut buf).unwrap());
+        assert_eq!(buf, vec![i as u8; i + 1]);
+    }
+    assert!(buf.capacity() >= 100);
+
+    // Missing keys leave the buffer empty
+    db.delete(&kv(5)).unwrap();
+    assert!(!db.get_into(&kv(5), &mut buf).unwrap());
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_get_prefix_bytes() {
+    clean_up("_test_get_prefix_bytes");
+    let db = Notus::temp("./testdir/_test_get_prefix_bytes").unwrap();
+    let value: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    db.put(b"large".to_vec(), value.clone()).unwrap();
+    db.put(b"small".to_vec(), b"abc".to_vec()).unwrap();
+
+    // Served from the write buffer
+    assert_eq!(db.get_prefix_bytes(b"large", 16).unwrap(), Some(value[..16].to_vec()));
+
+    // Served from disk
+    db.barrier().unwrap();
+    assert_eq!(db.get_prefix_bytes(b"large", 16).unwrap(), Some(value[..16].to_vec()));
+    assert_eq!(db.get_prefix_bytes(b"small", 16).unwrap(), Some(b"abc".to_vec()));
+    assert_eq!(db.get_prefix_bytes(b"small", 0).unwrap(), Some(vec![]));
+    assert_eq!(db.get_prefix_bytes(b"missing", 16).unwrap(), None);
+}
+
+#[test]
+fn test_on_background_error() {
+    use std::sync::atomic::Ordering;
+
+    let storage = Arc::new(MemoryStorage::default());
+    let (tx, rx) = std::sync::mpsc::channel();
+    let options = NotusOptions::default().on_background_error(move |e| {
+        let _ = tx.send(matches!(e, NotusError::IOError(_)));
+    });
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    db.put(kv(1), kv(1)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert!(rx.try_recv().is_err());
+
+    storage.fail_appends.store(true, Ordering::Release);
+    db.put(kv(2), kv(2)).unwrap();
+    assert!(rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap());
+    storage.fail_appends.store(false, Ordering::Release);
+}
+
+#[test]
+fn test_emptied_column_has_no_live_keys() {
+    clean_up("_test_emptied_column_has_no_live_keys");
+    let db = Notus::temp("./testdir/_test_emptied_column_has_no_live_keys").unwrap();
+    db.create_column_family("scratch", concatenate_merge).unwrap();
+    db.create_column_family("kept", concatenate_merge).unwrap();
+    db.put(kv(1), kv(1)).unwrap();
+    db.put_cf("kept", kv(1), kv(1)).unwrap();
+    for i in 0..10_usize {
+        db.put_cf("scratch", kv(i), kv(i)).unwrap();
+    }
+    db.barrier().unwrap();
+    for i in 0..10_usize {
+        db.delete_cf("scratch", &kv(i)).unwrap();
+    }
+
+    // An emptied column has no live keys left, only records waiting to be
+    // compacted
+    let stats = db.column_stats().unwrap();
+    assert_eq!(stats["scratch"].live_keys, 0);
+    assert_eq!(stats["scratch"].value_bytes, 0);
+    assert!(stats["scratch"].dead_bytes > 0);
+    assert_eq!(stats["$0"].live_keys, 1);
+    assert_eq!(db.iter_cf("scratch").count(), 0);
+
+    // Its registration outlives the keys until it is collected
+    assert_eq!(db.list_column_families(), vec!["kept", "scratch"]);
+    assert_eq!(db.gc_columns().unwrap(), vec!["scratch"]);
+    assert_eq!(db.list_column_families(), vec!["kept"]);
+    assert!(!db.column_family_exists("scratch"));
+    assert!(db.gc_columns().unwrap().is_empty());
+}
+
+#[test]
+fn test_compaction_reads_latest_values() {
+    clean_up("_test_compaction_reads_latest_values");
+    let dir = "./testdir/_test_compaction_reads_latest_values";
+    {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..50_usize {
+            db.put(kv(i), vec![0; 16]).unwrap();
+        }
+    }
+    let db = Notus::open(dir).unwrap();
+    for i in 0..25_usize {
+        db.put(kv(i), vec![1; 16]).unwrap();
+    }
+    db.barrier().unwrap();
+    let before = db.file_ids().unwrap();
+    db.compact().unwrap();
+
+    // Read through the same handle, so the index and the open pairs must
+    // already point at the merged pair
+    let after = db.file_ids().unwrap();
+    assert!(before.iter().all(|file_id| !after.contains(file_id)));
+    for i in 0..50_usize {
+        let value = if i < 25 { vec![1; 16] } else { vec![0; 16] };
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(value));
+    }
+}
+
+#[test]
+fn test_compaction_keeps_later_writes() {
+    clean_up("_test_compaction_keeps_later_writes");
+    let dir = "./testdir/_test_compaction_keeps_later_writes";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(kv(1), kv(1)).unwrap();
+        db.put(kv(2), kv(2)).unwrap();
+    }
+    {
+        let db = Notus::open(dir).unwrap();
+        db.compact().unwrap();
+        db.delete(&kv(1)).unwrap();
+        db.put(kv(2), kv(3)).unwrap();
+    }
+    // The merged pair must not shadow writes made after the compaction
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), None);
+    assert_eq!(db.get(&kv(2)).unwrap(), Some(kv(3)));
+}
+
+#[test]
+fn test_compaction_concurrent_deletes() {
+    clean_up("_test_compaction_concurrent_deletes");
+    let dir = "./testdir/_test_compaction_concurrent_deletes";
+    let n = 2000_usize;
+    let key = |i: usize| format!("key-{:05}", i).into_bytes();
+    {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..n {
+            db.put(key(i), vec![1; 256]).unwrap();
+        }
+    }
+    {
+        let db = Notus::open(dir).unwrap();
+        let deleter = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in (0..n).step_by(2) {
+                    db.delete(&key(i)).unwrap();
+                }
+            })
+        };
+        while !deleter.is_finished() {
+            db.compact().unwrap();
+        }
+        deleter.join().unwrap();
+
+        for i in 0..n {
+            let expected = if i % 2 == 0 { None } else { Some(vec![1; 256]) };
+            assert_eq!(db.get(&key(i)).unwrap(), expected, "key {}", i);
+        }
+    }
+
+    let db = Notus::open(dir).unwrap();
+    for i in 0..n {
+        let expected = if i % 2 == 0 { None } else { Some(vec![1; 256]) };
+        assert_eq!(db.get(&key(i)).unwrap(), expected, "key {} after reopen", i);
+    }
+}
+
+#[test]
+fn test_reopen_reuses_empty_pair() {
+    clean_up("_test_reopen_reuses_empty_pair");
+    let dir = "./testdir/_test_reopen_reuses_empty_pair";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(kv(1), kv(1)).unwrap();
+    }
+    for _ in 0..50 {
+        let db = Notus::open(dir).unwrap();
+        assert!(db.file_ids().unwrap().len() <= 2);
+    }
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.file_ids().unwrap().len(), 2);
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(kv(1)));
+}
+
+#[test]
+fn test_physical_scan() {
+    use std::sync::atomic::Ordering;
+
+    clean_up("_test_physical_scan");
+    let db = Notus::temp("./testdir/_test_physical_scan").unwrap();
+    for round in 0..3_u8 {
+        for i in 0..10_usize {
+            db.put(kv(i), vec![round]).unwrap();
+        }
+        db.barrier().unwrap();
+    }
+    db.delete(&kv(9)).unwrap();
+
+    let records: Vec<_> = db.physical_scan().map(|res| res.unwrap()).collect();
+    assert_eq!(records.len(), 30);
+    let mut live: Vec<_> = records
+        .iter()
+        .filter(|(_, _, _, _, is_live)| *is_live)
+        .map(|(_, _, key, value, _)| (key.clone(), value.clone()))
+        .collect();
+    live.sort();
+    let expected: Vec<_> = (0..9_usize)
+        .map(|i| (RawKey::new(DEFAULT_INDEX, kv(i)).encode(), vec![2]))
+        .collect();
+    assert_eq!(live, expected);
+
+    // Offsets grow within each file
+    for pair in records.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            assert!(pair[0].1 < pair[1].1);
+        }
+    }
+
+    // A failed flush is reported rather than scanning as an empty store
+    let storage = Arc::new(MemoryStorage::default());
+    let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+    db.put(kv(1), kv(1)).unwrap();
+    storage.fail_appends.store(true, Ordering::Release);
+    let records: Vec<_> = db.physical_scan().collect();
+    storage.fail_appends.store(false, Ordering::Release);
+    assert_eq!(records.len(), 1);
+    assert!(records[0].is_err());
+    assert!(db.physical_scan().all(|res| res.is_ok()));
+}
+
+#[test]
+fn test_delete_range_and_compact() {
+    let storage = Arc::new(MemoryStorage::default());
+    let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+    let key = |i: usize| format!("ts-{:04}", i).into_bytes();
+    for i in 0..100_usize {
+        db.put(key(i), vec![1; 512]).unwrap();
+    }
+    db.barrier().unwrap();
+    let before = data_bytes(&storage);
+
+    assert_eq!(db.delete_range_and_compact(key(0)..key(60)).unwrap(), 60);
+    for i in 0..60_usize {
+        assert_eq!(db.get(&key(i)).unwrap(), None);
+    }
+    for i in 60..100_usize {
+        assert_eq!(db.get(&key(i)).unwrap(), Some(vec![1; 512]));
+    }
+    assert!(data_bytes(&storage) < before / 2);
+
+    // Neither the records nor their tombstones are left on disk
+    let hints: usize = db
+        .file_ids()
+        .unwrap()
+        .iter()
+        .map(|file_id| db.hint_entries(file_id).unwrap().len())
+        .sum();
+    assert_eq!(hints, 40);
+    assert_eq!(db.delete_range_and_compact(key(0)..key(60)).unwrap(), 0);
+}
+
+#[test]
+fn test_write_amplification() {
+    clean_up("_test_write_amplification");
+    let dir = "./testdir/_test_write_amplification";
+    {
+        let db = Notus::open(dir).unwrap();
+        assert_eq!(db.write_amplification().unwrap(), 0.0);
+        for round in 0..5_u8 {
+            for i in 0..100_usize {
+                db.put(kv(i), vec![round; 100]).unwrap();
+            }
+            db.barrier().unwrap();
+        }
+        let amplification = db.write_amplification().unwrap();
+        assert!(amplification > 4.9 && amplification < 5.1, "{}", amplification);
+
+        db.compact().unwrap();
+        let compacted = db.write_amplification().unwrap();
+        assert!(compacted < amplification);
+        assert!((compacted - 1.0).abs() < 0.01, "{}", compacted);
+        db.put(kv(0), vec![0; 100]).unwrap();
+        db.barrier().unwrap();
+        assert!(db.write_amplification().unwrap() > compacted);
+    }
+
+    // Files on disk count as written when the store is reopened
+    let db = Notus::open(dir).unwrap();
+    let reopened = db.write_amplification().unwrap();
+    assert!(reopened > 1.0 && reopened < 1.02, "{}", reopened);
+}
+
+#[test]
+fn test_inspect_while_open() {
+    clean_up("_test_inspect_while_open");
+    let dir = "./testdir/_test_inspect_while_open";
+    let files = || {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        files
+    };
+    let data_file = || {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.extension().is_some_and(|ext| ext == "data")
+                    && std::fs::metadata(path).unwrap().len() > 0
+            })
+            .unwrap()
+    };
+    let keys = |range: std::ops::Range<usize>| -> Vec<Vec<u8>> {
+        range
+            .map(|i| RawKey::new(DEFAULT_INDEX, kv(i)).encode())
+            .collect()
+    };
+
+    {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..20_usize {
+            db.put(kv(i), vec![i as u8; 32]).unwrap();
+        }
+        db.barrier().unwrap();
+        db.put(kv(0), vec![100; 32]).unwrap();
+        db.barrier().unwrap();
+        db.delete(&kv(19)).unwrap();
+        db.barrier().unwrap();
+
+        // The handle holds the lock all along
+        let before = files();
+        let inspection = Notus::inspect(dir).unwrap();
+        assert!(inspection.is_ok());
+        assert_eq!(inspection.keys, keys(0..19));
+        assert_eq!(inspection.orphans.len(), 2);
+        assert_eq!(files(), before);
+
+        db.put(kv(20), vec![20]).unwrap();
+        db.barrier().unwrap();
+        assert_eq!(db.get(&kv(20)).unwrap(), Some(vec![20]));
+        assert_eq!(Notus::inspect(dir).unwrap().keys.len(), 20);
+    }
+
+    // Damage the value of the last record written
+    let path = data_file();
+    let mut bytes = std::fs::read(&path).unwrap();
+    *bytes.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+    let file_id = path.file_stem().unwrap().to_string_lossy().to_string();
+    let inspection = Notus::inspect(dir).unwrap();
+    assert_eq!(inspection.corrupt_files, vec![file_id.clone()]);
+    assert!(!inspection.is_ok());
+
+    // Without hints the deletes are lost, as they would be on open
+    std::fs::remove_file(path.with_extension("hint")).unwrap();
+    let inspection = Notus::inspect(dir).unwrap();
+    assert_eq!(inspection.missing_hints, vec![file_id]);
+    assert!(inspection.keys.contains(&RawKey::new(DEFAULT_INDEX, kv(19)).encode()));
+    assert!(!path.with_extension("hint").exists());
+
+    assert!(Notus::inspect("./testdir/_test_inspect_while_open_missing").is_err());
+}
+
+#[test]
+fn test_iter_cf_yields_only_its_column() {
+    clean_up("_test_iter_cf_yields_only_its_column");
+    let dir = "./testdir/_test_iter_cf_yields_only_its_column";
+    // Names that are prefixes of each other, and keys that look like the
+    // start of another column's encoding
+    let columns = [DEFAULT_INDEX, "a", "aa", "ab", "b", "a\0"];
+    let keys: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        vec![0, 0, 0, 1, b'a'],
+        vec![0, 0, 0, 2, b'a', b'a'],
+        b"a".to_vec(),
+        b"key".to_vec(),
+        vec![0xff, 0xff],
+    ];
+    let expected = |column: &str| -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries: Vec<_> = keys
+            .iter()
+            .map(|key| (key.clone(), [column.as_bytes(), key].concat()))
+            .collect();
+        entries.sort();
+        entries
+    };
+    let check = |db: &Notus| {
+        for column in columns {
+            let entries: Vec<_> = db.iter_cf(column).map(Result::unwrap).collect();
+            assert_eq!(entries, expected(column), "column {:?}", column);
+        }
+        let entries: Vec<_> = db.iter().map(Result::unwrap).collect();
+        assert_eq!(entries, expected(DEFAULT_INDEX));
+    };
+
+    {
+        let db = Notus::open(dir).unwrap();
+        // Half written out, half still buffered
+        for (n, key) in keys.iter().rev().enumerate() {
+            for column in columns.iter().rev() {
+                db.put_cf(column, key.clone(), [column.as_bytes(), key].concat())
+                    .unwrap();
+            }
+            if n == keys.len() / 2 {
+                db.barrier().unwrap();
+            }
+        }
+        check(&db);
+    }
+    check(&Notus::open(dir).unwrap());
+}
+
+#[test]
+fn test_integrity_none() {
+    clean_up("_test_integrity_none");
+    let dir = "./testdir/_test_integrity_none";
+    let unchecked = || NotusOptions::default().integrity(IntegrityAlgo::None);
+    {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..10_usize {
+            db.put(kv(i), vec![i as u8; 64]).unwrap();
+        }
+    }
+    {
+        let db = Notus::open_with_options(dir, unchecked()).unwrap();
+        for i in 10..20_usize {
+            db.put(kv(i), vec![i as u8; 64]).unwrap();
+        }
+        db.barrier().unwrap();
+        for i in 0..20_usize {
+            assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 64]));
+        }
+    }
+
+    // Damage the value of the last record of both data files
+    let mut data_files: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == "data")
+                && std::fs::metadata(path).unwrap().len() > 0
+        })
+        .collect();
+    data_files.sort();
+    assert_eq!(data_files.len(), 2);
+    for path in data_files.iter() {
+        let mut bytes = std::fs::read(path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    // Whatever the setting, records written with a crc are still verified
+    for options in [NotusOptions::default(), unchecked()] {
+        let db = Notus::open_with_options(dir, options).unwrap();
+        let corrupt = (0..10_usize)
+            .filter(|i| matches!(db.get(&kv(*i)), Err(NotusError::CorruptValue)))
+            .count();
+        assert_eq!(corrupt, 1);
+        let damaged = (10..20_usize)
+            .filter(|i| db.get(&kv(*i)).unwrap() != Some(vec![*i as u8; 64]))
+            .count();
+        assert_eq!(damaged, 1);
+        drop(db);
+    }
+}
+
+#[test]
+fn test_pin_holds_back_file_removal() {
+    clean_up("_test_pin_holds_back_file_removal");
+    clean_up("_test_pin_holds_back_file_removal_backup");
+    let dir = "./testdir/_test_pin_holds_back_file_removal";
+    let backup = "./testdir/_test_pin_holds_back_file_removal_backup";
+    let files = |dir: &str| -> std::collections::BTreeSet<_> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect()
+    };
+
+    let db = Notus::open(dir).unwrap();
+    for i in 0..100_usize {
+        db.put(kv(i), vec![1; 32]).unwrap();
+    }
+    db.barrier().unwrap();
+    db.compact().unwrap();
+    for i in 0..50_usize {
+        db.put(kv(i), vec![2; 32]).unwrap();
+    }
+    db.barrier().unwrap();
+
+    let pinned = files(dir);
+    let pin = db.pin().unwrap();
+    db.compact().unwrap();
+    db.put(kv(100), vec![3; 32]).unwrap();
+    db.barrier().unwrap();
+    assert!(files(dir).is_superset(&pinned));
+
+    std::fs::create_dir_all(backup).unwrap();
+    for name in files(dir) {
+        std::fs::copy(
+            std::path::Path::new(dir).join(&name),
+            std::path::Path::new(backup).join(&name),
+        )
+        .unwrap();
+    }
+    drop(pin);
+    assert!(!files(dir).is_superset(&pinned));
+
+    // The store goes on compacting as usual
+    let before = files(dir);
+    db.compact().unwrap();
+    assert!(!files(dir).is_superset(&before));
+    assert_eq!(db.get(&kv(0)).unwrap(), Some(vec![2; 32]));
+
+    let restored = Notus::open(backup).unwrap();
+    for i in 0..100_usize {
+        let expected = if i < 50 { vec![2; 32] } else { vec![1; 32] };
+        assert_eq!(restored.get(&kv(i)).unwrap(), Some(expected));
+    }
+    assert_eq!(restored.get(&kv(100)).unwrap(), Some(vec![3; 32]));
+}
+
+#[test]
+fn test_compression() {
+    let storage = Arc::new(MemoryStorage::default());
+    let open = |compression| {
+        let options = NotusOptions::default().compression(compression);
+        Notus::open_with_storage(storage.clone(), options).unwrap()
+    };
+    let json = |i: usize| {
+        format!(
+            r#"{{"id":{},"tags":["notus","json","blob"],"body":"{}"}}"#,
+            i,
+            "lorem ipsum ".repeat(40)
+        )
+        .into_bytes()
+    };
+    let keys = || (0..50).chain(100..150).chain(200..250);
+
+    let db = open(Compression::Zstd);
+    for i in 0..50 {
+        db.put(kv(i), json(i)).unwrap();
+    }
+    db.put(kv(50), vec![]).unwrap();
+    db.barrier().unwrap();
+    let raw: usize = (0..50).map(|i| json(i).len()).sum();
+    assert!(storage.used() < raw as u64 / 2, "{} bytes used", storage.used());
+    drop(db);
+
+    // Records keep the codec they were written with when the setting changes
+    let db = open(Compression::Lz4);
+    for i in 100..150 {
+        db.put(kv(i), json(i)).unwrap();
+    }
+    drop(db);
+    let db = open(Compression::None);
+    for i in 200..250 {
+        db.put(kv(i), json(i)).unwrap();
+    }
+    db.put(kv(300), json(0)).unwrap();
+    db.barrier().unwrap();
+    for i in keys() {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(json(i)));
+    }
+    assert_eq!(db.get(&kv(50)).unwrap(), Some(vec![]));
+
+    // Reads that do not go through get
+    assert_eq!(db.get_prefix_bytes(&kv(0), 10).unwrap(), Some(json(0)[..10].to_vec()));
+    let mut buf = vec![];
+    assert!(db.get_into(&kv(100), &mut buf).unwrap());
+    assert_eq!(buf, json(100));
+    assert_eq!(db.values_equal(&kv(0), &kv(300)).unwrap(), Some(true));
+    assert_eq!(db.values_equal(&kv(0), &kv(100)).unwrap(), Some(false));
+
+    db.compact().unwrap();
+    for i in keys() {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(json(i)));
+    }
+    let report = db.audit().unwrap();
+    assert!(report.corrupt.is_empty() && report.unreadable.is_empty());
+}
+
+#[test]
+fn test_corrupt_compressed_value() {
+    clean_up("_test_corrupt_compressed_value");
+    let dir = "./testdir/_test_corrupt_compressed_value";
+    // Without a crc nothing catches the damage before decompressing
+    let options = || {
+        NotusOptions::default()
+            .compression(Compression::Zstd)
+            .integrity(IntegrityAlgo::None)
+    };
+    let value = "lorem ipsum ".repeat(40).into_bytes();
+    let db = Notus::open_with_options(dir, options()).unwrap();
+    db.put(kv(1), value.clone()).unwrap();
+    db.barrier().unwrap();
+    db.put(kv(2), value.clone()).unwrap();
+    drop(db);
+
+    // Damage the stored value of the last record written
+    let stored = RawKey::new(DEFAULT_INDEX, kv(2)).encode();
+    let stored_size = DataEntry::new(stored.clone(), value.clone())
+        .compressed(Compression::Zstd)
+        .unwrap()
+        .value_size() as usize;
+    let path = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "data"))
+        .unwrap();
+    let mut bytes = std::fs::read(&path).unwrap();
+    let len = bytes.len();
+    bytes[len - stored_size..].iter_mut().for_each(|byte| *byte = 0xff);
+    std::fs::write(&path, bytes).unwrap();
+
+    let db = Notus::open_with_options(dir, options()).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(value));
+    match db.get(&kv(2)) {
+        Err(NotusError::DecompressionError { key, .. }) => assert_eq!(key, stored),
+        other => panic!("expected a decompression error, got {:?}", other),
+    }
+    let errors: Vec<_> = db.iter().filter_map(|item| item.err()).collect();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0], NotusError::DecompressionError { .. }));
+}
+
+#[test]
+fn test_config() {
+    clean_up("_test_config");
+    let options = NotusOptions {
+        max_open_files: 7,
+        mmap_active_file: true,
+        compaction_schedule: Some(Schedule::Every(std::time::Duration::from_secs(3600))),
+        ..Default::default()
+    }
+    .kv_separation(true)
+    .integrity(IntegrityAlgo::None);
+    let db = Notus::open_with_options("./testdir/_test_config", options).unwrap();
+    let config = db.config();
+    assert_eq!(config.max_open_files, 7);
+    assert!(config.mmap_active_file);
+    assert_eq!(
+        config.compaction_schedule,
+        Some(Schedule::Every(std::time::Duration::from_secs(3600)))
+    );
+    assert!(config.kv_separation);
+    assert_eq!(config.integrity, IntegrityAlgo::None);
+
+    let defaults = NotusOptions::default();
+    assert_eq!(config.mmap_chunk_size, defaults.mmap_chunk_size);
+    assert_eq!(config.max_total_bytes, None);
+    assert_eq!(config.eviction_policy, defaults.eviction_policy);
+    assert_eq!(config.stale_reads, None);
+    assert_eq!(config.recovery_budget, None);
+    assert!(config.key_normalizer.is_none());
+    assert_eq!(db.clone().config().max_open_files, 7);
+
+    let storage = Arc::new(MemoryStorage::default());
+    let options = NotusOptions {
+        mmap_active_file: true,
+        ..Default::default()
+    };
+    let db = Notus::open_with_storage(storage, options).unwrap();
+    assert!(!db.config().mmap_active_file);
+    assert_eq!(db.config().integrity, IntegrityAlgo::Crc32);
+}
+
+#[test]
+fn test_sync_on_write() {
+    let storage = Arc::new(MemoryStorage::default());
+    let options = NotusOptions::default().sync_on_write(true);
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    let before = storage.syncs();
+    for i in 0..50_usize {
+        db.put(kv(i), kv(i)).unwrap();
+        assert!(storage.syncs() > before + i as u64, "put {} was not synced", i);
+    }
+    let before = storage.syncs();
+    db.delete(&kv(0)).unwrap();
+    assert!(storage.syncs() > before);
+
+    let storage = Arc::new(MemoryStorage::default());
+    let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+    let before = storage.syncs();
+    for i in 0..50_usize {
+        db.put(kv(i), kv(i)).unwrap();
+    }
+    db.delete(&kv(0)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(storage.syncs(), before);
+    // One barrier syncs all of them at once
+    db.barrier().unwrap();
+    assert!(storage.syncs() - before < 50);
+}
+
+#[test]
+fn test_flush() {
+    clean_up("_test_flush");
+    clean_up("_test_flush_crashed");
+    let dir = "./testdir/_test_flush";
+    let crashed = "./testdir/_test_flush_crashed";
+    let db = Notus::open(dir).unwrap();
+    for i in 0..50_usize {
+        db.put(kv(i), vec![i as u8; 32]).unwrap();
+    }
+    db.flush().unwrap();
+
+    // Copy the files as they are on disk right now, as a crash would leave them
+    fs_extra::dir::create_all(crashed, false).unwrap();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.file_name().unwrap() != "nutos.lock" {
+            std::fs::copy(&path, std::path::Path::new(crashed).join(path.file_name().unwrap()))
+                .unwrap();
+        }
+    }
+    drop(db);
+    let db = Notus::open(crashed).unwrap();
+    for i in 0..50_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 32]));
+    }
+    drop(db);
+
+    let storage = Arc::new(MemoryStorage::default());
+    let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+    db.put(kv(1), kv(1)).unwrap();
+    let before = storage.syncs();
+    db.flush().unwrap();
+    assert!(storage.syncs() > before);
+
+    // Dropping the last handle syncs, even while a snapshot keeps the
+    // store open
+    db.put(kv(2), kv(2)).unwrap();
+    let snapshot = db.snapshot().unwrap();
+    let before = storage.syncs();
+    drop(db);
+    assert!(storage.syncs() > before);
+    drop(snapshot);
+}
+
+#[test]
+fn test_background_flush_interval() {
+    let storage = Arc::new(MemoryStorage::default());
+    let options = NotusOptions::default()
+        .background_flush_interval(std::time::Duration::from_millis(500));
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    db.put(kv(1), kv(1)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(data_bytes(&storage), 0);
+    std::thread::sleep(std::time::Duration::from_millis(700));
+    assert!(data_bytes(&storage) > 0);
+}
+
+#[test]
+fn test_drop_stops_background_worker() {
+    clean_up("_test_drop_stops_background_worker");
+    let dir = "./testdir/_test_drop_stops_background_worker";
+    let options =
+        NotusOptions::default().background_flush_interval(std::time::Duration::from_secs(5));
+    let db = Notus::open_with_options(dir, options.clone()).unwrap();
+    db.put(kv(1), kv(1)).unwrap();
+
+    // The worker is woken up and joined, the directory is unlocked right away
+    let dropped = std::time::Instant::now();
+    drop(db);
+    let db = Notus::open_with_options(dir, options).unwrap();
+    assert!(dropped.elapsed() < std::time::Duration::from_secs(1));
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(kv(1)));
+
+    // Also once the worker was told to stop beforehand
+    db.request_shutdown();
+    drop(db);
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(kv(1)));
+}
+
+#[test]
+fn test_merge_trigger_ratio() {
+    let storage = Arc::new(MemoryStorage::default());
+    let options = NotusOptions::default().merge_trigger_ratio(3.0);
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    for round in 0..10_u8 {
+        for i in 0..100_usize {
+            db.put(kv(i), vec![round; 64]).unwrap();
+        }
+        db.barrier().unwrap();
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while db.write_amplification().unwrap() >= 3.0 {
+        assert!(std::time::Instant::now() < deadline, "no compaction was triggered");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    for i in 0..100_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![9; 64]));
+    }
+}
+
+#[test]
+fn test_manifest_ignores_stray_files() {
+    clean_up("_test_manifest_ignores_stray_files");
+    let dir = "./testdir/_test_manifest_ignores_stray_files";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(kv(1), kv(1)).unwrap();
+    }
+
+    // A pair left behind by something other than the store, e.g. a copy
+    // interrupted half way, holding a newer value of the key. Both stores
+    // number their files from the same first id, so the copy is renamed
+    let stray = Notus::open("./testdir/_test_manifest_ignores_stray_files_src").unwrap();
+    stray.put(kv(1), kv(2)).unwrap();
+    stray.put(kv(2), kv(2)).unwrap();
+    drop(stray);
+    let mut stray_ids = vec![];
+    for entry in std::fs::read_dir("./testdir/_test_manifest_ignores_stray_files_src").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "data") {
+            let stray_id = (9_000_000_000_000_000_000_u64 + stray_ids.len() as u64).to_string();
+            std::fs::copy(&path, std::path::Path::new(dir).join(format!("{}.data", stray_id)))
+                .unwrap();
+            stray_ids.push(stray_id);
+        }
+    }
+    clean_up("_test_manifest_ignores_stray_files_src");
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(kv(1)));
+    assert_eq!(db.get(&kv(2)).unwrap(), None);
+    let file_ids = db.file_ids().unwrap();
+    for stray_id in stray_ids {
+        assert!(!file_ids.contains(&stray_id));
+        // Ignored files are not touched, not even to rebuild their hints
+        assert!(!std::path::Path::new(dir).join(format!("{}.hint", stray_id)).exists());
+    }
+}
+
+#[test]
+fn test_missing_manifest_falls_back_to_listing() {
+    clean_up("_test_missing_manifest_falls_back_to_listing");
+    let dir = "./testdir/_test_missing_manifest_falls_back_to_listing";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(kv(1), kv(1)).unwrap();
+    }
+    std::fs::remove_file(std::path::Path::new(dir).join("MANIFEST")).unwrap();
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(kv(1)));
+}
+
+#[test]
+fn test_truncated_tail_recovery() {
+    clean_up("_test_truncated_tail_recovery");
+    let dir = "./testdir/_test_truncated_tail_recovery";
+    let file = |ext: &str| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.extension().is_some_and(|e| e == ext)
+                    && std::fs::metadata(path).unwrap().len() > 0
+            })
+            .unwrap()
+    };
+    let cut = |path: &std::path::Path, bytes: u64| {
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - bytes).unwrap();
+    };
+    let len = |path: &std::path::Path| std::fs::metadata(path).unwrap().len();
+
+    {
+        let db = Notus::open(dir).unwrap();
+        // Flushed one at a time so the records are in key order
+        for i in 0..10_usize {
+            db.put(kv(i), vec![i as u8; 32]).unwrap();
+            db.barrier().unwrap();
+        }
+    }
+
+    // A crash while the last hint was appended
+    let (hint_file, data_file) = (file("hint"), file("data"));
+    let (hint_len, data_len) = (len(&hint_file), len(&data_file));
+    cut(&hint_file, 3);
+    {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..9_usize {
+            assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 32]));
+        }
+        assert_eq!(db.get(&kv(9)).unwrap(), None);
+        // The torn hint and the record it pointed at are gone from disk
+        assert_eq!(len(&hint_file), hint_len / 10 * 9);
+        assert!(len(&data_file) < data_len);
+        db.put(kv(9), vec![9; 32]).unwrap();
+    }
+
+    let db = Notus::open(dir).unwrap();
+    for i in 0..10_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 32]));
+    }
+    drop(db);
+
+    // A crash after the hints were written but before the data file was
+    // synced, so the last hint points past its end
+    let data_len = len(&data_file);
+    let hint_len = len(&hint_file);
+    cut(&data_file, 3);
+    let db = Notus::open(dir).unwrap();
+    for i in 0..8_usize {
+        assert_eq!(db.get(&kv(i)).unwrap(), Some(vec![i as u8; 32]));
+    }
+    assert_eq!(db.get(&kv(8)).unwrap(), None);
+    assert_eq!(db.get(&kv(9)).unwrap(), Some(vec![9; 32]));
+    assert!(len(&data_file) < data_len - 3);
+    assert!(len(&hint_file) < hint_len);
+}
+
+#[test]
+fn test_get_state() {
+    clean_up("_test_get_state");
+    let dir = "./testdir/_test_get_state";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(kv(1), kv(1)).unwrap();
+        db.put(kv(2), kv(2)).unwrap();
+        db.delete(&kv(2)).unwrap();
+
+        assert_eq!(db.get_state(&kv(1)).unwrap(), KeyState::Present(kv(1)));
+        assert_eq!(db.get_state(&kv(2)).unwrap(), KeyState::Deleted);
+        assert_eq!(db.get_state(&kv(3)).unwrap(), KeyState::Absent);
+    }
+
+    // Tombstones are found again on reopen and dropped by compaction
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get_state(&kv(2)).unwrap(), KeyState::Deleted);
+    assert_eq!(db.get_state(&kv(3)).unwrap(), KeyState::Absent);
+    db.put(kv(2), kv(4)).unwrap();
+    assert_eq!(db.get_state(&kv(2)).unwrap(), KeyState::Present(kv(4)));
+    db.delete(&kv(2)).unwrap();
+    assert_eq!(db.get_state(&kv(2)).unwrap(), KeyState::Deleted);
+    db.compact().unwrap();
+    assert_eq!(db.get_state(&kv(2)).unwrap(), KeyState::Absent);
+    assert_eq!(db.get_state(&kv(1)).unwrap(), KeyState::Present(kv(1)));
+}
+
+#[test]
+fn test_reserve_and_fulfill() {
+    clean_up("_test_reserve_and_fulfill");
+    let db = Arc::new(Notus::temp("./testdir/_test_reserve_and_fulfill").unwrap());
+    let start = Arc::new(std::sync::Barrier::new(N_THREADS));
+    let producers: Vec<_> = (0..N_THREADS)
+        .map(|t| {
+            let db = db.clone();
+            let start = start.clone();
+            std::thread::spawn(move || {
+                start.wait();
+                if !db.reserve(b"job").unwrap() {
+                    return false;
+                }
+                assert_eq!(db.get_state(b"job").unwrap(), KeyState::Reserved);
+                assert_eq!(db.get(&b"job".to_vec()).unwrap(), None);
+                db.fulfill(b"job", vec![t as u8]).unwrap();
+                true
+            })
+        })
+        .collect();
+    let winners: Vec<usize> = producers
+        .into_iter()
+        .enumerate()
+        .filter_map(|(t, producer)| producer.join().unwrap().then_some(t))
+        .collect();
+    assert_eq!(winners.len(), 1);
+    assert_eq!(db.get(&b"job".to_vec()).unwrap(), Some(vec![winners[0] as u8]));
+    assert_eq!(
+        db.get_state(b"job").unwrap(),
+        KeyState::Present(vec![winners[0] as u8])
+    );
+
+    // A key holding a value can not be reserved, nor fulfilled unreserved
+    assert!(!db.reserve(b"job").unwrap());
+    assert!(matches!(
+        db.fulfill(b"other", vec![1]),
+        Err(NotusError::NotReserved)
+    ));
+    assert_eq!(db.get(&b"other".to_vec()).unwrap(), None);
+    assert_eq!(db.iter().count(), 1);
+}
+
+#[test]
+fn test_freeze() {
+    clean_up("_test_freeze");
+    let db = Notus::open("./testdir/_test_freeze").unwrap();
+    for i in 0..200_usize {
+        db.put(kv(i), vec![i as u8; 8]).unwrap();
+        db.put_cf("other", kv(i), vec![1]).unwrap();
+    }
+    for i in (0..200_usize).step_by(3) {
+        db.delete(&kv(i)).unwrap();
+    }
+    // Left in the write buffer, freeze must flush it
+    db.put(kv(5), vec![5; 16]).unwrap();
+
+    let gets: Vec<_> = (0..210_usize).map(|i| db.get(&kv(i)).unwrap()).collect();
+    let all: Vec<_> = db.iter().map(|item| item.unwrap()).collect();
+    let range: Vec<_> = db.range(kv(20)..kv(80)).map(|item| item.unwrap()).collect();
+    let reversed: Vec<_> = db.range(kv(20)..=kv(80)).rev().map(|item| item.unwrap()).collect();
+
+    let frozen = db.freeze().unwrap();
+    assert_eq!(frozen.len(), all.len() + 200);
+    for (i, expected) in gets.iter().enumerate() {
+        assert_eq!(&frozen.get(&kv(i)).unwrap(), expected);
+    }
+    assert_eq!(frozen.get_cf("other", &kv(0)).unwrap(), Some(vec![1]));
+    assert_eq!(frozen.iter().map(|item| item.unwrap()).collect::<Vec<_>>(), all);
+    assert_eq!(
+        frozen.range(kv(20)..kv(80)).map(|item| item.unwrap()).collect::<Vec<_>>(),
+        range
+    );
+    assert_eq!(
+        frozen.range(kv(20)..=kv(80)).rev().map(|item| item.unwrap()).collect::<Vec<_>>(),
+        reversed
+    );
+}
+
+#[test]
+fn test_empty_values() {
+    clean_up("_test_empty_values");
+    let dir = "./testdir/_test_empty_values";
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(kv(1), vec![]).unwrap();
+        assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![]));
+        assert!(db.contains(&kv(1)).unwrap());
+        db.barrier().unwrap();
+        assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![]));
+        let mut buf = vec![1, 2, 3];
+        assert!(db.get_into(&kv(1), &mut buf).unwrap());
+        assert!(buf.is_empty());
+        assert_eq!(db.get_prefix_bytes(&kv(1), 4).unwrap(), Some(vec![]));
+    }
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![]));
+    assert!(db.contains(&kv(1)).unwrap());
+    assert_eq!(db.iter().map(|item| item.unwrap()).collect::<Vec<_>>(), vec![(kv(1), vec![])]);
+    assert_eq!(db.get(&kv(2)).unwrap(), None);
+    assert!(!db.contains(&kv(2)).unwrap());
+    db.compact().unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![]));
+}
+
+#[test]
+fn test_compaction_stops_on_shutdown() {
+    clean_up("_test_compaction_stops_on_shutdown");
+    let dir = "./testdir/_test_compaction_stops_on_shutdown";
+    let key = |i: usize| format!("key-{:06}", i).into_bytes();
+    let pairs = 20_usize;
+    let per_pair = 1000_usize;
+    for p in 0..pairs {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..per_pair {
+            db.put(key(p * per_pair + i), vec![p as u8; 128]).unwrap();
+        }
+    }
+
+    let db = Notus::open(dir).unwrap();
+    let before = db.file_ids().unwrap().len();
+    let compaction = {
+        let db = db.clone();
+        std::thread::spawn(move || db.compact())
+    };
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let requested = std::time::Instant::now();
+    db.request_shutdown();
+    compaction.join().unwrap().unwrap();
+    assert!(requested.elapsed() < std::time::Duration::from_millis(500));
+
+    // Pairs not reached were left alone, nothing was lost
+    let after = db.file_ids().unwrap().len();
+    assert!(after > 2, "compaction ran to completion, {} of {} pairs", after, before);
+    for i in 0..pairs * per_pair {
+        assert_eq!(db.get(&key(i)).unwrap(), Some(vec![(i / per_pair) as u8; 128]));
+    }
+    drop(db);
+
+    let db = Notus::open(dir).unwrap();
+    for i in 0..pairs * per_pair {
+        assert_eq!(db.get(&key(i)).unwrap(), Some(vec![(i / per_pair) as u8; 128]));
+    }
+    db.compact().unwrap();
+    assert_eq!(db.file_ids().unwrap().len(), 2);
+    assert_eq!(db.iter().count(), pairs * per_pair);
+}
+
+#[test]
+fn test_compact_after_shutdown_request() {
+    clean_up("_test_compact_after_shutdown_request");
+    let dir = "./testdir/_test_compact_after_shutdown_request";
+    for p in 0..5_u8 {
+        let db = Notus::open(dir).unwrap();
+        db.put(vec![p], vec![p; 16]).unwrap();
+    }
+
+    let db = Notus::open(dir).unwrap();
+    db.request_shutdown();
+    for _ in 0..10 {
+        db.compact().unwrap();
+        assert_eq!(db.file_ids().unwrap().len(), 2);
+    }
+    db.compact_with_options(CompactOptions::default()).unwrap();
+    assert_eq!(db.file_ids().unwrap().len(), 2);
+    for p in 0..5_u8 {
+        assert_eq!(db.get(&vec![p]).unwrap(), Some(vec![p; 16]));
+    }
+}
+
+#[test]
+fn test_bulk_load_sorted() {
+    clean_up("_test_bulk_load_sorted");
+    let dir = "./testdir/_test_bulk_load_sorted";
+    let key = |i: usize| format!("key-{:06}", i).into_bytes();
+    let count = 100_000_usize;
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(key(7), b"old".to_vec()).unwrap();
+        let loaded = db
+            .bulk_load_sorted((0..count).map(|i| (key(i), i.to_le_bytes().to_vec())))
+            .unwrap();
+        assert_eq!(loaded, count);
+        assert_eq!(db.get(&key(7)).unwrap(), Some(7_usize.to_le_bytes().to_vec()));
+        assert_eq!(db.get(&key(count - 1)).unwrap(), Some((count - 1).to_le_bytes().to_vec()));
+        assert_eq!(db.iter().count(), count);
+        let amplification = db.write_amplification().unwrap();
+
+        let unsorted = vec![
+            (b"zz-1".to_vec(), b"a".to_vec()),
+            (b"zz-3".to_vec(), b"b".to_vec()),
+            (b"zz-2".to_vec(), b"c".to_vec()),
+        ];
+        match db.bulk_load_sorted(unsorted.into_iter()) {
+            Err(NotusError::UnsortedInput(key)) => assert_eq!(key, b"zz-2".to_vec()),
+            other => panic!("expected UnsortedInput, got {:?}", other),
+        }
+        let duplicate = vec![
+            (b"zz-1".to_vec(), b"a".to_vec()),
+            (b"zz-1".to_vec(), b"b".to_vec()),
+        ];
+        assert!(matches!(
+            db.bulk_load_sorted(duplicate.into_iter()),
+            Err(NotusError::UnsortedInput(_))
+        ));
+        assert_eq!(db.get(&b"zz-1".to_vec()).unwrap(), None);
+        // Rejected loads are not counted as written
+        assert_eq!(db.write_amplification().unwrap(), amplification);
+        db.barrier().unwrap();
+        // The earlier buffered write must not be flushed over the load
+        assert_eq!(db.get(&key(7)).unwrap(), Some(7_usize.to_le_bytes().to_vec()));
+    }
+
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.iter().count(), count);
+    assert_eq!(db.get(&key(7)).unwrap(), Some(7_usize.to_le_bytes().to_vec()));
+    assert_eq!(db.get(&key(54_321)).unwrap(), Some(54_321_usize.to_le_bytes().to_vec()));
+    assert_eq!(db.get(&b"zz-1".to_vec()).unwrap(), None);
+    assert_eq!(db.get(&b"zz-3".to_vec()).unwrap(), None);
+}
+
+#[test]
+fn test_kv_separation() {
+    let storage = Arc::new(MemoryStorage::default());
+    let options = || NotusOptions::default().kv_separation(true);
+    let value = |i: usize, version: u8| vec![version; 4096 + i];
+    let vlog_size = |storage: &MemoryStorage| -> u64 {
+        storage
+            .list()
+            .unwrap()
+            .iter()
+            .filter(|name| name.ends_with(".vlog"))
+            .map(|name| storage.open(name).unwrap().len().unwrap())
+            .sum()
+    };
+    {
+        let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+        for i in 0..100_usize {
+            db.put(kv(i), value(i, 1)).unwrap();
+        }
+        db.barrier().unwrap();
+        for i in 0..50_usize {
+            db.put(kv(i), value(i, 2)).unwrap();
+        }
+        for i in 90..100_usize {
+            db.delete(&kv(i)).unwrap();
+        }
+        assert_eq!(db.get(&kv(0)).unwrap(), Some(value(0, 2)));
+        assert_eq!(db.get(&kv(60)).unwrap(), Some(value(60, 1)));
+        assert_eq!(db.get(&kv(95)).unwrap(), None);
+        let mut buf = vec![];
+        assert!(db.get_into(&kv(70), &mut buf).unwrap());
+        assert_eq!(buf, value(70, 1));
+        assert_eq!(db.get_prefix_bytes(&kv(10), 3).unwrap(), Some(vec![2; 3]));
+        assert_eq!(db.get_versions(&kv(10), 2).unwrap(), vec![value(10, 2), value(10, 1)]);
+        assert_eq!(db.iter().count(), 90);
+    }
+
+    let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+    // Data files only hold keys and pointers
+    let data_size: u64 = storage
+        .list()
+        .unwrap()
+        .iter()
+        .filter(|name| name.ends_with(".data"))
+        .map(|name| storage.open(name).unwrap().len().unwrap())
+        .sum();
+    assert!(data_size < 160 * 100);
+
+    // Key scans and compaction never touch the value log
+    let vlog_read = storage.bytes_read(".vlog");
+    assert_eq!(db.keys().collect::<Vec<_>>(), (0..90_usize).map(kv).collect::<Vec<_>>());
+    db.compact().unwrap();
+    assert_eq!(db.keys().count(), 90);
+    assert_eq!(storage.bytes_read(".vlog"), vlog_read);
+
+    // Only the 90 live values are kept, 60 overwritten or deleted ones go
+    let before = vlog_size(&storage);
+    let reclaimed = db.collect_value_log().unwrap();
+    assert!(reclaimed >= 60 * 4096);
+    assert_eq!(vlog_size(&storage), before - reclaimed);
+    assert!(vlog_size(&storage) < 91 * (4096 + 100 + 64));
+    for i in 0..90_usize {
+        let version = if i < 50 { 2 } else { 1 };
+        assert!(db.get(&kv(i)).unwrap() == Some(value(i, version)));
+    }
+    assert!(db.get_versions(&kv(10), 2).unwrap() == vec![value(10, 2)]);
+    // Only the value overwritten since is collected the second time
+    db.put(kv(0), value(0, 3)).unwrap();
+    let reclaimed = db.collect_value_log().unwrap();
+    assert!((4096..4096 + 64).contains(&reclaimed));
+    drop(db);
+
+    let db = Notus::open_with_storage(storage.clone(), options()).unwrap();
+    assert!(db.get(&kv(0)).unwrap() == Some(value(0, 3)));
+    for i in 1..90_usize {
+        let version = if i < 50 { 2 } else { 1 };
+        assert!(db.get(&kv(i)).unwrap() == Some(value(i, version)));
+    }
+    assert_eq!(db.get(&kv(95)).unwrap(), None);
+    assert_eq!(db.iter().count(), 90);
+}
+
+#[test]
+fn test_merge_propagates_write_errors() {
+    use std::sync::atomic::Ordering;
+
+    let storage = Arc::new(MemoryStorage::default());
+    // Values are appended to the value log as they are put, so a failing
+    // disk fails the put as well as the delete
+    let options = NotusOptions::default().kv_separation(true);
+    let db = Notus::open_with_storage(storage.clone(), options).unwrap();
+    db.put(kv(1), vec![1]).unwrap();
+    db.barrier().unwrap();
+
+    storage.fail_appends.store(true, Ordering::Release);
+    let put_result = db.merge(concatenate_merge, kv(1), vec![2]);
+    assert!(matches!(put_result, Err(NotusError::IOError(_))));
+    let delete_result = db.merge(|_: &[u8], _: Option<Vec<u8>>, _: &[u8]| None, kv(1), vec![]);
+    assert!(matches!(delete_result, Err(NotusError::IOError(_))));
+    storage.fail_appends.store(false, Ordering::Release);
+
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![1]));
+    db.merge(concatenate_merge, kv(1), vec![2]).unwrap();
+    assert_eq!(db.get(&kv(1)).unwrap(), Some(vec![1, 2]));
+}
+
+fn balance(value: Option<Vec<u8>>) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&value.unwrap());
+    u64::from_le_bytes(bytes)
+}
+
+fn transfer(db: &Notus, from: &[u8], to: &[u8], amount: u64) -> Result<bool, NotusError> {
+    db.atomically(|view| {
+        let from_balance = balance(view.get(from)?);
+        if from_balance < amount {
+            return Ok(false);
+        }
+        let to_balance = balance(view.get(to)?);
+        view.put(from.to_vec(), (from_balance - amount).to_le_bytes().to_vec());
+        view.put(to.to_vec(), (to_balance + amount).to_le_bytes().to_vec());
+        Ok(true)
+    })
+}
+
+#[test]
+fn test_atomically() {
+    clean_up("_test_atomically");
+    let db = Notus::temp("./testdir/_test_atomically").unwrap();
+    db.put(b"alice".to_vec(), 100_u64.to_le_bytes().to_vec()).unwrap();
+    db.put(b"bob".to_vec(), 50_u64.to_le_bytes().to_vec()).unwrap();
+
+    assert!(transfer(&db, b"alice", b"bob", 30).unwrap());
+    assert!(!transfer(&db, b"bob", b"alice", 1000).unwrap());
+    assert_eq!(balance(db.get(&b"alice".to_vec()).unwrap()), 70);
+    assert_eq!(balance(db.get(&b"bob".to_vec()).unwrap()), 80);
+
+    // Writes are visible to later reads in the same closure
+    let seen = db
+        .atomically(|view| {
+            view.put(b"carol".to_vec(), vec![1]);
+            let seen = view.get(b"carol")?;
+            view.delete(b"carol");
+            assert_eq!(view.get(b"carol")?, None);
+            Ok(seen)
+        })
+        .unwrap();
+    assert_eq!(seen, Some(vec![1]));
+    assert_eq!(db.get(&b"carol".to_vec()).unwrap(), None);
+
+    // An error drops everything the closure wrote
+    let result: Result<(), NotusError> = db.atomically(|view| {
+        view.put(b"alice".to_vec(), 0_u64.to_le_bytes().to_vec());
+        view.delete(b"bob");
+        Err(NotusError::Unknown)
+    });
+    assert!(result.is_err());
+    assert_eq!(balance(db.get(&b"alice".to_vec()).unwrap()), 70);
+    assert_eq!(balance(db.get(&b"bob".to_vec()).unwrap()), 80);
+}
+
+#[test]
+fn test_try_put() {
+    clean_up("_test_try_put");
+    let db = Arc::new(Notus::temp("./testdir/_test_try_put").unwrap());
+    let (entered, wait_entered) = std::sync::mpsc::channel();
+    let holder = {
+        let db = db.clone();
+        std::thread::spawn(move || {
+            db.atomically(|_| {
+                entered.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                Ok(())
+            })
+            .unwrap();
+        })
+    };
+    wait_entered.recv().unwrap();
+    let started = std::time::Instant::now();
+    assert!(!db.try_put(b"a".to_vec(), vec![1]).unwrap());
+    assert!(started.elapsed() < std::time::Duration::from_millis(100));
+    holder.join().unwrap();
+    assert_eq!(db.get(&b"a".to_vec()).unwrap(), None);
+
+    // The background flush may hold the lock for a moment
+    while !db.try_put(b"a".to_vec(), vec![2]).unwrap() {}
+    assert_eq!(db.get(&b"a".to_vec()).unwrap(), Some(vec![2]));
+}
+
+#[test]
+fn test_atomically_concurrent_transfers() {
+    clean_up("_test_atomically_concurrent_transfers");
+    let db = Arc::new(Notus::temp("./testdir/_test_atomically_concurrent_transfers").unwrap());
+    const ACCOUNTS: usize = 8;
+    const TOTAL: u64 = ACCOUNTS as u64 * 1000;
+    for i in 0..ACCOUNTS {
+        db.put(kv(i), 1000_u64.to_le_bytes().to_vec()).unwrap();
+    }
+
+    let writers: Vec<_> = (0..N_THREADS)
+        .map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 0..N_PER_THREAD * 5 {
+                    let from = (t + i) % ACCOUNTS;
+                    let to = (t * 3 + i * 7 + 1) % ACCOUNTS;
+                    if from != to {
+                        transfer(&db, &kv(from), &kv(to), (i % 13) as u64 * 10).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    let sum = db
+                        .atomically(|view| {
+                            let mut sum = 0;
+                            for i in 0..ACCOUNTS {
+                                sum += balance(view.get(&kv(i))?);
+                            }
+                            Ok(sum)
+                        })
+                        .unwrap();
+                    assert_eq!(sum, TOTAL);
+                }
+            })
+        })
+        .collect();
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().unwrap();
+    }
+
+    let sum: u64 = (0..ACCOUNTS).map(|i| balance(db.get(&kv(i)).unwrap())).sum();
+    assert_eq!(sum, TOTAL);
+}
+
+#[test]
+fn test_compact_tiered() {
+    clean_up("_test_compact_tiered");
+    let dir = "./testdir/_test_compact_tiered";
+    let data_files = || {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                entry.as_ref().unwrap().path().extension() == Some("data".as_ref())
+            })
+            .count()
+    };
+    {
+        let db = Notus::open(dir).unwrap();
+        db.put(b"large".to_vec(), vec![7; 32 * 1024]).unwrap();
+    }
+    for session in 0..20_usize {
+        let db = Notus::open(dir).unwrap();
+        for i in 0..10 {
+            db.put(kv(session * 10 + i), vec![session as u8; 100]).unwrap();
+        }
+        if session == 5 {
+            db.delete(&b"large".to_vec()).unwrap();
+        }
+        drop(db);
+    }
+    {
+        let db = Notus::open(dir).unwrap();
+        let before = data_files();
+        assert!(before > 20, "{}", before);
+        db.compact_tiered(16 * 1024).unwrap();
+        let after = data_files();
+        assert!(after <= 4, "{} -> {}", before, after);
+        for session in 0..20_usize {
+            for i in 0..10 {
+                assert_eq!(
+                    db.get(&kv(session * 10 + i)).unwrap(),
+                    Some(vec![session as u8; 100])
+                );
+            }
+        }
+        assert_eq!(db.get(&b"large".to_vec()).unwrap(), None);
+    }
+
+    // The large file kept its record, the tombstone was carried over
+    let db = Notus::open(dir).unwrap();
+    assert_eq!(db.get(&b"large".to_vec()).unwrap(), None);
+    assert_eq!(db.get(&kv(0)).unwrap(), Some(vec![0; 100]));
+    assert_eq!(db.get(&kv(199)).unwrap(), Some(vec![19; 100]));
+}
+
+#[test]
+fn test_get_timeout() {
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    let readers_started = || TIMED_READERS_STARTED.with(|started| started.get());
+    let before = readers_started();
+    let storage = Arc::new(MemoryStorage::default());
+    let db = Notus::open_with_storage(storage.clone(), NotusOptions::default()).unwrap();
+    db.put(kv(1), vec![1]).unwrap();
+    db.barrier().unwrap();
+    assert_eq!(
+        db.get_timeout(&kv(1), Duration::from_secs(1)).unwrap(),
+        Some(vec![1])
+    );
+
+    storage.read_delay.store(500, Ordering::Release);
+    let started = Instant::now();
+    let result = db.get_timeout(&kv(1), Duration::from_millis(50));
+    assert!(matches!(result, Err(NotusError::Timeout)), "{:?}", result);
+    assert!(started.elapsed() < Duration::from_millis(400));
+
+    // A deadline longer than the read still returns the value
+    assert_eq!(
+        db.get_timeout(&kv(1), Duration::from_secs(5)).unwrap(),
+        Some(vec![1])
+    );
+
+    // Stuck reads take up a bounded number of threads, which let go of
+    // the store once the last handle is dropped
+    for _ in 0..20 {
+        let result = db.get_timeout(&kv(1), Duration::from_millis(5));
+        assert!(matches!(result, Err(NotusError::Timeout)), "{:?}", result);
+    }
+    assert_eq!(readers_started(), before + 4);
+    drop(db);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Arc::strong_count(&storage) > 1 {
+        assert!(Instant::now() < deadline, "the store was not released");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(deadline - Instant::now() > Duration::from_secs(3));
+    storage.read_delay.store(0, Ordering::Release);
+}
+
+#[test]
+fn test_retain() {
+    clean_up("_test_retain");
+    let db = Notus::temp("./testdir/_test_retain").unwrap();
+    for i in 0..100_u8 {
+        db.put(vec![i], vec![i, 0, 0]).unwrap();
+        db.put_cf("other", vec![i], vec![i]).unwrap();
+    }
+    db.barrier().unwrap();
+    // Keys spread over more than one file and partly still buffered
+    for i in 0..50_u8 {
+        db.put(vec![i], vec![i, 1]).unwrap();
+    }
+
+    let removed = db.retain(|_, value| value[0] % 2 == 0).unwrap();
+    assert_eq!(removed, 50);
+    for i in 0..100_u8 {
+        let expected = match i {
+            i if i % 2 == 1 => None,
+            i if i < 50 => Some(vec![i, 1]),
+            i => Some(vec![i, 0, 0]),
+        };
+        assert_eq!(db.get(&vec![i]).unwrap(), expected);
+        assert_eq!(db.get_cf("other", &[i]).unwrap(), Some(vec![i]));
+    }
+    assert_eq!(db.retain(|_, value| value[0] % 2 == 0).unwrap(), 0);
+
+    let removed = db.retain_cf("other", |key, _| key[0] < 10).unwrap();
+    assert_eq!(removed, 90);
+    assert_eq!(db.keys_cf("other").count(), 10);
+    assert_eq!(db.keys().count(), 50);
+}
+
+#[test]
+fn test_prepared_batch_survives_crash() {
+    clean_up("_test_prepared_batch");
+    clean_up("_test_prepared_batch_crashed");
+    let db = Notus::temp("./testdir/_test_prepared_batch").unwrap();
+    db.put(b"k0".to_vec(), vec![0]).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"a".to_vec(), vec![1]);
+    batch.put_cf("other", b"b".to_vec(), vec![2]);
+    batch.delete(b"k0".to_vec());
+    db.prepare("txn-1", batch).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"c".to_vec(), vec![3]);
+    db.prepare("txn-2", batch.clone()).unwrap();
+    assert!(matches!(db.prepare("txn-2", batch), Err(NotusError::Conflict)));
+    db.barrier().unwrap();
+    assert_eq!(db.get(&b"a".to_vec()).unwrap(), None);
+    assert_eq!(db.get(&b"k0".to_vec()).unwrap(), Some(vec![0]));
+
+    // Copy the files as they are on disk right now, as a crash would leave them
+    fs_extra::dir::create_all("./testdir/_test_prepared_batch_crashed", false).unwrap();
+    for entry in std::fs::read_dir("./testdir/_test_prepared_batch").unwrap() {
+        let path = entry.unwrap().path();
+        if path.file_name().unwrap() != "nutos.lock" {
+            let target = std::path::Path::new("./testdir/_test_prepared_batch_crashed")
+                .join(path.file_name().unwrap());
+            std::fs::copy(&path, target).unwrap();
+        }
+    }
+    drop(db);
+
+    let dir = "./testdir/_test_prepared_batch_crashed";
+    {
+        let crashed = Notus::open(dir).unwrap();
+        let prepared = crashed.prepared().unwrap();
+        let ids: Vec<_> = prepared.iter().map(|batch| batch.id().to_string()).collect();
+        assert_eq!(ids, vec!["txn-1", "txn-2"]);
+        assert_eq!(crashed.get(&b"a".to_vec()).unwrap(), None);
+
+        let mut prepared = prepared.into_iter();
+        prepared.next().unwrap().commit().unwrap();
+        prepared.next().unwrap().abort().unwrap();
+        assert_eq!(crashed.get(&b"a".to_vec()).unwrap(), Some(vec![1]));
+        assert_eq!(crashed.get_cf("other", b"b").unwrap(), Some(vec![2]));
+        assert_eq!(crashed.get(&b"k0".to_vec()).unwrap(), None);
+        assert_eq!(crashed.get(&b"c".to_vec()).unwrap(), None);
+        assert!(crashed.prepared().unwrap().is_empty());
+    }
+
+    let reopened = Notus::open(dir).unwrap();
+    assert!(reopened.prepared().unwrap().is_empty());
+    assert_eq!(reopened.get(&b"a".to_vec()).unwrap(), Some(vec![1]));
+    assert_eq!(reopened.get(&b"k0".to_vec()).unwrap(), None);
+    assert_eq!(reopened.get(&b"c".to_vec()).unwrap(), None);
+}